@@ -0,0 +1,2 @@
+#!/bin/sh
+echo "hello from $(basename "$0") with $1"
@@ -0,0 +1,117 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+use anyhow::*;
+use serde::{Deserialize, Serialize};
+
+use crate::flags::*;
+use crate::metric_value::*;
+
+/// What an assertion's limits are compared against.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Over {
+    /// Every iteration's value on its own.
+    Iteration,
+    /// The mean over all iterations.
+    Mean,
+}
+
+impl Over {
+    pub(crate) fn parse(value: &str) -> Option<Self> {
+        match value {
+            "iteration" => Some(Over::Iteration),
+            "mean" => Some(Over::Mean),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Assertion {
+    metric: String,
+    min: Option<f64>,
+    max: Option<f64>,
+    over: Over,
+}
+
+/// Limits on metrics that make the run fail when crossed, as given in the
+/// `assertions` config key. They apply to any metric in the iterations,
+/// including ones sent over statsd by the tested program.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub(crate) struct Assertions {
+    rules: Vec<Assertion>,
+}
+
+impl Assertions {
+    pub(crate) fn add(
+        &mut self,
+        metric: &str,
+        min: Option<f64>,
+        max: Option<f64>,
+        over: Over,
+    ) -> Result<()> {
+        ensure!(min.is_some() || max.is_some(), "needs a 'min' or a 'max'");
+        self.rules.push(Assertion {
+            metric: metric.to_owned(),
+            min,
+            max,
+            over,
+        });
+        Ok(())
+    }
+
+    /// Returns a description of every limit crossed. Failed iterations are
+    /// left out, since they fail the run anyway.
+    pub(crate) fn check(&self, iterations: &[MetricValue]) -> Vec<String> {
+        let mut failures = Vec::new();
+        for rule in &self.rules {
+            let values: Vec<(usize, f64)> = iterations
+                .iter()
+                .enumerate()
+                .filter_map(|(i, iteration)| Some((i, iteration.as_map().ok()?)))
+                .filter(|(_, iteration)| !is_failed(iteration))
+                .filter_map(|(i, iteration)| match get_nested(iteration, &rule.metric) {
+                    Some(MetricValue::Num(value)) => Some((i, *value)),
+                    _ => None,
+                })
+                .collect();
+            if values.is_empty() {
+                failures.push(format!("{} was never reported", rule.metric));
+                continue;
+            }
+            match rule.over {
+                Over::Iteration => {
+                    for (i, value) in values {
+                        if let Some(limit) = rule.crossed(value) {
+                            failures.push(format!(
+                                "{} was {} in iteration {}, {}",
+                                rule.metric, value, i, limit
+                            ));
+                        }
+                    }
+                }
+                Over::Mean => {
+                    let mean =
+                        values.iter().map(|(_, value)| value).sum::<f64>() / values.len() as f64;
+                    if let Some(limit) = rule.crossed(mean) {
+                        failures.push(format!("mean {} was {}, {}", rule.metric, mean, limit));
+                    }
+                }
+            }
+        }
+        failures
+    }
+}
+
+impl Assertion {
+    fn crossed(&self, value: f64) -> Option<String> {
+        match (self.min, self.max) {
+            (Some(min), _) if value < min => Some(format!("below the min of {}", min)),
+            (_, Some(max)) if value > max => Some(format!("above the max of {}", max)),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,137 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+use anyhow::*;
+use log::warn;
+use std::fs::read_to_string;
+
+use crate::config::*;
+use crate::fingerprint::*;
+use crate::glob::*;
+use crate::metric_value::*;
+use crate::summarize::*;
+
+/// The iterations of the last results with the given name and variant in
+/// lines of results, as written by `sirun`. As with `--summarize`, the last
+/// matching line wins.
+pub(crate) fn last_iterations(
+    contents: &str,
+    name: Option<&str>,
+    variant: Option<&str>,
+) -> Option<(MetricMap, Vec<MetricValue>)> {
+    let field = |line: &MetricMap, key: &str| match line.get(key) {
+        Some(MetricValue::Str(value)) => Some(value.clone()),
+        _ => None,
+    };
+    contents
+        .lines()
+        .rev()
+        .filter_map(|line| serde_json::from_str::<MetricMap>(line).ok())
+        .filter(|line| {
+            field(line, "name").as_deref() == name && field(line, "variant").as_deref() == variant
+        })
+        .find_map(|mut line| match line.remove("iterations") {
+            Some(MetricValue::Arr(iterations)) => Some((line, iterations)),
+            _ => None,
+        })
+}
+
+/// Finds the results for `config`'s name and variant in a file of earlier
+/// results.
+fn find_baseline(path: &str, config: &Config) -> Result<Option<(MetricMap, Vec<MetricValue>)>> {
+    let contents =
+        read_to_string(path).with_context(|| format!("cannot read baseline {}", path))?;
+    Ok(last_iterations(
+        &contents,
+        config.name.as_deref(),
+        config.variant.as_deref(),
+    ))
+}
+
+/// Compares the means of this run's metrics with those of the baseline given
+/// with `--baseline`, printing the differences. Fails if any metric got worse
+/// by more than the `--fail-on-regression` percentage.
+pub(crate) fn compare_with_baseline(config: &Config, iterations: &[MetricValue]) -> Result<()> {
+    let path = match &config.baseline {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let baseline = match find_baseline(path, config)? {
+        Some((line, baseline)) => {
+            if let Some(MetricValue::Str(theirs)) = line.get(CONFIG_HASH_KEY) {
+                let ours = config_hash(config)?;
+                if *theirs != ours {
+                    warn!(
+                        "baseline {} was run with a different config (hash {}, now {})",
+                        path, theirs, ours
+                    );
+                }
+            }
+            means(&baseline).with_context(|| format!("invalid results in baseline {}", path))?
+        }
+        None => {
+            warn!(
+                "no results for name {:?} and variant {:?} in baseline {}",
+                config.name.as_deref().unwrap_or(""),
+                config.variant.as_deref().unwrap_or(""),
+                path
+            );
+            return Ok(());
+        }
+    };
+    eprintln!("Compared with baseline {}:", path);
+    let mut regressed = Vec::new();
+    for (metric, exact) in means(iterations)? {
+        // Changes are worked out from the exact mean, which is only shown
+        // rounded like the results are.
+        let current = match &config.metric_precision {
+            Some(precision) => precision.round(&metric, exact),
+            None => exact,
+        };
+        let previous = match baseline.get(&metric) {
+            Some(previous) => *previous,
+            None => {
+                eprintln!("  {}: {} (not in baseline)", metric, current);
+                continue;
+            }
+        };
+        if previous == 0.0 {
+            eprintln!("  {}: {} -> {}", metric, previous, current);
+            continue;
+        }
+        let change = (exact - previous) / previous.abs() * 100.0;
+        let higher_is_better = config
+            .higher_is_better
+            .iter()
+            .any(|pattern| glob_match(pattern, &metric));
+        let worse_by = if higher_is_better { -change } else { change };
+        let regression = config
+            .fail_on_regression
+            .is_some_and(|threshold| worse_by > threshold);
+        eprintln!(
+            "  {}: {} -> {} ({:+.1}%){}",
+            metric,
+            previous,
+            current,
+            change,
+            if regression { " REGRESSION" } else { "" }
+        );
+        if regression {
+            regressed.push(metric);
+        }
+    }
+    ensure!(
+        regressed.is_empty(),
+        "{} regressed by more than {}% from the baseline: {}",
+        if regressed.len() == 1 {
+            "1 metric"
+        } else {
+            "metrics"
+        },
+        config.fail_on_regression.unwrap_or_default(),
+        regressed.join(", ")
+    );
+    Ok(())
+}
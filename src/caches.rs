@@ -0,0 +1,82 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+//! Emptying the page cache before each iteration with `drop_caches`, for
+//! benchmarks of cold starts.
+
+use anyhow::*;
+use async_std::{io::prelude::WriteExt, process::Command};
+use nix::libc;
+use serde::{Deserialize, Serialize};
+use std::{fs, io, process::Stdio, time::Instant};
+
+use crate::metric_value::*;
+
+const DROP_CACHES_PATH: &str = "/proc/sys/vm/drop_caches";
+
+/// How the page cache is dropped.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum DropCaches {
+    /// By writing to `/proc/sys/vm/drop_caches`, which takes root or
+    /// `CAP_SYS_ADMIN`.
+    Direct,
+    /// By a helper command that's given the path of `drop_caches` as its
+    /// last argument and what to write to it on stdin, such as `sudo -n tee`.
+    Helper(Vec<String>),
+}
+
+impl DropCaches {
+    /// Writes dirty pages out and then drops the page cache, along with the
+    /// dentries and inodes, recording that it did and how long it took.
+    pub(crate) async fn drop(&self, metrics: &mut MetricMap) -> Result<()> {
+        let start = Instant::now();
+        unsafe {
+            libc::sync();
+        }
+        match self {
+            DropCaches::Direct => fs::write(DROP_CACHES_PATH, "3").map_err(|e| match e.kind() {
+                io::ErrorKind::PermissionDenied => anyhow!(
+                    "'drop_caches' needs root or CAP_SYS_ADMIN to write to {}, which sirun \
+                     doesn't have. It can be set to a helper command that does instead, \
+                     such as \"sudo -n tee\"",
+                    DROP_CACHES_PATH
+                ),
+                _ => anyhow!("'drop_caches' cannot write to {}: {}", DROP_CACHES_PATH, e),
+            })?,
+            DropCaches::Helper(helper) => run_helper(helper).await?,
+        }
+        metrics.insert("caches.dropped".into(), 1.into());
+        metrics.insert(
+            "caches.drop.ms".into(),
+            (start.elapsed().as_secs_f64() * 1000.0).into(),
+        );
+        Ok(())
+    }
+}
+
+async fn run_helper(helper: &[String]) -> Result<()> {
+    let mut child = Command::new(&helper[0])
+        .args(&helper[1..])
+        .arg(DROP_CACHES_PATH)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("cannot start 'drop_caches' helper {:?}", helper))?;
+    let mut stdin = child.stdin.take().unwrap();
+    // A helper that fails may never read it, which its status explains.
+    let _ = stdin.write_all(b"3\n").await;
+    drop(stdin);
+    let output = child.output().await?;
+    ensure!(
+        output.status.success(),
+        "'drop_caches' helper {:?} failed ({}): {}",
+        helper,
+        output.status,
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+    Ok(())
+}
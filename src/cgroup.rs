@@ -0,0 +1,187 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+//! Accounting for the CPU time and memory of the `run` command through a
+//! cgroup v2 of its own, with `cgroup_accounting`. Unlike `getrusage`, this
+//! counts the command and every one of its descendants, including those that
+//! were reparented or are still running when it exits, and nothing else.
+
+use anyhow::*;
+use nix::libc;
+use std::{
+    ffi::CString,
+    fs, io,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::Duration,
+};
+
+use crate::metric_value::*;
+use crate::rusage::*;
+
+/// A cgroup created for one run of the command, which is removed when
+/// dropped.
+pub(crate) struct Cgroup {
+    path: PathBuf,
+}
+
+/// Tells apart the cgroups a process creates.
+static CREATED: AtomicUsize = AtomicUsize::new(0);
+
+impl Cgroup {
+    /// Creates a cgroup below the one this process is in, which needs cgroup
+    /// v2 to be mounted and that cgroup to be delegated to this user.
+    pub(crate) fn create() -> Result<Self> {
+        let mount = cgroup2_mount().ok_or_else(|| anyhow!("no cgroup v2 hierarchy is mounted"))?;
+        let parent = mount.join(own_cgroup()?.trim_start_matches('/'));
+        // `memory.peak` only exists with the memory controller enabled, which
+        // isn't allowed for cgroups that have processes of their own, other
+        // than the root. Without it, there's just no peak.
+        let _ = fs::write(parent.join("cgroup.subtree_control"), "+memory");
+        let path = parent.join(format!(
+            "sirun-{}-{}",
+            process::id(),
+            CREATED.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::create_dir(&path)
+            .with_context(|| format!("cannot create cgroup {}", path.display()))?;
+        Ok(Cgroup { path })
+    }
+
+    /// A `pre_exec` hook that moves the command into the cgroup once it's
+    /// forked, so that all of its children are in it from the start. The path
+    /// is prepared beforehand, since nothing may be allocated after a fork.
+    pub(crate) fn enter_hook(&self) -> impl FnMut() -> io::Result<()> + Send + Sync + 'static {
+        let procs = CString::new(self.path.join("cgroup.procs").as_os_str().as_bytes())
+            .expect("cgroup path has a NUL byte");
+        move || unsafe {
+            let fd = libc::open(procs.as_ptr(), libc::O_WRONLY);
+            if fd == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            // Writing 0 moves the process that writes it.
+            let written = libc::write(fd, b"0".as_ptr() as *const libc::c_void, 1);
+            let error = io::Error::last_os_error();
+            libc::close(fd);
+            if written == -1 {
+                return Err(error);
+            }
+            Ok(())
+        }
+    }
+
+    /// Replaces the times in `usage` with those the cgroup counted, and
+    /// records its peak memory use as `memory.peak.bytes` if it's known.
+    pub(crate) fn account(&self, usage: &mut Rusage, metrics: &mut MetricMap) -> Result<()> {
+        let cpu_stat = fs::read_to_string(self.path.join("cpu.stat"))
+            .with_context(|| format!("cannot read cpu.stat of cgroup {}", self.path.display()))?;
+        let field = |name: &str| {
+            keyed_field(&cpu_stat, name).ok_or_else(|| anyhow!("cpu.stat has no {}", name))
+        };
+        usage.user_time = field("user_usec")?;
+        usage.system_time = field("system_usec")?;
+        if let Some(peak) = read_number(&self.path.join("memory.peak")) {
+            metrics.insert("memory.peak.bytes".into(), peak.into());
+        }
+        Ok(())
+    }
+
+    /// Has the kernel OOM kill the cgroup's processes once they use more than
+    /// `bytes` between them, without letting them swap. Returns whether it
+    /// could, which needs the memory controller.
+    pub(crate) fn limit_memory(&self, bytes: u64) -> bool {
+        let limited = fs::write(self.path.join("memory.max"), bytes.to_string()).is_ok();
+        if limited {
+            let _ = fs::write(self.path.join("memory.swap.max"), "0");
+        }
+        limited
+    }
+
+    /// Whether any of the cgroup's processes were OOM killed, which
+    /// `memory.events` counts on its `oom_kill` line.
+    pub(crate) fn oom_killed(&self) -> bool {
+        fs::read_to_string(self.path.join("memory.events"))
+            .ok()
+            .and_then(|events| keyed_field(&events, "oom_kill"))
+            .is_some_and(|kills| kills > 0.0)
+    }
+}
+
+impl Drop for Cgroup {
+    /// A cgroup can only be removed once it's empty, so anything the command
+    /// left running is killed first.
+    fn drop(&mut self) {
+        let _ = fs::write(self.path.join("cgroup.kill"), "1");
+        for _ in 0..50 {
+            match fs::remove_dir(&self.path) {
+                Err(e) if e.raw_os_error() == Some(libc::EBUSY) => {
+                    thread::sleep(Duration::from_millis(10))
+                }
+                _ => return,
+            }
+        }
+    }
+}
+
+/// Fails unless commands can be put into a cgroup of their own here, by
+/// trying it with `true`.
+pub(crate) fn check_cgroups() -> Result<()> {
+    use std::os::unix::process::CommandExt;
+    let cgroup = Cgroup::create()?;
+    let mut command = process::Command::new("true");
+    unsafe {
+        command.pre_exec(cgroup.enter_hook());
+    }
+    let status = command
+        .status()
+        .context("cannot move a process into a new cgroup")?;
+    ensure!(status.success(), "'true' failed in a new cgroup");
+    Ok(())
+}
+
+/// Where the cgroup v2 hierarchy is mounted, which is usually
+/// `/sys/fs/cgroup`, or `/sys/fs/cgroup/unified` alongside cgroup v1. The
+/// lines of `/proc/self/mountinfo` have the mount point in their fifth field,
+/// and the filesystem type after a `-`.
+fn cgroup2_mount() -> Option<PathBuf> {
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo").ok()?;
+    mountinfo.lines().find_map(|line| {
+        let (mount, filesystem) = line.split_once(" - ")?;
+        if filesystem.split_whitespace().next()? != "cgroup2" {
+            return None;
+        }
+        mount.split_whitespace().nth(4).map(PathBuf::from)
+    })
+}
+
+/// The path of this process's cgroup v2 below the mount point, from the `0::`
+/// line of `/proc/self/cgroup`.
+fn own_cgroup() -> Result<String> {
+    fs::read_to_string("/proc/self/cgroup")?
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .map(str::to_owned)
+        .ok_or_else(|| anyhow!("this process isn't in a cgroup v2"))
+}
+
+/// A field of a flat keyed file like `cpu.stat`, whose lines look like
+/// `user_usec 111195`.
+fn keyed_field(contents: &str, name: &str) -> Option<f64> {
+    contents.lines().find_map(|line| {
+        let (field, value) = line.split_once(' ')?;
+        if field == name {
+            value.trim().parse().ok()
+        } else {
+            None
+        }
+    })
+}
+
+fn read_number(path: &Path) -> Option<f64> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
@@ -0,0 +1,398 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+use anyhow::*;
+use serde_yaml::Mapping;
+use std::{collections::BTreeMap, env, fmt};
+
+use crate::config::*;
+use crate::export::*;
+use crate::glob::*;
+
+pub(crate) const USAGE: &str = "\
+Usage:
+  sirun [OPTIONS] <CONFIG>      Run the benchmark in a config file (- for stdin)
+  sirun [OPTIONS] <DIRECTORY>   Run each config file in a directory, as a suite
+  sirun --summarize [OPTIONS] [FILE...]
+                                Summarize results from files, or stdin
+  sirun history show [OPTIONS] <NAME>
+                                List the last runs of a benchmark in a history file
+  sirun exec [OPTIONS] -- <COMMAND> [ARG...]
+                                Run a command as if it were a config's `run`
+  sirun --schema                Print a JSON Schema for config files
+  sirun --help | --version
+
+Options:
+  --iterations N                Override the number of iterations
+  --variant NAME                Run only the given variant
+  --benchmark NAME              Run only the given entry of the config's benchmarks
+  --list-variants               Print the variant names and exit
+  --only PATTERN                Run only variants matching the glob pattern
+  --skip PATTERN                Don't run variants matching the glob pattern
+  --jobs N                      Run up to N variants at the same time
+  --fail-fast                   Stop at the first variant that fails
+  --pattern GLOB                Config files to run in a directory (default *.json,
+                                *.yml and *.yaml)
+  --output FILE                 Write results to FILE instead of stdout
+  --stream                      Write each iteration's results as it completes
+  --quiet                       Don't report progress on stderr
+  --summary-table               Print statistics even when stderr isn't a terminal
+  --exclusive                   Wait for other exclusive sirun runs to finish first
+  --dry-run                     Print the resolved config and exit
+  --set KEY=VALUE               Override a config key, or env.NAME, for this run
+  --package                     Read the config from a package.json's sirun field
+  --baseline FILE               Compare results with earlier ones in FILE
+  --fail-on-regression PCT      Fail if a metric is PCT percent worse than the baseline
+  --history FILE                Compare results with the last run in FILE, then add them
+  --export TARGET               Send results to datadog, prometheus-textfile=PATH
+                                or pushgateway=URL after the run
+  --export-dry-run              Print what would be exported instead of sending it
+  -h, --help                    Print this help
+  -V, --version                 Print the version
+
+Summarize options:
+  --exclude-flagged             Leave flagged iterations out of the statistics
+  --group-by run|all            Keep the last run of each variant, or combine them all
+  --merge-iterations            Same as --group-by all
+  --require-complete            Fail if a metric is missing from any iteration
+  --strict                      Fail if any line is malformed
+  --stats LIST                  Give only these statistics, like mean,stddev,p95
+  --compare-variants VARIANT    Test other variants for differences from VARIANT
+  --alpha P                     Flag differences with p-values under P (default 0.05)
+  --format FORMAT               Write json (the default), csv, csv-wide or markdown
+  --metrics LIST                Show only these metrics in markdown, like wall.time
+  --precision [GLOB=]N          Round statistics to N significant digits
+  --follow FILE                 Keep summarizing FILE as lines are added
+
+Exec options:
+  --iterations N                Run the command N times
+  --name NAME                   Name to give the results
+  --env KEY=VALUE               Set an environment variable for the command
+  --timeout SECONDS             Fail if the command takes longer than SECONDS
+  --instructions                Count instructions, on Linux
+
+History options:
+  --history FILE                Read FILE (default ~/.sirun/history.ndjson)
+  --variant NAME                List only runs of the given variant
+  --last N                      List the last N runs (default 10)
+
+See the README for config keys and environment variables.
+";
+
+/// Something wrong with how sirun was invoked, as opposed to with the run
+/// itself. These are reported along with the usage synopsis.
+#[derive(Debug)]
+pub(crate) struct UsageError(pub(crate) String);
+
+impl fmt::Display for UsageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for UsageError {}
+
+/// Treats any error in parsing arguments as a usage error.
+pub(crate) fn usage_error(e: Error) -> Error {
+    UsageError(format!("{:#}", e)).into()
+}
+
+/// What `--version` prints: the crate version and the commit it was built
+/// from.
+pub(crate) fn version() -> String {
+    format!(
+        "sirun {} ({})",
+        env!("CARGO_PKG_VERSION"),
+        env!("SIRUN_GIT_HASH")
+    )
+}
+
+/// Options given on the command line (or their environment variable
+/// equivalents) when running a config file.
+pub(crate) struct Cli {
+    pub(crate) config_file: String,
+    pub(crate) iterations: Option<u64>,
+    pub(crate) variant: Option<String>,
+    pub(crate) benchmark: Option<String>,
+    pub(crate) list_variants: bool,
+    pub(crate) output: Option<String>,
+    pub(crate) dry_run: bool,
+    pub(crate) fail_fast: bool,
+    pub(crate) stream: bool,
+    pub(crate) quiet: bool,
+    pub(crate) summary_table: bool,
+    /// Whether to run with the machine lock held, as with `exclusive`.
+    pub(crate) exclusive: bool,
+    /// How many variants to run at once.
+    pub(crate) jobs: usize,
+    pub(crate) package: bool,
+    /// Glob patterns variants must match one of, if any are given.
+    pub(crate) only: Vec<String>,
+    /// Glob patterns of variants not to run.
+    pub(crate) skip: Vec<String>,
+    /// Results file to compare this run's results with.
+    pub(crate) baseline: Option<String>,
+    /// Percentage by which metrics may regress from the baseline.
+    pub(crate) fail_on_regression: Option<f64>,
+    /// File of past runs to compare with and add to.
+    pub(crate) history: Option<String>,
+    /// Where to send the results.
+    pub(crate) exports: Vec<ExportTarget>,
+    pub(crate) export_dry_run: bool,
+    /// Glob patterns for the config files to run when given a directory.
+    pub(crate) patterns: Vec<String>,
+    /// Config keys set with `--set`.
+    pub(crate) overrides: Mapping,
+    /// Where each overridden setting came from, for `--dry-run`.
+    pub(crate) sources: BTreeMap<String, String>,
+}
+
+pub(crate) fn flag_value(args: &mut impl Iterator<Item = String>, flag: &str) -> Result<String> {
+    args.next()
+        .ok_or_else(|| anyhow!("{} requires a value", flag))
+}
+
+/// Options that take a value, so that it isn't mistaken for the config file.
+const VALUE_OPTIONS: &[&str] = &[
+    "--variant",
+    "--benchmark",
+    "--output",
+    "--iterations",
+    "--jobs",
+    "--only",
+    "--skip",
+    "--baseline",
+    "--fail-on-regression",
+    "--history",
+    "--export",
+    "--pattern",
+    "--set",
+];
+
+/// The arguments to run one config in a suite with: the same ones as the
+/// suite, minus the directory, and minus `--output`, `--pattern` and
+/// `--variant`, which the suite takes care of.
+pub(crate) fn suite_child_args(mut args: impl Iterator<Item = String>) -> Vec<String> {
+    let mut child_args = Vec::new();
+    while let Some(arg) = args.next() {
+        if arg == "--output" || arg == "--pattern" || arg == "--variant" {
+            args.next();
+        } else if VALUE_OPTIONS.contains(&arg.as_str()) {
+            child_args.push(arg);
+            child_args.extend(args.next());
+        } else if arg.starts_with("--") {
+            child_args.push(arg);
+        }
+    }
+    child_args
+}
+
+impl Cli {
+    pub(crate) fn from_args(args: impl Iterator<Item = String>) -> Result<Self> {
+        Self::parse(args).map_err(usage_error)
+    }
+
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self> {
+        let mut sources = BTreeMap::new();
+        let mut config_file = None;
+        let mut iterations = None;
+        if let Ok(value) = env::var("SIRUN_ITERATIONS") {
+            iterations = Some(parse_iterations(&value)?);
+            sources.insert("iterations".into(), "SIRUN_ITERATIONS".into());
+        }
+        let mut variant = env::var("SIRUN_VARIANT").ok();
+        if variant.is_some() {
+            sources.insert("variant".into(), "SIRUN_VARIANT".into());
+        }
+        let mut benchmark = env::var("SIRUN_BENCHMARK").ok();
+        if benchmark.is_some() {
+            sources.insert("benchmark".into(), "SIRUN_BENCHMARK".into());
+        }
+        let mut output = env::var("SIRUN_OUTPUT_FILE").ok();
+        if output.is_some() {
+            sources.insert("output_file".into(), "SIRUN_OUTPUT_FILE".into());
+        }
+        let mut list_variants = false;
+        let mut dry_run = false;
+        let mut fail_fast = false;
+        let mut stream = env::var("SIRUN_STREAM").is_ok();
+        let mut quiet = false;
+        let mut summary_table = false;
+        let mut exclusive = false;
+        let mut jobs = 1;
+        let mut package = false;
+        let mut only = Vec::new();
+        if let Ok(pattern) = env::var("SIRUN_VARIANT_FILTER") {
+            validate_glob(&pattern)?;
+            only.push(pattern);
+        }
+        let mut skip = Vec::new();
+        let mut baseline = None;
+        let mut fail_on_regression = None;
+        let mut history = None;
+        let mut exports = Vec::new();
+        let mut export_dry_run = false;
+        let mut overrides = Mapping::new();
+        let mut patterns = Vec::new();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--variant" => {
+                    variant = Some(flag_value(&mut args, &arg)?);
+                    sources.insert("variant".into(), arg);
+                }
+                "--benchmark" => {
+                    benchmark = Some(flag_value(&mut args, &arg)?);
+                    sources.insert("benchmark".into(), arg);
+                }
+                "--list-variants" => list_variants = true,
+                "--output" => {
+                    output = Some(flag_value(&mut args, &arg)?);
+                    sources.insert("output_file".into(), arg);
+                }
+                "--iterations" => {
+                    iterations = Some(parse_iterations(&flag_value(&mut args, &arg)?)?);
+                    sources.insert("iterations".into(), arg);
+                }
+                "--dry-run" => dry_run = true,
+                "--fail-fast" => fail_fast = true,
+                "--stream" => stream = true,
+                "--quiet" => quiet = true,
+                "--summary-table" => summary_table = true,
+                "--exclusive" => exclusive = true,
+                "--jobs" => {
+                    jobs = match flag_value(&mut args, &arg)?.parse() {
+                        Ok(jobs) if jobs > 0 => jobs,
+                        _ => bail!("--jobs must be an integer >=1"),
+                    };
+                }
+                "--package" => package = true,
+                "--only" | "--skip" => {
+                    let pattern = flag_value(&mut args, &arg)?;
+                    validate_glob(&pattern)?;
+                    if arg == "--only" {
+                        only.push(pattern);
+                    } else {
+                        skip.push(pattern);
+                    }
+                }
+                "--baseline" => baseline = Some(flag_value(&mut args, &arg)?),
+                "--fail-on-regression" => {
+                    fail_on_regression = match flag_value(&mut args, &arg)?.parse::<f64>() {
+                        Ok(pct) if pct >= 0.0 => Some(pct),
+                        _ => bail!("--fail-on-regression must be a non-negative number"),
+                    };
+                }
+                "--history" => history = Some(flag_value(&mut args, &arg)?),
+                "--export" => exports.push(ExportTarget::parse(&flag_value(&mut args, &arg)?)?),
+                "--export-dry-run" => export_dry_run = true,
+                "--pattern" => {
+                    let pattern = flag_value(&mut args, &arg)?;
+                    validate_glob(&pattern)?;
+                    patterns.push(pattern);
+                }
+                "--set" => {
+                    let spec = flag_value(&mut args, &arg)?;
+                    add_override(&mut overrides, &spec)?;
+                    let key = spec.split('=').next().unwrap_or_default();
+                    sources.insert(key.into(), arg);
+                }
+                _ if arg.starts_with("--") => bail!("unknown option '{}'", arg),
+                _ => {
+                    ensure!(config_file.is_none(), "unexpected argument '{}'", arg);
+                    config_file = Some(arg);
+                }
+            }
+        }
+        ensure!(
+            fail_on_regression.is_none() || baseline.is_some(),
+            "--fail-on-regression requires --baseline"
+        );
+        if env::var("SIRUN_NAME").is_ok() {
+            sources.insert("name".into(), "SIRUN_NAME".into());
+        }
+        Ok(Cli {
+            config_file: config_file.ok_or_else(|| anyhow!("missing file argument"))?,
+            iterations,
+            variant,
+            benchmark,
+            list_variants,
+            output,
+            dry_run,
+            fail_fast,
+            stream,
+            quiet,
+            summary_table,
+            exclusive,
+            jobs,
+            package,
+            only,
+            skip,
+            baseline,
+            fail_on_regression,
+            history,
+            exports,
+            export_dry_run,
+            patterns,
+            overrides,
+            sources,
+        })
+    }
+
+    /// Applies command line overrides on top of a config read from a file.
+    pub(crate) fn apply(&self, config: &mut Config) -> Result<()> {
+        apply_overrides(config, &self.overrides)?;
+        if let Some(iterations) = self.iterations {
+            config.iterations = iterations;
+            config.duration = None;
+        }
+        if let Some(output) = &self.output {
+            config.output_file = Some(output.clone());
+        }
+        config.stream |= self.stream;
+        config.quiet |= self.quiet;
+        config.summary_table |= self.summary_table;
+        config.exclusive |= self.exclusive;
+        if let Some(baseline) = &self.baseline {
+            config.baseline = Some(baseline.clone());
+            config.fail_on_regression = self.fail_on_regression;
+        }
+        if let Some(history) = &self.history {
+            config.history = Some(history.clone());
+        }
+        for target in &self.exports {
+            config.export.enable(target);
+        }
+        config.export.dry_run |= self.export_dry_run;
+        ensure!(
+            !config.export.dry_run || !config.export.is_empty(),
+            "--export-dry-run needs --export or the 'export' config key"
+        );
+        if let Some(variants) = config.variants.take() {
+            config.variants = Some(self.filter_variants(variants)?);
+        }
+        Ok(())
+    }
+
+    fn filter_variants(&self, variants: Vec<String>) -> Result<Vec<String>> {
+        if self.only.is_empty() && self.skip.is_empty() {
+            return Ok(variants);
+        }
+        let matches = |patterns: &[String], variant: &str| {
+            patterns.iter().any(|pattern| glob_match(pattern, variant))
+        };
+        let filtered: Vec<_> = variants
+            .iter()
+            .filter(|variant| self.only.is_empty() || matches(&self.only, variant))
+            .filter(|variant| !matches(&self.skip, variant))
+            .cloned()
+            .collect();
+        ensure!(
+            !filtered.is_empty(),
+            "no variants match the filter; available variants are: {}",
+            variants.join(", ")
+        );
+        Ok(filtered)
+    }
+}
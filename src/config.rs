@@ -5,143 +5,661 @@
 
 use anyhow::*;
 use lazy_static::lazy_static;
+use log::warn;
 use serde::{Deserialize, Serialize};
 use serde_yaml::{from_str, to_string, Mapping, Value};
 use std::fmt;
-use std::{collections::HashMap, env, fs::read_to_string};
 
+use crate::assertions::*;
+use crate::caches::*;
+use crate::export::*;
+use crate::host::*;
+use crate::noise::*;
+use crate::precision::*;
+use crate::profilers::*;
+use crate::raw_config::*;
+use crate::redact::*;
+use crate::results_file::*;
+use crate::statsd::*;
+use crate::stdout_metrics::*;
+use crate::subproc::*;
+use crate::valgrind::*;
+use crate::wait::*;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env,
+    fs::read_to_string,
+    io::{stdin, Read},
+    path::{Path, PathBuf},
+};
+
+/// Everything about how to run a benchmark, as read from a config file and
+/// resolved for a single variant, or built up with [`Config::new`].
 #[derive(Serialize, Deserialize, Clone)]
-pub(crate) struct Config {
+pub struct Config {
     pub(crate) name: Option<String>,
     pub(crate) variant: Option<String>,
-    pub(crate) service: Option<Vec<String>>,
-    pub(crate) setup: Option<Vec<String>>,
-    pub(crate) teardown: Option<Vec<String>>,
+    pub(crate) service: Option<Vec<Vec<String>>>,
+    pub(crate) setup: Option<Vec<Vec<String>>>,
+    pub(crate) teardown: Option<Vec<Vec<String>>>,
     pub(crate) run: Vec<String>,
     pub(crate) timeout: Option<u64>,
-    pub(crate) env: HashMap<String, String>,
+    /// Ordered so that serializing a config always gives the same output.
+    pub(crate) env: BTreeMap<String, String>,
+    /// Inherited variables that commands shouldn't see.
+    pub(crate) env_remove: BTreeSet<String>,
+    /// Start commands from an empty environment, plus `env`.
+    pub(crate) clear_env: bool,
+    /// CPUs the `run` command is pinned to, or all of them if empty.
+    pub(crate) cpu_affinity: Vec<usize>,
+    /// Scheduling priority of the `run` command, from -20 to 19.
+    pub(crate) nice: Option<i32>,
+    /// Whether `cpu_affinity` and `nice` apply to services too.
+    pub(crate) pin_services: bool,
     pub(crate) cachegrind: bool,
+    /// The valgrind tool to count each iteration's instructions and cache
+    /// misses with.
+    pub(crate) valgrind: Option<ValgrindTool>,
     pub(crate) iterations: u64,
+    /// How many copies of the `run` command each iteration starts at once.
+    pub(crate) concurrency: u64,
     pub(crate) instructions: bool,
+    /// Events to count by running the `run` command under `perf stat`.
+    pub(crate) perf_stat: Vec<String>,
+    /// The tool to measure peak heap usage with.
+    pub(crate) heap_profile: Option<HeapProfiler>,
+    /// Whether system calls are counted with `strace`.
+    pub(crate) syscalls: bool,
+    /// Whether the raw output of the profiler is kept after each iteration.
+    pub(crate) keep_profiles: bool,
     pub(crate) variants: Option<Vec<String>>,
+    /// Name of the entry in `benchmarks` this config is for, if any.
+    pub(crate) benchmark: Option<String>,
+    /// Names of the entries in `benchmarks`, until one is selected.
+    pub(crate) benchmarks: Option<Vec<String>>,
+    pub(crate) metric_precision: Option<Precision>,
+    pub(crate) output_file: Option<String>,
+    pub(crate) strict: bool,
+    pub(crate) shell: Option<String>,
+    /// Directory commands are run in. Absolute once the config is resolved.
+    pub(crate) cwd: Option<String>,
+    pub(crate) paths_relative_to_config: bool,
+    pub(crate) interleave: bool,
+    /// Whether `TMPDIR` points at the per-iteration scratch directory.
+    pub(crate) manage_tmpdir: bool,
+    /// Paths whose disk usage is compared before and after `run`.
+    pub(crate) measure_disk: Vec<String>,
+    pub(crate) wait_for: Vec<WaitCondition>,
+    /// Whether services may exit before `run` finishes.
+    pub(crate) service_may_exit: bool,
+    /// How many more times a failed iteration is attempted.
+    pub(crate) iteration_retries: u64,
+    /// Whether failed iterations are recorded rather than ending the run.
+    pub(crate) continue_on_failure: bool,
+    /// Seconds after which no more iterations are started.
+    pub(crate) total_timeout: Option<u64>,
+    /// Seconds to keep starting iterations for, instead of a fixed number.
+    pub(crate) duration: Option<u64>,
+    pub(crate) min_iterations: Option<u64>,
+    pub(crate) max_iterations: Option<u64>,
+    pub(crate) assertions: Option<Assertions>,
+    /// Metrics to scrape from the lines the `run` command prints.
+    pub(crate) stdout_metrics: Option<StdoutMetrics>,
+    /// Whether the `run` command can write metrics to `SIRUN_RESULTS_FILE`,
+    /// and which win over those sent over statsd.
+    pub(crate) results_file: Option<ResultsPrecedence>,
+    /// Glob patterns of metrics for which a lower value is a regression.
+    pub(crate) higher_is_better: Vec<String>,
+    /// Whether a description of the machine is included in the results.
+    pub(crate) collect_host_info: bool,
+    /// Whether the load and CPU frequencies are recorded before each
+    /// iteration.
+    pub(crate) collect_system_state: bool,
+    /// Bytes of memory the `run` command may use.
+    pub(crate) max_memory: Option<u64>,
+    /// Whether dotted names of metrics the benchmark sends are folded into
+    /// nested maps.
+    pub(crate) nest_metrics: bool,
+    /// Whether processes that commands leave running are killed at the end
+    /// of each iteration.
+    pub(crate) reap_orphans: bool,
+    /// Whether to hold the machine lock while running, so that other sirun
+    /// runs wait for this one.
+    pub(crate) exclusive: bool,
+    /// Seconds to wait for the machine lock before giving up.
+    pub(crate) exclusive_timeout: u64,
+    /// How the page cache is dropped before each iteration, if it is.
+    pub(crate) drop_caches: Option<DropCaches>,
+    /// Whether the `run` command is run without address space layout
+    /// randomization.
+    pub(crate) disable_aslr: bool,
+    /// Whether the `run` command's CPU time and memory are accounted for
+    /// through a cgroup of its own.
+    pub(crate) cgroup_accounting: bool,
+    /// How quiet the machine has to be before the first iteration.
+    pub(crate) noise_check: Option<NoiseCheck>,
+    /// Whether each iteration's results are written as soon as it's done,
+    /// from `--stream`.
+    pub(crate) stream: bool,
+    /// Whether to leave out progress reports, from `--quiet`.
+    pub(crate) quiet: bool,
+    /// Whether to print the table of statistics at the end of the run even
+    /// when stderr isn't a terminal, from `--summary-table`.
+    pub(crate) summary_table: bool,
+    /// Results to compare this run with, from `--baseline`.
+    pub(crate) baseline: Option<String>,
+    /// Percentage by which a metric can regress from the baseline without
+    /// failing the run, from `--fail-on-regression`.
+    pub(crate) fail_on_regression: Option<f64>,
+    /// A file of past runs to compare this one with and add it to, from
+    /// `--history`.
+    pub(crate) history: Option<String>,
+    /// Where results are sent once the run is done.
+    pub(crate) export: Export,
+    /// The `host:port` of a DogStatsD agent to relay each iteration's metrics
+    /// to.
+    pub(crate) statsd_forward: Option<String>,
+    /// What the names of relayed metrics start with.
+    pub(crate) statsd_prefix: Option<String>,
+    /// The address the statsd listener binds to, instead of 127.0.0.1.
+    pub(crate) statsd_host: Option<String>,
+    /// Whether the statsd listener takes packets or connections.
+    pub(crate) statsd_protocol: StatsdProtocol,
+    /// More patterns of environment variable names whose values are kept out
+    /// of anything that shows the config.
+    pub(crate) redact_env: Vec<String>,
+    pub(crate) setup_stdio: Option<StdioTarget>,
+    pub(crate) run_stdio: Option<StdioTarget>,
+    pub(crate) service_stdio: Option<StdioTarget>,
+    pub(crate) teardown_stdio: Option<StdioTarget>,
+    /// Index of the iteration this config is being run for, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) iteration: Option<u64>,
+    /// Where the profiler writes its output for this iteration, when it's
+    /// kept.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) profile_dir: Option<PathBuf>,
+    /// Directory containing the config file, where git metadata is looked for.
+    #[serde(skip)]
+    pub(crate) config_dir: PathBuf,
 }
 
 impl fmt::Display for Config {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", to_string(self).unwrap())
+        write!(f, "{}", to_string(&redacted(self)).unwrap())
     }
 }
 
-fn get_shell_command(obj: &Mapping, name: &Value) -> Result<Vec<String>> {
-    let run = obj
-        .get(name)
-        .unwrap()
-        .as_str()
-        .ok_or_else(|| anyhow!("'{}' must be a string", name.as_str().unwrap()))?;
+impl Config {
+    /// A config that runs `command` once, with its arguments used exactly as
+    /// given, like `sirun exec` does.
+    pub fn new<I, S>(command: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let mut config = empty_config(PathBuf::from("."));
+        config.run = command.into_iter().map(Into::into).collect();
+        config
+    }
 
-    shlex::split(run).ok_or_else(|| {
-        anyhow!(
-            "'{}' must be a properly formed shell command",
-            name.as_str().unwrap()
-        )
-    })
+    /// Reads a config file, resolved for the given variant. A config file
+    /// with variants can only be run once one of them is picked, and one with
+    /// `benchmarks` can't be run this way.
+    pub fn from_file(path: impl AsRef<Path>, variant: Option<&str>) -> Result<Self> {
+        let source = read_config(&path.as_ref().to_string_lossy())?;
+        get_config(&source, variant)
+    }
+
+    /// The names (or indices) of the config file's variants, if it has any
+    /// and none was picked.
+    pub fn variants(&self) -> Option<&[String]> {
+        self.variants.as_deref()
+    }
+
+    /// Sets the `name` the results are given.
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Sets the number of `iterations`, which must be at least 1.
+    pub fn with_iterations(mut self, iterations: u64) -> Self {
+        self.iterations = iterations;
+        self.duration = None;
+        self
+    }
+
+    /// Sets an environment variable for the commands that are run.
+    pub fn with_env(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        let name = name.into();
+        self.env_remove.remove(&name);
+        self.env.insert(name, value.into());
+        self
+    }
+
+    /// Sets the `timeout` for each iteration, in seconds.
+    pub fn with_timeout(mut self, timeout: u64) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Sets whether `instructions` are counted, which is only possible on
+    /// Linux.
+    pub fn with_instructions(mut self, instructions: bool) -> Self {
+        self.instructions = instructions;
+        self
+    }
 }
 
-fn get_env(env: &mut HashMap<String, String>, config_env: &Value) -> Result<()> {
-    let config_env = config_env
-        .as_mapping()
-        .ok_or_else(|| anyhow!("env must be an object"))?;
-    for (name, value) in config_env.iter() {
-        let value = value
-            .as_str()
-            .ok_or_else(|| anyhow!("env vars must be strings"))?;
-        let name = name
-            .as_str()
-            .ok_or_else(|| anyhow!("env var names must be strings"))?;
-        env.insert(name.to_owned(), value.to_owned());
+/// Environment values are always strings, so other scalars are converted.
+/// Floats keep their decimal point, so `1.0` stays `1.0` rather than `1`.
+pub(crate) fn env_value(value: &Value) -> Option<String> {
+    match value {
+        Value::String(value) => Some(value.clone()),
+        Value::Bool(value) => Some(value.to_string()),
+        Value::Number(value) => match value.as_f64() {
+            Some(float) if value.is_f64() && float.is_finite() => Some(format!("{:?}", float)),
+            _ => Some(value.to_string()),
+        },
+        _ => None,
+    }
+}
+
+/// Seconds to wait for the machine lock by default, which is enough for
+/// most other benchmarks to finish.
+const DEFAULT_EXCLUSIVE_TIMEOUT: u64 = 600;
+
+const ITERATIONS_ERROR: &str = "iterations must be an integer >=1";
+
+/// Parses an iteration count given outside of the config file, such as on the
+/// command line, with the same validation as the `iterations` key.
+pub(crate) fn parse_iterations(value: &str) -> Result<u64> {
+    match value.parse::<u64>() {
+        Ok(iterations) if iterations > 0 => Ok(iterations),
+        _ => bail!(ITERATIONS_ERROR),
     }
-    Ok(())
 }
 
 lazy_static! {
     static ref NAME_KEY: Value = "name".into();
-    static ref RUN_KEY: Value = "run".into();
-    static ref SERVICE_KEY: Value = "service".into();
-    static ref SETUP_KEY: Value = "setup".into();
-    static ref TEARDOWN_KEY: Value = "teardown".into();
-    static ref TIMEOUT_KEY: Value = "timeout".into();
-    static ref CACHEGRIND_KEY: Value = "cachegrind".into();
-    static ref ITERATIONS_KEY: Value = "iterations".into();
-    static ref INSTRUCTIONS_KEY: Value = "instructions".into();
-}
-
-fn apply_config(config: &mut Config, config_val: &Value) -> Result<()> {
-    let config_val = config_val
-        .as_mapping()
-        .ok_or_else(|| anyhow!("invalid json"))?;
+    static ref EXTENDS_KEY: Value = "extends".into();
+    static ref MATRIX_KEY: Value = "matrix".into();
+    static ref BENCHMARKS_KEY: Value = "benchmarks".into();
+}
 
-    if let Ok(name) = env::var("SIRUN_NAME") {
-        config.name = Some(name)
-    } else if let Some(name_val) = config_val.get(&NAME_KEY) {
-        config.name = Some(
-            name_val
-                .as_str()
-                .ok_or_else(|| anyhow!("'name' must be a string"))?
-                .to_owned(),
-        );
+/// Every key a `RawConfig` has, plus `variants`, `matrix`, `benchmarks` and
+/// `extends`, which `get_config` handles itself.
+pub(crate) const KNOWN_KEYS: &[&str] = &[
+    "extends",
+    "matrix",
+    "benchmarks",
+    "name",
+    "run",
+    "service",
+    "setup",
+    "teardown",
+    "timeout",
+    "cachegrind",
+    "iterations",
+    "concurrency",
+    "instructions",
+    "valgrind",
+    "perf_stat",
+    "heap_profile",
+    "syscalls",
+    "keep_profiles",
+    "metric_precision",
+    "output_file",
+    "strict",
+    "shell",
+    "cwd",
+    "paths_relative_to_config",
+    "interleave",
+    "manage_tmpdir",
+    "measure_disk",
+    "wait_for",
+    "service_may_exit",
+    "iteration_retries",
+    "continue_on_failure",
+    "total_timeout",
+    "duration",
+    "min_iterations",
+    "max_iterations",
+    "assertions",
+    "stdout_metrics",
+    "results_file",
+    "higher_is_better",
+    "collect_host_info",
+    "collect_system_state",
+    "noise_check",
+    "cgroup_accounting",
+    "max_memory",
+    "disable_aslr",
+    "drop_caches",
+    "nest_metrics",
+    "reap_orphans",
+    "exclusive",
+    "exclusive_timeout",
+    "export",
+    "statsd_forward",
+    "statsd_prefix",
+    "statsd_host",
+    "statsd_protocol",
+    "redact_env",
+    "setup_stdio",
+    "run_stdio",
+    "service_stdio",
+    "teardown_stdio",
+    "env",
+    "clear_env",
+    "cpu_affinity",
+    "nice",
+    "pin_services",
+    "variants",
+];
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let cur = row[j + 1];
+            row[j + 1] = if ca == *cb {
+                prev
+            } else {
+                1 + prev.min(row[j]).min(row[j + 1])
+            };
+            prev = cur;
+        }
     }
+    row[b.len()]
+}
 
-    if config_val.contains_key(&SERVICE_KEY) {
-        config.service = Some(get_shell_command(config_val, &SERVICE_KEY)?);
+fn unknown_key_message(path: &str, key: &str) -> String {
+    let suggestion = KNOWN_KEYS
+        .iter()
+        .map(|known| (edit_distance(key, known), known))
+        .filter(|(distance, known)| *distance <= 2.max(known.len() / 3))
+        .min_by_key(|(distance, _)| *distance);
+    match suggestion {
+        Some((_, known)) => format!(
+            "unknown config key '{}' (did you mean '{}'?)",
+            join_path(path, key),
+            known
+        ),
+        None => format!("unknown config key '{}'", join_path(path, key)),
     }
+}
 
-    if config_val.contains_key(&RUN_KEY) {
-        config.run = get_shell_command(config_val, &RUN_KEY)?;
+/// Unknown keys are most likely typos, so they're reported as warnings, or as
+/// errors when `strict` is set. `path` is where the config is nested, e.g.
+/// `variants.big`, for the message.
+pub(crate) fn check_keys(strict: bool, config_val: &Mapping, path: &str) -> Result<()> {
+    for (key, _) in config_val.iter() {
+        let key = key
+            .as_str()
+            .ok_or_else(|| anyhow!("config keys must be strings"))?;
+        if KNOWN_KEYS.contains(&key) {
+            continue;
+        }
+        if strict {
+            bail!(unknown_key_message(path, key));
+        }
+        warn!("{}", unknown_key_message(path, key));
     }
+    Ok(())
+}
 
-    if config_val.contains_key(&SETUP_KEY) {
-        config.setup = Some(get_shell_command(config_val, &SETUP_KEY)?);
+/// The text of a config file. It's read once up front so that it can be
+/// resolved for several variants, since stdin can only be read once.
+#[derive(Clone)]
+pub(crate) struct ConfigSource {
+    pub(crate) name: String,
+    pub(crate) contents: String,
+    pub(crate) from_stdin: bool,
+    /// The config is the `sirun` field of a package.json.
+    pub(crate) package_json: bool,
+    /// Which of the config's `benchmarks` to resolve it for.
+    pub(crate) benchmark: Option<String>,
+}
+
+/// Reads a config file, or stdin if `filename` is `-`.
+pub(crate) fn read_config(filename: &str) -> Result<ConfigSource> {
+    if filename == "-" {
+        let mut contents = String::new();
+        stdin()
+            .read_to_string(&mut contents)
+            .context("cannot read config from stdin")?;
+        Ok(ConfigSource {
+            name: "stdin".into(),
+            contents,
+            from_stdin: true,
+            package_json: false,
+            benchmark: None,
+        })
+    } else {
+        Ok(ConfigSource {
+            name: filename.into(),
+            contents: read_to_string(filename)
+                .with_context(|| format!("cannot read config file {}", filename))?,
+            from_stdin: false,
+            package_json: Path::new(filename).file_name() == Some("package.json".as_ref()),
+            benchmark: None,
+        })
     }
+}
 
-    if config_val.contains_key(&TEARDOWN_KEY) {
-        config.teardown = Some(get_shell_command(config_val, &TEARDOWN_KEY)?);
+/// The directory containing the config file, or the current one for config
+/// read from stdin.
+fn config_dir(source: &ConfigSource) -> PathBuf {
+    match Path::new(&source.name).parent() {
+        Some(dir) if !source.from_stdin => dir.to_owned(),
+        _ => PathBuf::from("."),
     }
+}
 
-    if let Some(timeout_val) = config_val.get(&TIMEOUT_KEY) {
-        config.timeout = Some(
-            timeout_val
-                .as_u64()
-                .ok_or_else(|| anyhow!("'timeout' must be a positive integer"))?,
-        );
+/// Makes commands starting with `./` or `../` relative to the config file.
+fn resolve_command_paths(config: &mut Config, source: &ConfigSource) -> Result<()> {
+    let dir = config_dir(source).canonicalize()?;
+    let commands = std::iter::once(&mut config.run)
+        .chain(config.service.iter_mut().flatten())
+        .chain(config.setup.iter_mut().flatten())
+        .chain(config.teardown.iter_mut().flatten());
+    for program in commands.filter_map(|command| command.first_mut()) {
+        if program.starts_with("./") || program.starts_with("../") {
+            *program = dir.join(&program).to_string_lossy().into_owned();
+        }
     }
+    Ok(())
+}
 
-    if let Some(cachegrind_val) = config_val.get(&CACHEGRIND_KEY) {
-        config.cachegrind = cachegrind_val
-            .as_bool()
-            .ok_or_else(|| anyhow!("'cachegrind' must be a boolean"))?;
+/// Relative directories are relative to the config file, so that configs work
+/// wherever sirun is run from. Config read from stdin has no directory, so the
+/// current one is used instead.
+fn resolve_cwd(source: &ConfigSource, cwd: &str) -> Result<String> {
+    let dir = config_dir(source).join(cwd);
+    ensure!(dir.is_dir(), "'cwd' directory {} does not exist", dir.display());
+    Ok(dir.canonicalize()?.to_string_lossy().into_owned())
+}
+
+/// The entries of `benchmarks`, in order.
+fn get_benchmarks(benchmarks: &Value) -> Result<Vec<(String, Value)>> {
+    let benchmarks = benchmarks
+        .as_mapping()
+        .ok_or_else(|| anyhow!("'benchmarks' must be an object"))?;
+    ensure!(!benchmarks.is_empty(), "'benchmarks' must not be empty");
+    benchmarks
+        .iter()
+        .map(|(key, benchmark)| {
+            let key = key
+                .as_str()
+                .ok_or_else(|| anyhow!("benchmark names must be strings"))?;
+            ensure!(
+                benchmark.is_mapping(),
+                "benchmark {} must be an object",
+                key
+            );
+            ensure!(
+                benchmark.get(&*BENCHMARKS_KEY).is_none(),
+                "benchmark {} can't have benchmarks of its own",
+                key
+            );
+            Ok((key.to_owned(), benchmark.clone()))
+        })
+        .collect()
+}
+
+/// A file's variants, with a `matrix` expanded into the equivalent
+/// `variants` object.
+fn get_variants(file: &Value) -> Result<Option<Value>> {
+    match (file.get("variants"), file.get(&*MATRIX_KEY)) {
+        (Some(_), Some(_)) => bail!("'variants' and 'matrix' can't be used together"),
+        (Some(variants), None) => Ok(Some(variants.clone())),
+        (None, Some(matrix)) => Ok(Some(expand_matrix(matrix)?)),
+        (None, None) => Ok(None),
     }
+}
 
-    if let Some(iterations_val) = config_val.get(&ITERATIONS_KEY) {
-        config.iterations = iterations_val
-            .as_u64()
-            .ok_or_else(|| anyhow!("iterations must be an integer >=1"))?;
-        ensure!(config.iterations > 0, "iterations must be an integer >=1");
+/// Plain matrix values are passed to commands in an environment variable
+/// named after their dimension, e.g. `payload-size` becomes `PAYLOAD_SIZE`.
+fn matrix_env_name(dimension: &str) -> String {
+    dimension
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+        .collect()
+}
+
+/// Parses one dimension of a matrix into labels and the config each label
+/// stands for. An array holds plain values, while an object maps labels to
+/// either plain values or config objects.
+fn matrix_dimension(dimension: &str, values: &Value) -> Result<Vec<(String, Mapping)>> {
+    let env_config = |value: &Value| {
+        let value = env_value(value)?;
+        let mut env = Mapping::new();
+        env.insert(matrix_env_name(dimension).into(), value.clone().into());
+        let mut config = Mapping::new();
+        config.insert("env".into(), env.into());
+        Some((value, config))
+    };
+    let invalid = || {
+        anyhow!(
+            "matrix dimension '{}' must be a non-empty array of strings, numbers or booleans, or an object",
+            dimension
+        )
+    };
+    let values = match values {
+        Value::Sequence(values) => values
+            .iter()
+            .map(|value| env_config(value).ok_or_else(invalid))
+            .collect::<Result<Vec<_>>>()?,
+        Value::Mapping(values) => values
+            .iter()
+            .map(|(label, value)| {
+                let label = env_value(label).ok_or_else(invalid)?;
+                let config = match value {
+                    Value::Mapping(config) => config.clone(),
+                    _ => env_config(value).ok_or_else(invalid)?.1,
+                };
+                Ok((label, config))
+            })
+            .collect::<Result<Vec<_>>>()?,
+        _ => bail!(invalid()),
+    };
+    ensure!(!values.is_empty(), invalid());
+    Ok(values)
+}
+
+/// Merges one variant's config into another, the same way
+/// `RawConfig::merge` would merge them.
+fn merge_variant(into: &mut Mapping, from: &Mapping) {
+    for (key, value) in from {
+        match (into.get_mut(key), value) {
+            (Some(Value::Mapping(env)), Value::Mapping(from_env)) if key.as_str() == Some("env") => {
+                for (name, value) in from_env {
+                    env.insert(name.clone(), value.clone());
+                }
+            }
+            _ => {
+                into.insert(key.clone(), value.clone());
+            }
+        }
     }
+}
 
-    if let Some(instructions_val) = config_val.get(&INSTRUCTIONS_KEY) {
-        config.instructions = instructions_val
-            .as_bool()
-            .ok_or_else(|| anyhow!("'instructions' must be a boolean"))?;
+/// Expands a matrix into one variant per combination of its dimensions'
+/// values, named like `version=2.x,payload=10k`.
+fn expand_matrix(matrix: &Value) -> Result<Value> {
+    let matrix = matrix
+        .as_mapping()
+        .ok_or_else(|| anyhow!("'matrix' must be an object"))?;
+    let mut variants = vec![(Vec::new(), Mapping::new())];
+    for (dimension, values) in matrix {
+        let dimension = dimension
+            .as_str()
+            .ok_or_else(|| anyhow!("matrix dimension names must be strings"))?;
+        let values = matrix_dimension(dimension, values)?;
+        variants = variants
+            .into_iter()
+            .flat_map(|(name, config)| {
+                values.iter().map(move |(label, value)| {
+                    let mut name = name.clone();
+                    name.push(format!("{}={}", dimension, label));
+                    let mut config = config.clone();
+                    merge_variant(&mut config, value);
+                    (name, config)
+                })
+            })
+            .collect();
     }
+    Ok(Value::Mapping(
+        variants
+            .into_iter()
+            .map(|(name, config)| (name.join(",").into(), config.into()))
+            .collect(),
+    ))
+}
 
-    if let Some(env) = config_val.get(&"env".to_owned().into()) {
-        get_env(&mut config.env, &env)?;
+/// Loads the chain of files that `config_val` extends, adding them to `files`
+/// with the most basic first, along with their paths. `chain` holds the files
+/// seen so far, to catch cycles.
+fn load_extends(
+    config_val: &Value,
+    dir: &Path,
+    chain: &mut Vec<PathBuf>,
+    files: &mut Vec<(Value, Option<PathBuf>)>,
+) -> Result<()> {
+    let base = match config_val.get(&*EXTENDS_KEY) {
+        Some(base) => base
+            .as_str()
+            .ok_or_else(|| anyhow!("'extends' must be a string"))?,
+        None => return Ok(()),
+    };
+    let path = dir.join(base);
+    let path = path
+        .canonicalize()
+        .with_context(|| format!("cannot read config file {}", path.display()))?;
+    let seen = chain.contains(&path);
+    chain.push(path.clone());
+    if seen {
+        let chain: Vec<_> = chain.iter().map(|path| path.display().to_string()).collect();
+        bail!("config files extend each other in a cycle: {}", chain.join(" -> "));
     }
+    let contents = read_to_string(&path)
+        .with_context(|| format!("cannot read config file {}", path.display()))?;
+    let base_val: Value = from_str(&contents)
+        .map_err(|e| anyhow!("invalid config in {}: {}", path.display(), e))?;
+    load_extends(&base_val, path.parent().unwrap(), chain, files)?;
+    files.push((base_val, Some(path)));
     Ok(())
 }
 
-pub(crate) fn get_config(filename: &str) -> Result<Config> {
-    let mut config = Config {
+/// A config with nothing set yet, whose relative paths are relative to
+/// `config_dir`.
+fn empty_config(config_dir: PathBuf) -> Config {
+    Config {
         name: None,
         variant: None,
         service: None,
@@ -149,33 +667,181 @@ pub(crate) fn get_config(filename: &str) -> Result<Config> {
         teardown: None,
         run: vec!["INIT".into()],
         timeout: None,
-        env: HashMap::new(),
+        env: BTreeMap::new(),
+        env_remove: BTreeSet::new(),
+        clear_env: false,
+        cpu_affinity: Vec::new(),
+        nice: None,
+        pin_services: false,
         cachegrind: false,
+        valgrind: None,
         instructions: false,
+        perf_stat: Vec::new(),
+        heap_profile: None,
+        syscalls: false,
+        keep_profiles: false,
         iterations: 1,
+        concurrency: 1,
         variants: None,
-    };
-    let json_str = read_to_string(filename)?;
-    let config_val: Value = from_str(&json_str)?;
+        benchmark: None,
+        benchmarks: None,
+        metric_precision: None,
+        output_file: None,
+        strict: false,
+        shell: None,
+        cwd: None,
+        paths_relative_to_config: false,
+        interleave: false,
+        manage_tmpdir: false,
+        measure_disk: Vec::new(),
+        wait_for: Vec::new(),
+        service_may_exit: false,
+        iteration_retries: 0,
+        continue_on_failure: false,
+        total_timeout: None,
+        duration: None,
+        min_iterations: None,
+        max_iterations: None,
+        assertions: None,
+        stdout_metrics: None,
+        results_file: None,
+        higher_is_better: Vec::new(),
+        collect_host_info: true,
+        collect_system_state: true,
+        noise_check: None,
+        cgroup_accounting: false,
+        max_memory: None,
+        disable_aslr: false,
+        nest_metrics: false,
+        reap_orphans: false,
+        exclusive: false,
+        exclusive_timeout: DEFAULT_EXCLUSIVE_TIMEOUT,
+        drop_caches: None,
+        stream: false,
+        quiet: false,
+        summary_table: false,
+        baseline: None,
+        fail_on_regression: None,
+        history: None,
+        export: Export::default(),
+        statsd_forward: None,
+        statsd_prefix: None,
+        statsd_host: None,
+        statsd_protocol: StatsdProtocol::Udp,
+        redact_env: Vec::new(),
+        setup_stdio: None,
+        run_stdio: None,
+        service_stdio: None,
+        teardown_stdio: None,
+        iteration: None,
+        profile_dir: None,
+        config_dir,
+    }
+}
+
+pub(crate) fn get_config(source: &ConfigSource, variant: Option<&str>) -> Result<Config> {
+    let mut config = empty_config(config_dir(source));
+    let mut config_val: Value = from_str(&source.contents)
+        .map_err(|e| anyhow!("invalid config in {}: {}", source.name, e))?;
+    if source.package_json {
+        config_val = match config_val.get("sirun") {
+            Some(sirun) if sirun.is_mapping() => sirun.clone(),
+            Some(_) => bail!("'sirun' in {} must be an object", source.name),
+            None => bail!("{} has no 'sirun' field", source.name),
+        };
+    }
+
+    let mut chain = vec![match source.from_stdin {
+        true => PathBuf::from("stdin"),
+        false => Path::new(&source.name).canonicalize()?,
+    }];
+    let mut files = Vec::new();
+    load_extends(&config_val, &config_dir(source), &mut chain, &mut files)?;
+    files.push((config_val, None));
+    // Where each layer sits, for error messages: the file it's in if that's
+    // one being extended, and its key path within it.
+    let mut paths = vec![String::new(); files.len()];
+
+    // A benchmark's config is one more layer on top of the file's, so it has
+    // the file's settings as defaults, and can have variants of its own.
+    let benchmarks = files
+        .iter()
+        .rev()
+        .find_map(|(file, _)| file.get(&*BENCHMARKS_KEY))
+        .map(get_benchmarks)
+        .transpose()?;
+    let mut benchmark_names = None;
+    let mut benchmark_name = None;
+    if let Some(benchmarks) = benchmarks {
+        match &source.benchmark {
+            Some(name) => {
+                let benchmark = match benchmarks.iter().find(|(key, _)| key == name) {
+                    Some((_, benchmark)) => benchmark.clone(),
+                    None => bail!("benchmark {} does not exist", name),
+                };
+                if benchmark.get(&*NAME_KEY).is_none() {
+                    benchmark_name = Some(name.clone());
+                }
+                config.benchmark = Some(name.clone());
+                files.push((benchmark, None));
+                paths.push(join_path(BENCHMARKS_KEY.as_str().unwrap(), name));
+            }
+            None => benchmark_names = Some(benchmarks.into_iter().map(|(key, _)| key).collect()),
+        }
+    }
 
-    apply_config(&mut config, &config_val)?;
+    let parse_layer = |raw: &RawConfig, value: &Value, file: &Option<PathBuf>, path: &str| {
+        let layer = RawConfig::parse(value, raw.strict(false), path);
+        match file {
+            Some(file) => layer.with_context(|| format!("in {}", file.display())),
+            None => layer,
+        }
+    };
+    let mut raw = RawConfig::default();
+    for ((value, file), path) in files.iter().zip(&paths) {
+        let layer = parse_layer(&raw, value, file, path)?;
+        raw = raw.merge(layer);
+    }
+    // Results are told apart by name, so each benchmark has its own unless it
+    // sets one, or `SIRUN_NAME` is set.
+    let named = |mut config: Config| {
+        if benchmark_name.is_some() && env::var("SIRUN_NAME").is_err() {
+            config.name = benchmark_name.clone();
+        }
+        config
+    };
+    if benchmark_names.is_some() {
+        raw.apply(&mut config);
+        config.benchmarks = benchmark_names;
+        return Ok(named(config));
+    }
 
-    if let Some(variants) = config_val.get("variants") {
-        let variant_key = match env::var("SIRUN_VARIANT") {
-            Ok(variant_key) => variant_key,
-            Err(_) => {
+    // A config's own variants replace those of the config it extends.
+    let mut variants = None;
+    for (i, (file, _)) in files.iter().enumerate().rev() {
+        variants = get_variants(file)?.map(|variants| (i, variants));
+        if variants.is_some() {
+            break;
+        }
+    }
+    if let Some((i, variants)) = &variants {
+        let variant_key = match variant {
+            Some(variant_key) => variant_key.to_owned(),
+            None => {
                 if let Some(variants) = variants.as_sequence() {
                     let usize_ids: Vec<usize> = (0..variants.len()).collect();
+                    raw.apply(&mut config);
                     config.variants = Some(usize_ids.iter().map(|i| i.to_string()).collect());
-                    return Ok(config);
+                    return Ok(named(config));
                 } else if let Some(variants) = variants.as_mapping() {
+                    raw.apply(&mut config);
                     config.variants = Some(
                         variants
                             .iter()
                             .map(|(k, _v)| k.as_str().unwrap().to_owned())
                             .collect(),
                     );
-                    return Ok(config);
+                    return Ok(named(config));
                 } else {
                     bail!("variants must be an array or object");
                 }
@@ -183,28 +849,200 @@ pub(crate) fn get_config(filename: &str) -> Result<Config> {
         };
 
         config.variant = Some(variant_key.clone());
-        let config_json = if let Some(variants) = variants.as_sequence() {
+        let (file, path) = (&files[*i].1, &paths[*i]);
+        let (config_json, variant_path) = if let Some(variants) = variants.as_sequence() {
             let id = variant_key.parse()?;
             ensure!(
                 variants.len() > id,
                 "variant index {} does not exist in array",
                 id
             );
-            &variants[id]
+            (&variants[id], format!("{}[{}]", join_path(path, "variants"), id))
         } else if let Some(variants) = variants.as_mapping() {
-            match variants.get(&variant_key.clone().into()) {
+            let val = match variants.get(&variant_key.clone().into()) {
                 Some(val) => val,
                 None => bail!("variant key {} does not exist in object", variant_key),
-            }
+            };
+            let variant_path = match files[*i].0.get(&*MATRIX_KEY) {
+                Some(_) => format!("{}[{}]", join_path(path, "matrix"), variant_key),
+                None => join_path(&join_path(path, "variants"), &variant_key),
+            };
+            (val, variant_path)
         } else {
             bail!("variants must be array or object")
         };
-        apply_config(&mut config, &config_json)?;
+        let layer = parse_layer(&raw, config_json, file, &variant_path)?;
+        raw = raw.merge(layer);
     }
+    raw.apply(&mut config);
+    let mut config = named(config);
 
     if config.run.concat() == "INIT" {
-        bail!("'run' must be provided");
+        // When a variant or benchmark is selected, name the layers that
+        // could have set it, since any of them could be the one missing it.
+        let mut layers = vec!["the config".to_owned()];
+        if let Some(benchmark) = &config.benchmark {
+            layers.push(format!("benchmark '{}'", benchmark));
+        }
+        if let Some(variant) = &config.variant {
+            layers.push(format!("variant '{}'", variant));
+        }
+        match layers.len() {
+            1 => bail!("'run' must be provided"),
+            2 => bail!("'run' must be provided, but neither {} sets it", layers.join(" nor ")),
+            _ => bail!("'run' must be provided, but none of {} set it", layers.join(", ")),
+        }
+    }
+    ensure!(
+        config.valgrind.is_none() || !config.instructions,
+        "'valgrind' and 'instructions' can't be used together, since both count instructions"
+    );
+    let profilers: Vec<_> = [
+        ("valgrind", config.valgrind.is_some()),
+        ("perf_stat", !config.perf_stat.is_empty()),
+        ("heap_profile", config.heap_profile.is_some()),
+        ("syscalls", config.syscalls),
+    ]
+    .iter()
+    .filter(|(_, set)| *set)
+    .map(|(key, _)| *key)
+    .collect();
+    if let [first, second, ..] = profilers[..] {
+        bail!(
+            "'{}' and '{}' can't be used together, since both wrap the 'run' command",
+            first,
+            second
+        );
+    }
+    if config.concurrency > 1 {
+        let single: Vec<_> = [
+            ("instructions", config.instructions),
+            ("stdout_metrics", config.stdout_metrics.is_some()),
+        ]
+        .iter()
+        .filter(|(_, set)| *set)
+        .map(|(key, _)| *key)
+        .chain(profilers.first().copied())
+        .collect();
+        if let Some(key) = single.first() {
+            bail!(
+                "'concurrency' and '{}' can't be used together, since '{}' only works with \
+                 one copy of the 'run' command",
+                key,
+                key
+            );
+        }
+    }
+    if !config.cpu_affinity.is_empty() || config.nice.is_some() {
+        if cfg!(target_os = "linux") {
+            let online = online_cpus().unwrap_or(u64::MAX);
+            for cpu in &config.cpu_affinity {
+                ensure!(
+                    (*cpu as u64) < online,
+                    "'cpu_affinity' has CPU {}, but only CPUs 0 to {} are online",
+                    cpu,
+                    online - 1
+                );
+            }
+        } else {
+            warn!("'cpu_affinity' and 'nice' are only supported on Linux, so they're ignored");
+        }
+    }
+    if config.disable_aslr && !cfg!(target_os = "linux") {
+        warn!("'disable_aslr' is only supported on Linux, so it's ignored");
+    }
+    ensure!(
+        config.drop_caches.is_none() || cfg!(target_os = "linux"),
+        "'drop_caches' is only supported on Linux"
+    );
+    if config.heap_profile.is_some() && (config.iterations > 1 || config.duration.is_some()) {
+        warn!("'heap_profile' makes iterations much slower, so one is usually enough");
+    }
+    ensure!(
+        !config.instructions || !config.perf_stat.iter().any(|event| event == "instructions"),
+        "'instructions' can't be used along with a 'perf_stat' that counts instructions"
+    );
+    if let (Some(min), Some(max)) = (config.min_iterations, config.max_iterations) {
+        ensure!(
+            min <= max,
+            "'min_iterations' ({}) must not be greater than 'max_iterations' ({})",
+            min,
+            max
+        );
+    }
+
+    if let Some(cwd) = &config.cwd {
+        config.cwd = Some(resolve_cwd(source, cwd)?);
+    }
+    if config.paths_relative_to_config {
+        resolve_command_paths(&mut config, source)?;
     }
 
     Ok(config)
 }
+
+/// Keys that can be given with `--set`, besides `env.NAME`: those that make
+/// sense to change for a single run without editing the config file.
+const SETTABLE_KEYS: &[&str] = &[
+    "name",
+    "iterations",
+    "duration",
+    "timeout",
+    "total_timeout",
+    "instructions",
+    "cachegrind",
+    "valgrind",
+    "iteration_retries",
+    "continue_on_failure",
+    "collect_host_info",
+    "exclusive",
+    "exclusive_timeout",
+];
+
+/// Adds a `--set key=value` to `overrides`, which is applied on top of the
+/// resolved config like one more layer of config file. Values are parsed as
+/// YAML, so that `true` and `5` are a boolean and a number, except for names
+/// and environment variables, which are always strings.
+pub(crate) fn add_override(overrides: &mut Mapping, spec: &str) -> Result<()> {
+    let (key, value) = match spec.find('=') {
+        Some(i) => (&spec[..i], &spec[i + 1..]),
+        None => bail!("--set must be given as KEY=VALUE, got '{}'", spec),
+    };
+    if let Some(name) = key.strip_prefix("env.") {
+        ensure!(!name.is_empty(), "--set env.NAME requires a variable name");
+        let env_key: Value = "env".into();
+        if !overrides.contains_key(&env_key) {
+            overrides.insert(env_key.clone(), Mapping::new().into());
+        }
+        if let Some(Value::Mapping(env)) = overrides.get_mut(&env_key) {
+            env.insert(name.into(), value.into());
+        }
+        return Ok(());
+    }
+    ensure!(
+        SETTABLE_KEYS.contains(&key),
+        "cannot --set '{}'; settable keys are {} and env.NAME",
+        key,
+        SETTABLE_KEYS.join(", ")
+    );
+    let value = match key {
+        "name" => value.into(),
+        _ => from_str(value).with_context(|| format!("invalid value for '{}'", key))?,
+    };
+    overrides.insert(key.into(), value);
+    Ok(())
+}
+
+/// Applies `--set` overrides with the same validation as the config file.
+pub(crate) fn apply_overrides(config: &mut Config, overrides: &Mapping) -> Result<()> {
+    if overrides.is_empty() {
+        return Ok(());
+    }
+    RawConfig::parse(&Value::Mapping(overrides.clone()), config.strict, "")?.apply(config);
+    // Unlike in a config file, a name given on the command line takes
+    // precedence over `SIRUN_NAME`.
+    if let Some(Value::String(name)) = overrides.get(&NAME_KEY) {
+        config.name = Some(name.clone());
+    }
+    Ok(())
+}
@@ -0,0 +1,90 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+//! Summaries as CSV, for `--summarize --format csv` and `csv-wide`.
+
+use anyhow::*;
+use std::collections::BTreeSet;
+
+use crate::metric_value::*;
+use crate::summarize::*;
+
+/// Quotes a field if it has anything in it that would otherwise be taken for
+/// the end of one, doubling any quotes.
+fn field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+fn row(fields: impl IntoIterator<Item = String>) -> String {
+    fields
+        .into_iter()
+        .map(|value| field(&value))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// A statistic's cell, which is empty where it couldn't be computed, like a
+/// geometric mean without positive values.
+fn cell(stats: Option<&MetricMap>, stat: &str) -> String {
+    match stats.and_then(|stats| stats.get(stat)) {
+        Some(MetricValue::Num(value)) => value.to_string(),
+        _ => String::new(),
+    }
+}
+
+/// Writes the summaries of each name and variant as CSV with a header row,
+/// with the statistics in the order given. Rows are sorted by name, variant
+/// and then metric, and so are the columns of the wide layout.
+pub(crate) fn summary_csv(result_data: &MetricMap, stats: &[String], wide: bool) -> Result<String> {
+    let mut summaries = Vec::new();
+    for (name, variants) in result_data {
+        for (variant, data) in variants.as_map()? {
+            summaries.push((name, variant, summary_metrics(data.as_map()?)?));
+        }
+    }
+
+    let header = ["name", "variant"].iter().map(|column| column.to_string());
+    let mut lines = Vec::new();
+    if wide {
+        let all_metrics: BTreeSet<&String> = summaries
+            .iter()
+            .flat_map(|(_, _, metrics)| metrics.keys())
+            .collect();
+        lines.push(row(header.chain(all_metrics.iter().flat_map(|metric| {
+            stats.iter().map(move |stat| format!("{}.{}", metric, stat))
+        }))));
+        for (name, variant, metrics) in &summaries {
+            let cells = all_metrics.iter().flat_map(|metric| {
+                stats
+                    .iter()
+                    .map(move |stat| cell(metrics.get(*metric).copied(), stat))
+            });
+            lines.push(row(vec![name.to_string(), variant.to_string()]
+                .into_iter()
+                .chain(cells)));
+        }
+    } else {
+        lines.push(row(header
+            .chain(Some("metric".to_owned()))
+            .chain(stats.iter().cloned())));
+        for (name, variant, metrics) in &summaries {
+            for (metric, metric_stats) in metrics {
+                let cells = stats.iter().map(|stat| cell(Some(metric_stats), stat));
+                lines.push(row(vec![
+                    name.to_string(),
+                    variant.to_string(),
+                    metric.clone(),
+                ]
+                .into_iter()
+                .chain(cells)));
+            }
+        }
+    }
+    Ok(lines.join("\n"))
+}
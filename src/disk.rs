@@ -0,0 +1,120 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+use log::warn;
+use std::{
+    fs, io,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use crate::config::*;
+use crate::metric_value::*;
+
+/// Total size in bytes of the files under `path`, following symlinks. A path
+/// that doesn't exist counts as empty. Anything that can't be read, and
+/// symlinks leading back into a directory being walked, are skipped with a
+/// warning.
+pub(crate) fn disk_usage(path: &Path) -> u64 {
+    match fs::metadata(path) {
+        Ok(metadata) => usage_of(path, &metadata, &mut Vec::new()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+        Err(e) => {
+            warn_skipped(path, &e);
+            0
+        }
+    }
+}
+
+/// `ancestors` holds the device and inode of every directory above `path`.
+fn usage_of(path: &Path, metadata: &fs::Metadata, ancestors: &mut Vec<(u64, u64)>) -> u64 {
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+    let id = (metadata.dev(), metadata.ino());
+    if ancestors.contains(&id) {
+        warn!("skipping {}, a symlink loop", path.display());
+        return 0;
+    }
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn_skipped(path, &e);
+            return 0;
+        }
+    };
+    ancestors.push(id);
+    let mut total = 0;
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry.path(),
+            Err(e) => {
+                warn_skipped(path, &e);
+                continue;
+            }
+        };
+        match fs::metadata(&entry) {
+            Ok(metadata) => total += usage_of(&entry, &metadata, ancestors),
+            Err(e) => warn_skipped(&entry, &e),
+        }
+    }
+    ancestors.pop();
+    total
+}
+
+fn warn_skipped(path: &Path, e: &io::Error) {
+    warn!(
+        "skipping {} when measuring disk usage: {}",
+        path.display(),
+        e
+    );
+}
+
+/// Name of the metric for a `measure_disk` path, e.g. `logs/tracer` becomes
+/// `disk.logs_tracer.bytes.delta`.
+fn disk_metric_name(path: &str) -> String {
+    let sanitized: String = path
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("disk.{}.bytes.delta", sanitized.trim_matches('_'))
+}
+
+/// Sizes of the `measure_disk` paths, taken before and after `run`.
+pub(crate) struct DiskUsage(Vec<u64>);
+
+impl DiskUsage {
+    pub(crate) fn measure(config: &Config) -> Self {
+        DiskUsage(
+            config
+                .measure_disk
+                .iter()
+                .map(|path| disk_usage(&measured_path(config, path)))
+                .collect(),
+        )
+    }
+
+    /// Records how much each path has grown (or shrunk) since `before`.
+    pub(crate) fn add_deltas(&self, before: &DiskUsage, config: &Config, metrics: &mut MetricMap) {
+        for ((path, after), before) in config.measure_disk.iter().zip(&self.0).zip(&before.0) {
+            let delta = *after as f64 - *before as f64;
+            metrics.insert(disk_metric_name(path), delta.into());
+        }
+    }
+}
+
+/// Relative paths are relative to the directory commands are run in.
+fn measured_path(config: &Config, path: &str) -> PathBuf {
+    match &config.cwd {
+        Some(cwd) => Path::new(cwd).join(path),
+        None => PathBuf::from(path),
+    }
+}
@@ -0,0 +1,75 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+//! `sirun exec`, for measuring a command without writing a config file for it.
+
+use anyhow::*;
+use serde_json::{json, Map, Value};
+
+use crate::cli::*;
+use crate::config::*;
+
+/// Turns the arguments following `exec` into the config file they stand for,
+/// so that it's checked and resolved the same way as any other.
+pub(crate) fn exec_config(mut args: impl Iterator<Item = String>) -> Result<ConfigSource> {
+    let mut config = Map::new();
+    let mut env = Map::new();
+    let mut run = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--" => {
+                run.extend(args.by_ref());
+                break;
+            }
+            "--iterations" => {
+                let iterations = parse_iterations(&flag_value(&mut args, &arg)?)?;
+                config.insert("iterations".into(), iterations.into());
+            }
+            "--name" => {
+                config.insert("name".into(), flag_value(&mut args, &arg)?.into());
+            }
+            "--env" => {
+                let pair = flag_value(&mut args, &arg)?;
+                let (name, value) = match pair.find('=') {
+                    Some(0) | None => bail!("--env must be given as KEY=VALUE, got '{}'", pair),
+                    Some(i) => (&pair[..i], &pair[i + 1..]),
+                };
+                env.insert(name.into(), value.into());
+            }
+            "--timeout" => {
+                let timeout = match flag_value(&mut args, &arg)?.parse::<u64>() {
+                    Ok(timeout) if timeout > 0 => timeout,
+                    _ => bail!("--timeout must be an integer >=1"),
+                };
+                config.insert("timeout".into(), timeout.into());
+            }
+            "--instructions" => {
+                config.insert("instructions".into(), true.into());
+            }
+            _ if arg.starts_with("--") => bail!("unknown exec option '{}'", arg),
+            // Without `--`, the command starts at the first argument that
+            // isn't an option.
+            _ => {
+                run.push(arg);
+                run.extend(args.by_ref());
+                break;
+            }
+        }
+    }
+    ensure!(!run.is_empty(), "exec requires a command to run");
+    config.insert("run".into(), json!(run));
+    if !env.is_empty() {
+        config.insert("env".into(), Value::Object(env));
+    }
+    Ok(ConfigSource {
+        name: "exec".into(),
+        contents: Value::Object(config).to_string(),
+        // There's no file, so paths are relative to the current directory,
+        // as they are for config read from stdin.
+        from_stdin: true,
+        package_json: false,
+        benchmark: None,
+    })
+}
@@ -0,0 +1,560 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+//! Sending results to Datadog or Prometheus once a run is done, as set up by
+//! the `export` config key or `--export`.
+
+use anyhow::*;
+use log::error;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use serde_yaml::Value;
+use std::{
+    collections::BTreeMap,
+    env,
+    io::Write,
+    process::{Command, Stdio},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tempfile::NamedTempFile;
+use which::which;
+
+use crate::config::*;
+use crate::flags::*;
+use crate::metric_value::*;
+use crate::precision::*;
+use crate::prometheus::*;
+use crate::raw_config::*;
+use crate::statistics::*;
+use crate::summarize::*;
+
+/// The places results can be exported to, as named in the `export` config
+/// key.
+pub(crate) const EXPORT_KEYS: &[&str] = &["datadog", "prometheus_textfile", "pushgateway"];
+
+const DATADOG_KEYS: &[&str] = &["site", "prefix", "tags", "send", "strict"];
+
+const DEFAULT_SITE: &str = "datadoghq.com";
+
+const DEFAULT_PREFIX: &str = "sirun.";
+
+/// The type of a gauge in Datadog's series API.
+const GAUGE_TYPE: u64 = 3;
+
+/// How long to wait for metrics to be taken, in seconds.
+const UPLOAD_TIMEOUT_SECS: u64 = 30;
+
+/// Which metrics are sent to Datadog.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ExportedMetrics {
+    /// The statistics of each metric over the iterations that didn't fail.
+    Summary,
+    /// Each iteration's metrics, timestamped with when it started.
+    Iterations,
+    Both,
+}
+
+/// How results are sent to Datadog, as given in `export.datadog`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub(crate) struct DatadogExport {
+    /// Falls back to `DD_SITE`, and then to `datadoghq.com`.
+    site: Option<String>,
+    prefix: String,
+    tags: Vec<String>,
+    send: ExportedMetrics,
+    /// Whether a failed export fails the run, rather than only being logged.
+    strict: bool,
+}
+
+impl Default for DatadogExport {
+    fn default() -> Self {
+        DatadogExport {
+            site: None,
+            prefix: DEFAULT_PREFIX.to_owned(),
+            tags: Vec::new(),
+            send: ExportedMetrics::Summary,
+            strict: false,
+        }
+    }
+}
+
+impl DatadogExport {
+    /// Parses `true` for the defaults, or an object with any of
+    /// `DATADOG_KEYS`.
+    fn parse(value: &Value) -> Result<Self> {
+        if value.as_bool() == Some(true) {
+            return Ok(DatadogExport::default());
+        }
+        let value = value
+            .as_mapping()
+            .ok_or_else(|| anyhow!("must be true or an object"))?;
+        for (key, _) in value.iter() {
+            let key = key.as_str().unwrap_or_default();
+            ensure!(DATADOG_KEYS.contains(&key), "has unknown key '{}'", key);
+        }
+        let get = |key: &str| value.get(&key.into());
+        let text = |key: &str| -> Result<Option<String>> {
+            get(key)
+                .map(|val| {
+                    val.as_str()
+                        .map(str::to_owned)
+                        .ok_or_else(|| anyhow!("'.{}' must be a string", key))
+                })
+                .transpose()
+        };
+        Ok(DatadogExport {
+            site: text("site")?,
+            prefix: text("prefix")?.unwrap_or_else(|| DEFAULT_PREFIX.to_owned()),
+            tags: match get("tags") {
+                Some(tags) => tags
+                    .as_sequence()
+                    .and_then(|tags| {
+                        tags.iter()
+                            .map(|tag| tag.as_str().map(str::to_owned))
+                            .collect()
+                    })
+                    .ok_or_else(|| anyhow!("'.tags' must be an array of strings"))?,
+                None => Vec::new(),
+            },
+            send: match text("send")?.as_deref() {
+                None | Some("summary") => ExportedMetrics::Summary,
+                Some("iterations") => ExportedMetrics::Iterations,
+                Some("both") => ExportedMetrics::Both,
+                Some(_) => bail!("'.send' must be 'summary', 'iterations' or 'both'"),
+            },
+            strict: match get("strict") {
+                Some(strict) => strict
+                    .as_bool()
+                    .ok_or_else(|| anyhow!("'.strict' must be a boolean"))?,
+                None => false,
+            },
+        })
+    }
+
+    fn url(&self) -> String {
+        let site = self
+            .site
+            .clone()
+            .or_else(|| env::var("DD_SITE").ok())
+            .unwrap_or_else(|| DEFAULT_SITE.to_owned());
+        format!("https://api.{}/api/v2/series", site)
+    }
+
+    /// Tags for the results' name, variant and version, followed by the
+    /// configured ones.
+    fn tags(&self, results: &MetricMap) -> Vec<String> {
+        let mut tags: Vec<String> = ["name", "variant", "version"]
+            .iter()
+            .filter_map(|key| match results.get(*key) {
+                Some(MetricValue::Str(value)) => Some(format!("{}:{}", key, value)),
+                _ => None,
+            })
+            .collect();
+        tags.extend(self.tags.iter().cloned());
+        tags
+    }
+
+    /// The body of a request to Datadog's series API, with a gauge for each
+    /// metric, or for each statistic of each metric. Values are rounded to
+    /// `precision`, statistics only once they've been computed.
+    fn payload(
+        &self,
+        results: &MetricMap,
+        precision: Option<&Precision>,
+    ) -> Result<serde_json::Value> {
+        let iterations = match results.get("iterations") {
+            Some(iterations) => iterations.as_vec()?,
+            None => Vec::new(),
+        };
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let tags = self.tags(results);
+        let mut series = Vec::new();
+        let mut add = |metric: String, points: Vec<(u64, f64)>| {
+            let points: Vec<_> = points
+                .into_iter()
+                .map(|(timestamp, value)| json!({"timestamp": timestamp, "value": value}))
+                .collect();
+            series.push(json!({
+                "metric": format!("{}{}", self.prefix, metric),
+                "type": GAUGE_TYPE,
+                "points": points,
+                "tags": tags,
+            }));
+        };
+
+        if self.send != ExportedMetrics::Summary {
+            let mut points = BTreeMap::<String, Vec<(u64, f64)>>::new();
+            for iteration in &iterations {
+                let metrics = iteration.as_map()?;
+                if is_failed(metrics) {
+                    continue;
+                }
+                let timestamp = match metrics.get(START_EPOCH_MS_KEY) {
+                    Some(MetricValue::Num(ms)) => (ms / 1000.0) as u64,
+                    _ => now,
+                };
+                for (path, values) in collect_stats(std::slice::from_ref(iteration))? {
+                    let name = path.join(".");
+                    let metric = points.entry(name.clone()).or_default();
+                    metric.extend(values.into_iter().map(|value| match precision {
+                        Some(precision) => (timestamp, precision.round(&name, value)),
+                        None => (timestamp, value),
+                    }));
+                }
+            }
+            for (metric, points) in points {
+                add(metric, points);
+            }
+        }
+        if self.send != ExportedMetrics::Iterations {
+            let mut statistics = statistics(&iterations, DEFAULT_STATISTICS)?;
+            if let Some(precision) = precision {
+                precision.round_statistics(DEFAULT_STATISTICS, &mut statistics);
+            }
+            for (metric, values) in statistics {
+                for (stat, value) in DEFAULT_STATISTICS.iter().zip(values) {
+                    if let Some(value) = value {
+                        add(format!("{}.{}", metric, stat.name()), vec![(now, value)]);
+                    }
+                }
+            }
+        }
+        Ok(json!({ "series": series }))
+    }
+}
+
+/// A place to send results to, as given to `--export`.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum ExportTarget {
+    Datadog,
+    PrometheusTextfile(String),
+    Pushgateway(String),
+}
+
+impl ExportTarget {
+    /// Parses `datadog`, `prometheus-textfile=PATH` or `pushgateway=URL`.
+    pub(crate) fn parse(spec: &str) -> Result<Self> {
+        let (target, value) = match spec.split_once('=') {
+            Some((target, value)) => (target, Some(value).filter(|value| !value.is_empty())),
+            None => (spec, None),
+        };
+        Ok(match (target, value) {
+            ("datadog", None) => ExportTarget::Datadog,
+            ("prometheus-textfile", Some(path)) => {
+                ExportTarget::PrometheusTextfile(path.to_owned())
+            }
+            ("pushgateway", Some(url)) => ExportTarget::Pushgateway(url.to_owned()),
+            _ => bail!(
+                "unknown export target '{}', expected datadog, prometheus-textfile=PATH or \
+                 pushgateway=URL",
+                spec
+            ),
+        })
+    }
+}
+
+/// Where results are sent after a run, from the `export` config key and
+/// `--export`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub(crate) struct Export {
+    pub(crate) datadog: Option<DatadogExport>,
+    /// A file for node_exporter's textfile collector to read.
+    pub(crate) prometheus_textfile: Option<String>,
+    /// The URL of a Prometheus Pushgateway.
+    pub(crate) pushgateway: Option<String>,
+    /// Whether what would be sent is printed instead, from
+    /// `--export-dry-run`.
+    pub(crate) dry_run: bool,
+}
+
+impl Export {
+    /// Parses an object with a key for each of `EXPORT_KEYS` to send results
+    /// to. Any of them can be `false`, to turn off one set in a layer below.
+    pub(crate) fn parse(value: &Value) -> Result<Self> {
+        let value = value
+            .as_mapping()
+            .ok_or_else(|| anyhow!("must be an object"))?;
+        let mut export = Export::default();
+        for (key, target) in value {
+            let key = key.as_str().unwrap_or_default();
+            ensure!(EXPORT_KEYS.contains(&key), "has unknown key '{}'", key);
+            if target.as_bool() == Some(false) {
+                continue;
+            }
+            let at_key = |e| at_path(&format!(".{}", key), e);
+            let text = || {
+                target
+                    .as_str()
+                    .filter(|text| !text.is_empty())
+                    .map(str::to_owned)
+                    .ok_or_else(|| at_key(anyhow!("must be a non-empty string or false")))
+            };
+            match key {
+                "datadog" => export.datadog = Some(DatadogExport::parse(target).map_err(at_key)?),
+                "prometheus_textfile" => export.prometheus_textfile = Some(text()?),
+                _ => export.pushgateway = Some(text()?),
+            }
+        }
+        Ok(export)
+    }
+
+    /// Sends results to `target`, with the defaults for Datadog unless the
+    /// config already sets it up with settings of its own.
+    pub(crate) fn enable(&mut self, target: &ExportTarget) {
+        match target {
+            ExportTarget::Datadog => {
+                if self.datadog.is_none() {
+                    self.datadog = Some(DatadogExport::default());
+                }
+            }
+            ExportTarget::PrometheusTextfile(path) => self.prometheus_textfile = Some(path.clone()),
+            ExportTarget::Pushgateway(url) => self.pushgateway = Some(url.clone()),
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.datadog.is_none() && self.prometheus_textfile.is_none() && self.pushgateway.is_none()
+    }
+}
+
+/// Sends a payload to `url` with `curl`. Headers are given to it in a file,
+/// since other users can see the arguments of processes, and some of them
+/// hold keys.
+fn upload(method: &str, url: &str, headers: &[String], payload: &str) -> Result<()> {
+    let curl = which("curl").map_err(|_| anyhow!("curl is needed, but isn't on the PATH"))?;
+    let mut header_file = NamedTempFile::new()?;
+    for header in headers {
+        writeln!(header_file, "{}", header)?;
+    }
+    let mut child = Command::new(curl)
+        .args(["--silent", "--show-error", "--fail", "--max-time"])
+        .arg(UPLOAD_TIMEOUT_SECS.to_string())
+        .args(["--request", method, "--header"])
+        .arg(format!("@{}", header_file.path().display()))
+        .args(["--data-binary", "@-", url])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    child.stdin.take().unwrap().write_all(payload.as_bytes())?;
+    let output = child.wait_with_output()?;
+    ensure!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+    Ok(())
+}
+
+fn export_to_datadog(
+    datadog: &DatadogExport,
+    dry_run: bool,
+    results: &MetricMap,
+    precision: Option<&Precision>,
+) -> Result<()> {
+    let payload = datadog.payload(results, precision)?;
+    let url = datadog.url();
+    if dry_run {
+        eprintln!(
+            "Would send to {}:\n{}",
+            url,
+            serde_json::to_string_pretty(&payload)?
+        );
+        return Ok(());
+    }
+    let api_key = env::var("DD_API_KEY").map_err(|_| anyhow!("DD_API_KEY isn't set"))?;
+    let headers = [
+        format!("DD-API-KEY: {}", api_key),
+        "Content-Type: application/json".to_owned(),
+    ];
+    upload("POST", &url, &headers, &payload.to_string())
+}
+
+fn export_to_textfile(
+    path: &str,
+    dry_run: bool,
+    results: &MetricMap,
+    precision: Option<&Precision>,
+) -> Result<()> {
+    if dry_run {
+        eprint!(
+            "Would write to {}:\n{}",
+            path,
+            exposition(results, precision)?
+        );
+        return Ok(());
+    }
+    write_textfile(path, results, precision)
+}
+
+fn export_to_pushgateway(
+    base: &str,
+    dry_run: bool,
+    results: &MetricMap,
+    precision: Option<&Precision>,
+) -> Result<()> {
+    let url = pushgateway_url(base, results);
+    let payload = exposition(results, precision)?;
+    if dry_run {
+        eprint!("Would send to {}:\n{}", url, payload);
+        return Ok(());
+    }
+    let headers = ["Content-Type: text/plain; version=0.0.4".to_owned()];
+    upload("PUT", &url, &headers, &payload)
+}
+
+/// Sends the results wherever the config says to. Failing to isn't the
+/// benchmark's fault, so it's only logged, unless a Datadog export is
+/// `strict`. The results are exact, and rounded to `metric_precision` only
+/// as they're exported.
+pub(crate) fn export_results(config: &Config, results: &MetricMap) -> Result<()> {
+    let export = &config.export;
+    let precision = config.metric_precision.as_ref();
+    if let Some(path) = &export.prometheus_textfile {
+        if let Err(e) = export_to_textfile(path, export.dry_run, results, precision) {
+            error!("cannot export results to {}: {:#}", path, e);
+        }
+    }
+    if let Some(url) = &export.pushgateway {
+        if let Err(e) = export_to_pushgateway(url, export.dry_run, results, precision) {
+            error!(
+                "cannot export results to the Pushgateway at {}: {:#}",
+                url, e
+            );
+        }
+    }
+    let datadog = match &export.datadog {
+        Some(datadog) => datadog,
+        None => return Ok(()),
+    };
+    match export_to_datadog(datadog, export.dry_run, results, precision) {
+        Ok(()) => Ok(()),
+        Err(e) if datadog.strict => Err(e.context("cannot export results to Datadog")),
+        Err(e) => {
+            error!("cannot export results to Datadog: {:#}", e);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn results() -> MetricMap {
+        let iteration = |start: f64, wall: f64| {
+            let mut iteration = MetricMap::new();
+            iteration.insert(START_EPOCH_MS_KEY.to_owned(), start.into());
+            iteration.insert(ITERATION_KEY.to_owned(), 0.into());
+            iteration.insert("wall.time".to_owned(), wall.into());
+            MetricValue::Map(iteration)
+        };
+        let mut results = MetricMap::new();
+        results.insert("name".to_owned(), "startup".to_owned().into());
+        results.insert("version".to_owned(), "abc123".to_owned().into());
+        results.insert(
+            "iterations".to_owned(),
+            MetricValue::Arr(vec![
+                iteration(1_000_000.0, 10.0),
+                iteration(2_000_500.0, 20.0),
+            ]),
+        );
+        results
+    }
+
+    #[test]
+    fn parse() {
+        let parse = |yaml: &str| Export::parse(&serde_yaml::from_str(yaml).unwrap());
+        assert_eq!(
+            parse("{datadog: true}").unwrap().datadog,
+            Some(DatadogExport::default())
+        );
+        assert!(parse("{datadog: false}").unwrap().is_empty());
+        let datadog = parse("{datadog: {site: datadoghq.eu, tags: [team:apm], send: both}}")
+            .unwrap()
+            .datadog
+            .unwrap();
+        assert_eq!(datadog.url(), "https://api.datadoghq.eu/api/v2/series");
+        assert_eq!(datadog.tags, vec!["team:apm"]);
+        assert_eq!(datadog.send, ExportedMetrics::Both);
+        assert_eq!(
+            parse("{prometheus: true}").unwrap_err().to_string(),
+            "has unknown key 'prometheus'"
+        );
+        assert_eq!(
+            parse("{datadog: {send: all}}").unwrap_err().to_string(),
+            "'.datadog.send' must be 'summary', 'iterations' or 'both'"
+        );
+    }
+
+    #[test]
+    fn payload() {
+        let datadog = DatadogExport {
+            send: ExportedMetrics::Both,
+            tags: vec!["team:apm".to_owned()],
+            ..DatadogExport::default()
+        };
+        let payload = datadog.payload(&results(), None).unwrap();
+        let series = payload["series"].as_array().unwrap();
+        let find = |metric: &str| {
+            series
+                .iter()
+                .find(|series| series["metric"] == metric)
+                .unwrap_or_else(|| panic!("no series for {}", metric))
+        };
+        let wall_time = find("sirun.wall.time");
+        assert_eq!(
+            wall_time["points"],
+            json!([
+                {"timestamp": 1000, "value": 10.0},
+                {"timestamp": 2000, "value": 20.0}
+            ])
+        );
+        assert_eq!(
+            wall_time["tags"],
+            json!(["name:startup", "version:abc123", "team:apm"])
+        );
+        assert_eq!(find("sirun.wall.time.mean")["points"][0]["value"], 15.0);
+        assert_eq!(find("sirun.wall.time.count")["points"][0]["value"], 2.0);
+        assert!(series
+            .iter()
+            .all(|series| series["metric"] != "sirun.iteration"));
+    }
+
+    #[test]
+    fn rounded_payload() {
+        let mut results = results();
+        let mut iterations = results["iterations"].as_vec().unwrap();
+        iterations.push(iterations[0].clone());
+        for (iteration, wall) in iterations.iter_mut().zip([14.0, 14.0, 18.0]) {
+            if let MetricValue::Map(iteration) = iteration {
+                iteration.insert("wall.time".to_owned(), wall.into());
+            }
+        }
+        results.insert("iterations".to_owned(), MetricValue::Arr(iterations));
+        let mut precision = Precision::default();
+        precision.add_rule("wall.*", 1).unwrap();
+        let datadog = DatadogExport {
+            send: ExportedMetrics::Both,
+            ..DatadogExport::default()
+        };
+        let payload = datadog.payload(&results, Some(&precision)).unwrap();
+        let series = payload["series"].as_array().unwrap();
+        let find = |metric: &str| {
+            series
+                .iter()
+                .find(|series| series["metric"] == metric)
+                .unwrap_or_else(|| panic!("no series for {}", metric))
+        };
+        let points = find("sirun.wall.time")["points"].as_array().unwrap();
+        let walls: Vec<_> = points.iter().map(|point| &point["value"]).collect();
+        assert_eq!(walls, [10.0, 10.0, 20.0]);
+        // The mean of 15.3 is rounded, not the mean of the rounded values,
+        // which would be 13.3 and round to 10.
+        assert_eq!(find("sirun.wall.time.mean")["points"][0]["value"], 20.0);
+        assert_eq!(find("sirun.wall.time.count")["points"][0]["value"], 3.0);
+    }
+}
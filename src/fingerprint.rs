@@ -0,0 +1,152 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+//! A fingerprint of how a benchmark was defined, added to its results, so
+//! that results from different definitions can be told apart.
+
+use anyhow::*;
+use serde_json::Value;
+
+use crate::config::*;
+use crate::redact::*;
+
+/// The key of the results that holds the config's hash.
+pub(crate) const CONFIG_HASH_KEY: &str = "config.hash";
+
+/// The key of the results that holds the `run` command.
+pub(crate) const RUN_COMMAND_KEY: &str = "run.command";
+
+/// Config keys that only change where results go or how progress is shown,
+/// so that the same benchmark run differently keeps its hash.
+const UNHASHED_KEYS: &[&str] = &[
+    "output_file",
+    "stream",
+    "quiet",
+    "summary_table",
+    "baseline",
+    "fail_on_regression",
+    "history",
+    "export",
+    "statsd_forward",
+    "statsd_prefix",
+    "statsd_host",
+    "statsd_protocol",
+    "iteration",
+    "profile_dir",
+];
+
+/// Environment variables that sirun sets itself, which differ from one run
+/// to the next.
+const SIRUN_ENV: &[&str] = &[
+    "SIRUN_STATSD_PORT",
+    "SIRUN_STATSD_ADDR",
+    "SIRUN_STATSD_PROTOCOL",
+];
+
+/// JSON with the keys of objects sorted, so that the order maps were built
+/// in doesn't matter.
+fn canonical(value: &Value) -> String {
+    match value {
+        Value::Object(map) => {
+            let mut entries: Vec<_> = map.iter().collect();
+            entries.sort_by(|a, b| a.0.cmp(b.0));
+            let entries: Vec<String> = entries
+                .into_iter()
+                .map(|(key, value)| format!("{}:{}", Value::from(key.as_str()), canonical(value)))
+                .collect();
+            format!("{{{}}}", entries.join(","))
+        }
+        Value::Array(items) => {
+            let items: Vec<String> = items.iter().map(canonical).collect();
+            format!("[{}]", items.join(","))
+        }
+        value => value.to_string(),
+    }
+}
+
+/// 64-bit FNV-1a, which unlike the standard library's hasher is guaranteed
+/// to give the same hash in every build.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// A hash of the fully resolved config, leaving out `UNHASHED_KEYS`,
+/// `SIRUN_ENV` and the values of secret-looking environment variables, so
+/// that rotating a key doesn't look like a change to the benchmark.
+pub(crate) fn config_hash(config: &Config) -> Result<String> {
+    let mut value = serde_json::to_value(redacted(config))?;
+    let map = value.as_object_mut().unwrap();
+    for key in UNHASHED_KEYS {
+        map.remove(*key);
+    }
+    if let Some(Value::Object(env)) = map.get_mut("env") {
+        for name in SIRUN_ENV {
+            env.remove(*name);
+        }
+    }
+    Ok(format!("{:016x}", fnv1a(canonical(&value).as_bytes())))
+}
+
+/// The `run` command as it could be typed in a shell.
+pub(crate) fn run_command(config: &Config) -> String {
+    shlex::join(config.run.iter().map(String::as_str))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash() {
+        let config = Config::new(vec!["node", "bench.js"]);
+        let hash = config_hash(&config).unwrap();
+        assert_eq!(hash.len(), 16);
+        assert_eq!(config_hash(&config.clone()).unwrap(), hash);
+
+        let mut reported = config.clone();
+        reported.output_file = Some("results.ndjson".into());
+        reported.quiet = true;
+        assert_eq!(config_hash(&reported).unwrap(), hash);
+
+        let mut changed = config.clone();
+        changed.run.push("--fast".into());
+        assert_ne!(config_hash(&changed).unwrap(), hash);
+
+        let mut keyed = config.clone();
+        keyed.env.insert("DD_API_KEY".into(), "one".into());
+        keyed.env.insert("NODE_ENV".into(), "production".into());
+        let keyed_hash = config_hash(&keyed).unwrap();
+        assert_ne!(keyed_hash, hash);
+        keyed.env.insert("DD_API_KEY".into(), "two".into());
+        assert_eq!(config_hash(&keyed).unwrap(), keyed_hash);
+        keyed.env.insert("SIRUN_STATSD_PORT".into(), "8125".into());
+        assert_eq!(config_hash(&keyed).unwrap(), keyed_hash);
+        keyed.env.insert("NODE_ENV".into(), "development".into());
+        assert_ne!(config_hash(&keyed).unwrap(), keyed_hash);
+    }
+
+    #[test]
+    fn canonical_order() {
+        let a: Value =
+            serde_json::from_str(r#"{"b": {"y": 1, "x": [2, "3"]}, "a": null}"#).unwrap();
+        let b: Value =
+            serde_json::from_str(r#"{"a": null, "b": {"x": [2, "3"], "y": 1}}"#).unwrap();
+        assert_eq!(canonical(&a), canonical(&b));
+        assert_eq!(canonical(&a), r#"{"a":null,"b":{"x":[2,"3"],"y":1}}"#);
+        // FNV-1a's published test vectors.
+        assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+    }
+
+    #[test]
+    fn command() {
+        assert_eq!(
+            run_command(&Config::new(vec!["node", "-e", "console.log('hi')"])),
+            r#"node -e "console.log('hi')""#
+        );
+    }
+}
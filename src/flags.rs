@@ -0,0 +1,117 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::metric_value::*;
+
+/// Key under which the reasons an iteration is suspect are stored.
+pub(crate) const FLAGS_KEY: &str = "flags";
+
+/// Key for the top-level count of flagged iterations.
+pub(crate) const FLAGGED_KEY: &str = "flagged";
+
+/// Key marking results cut short by `total_timeout`.
+pub(crate) const TRUNCATED_KEY: &str = "truncated";
+
+/// Key marking an iteration whose `run` command failed, which is only
+/// recorded under `continue_on_failure`.
+pub(crate) const FAILED_KEY: &str = "failed";
+
+/// Something that can tell whether an iteration was affected by outside
+/// interference. `begin` is called right before the iteration starts and `end`
+/// right after its metrics have been collected. Returning a reason from `end`
+/// flags the iteration.
+pub(crate) trait Detector {
+    fn begin(&mut self) {}
+    fn end(&mut self, metrics: &MetricMap) -> Option<String>;
+}
+
+pub(crate) struct Detectors(Vec<Box<dyn Detector>>);
+
+impl Detectors {
+    pub(crate) fn new() -> Self {
+        Detectors(vec![
+            Box::new(ClockAdjustment::default()),
+            Box::new(StatsdLoss),
+        ])
+    }
+
+    pub(crate) fn begin(&mut self) {
+        for detector in self.0.iter_mut() {
+            detector.begin();
+        }
+    }
+
+    /// Runs every detector against the iteration's metrics and records their
+    /// reasons under `flags`. Returns whether the iteration was flagged.
+    pub(crate) fn end(&mut self, metrics: &mut MetricMap) -> bool {
+        let flags: Vec<MetricValue> = self
+            .0
+            .iter_mut()
+            .filter_map(|detector| detector.end(metrics))
+            .map(|reason| reason.into())
+            .collect();
+        let flagged = !flags.is_empty();
+        metrics.insert(FLAGS_KEY.into(), MetricValue::Arr(flags));
+        flagged
+    }
+}
+
+pub(crate) fn is_flagged(iteration: &MetricMap) -> bool {
+    match iteration.get(FLAGS_KEY) {
+        Some(MetricValue::Arr(flags)) => !flags.is_empty(),
+        _ => false,
+    }
+}
+
+pub(crate) fn is_failed(iteration: &MetricMap) -> bool {
+    iteration.contains_key(FAILED_KEY)
+}
+
+/// Flags iterations during which the system clock was stepped, by comparing
+/// the monotonic clock against the wall clock.
+#[derive(Default)]
+struct ClockAdjustment {
+    start: Option<(Instant, SystemTime)>,
+}
+
+const MAX_CLOCK_SKEW: Duration = Duration::from_millis(50);
+
+impl Detector for ClockAdjustment {
+    fn begin(&mut self) {
+        self.start = Some((Instant::now(), SystemTime::now()));
+    }
+
+    fn end(&mut self, _metrics: &MetricMap) -> Option<String> {
+        let (instant, system) = self.start.take()?;
+        let monotonic = instant.elapsed();
+        let adjusted = match system.elapsed() {
+            Ok(wall) if wall > monotonic => wall - monotonic > MAX_CLOCK_SKEW,
+            Ok(wall) => monotonic - wall > MAX_CLOCK_SKEW,
+            Err(_) => true,
+        };
+        if adjusted {
+            Some("clock_adjusted".into())
+        } else {
+            None
+        }
+    }
+}
+
+/// Flags iterations whose kernel metrics never arrived over statsd. A failed
+/// iteration may never have sent them. `max.res.size` is looked for since,
+/// unlike the timings, it's never renamed by a profiler.
+struct StatsdLoss;
+
+impl Detector for StatsdLoss {
+    fn end(&mut self, metrics: &MetricMap) -> Option<String> {
+        if metrics.contains_key("max.res.size") || is_failed(metrics) {
+            None
+        } else {
+            Some("statsd_loss".into())
+        }
+    }
+}
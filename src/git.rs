@@ -0,0 +1,138 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+//! Finds out which commit a config file is checked out at, by reading the
+//! files in `.git` directly.
+
+use std::{
+    fs::read_to_string,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use crate::metric_value::*;
+
+/// Symbolic refs pointing at symbolic refs are rare, but shouldn't loop.
+const MAX_REF_DEPTH: usize = 5;
+
+pub(crate) struct GitInfo {
+    commit: Option<String>,
+    branch: Option<String>,
+    dirty: Option<bool>,
+}
+
+impl GitInfo {
+    /// Looks for a repository in `dir` or any of its parents. Returns `None`
+    /// outside of one.
+    pub(crate) fn detect(dir: &Path) -> Option<Self> {
+        let dir = dir.canonicalize().ok()?;
+        let (work_tree, git_dir) = find_git_dir(&dir)?;
+        // Linked worktrees keep their own HEAD, but share refs with the main
+        // repository.
+        let common_dir = match read_to_string(git_dir.join("commondir")) {
+            Ok(common_dir) => git_dir.join(common_dir.trim()),
+            Err(_) => git_dir.clone(),
+        };
+        let head = read_to_string(git_dir.join("HEAD")).ok()?;
+        let head = head.trim();
+        let (commit, branch) = match head.strip_prefix("ref:") {
+            Some(name) => {
+                let name = name.trim();
+                (
+                    resolve_ref(&git_dir, &common_dir, name, 0),
+                    name.strip_prefix("refs/heads/").map(String::from),
+                )
+            }
+            // Detached HEAD
+            None => (Some(head.to_owned()).filter(|hash| is_hash(hash)), None),
+        };
+        Some(GitInfo {
+            commit,
+            branch,
+            dirty: is_dirty(&work_tree),
+        })
+    }
+
+    /// Adds `version`, `git.branch` and `git.dirty` (1 or 0) for whatever is
+    /// known.
+    pub(crate) fn add_to(self, metrics: &mut MetricMap) {
+        if let Some(commit) = self.commit {
+            metrics.insert("version".into(), commit.into());
+        }
+        if let Some(branch) = self.branch {
+            metrics.insert("git.branch".into(), branch.into());
+        }
+        if let Some(dirty) = self.dirty {
+            metrics.insert("git.dirty".into(), (dirty as i32).into());
+        }
+    }
+}
+
+/// Returns the work tree and git directory. `.git` can be a file pointing at
+/// the git directory elsewhere, as in submodules and linked worktrees.
+fn find_git_dir(dir: &Path) -> Option<(PathBuf, PathBuf)> {
+    for dir in dir.ancestors() {
+        let dot_git = dir.join(".git");
+        if dot_git.is_dir() {
+            return Some((dir.to_owned(), dot_git));
+        }
+        if dot_git.is_file() {
+            let contents = read_to_string(&dot_git).ok()?;
+            let git_dir = contents.strip_prefix("gitdir:")?.trim();
+            return Some((dir.to_owned(), dir.join(git_dir)));
+        }
+    }
+    None
+}
+
+fn resolve_ref(git_dir: &Path, common_dir: &Path, name: &str, depth: usize) -> Option<String> {
+    if depth > MAX_REF_DEPTH {
+        return None;
+    }
+    let loose =
+        read_to_string(git_dir.join(name)).or_else(|_| read_to_string(common_dir.join(name)));
+    if let Ok(contents) = loose {
+        let contents = contents.trim();
+        return match contents.strip_prefix("ref:") {
+            Some(target) => resolve_ref(git_dir, common_dir, target.trim(), depth + 1),
+            None => Some(contents.to_owned()).filter(|hash| is_hash(hash)),
+        };
+    }
+    // e.g. `<hash> refs/heads/main`, with comments starting with `#` and
+    // peeled tags starting with `^`.
+    let packed = read_to_string(common_dir.join("packed-refs")).ok()?;
+    packed.lines().find_map(|line| {
+        let (hash, ref_name) = line.split_once(' ')?;
+        if ref_name == name && is_hash(hash) {
+            Some(hash.to_owned())
+        } else {
+            None
+        }
+    })
+}
+
+/// SHA-1 or SHA-256 object names.
+fn is_hash(hash: &str) -> bool {
+    (hash.len() == 40 || hash.len() == 64) && hash.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Whether tracked files have uncommitted changes. Telling needs the index
+/// and objects, so this asks `git` itself, and is unknown if that fails.
+fn is_dirty(work_tree: &Path) -> Option<bool> {
+    let output = Command::new("git")
+        .args(["status", "--porcelain", "--untracked-files=no"])
+        .current_dir(work_tree)
+        .env_remove("GIT_DIR")
+        .env_remove("GIT_WORK_TREE")
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    if output.status.success() {
+        Some(!output.stdout.is_empty())
+    } else {
+        None
+    }
+}
@@ -0,0 +1,80 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+//! Minimal shell-style glob matching for metric and variant names. Supports
+//! `*`, `?` and bracketed character classes such as `[abc]`, `[a-z]` and
+//! `[!0-9]`.
+
+use anyhow::*;
+
+pub(crate) fn validate_glob(pattern: &str) -> Result<()> {
+    ensure!(!pattern.is_empty(), "glob pattern must not be empty");
+    let chars: Vec<char> = pattern.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        if chars[i] == '[' {
+            match class_end(&chars, i) {
+                Some(end) => i = end,
+                None => bail!("glob pattern '{}' has an unclosed '['", pattern),
+            }
+        }
+        i += 1;
+    }
+    Ok(())
+}
+
+pub(crate) fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    matches(&pattern, &name)
+}
+
+fn matches(pattern: &[char], name: &[char]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some('*') => (0..=name.len()).any(|skip| matches(&pattern[1..], &name[skip..])),
+        Some('?') => !name.is_empty() && matches(&pattern[1..], &name[1..]),
+        Some('[') => match (class_end(pattern, 0), name.first()) {
+            (Some(end), Some(c)) => {
+                class_matches(&pattern[1..end], *c) && matches(&pattern[end + 1..], &name[1..])
+            }
+            (None, Some(c)) => *c == '[' && matches(&pattern[1..], &name[1..]),
+            (_, None) => false,
+        },
+        Some(p) => name.first() == Some(p) && matches(&pattern[1..], &name[1..]),
+    }
+}
+
+/// Finds the index of the `]` closing the class that opens at `start`.
+fn class_end(pattern: &[char], start: usize) -> Option<usize> {
+    let mut i = start + 1;
+    if pattern.get(i) == Some(&'!') {
+        i += 1;
+    }
+    // A `]` right after the opening bracket is a literal member of the class.
+    if pattern.get(i) == Some(&']') {
+        i += 1;
+    }
+    (i..pattern.len()).find(|&i| pattern[i] == ']')
+}
+
+fn class_matches(class: &[char], c: char) -> bool {
+    let (negated, class) = match class.first() {
+        Some('!') => (true, &class[1..]),
+        _ => (false, class),
+    };
+    let mut found = false;
+    let mut i = 0;
+    while i < class.len() {
+        if i + 2 < class.len() && class[i + 1] == '-' {
+            found |= class[i] <= c && c <= class[i + 2];
+            i += 3;
+        } else {
+            found |= class[i] == c;
+            i += 1;
+        }
+    }
+    found != negated
+}
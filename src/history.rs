@@ -0,0 +1,331 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+//! Remembering past runs in a local file, with `--history`, so that each run
+//! shows how its metrics changed since the last one, and `sirun history
+//! show` lists how they've trended.
+
+use anyhow::*;
+use nix::fcntl::{flock, FlockArg};
+use std::{
+    collections::{BTreeMap, HashMap},
+    env,
+    fs::{self, OpenOptions},
+    io::{Read, Write},
+    os::unix::io::AsRawFd,
+    path::PathBuf,
+};
+
+use crate::baseline::*;
+use crate::cli::*;
+use crate::fingerprint::*;
+use crate::metric_value::*;
+use crate::progress::*;
+use crate::summarize::*;
+use crate::units::*;
+
+/// The key of the results that compares them with the last run.
+pub(crate) const LAST_RUN_KEY: &str = "last_run";
+
+/// Where `sirun history show` reads without `--history`.
+const DEFAULT_HISTORY: &str = "~/.sirun/history.ndjson";
+
+/// How many runs `sirun history show` lists without `--last`.
+const DEFAULT_LAST: usize = 10;
+
+/// The metric that `sirun history show` lists.
+const SHOWN_METRIC: &str = "wall.time";
+
+/// A path with a leading `~` standing for `HOME`, since it's often quoted
+/// where the shell won't expand it.
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), env::var_os("HOME")) {
+        (Some(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+fn field<'a>(results: &'a MetricMap, key: &str) -> Option<&'a str> {
+    match results.get(key) {
+        Some(MetricValue::Str(value)) => Some(value),
+        _ => None,
+    }
+}
+
+/// The last run before this one with the same name and variant.
+pub(crate) struct LastRun {
+    started_at: Option<String>,
+    version: Option<String>,
+    config_hash: Option<String>,
+    pub(crate) means: BTreeMap<String, f64>,
+}
+
+impl LastRun {
+    /// Compares the means of `iterations` with the last run's, as the
+    /// percentage each changed by. Metrics only one of them has, or whose
+    /// last mean was zero, are left out.
+    pub(crate) fn changes(&self, iterations: &[MetricValue]) -> BTreeMap<String, f64> {
+        let means = means(iterations).unwrap_or_default();
+        means
+            .iter()
+            .filter_map(|(name, mean)| match self.means.get(name) {
+                Some(last) if *last != 0.0 => Some((name.clone(), percent_change(*last, *mean))),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The value of `LAST_RUN_KEY` in the results of `iterations`.
+    pub(crate) fn to_metric(&self, iterations: &[MetricValue]) -> MetricValue {
+        let mut last_run = MetricMap::new();
+        if let Some(started_at) = &self.started_at {
+            last_run.insert("started_at".into(), started_at.clone().into());
+        }
+        if let Some(version) = &self.version {
+            last_run.insert("version".into(), version.clone().into());
+        }
+        if let Some(config_hash) = &self.config_hash {
+            last_run.insert(CONFIG_HASH_KEY.into(), config_hash.clone().into());
+        }
+        let changes = self
+            .changes(iterations)
+            .into_iter()
+            .map(|(name, change)| (name, ((change * 100.0).round() / 100.0).into()))
+            .collect::<MetricMap>();
+        last_run.insert("changes".into(), changes.into());
+        last_run.into()
+    }
+}
+
+fn percent_change(last: f64, current: f64) -> f64 {
+    (current - last) / last.abs() * 100.0
+}
+
+/// Appends the results to the history at `path`, returning the last run
+/// before them with the same name and variant. The file is locked meanwhile,
+/// so that variants run at the same time neither interleave their lines nor
+/// miss each other's.
+pub(crate) fn record_history(path: &str, results: &MetricMap) -> Result<Option<LastRun>> {
+    let path = expand_home(path);
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(&path)?;
+    flock(file.as_raw_fd(), FlockArg::LockExclusive)?;
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    let last = last_iterations(&contents, field(results, "name"), field(results, "variant"))
+        .map(|(line, iterations)| {
+            Ok::<_, Error>(LastRun {
+                started_at: field(&line, "started_at").map(str::to_owned),
+                version: field(&line, "version").map(str::to_owned),
+                config_hash: field(&line, CONFIG_HASH_KEY).map(str::to_owned),
+                means: means(&iterations)?,
+            })
+        })
+        .transpose()
+        .with_context(|| format!("invalid results in history {}", path.display()))?;
+    if !contents.is_empty() && !contents.ends_with('\n') {
+        // A line cut short by a run that was killed while writing it.
+        file.write_all(b"\n")?;
+    }
+    writeln!(file, "{}", serde_json::to_string(results)?)?;
+    Ok(last)
+}
+
+/// Options for `sirun history show`.
+#[derive(Debug)]
+pub(crate) struct HistoryOptions {
+    name: String,
+    variant: Option<String>,
+    last: usize,
+    history: String,
+}
+
+impl HistoryOptions {
+    pub(crate) fn from_args(mut args: impl Iterator<Item = String>) -> Result<Self> {
+        match args.next().as_deref() {
+            Some("show") => {}
+            Some(action) => bail!("unknown history action '{}', expected show", action),
+            None => bail!("history requires an action, like show"),
+        }
+        let mut name = None;
+        let mut variant = None;
+        let mut last = DEFAULT_LAST;
+        let mut history = DEFAULT_HISTORY.to_owned();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--variant" => variant = Some(flag_value(&mut args, &arg)?),
+                "--history" => history = flag_value(&mut args, &arg)?,
+                "--last" => {
+                    last = match flag_value(&mut args, &arg)?.parse::<usize>() {
+                        Ok(last) if last > 0 => last,
+                        _ => bail!("--last must be an integer >=1"),
+                    }
+                }
+                _ if arg.starts_with("--") => bail!("unknown history option '{}'", arg),
+                _ if name.is_none() => name = Some(arg),
+                _ => bail!("history show takes one name, got '{}'", arg),
+            }
+        }
+        Ok(HistoryOptions {
+            name: name.ok_or_else(|| anyhow!("history show requires a benchmark name"))?,
+            variant,
+            last,
+            history,
+        })
+    }
+}
+
+/// A table of the last runs with the options' name, oldest first, with the
+/// mean `wall.time` of each and how it changed since the run before it of
+/// the same variant.
+fn history_table(contents: &str, options: &HistoryOptions) -> Option<String> {
+    let mut previous: HashMap<Option<String>, f64> = HashMap::new();
+    let mut runs = Vec::new();
+    for line in contents.lines() {
+        let results = match serde_json::from_str::<MetricMap>(line) {
+            Ok(results) => results,
+            Err(_) => continue,
+        };
+        let variant = field(&results, "variant").map(str::to_owned);
+        if field(&results, "name") != Some(&options.name)
+            || (options.variant.is_some() && variant != options.variant)
+        {
+            continue;
+        }
+        let iterations = match results.get("iterations") {
+            Some(MetricValue::Arr(iterations)) => iterations,
+            _ => continue,
+        };
+        let mean = means(iterations)
+            .ok()
+            .and_then(|means| means.get(SHOWN_METRIC).copied());
+        let change = match (mean, previous.get(&variant)) {
+            (Some(mean), Some(last)) if *last != 0.0 => {
+                format!("{:+.1}%", percent_change(*last, mean))
+            }
+            _ => String::new(),
+        };
+        if let Some(mean) = mean {
+            previous.insert(variant.clone(), mean);
+        }
+        let mut version = field(&results, "version").unwrap_or_default().to_owned();
+        version.truncate(12);
+        runs.push(vec![
+            field(&results, "started_at").unwrap_or_default().to_owned(),
+            version,
+            variant.unwrap_or_default(),
+            iterations.len().to_string(),
+            mean.map(|mean| Readable::new(SHOWN_METRIC, mean).format(mean))
+                .unwrap_or_default(),
+            change,
+        ]);
+    }
+    if runs.is_empty() {
+        return None;
+    }
+    let mut rows = vec![vec![
+        "started_at".to_owned(),
+        "version".to_owned(),
+        "variant".to_owned(),
+        "iterations".to_owned(),
+        SHOWN_METRIC.to_owned(),
+        "change".to_owned(),
+    ]];
+    rows.extend(runs.split_off(runs.len().saturating_sub(options.last)));
+    Some(align(&rows))
+}
+
+pub(crate) fn history_main(options: HistoryOptions) -> Result<()> {
+    let path = expand_home(&options.history);
+    let contents = fs::read_to_string(&path)
+        .with_context(|| format!("cannot read history {}", path.display()))?;
+    match history_table(&contents, &options) {
+        Some(table) => print!("{}", table),
+        None => bail!(
+            "no runs of {:?} in history {}",
+            options.name,
+            path.display()
+        ),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(variant: &str, started_at: &str, walls: &[f64]) -> String {
+        let iterations: Vec<_> = walls
+            .iter()
+            .map(|wall| serde_json::json!({ "wall.time": wall }))
+            .collect();
+        serde_json::json!({
+            "name": "startup",
+            "variant": variant,
+            "version": "0123456789abcdef",
+            "started_at": started_at,
+            "iterations": iterations,
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn recorded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nested").join("history.ndjson");
+        let path = path.to_str().unwrap();
+        let results = |walls: &[f64]| -> MetricMap {
+            serde_json::from_str(&line("fast", "2021-01-01T00:00:00Z", walls)).unwrap()
+        };
+        assert!(record_history(path, &results(&[100.0])).unwrap().is_none());
+        let last = record_history(path, &results(&[110.0, 130.0]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(last.means["wall.time"], 100.0);
+        let current: Vec<MetricValue> = vec![serde_json::from_str("{\"wall.time\": 90}").unwrap()];
+        assert_eq!(last.changes(&current)["wall.time"], -10.0);
+        let last = record_history(path, &results(&[90.0])).unwrap().unwrap();
+        assert_eq!(last.means["wall.time"], 120.0);
+        assert_eq!(fs::read_to_string(path).unwrap().lines().count(), 3);
+    }
+
+    #[test]
+    fn table() {
+        let contents = [
+            line("fast", "2021-01-01T00:00:00Z", &[100.0]),
+            line("slow", "2021-01-01T00:00:01Z", &[300.0]),
+            "not results".to_owned(),
+            line("fast", "2021-01-02T00:00:00Z", &[150.0, 250.0]),
+        ]
+        .join("\n");
+        let options = |args: &[&str]| {
+            HistoryOptions::from_args(args.iter().map(|arg| arg.to_string())).unwrap()
+        };
+        assert_eq!(
+            history_table(&contents, &options(&["show", "startup"])).unwrap(),
+            "\
+started_at                 version  variant  iterations  wall.time   change
+2021-01-01T00:00:00Z  0123456789ab     fast           1     100 µs
+2021-01-01T00:00:01Z  0123456789ab     slow           1     300 µs
+2021-01-02T00:00:00Z  0123456789ab     fast           2     200 µs  +100.0%
+"
+        );
+        let fast = history_table(
+            &contents,
+            &options(&["show", "startup", "--variant", "fast", "--last", "1"]),
+        )
+        .unwrap();
+        assert_eq!(fast.lines().count(), 2);
+        assert!(fast.ends_with("+100.0%\n"));
+        assert!(history_table(&contents, &options(&["show", "other"])).is_none());
+        assert!(HistoryOptions::from_args(vec!["list".to_owned()].into_iter()).is_err());
+    }
+}
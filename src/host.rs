@@ -0,0 +1,171 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+use nix::{libc, sys::utsname::uname, unistd::gethostname};
+
+use crate::metric_value::*;
+
+/// Describes the machine the benchmark ran on, so that results can be told
+/// apart later. Anything that can't be found out is left out.
+pub(crate) fn host_info() -> MetricMap {
+    let mut host = MetricMap::new();
+    let mut buf = [0u8; 256];
+    if let Ok(hostname) = gethostname(&mut buf) {
+        host.insert(
+            "hostname".into(),
+            hostname.to_string_lossy().into_owned().into(),
+        );
+    }
+    let uts = uname();
+    host.insert("os".into(), uts.sysname().to_owned().into());
+    host.insert("kernel".into(), uts.release().to_owned().into());
+    if let Some(model) = cpu_model() {
+        host.insert("cpu_model".into(), model.into());
+    }
+    if let Some(count) = online_cpus() {
+        host.insert("cpu_count".into(), count.into());
+    }
+    if let (Some(pages), Some(page_size)) =
+        (sysconf(libc::_SC_PHYS_PAGES), sysconf(libc::_SC_PAGESIZE))
+    {
+        host.insert("memory_bytes".into(), (pages * page_size).into());
+    }
+    host.insert(
+        "sirun_version".into(),
+        env!("CARGO_PKG_VERSION").to_owned().into(),
+    );
+    host
+}
+
+pub(crate) fn online_cpus() -> Option<u64> {
+    sysconf(libc::_SC_NPROCESSORS_ONLN)
+}
+
+fn sysconf(name: libc::c_int) -> Option<u64> {
+    let value = unsafe { libc::sysconf(name) };
+    if value > 0 {
+        Some(value as u64)
+    } else {
+        None
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn cpu_model() -> Option<String> {
+    // x86 has `model name`, while some ARM kernels only report `Hardware` or
+    // `Processor`.
+    let cpuinfo = std::fs::read_to_string("/proc/cpuinfo").ok()?;
+    ["model name", "Hardware", "Processor"]
+        .iter()
+        .find_map(|field| {
+            cpuinfo.lines().find_map(|line| {
+                let (key, value) = line.split_once(':')?;
+                if key.trim() == *field && !value.trim().is_empty() {
+                    Some(value.trim().to_owned())
+                } else {
+                    None
+                }
+            })
+        })
+}
+
+#[cfg(target_os = "macos")]
+fn cpu_model() -> Option<String> {
+    let output = std::process::Command::new("sysctl")
+        .args(["-n", "machdep.cpu.brand_string"])
+        .output()
+        .ok()?;
+    let model = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+    if output.status.success() && !model.is_empty() {
+        Some(model)
+    } else {
+        None
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn cpu_model() -> Option<String> {
+    None
+}
+
+/// Samples how busy the machine is and how its CPUs are clocked, as metrics
+/// for an iteration that's about to start, so that a noisy neighbor or a
+/// throttled CPU leaves evidence in the results. Anything that can't be
+/// found out is left out.
+pub(crate) fn system_state() -> MetricMap {
+    let mut state = MetricMap::new();
+    if let Ok(loadavg) = std::fs::read_to_string("/proc/loadavg") {
+        add_loadavg(&loadavg, &mut state);
+    }
+    #[cfg(target_os = "linux")]
+    add_cpufreq(&mut state);
+    state
+}
+
+/// `/proc/loadavg` has the 1, 5 and 15 minute load averages, then the number
+/// of running and total processes as `running/total`.
+fn add_loadavg(loadavg: &str, state: &mut MetricMap) {
+    let fields: Vec<&str> = loadavg.split_whitespace().collect();
+    for (field, key) in fields.iter().zip(&["sys.load1", "sys.load5", "sys.load15"]) {
+        if let Ok(load) = field.parse::<f64>() {
+            state.insert((*key).into(), load.into());
+        }
+    }
+    if let Some((running, total)) = fields.get(3).and_then(|procs| procs.split_once('/')) {
+        if let (Ok(running), Ok(total)) = (running.parse::<u64>(), total.parse::<u64>()) {
+            state.insert("sys.procs.running".into(), running.into());
+            state.insert("sys.procs.total".into(), total.into());
+        }
+    }
+}
+
+/// The scaling governors of the CPUs (joined with commas if they differ), the
+/// lowest and highest frequencies they may run at, and their mean current
+/// frequency, in kHz.
+#[cfg(target_os = "linux")]
+fn add_cpufreq(state: &mut MetricMap) {
+    let cpus = match std::fs::read_dir("/sys/devices/system/cpu") {
+        Ok(entries) => entries.filter_map(|entry| entry.ok()),
+        Err(_) => return,
+    };
+    let mut governors = std::collections::BTreeSet::new();
+    let (mut min, mut max, mut cur) = (Vec::new(), Vec::new(), Vec::new());
+    for cpu in cpus {
+        let name = cpu.file_name().to_string_lossy().into_owned();
+        if !name
+            .strip_prefix("cpu")
+            .is_some_and(|index| !index.is_empty() && index.bytes().all(|b| b.is_ascii_digit()))
+        {
+            continue;
+        }
+        let cpufreq = cpu.path().join("cpufreq");
+        let read = |file: &str| {
+            std::fs::read_to_string(cpufreq.join(file))
+                .ok()
+                .map(|value| value.trim().to_owned())
+        };
+        let khz = |file: &str| read(file).and_then(|value| value.parse::<f64>().ok());
+        if let Some(governor) = read("scaling_governor") {
+            governors.insert(governor);
+        }
+        min.extend(khz("scaling_min_freq"));
+        max.extend(khz("scaling_max_freq"));
+        cur.extend(khz("scaling_cur_freq"));
+    }
+    if !governors.is_empty() {
+        let governors: Vec<String> = governors.into_iter().collect();
+        state.insert("sys.cpufreq.governor".into(), governors.join(",").into());
+    }
+    if let Some(min) = min.into_iter().reduce(f64::min) {
+        state.insert("sys.cpufreq.min.khz".into(), min.into());
+    }
+    if let Some(max) = max.into_iter().reduce(f64::max) {
+        state.insert("sys.cpufreq.max.khz".into(), max.into());
+    }
+    if !cur.is_empty() {
+        let mean = cur.iter().sum::<f64>() / cur.len() as f64;
+        state.insert("sys.cpufreq.cur.khz".into(), mean.into());
+    }
+}
@@ -0,0 +1,1848 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+//! sirun runs benchmarks, measuring them through the kernel and collecting
+//! whatever metrics they send over statsd. Besides the `sirun` command line
+//! tool, it can be used as a library:
+//!
+//! ```no_run
+//! fn main() -> anyhow::Result<()> {
+//!     // Iterations are run in child processes of this executable.
+//!     sirun::run_iteration_if_child();
+//!
+//!     let config = sirun::Config::new(vec!["node", "bench.js"])
+//!         .with_name("bench")
+//!         .with_iterations(10);
+//!     let result = sirun::run_benchmark(config)?;
+//!     for iteration in &result.iterations {
+//!         println!("{:?}", iteration.get("wall.time"));
+//!     }
+//!     Ok(())
+//! }
+//! ```
+
+use anyhow::*;
+use async_std::{
+    future::{self, Future},
+    io::{prelude::{BufReadExt, WriteExt}, BufReader},
+    stream::StreamExt,
+    net::{TcpStream, UdpSocket},
+    prelude::FutureExt,
+    process::{Command, Stdio, Child, ExitStatus},
+    sync::{Arc, RwLock},
+    task::{block_on, sleep, spawn, JoinHandle},
+};
+use log::{debug, error, info, warn};
+use serde_json::json;
+use std::{
+    collections::{HashMap, VecDeque},
+    env,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    net::{Shutdown, SocketAddr},
+    os::unix::process::ExitStatusExt,
+    path::Path,
+    pin::Pin,
+    process::exit,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+use which::which;
+
+mod config;
+use config::*;
+
+mod raw_config;
+pub use config::Config;
+
+mod cli;
+use cli::*;
+
+mod rusage;
+use rusage::*;
+
+mod cgroup;
+use cgroup::*;
+
+mod memory_limit;
+use memory_limit::*;
+
+mod caches;
+
+mod lock;
+use lock::*;
+
+mod reaper;
+use reaper::*;
+
+mod subproc;
+use subproc::*;
+
+mod statsd;
+use statsd::*;
+
+mod metric_value;
+use metric_value::*;
+pub use metric_value::{MetricMap, MetricValue};
+
+mod summarize;
+use summarize::*;
+pub use summarize::{summarize, Summary};
+
+mod results;
+pub use results::BenchmarkResult;
+
+mod flags;
+use flags::*;
+
+mod glob;
+
+mod precision;
+
+mod statistics;
+
+mod csv;
+
+mod markdown;
+
+mod units;
+
+mod assertions;
+
+mod stdout_metrics;
+use stdout_metrics::*;
+
+mod results_file;
+use results_file::*;
+
+mod exec;
+use exec::*;
+
+mod suite;
+use suite::*;
+
+mod baseline;
+use baseline::*;
+
+mod export;
+use export::*;
+
+mod prometheus;
+
+mod history;
+use history::*;
+
+mod fingerprint;
+use fingerprint::*;
+
+mod redact;
+
+mod host;
+use host::*;
+
+mod git;
+use git::*;
+
+mod timestamp;
+use timestamp::*;
+
+mod progress;
+use progress::*;
+
+mod logging;
+use logging::*;
+
+mod tmpdir;
+use tmpdir::*;
+
+mod disk;
+use disk::*;
+
+mod wait;
+use wait::*;
+
+mod noise;
+use noise::*;
+
+mod sysmetrics;
+use sysmetrics::*;
+
+mod schema;
+use schema::*;
+
+mod valgrind;
+
+mod perf_stat;
+
+mod profilers;
+use profilers::*;
+
+fn get_kernel_metrics(wall_time: f64, data: Rusage, metrics: &mut MetricMap) {
+    metrics.insert("max.res.size".into(), data.max_res_size.into());
+    metrics.insert("user.time".into(), data.user_time.into());
+    metrics.insert("system.time".into(), data.system_time.into());
+
+    let pct = (data.user_time + data.system_time) * 100.0 / wall_time;
+    metrics.insert("cpu.pct.wall.time".into(), pct.into());
+}
+
+async fn test_timeout(timeout: u64) {
+    sleep(std::time::Duration::from_secs(timeout)).await;
+    eprintln!("Timeout of {} seconds exceeded.", timeout);
+    exit(1);
+}
+
+#[cfg(target_os = "linux")]
+async fn run_with_instruction_count(child: &mut Child, config: &Config) -> Result<(ExitStatus, Option<u64>)> {
+    use perfcnt::AbstractPerfCounter;
+    use perfcnt::linux::{PerfCounterBuilderLinux, HardwareEventType};
+    if config.instructions {
+        let pid = child.id();
+        let mut counter = PerfCounterBuilderLinux::from_hardware_event(HardwareEventType::Instructions)
+            .for_pid(pid as i32)
+            .finish()?;
+        counter.start()?;
+        let status = child.status().await?;
+        counter.stop()?;
+        let instructions = counter.read()?;
+
+        Ok((status, Some(instructions)))
+    } else {
+        Ok((child.status().await?, None))
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn run_with_instruction_count(child: &mut Child, _config: &Config) -> Result<(ExitStatus, Option<u64>)> {
+    Ok((child.status().await?, None))
+}
+
+/// How long the pipes of the `run` command are read from after it exits.
+const PIPE_DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Tells each copy of the `run` command which of the `concurrency` it is.
+const WORKER_INDEX_ENV: &str = "SIRUN_WORKER_INDEX";
+
+/// Runs the `run` command, as many copies of it at once as `concurrency`
+/// says, returning the code the iteration child should exit with.
+async fn run_test(config: &Config, metrics: &mut MetricMap) -> Result<i32> {
+    if let Some(timeout) = config.timeout {
+        spawn(test_timeout(timeout));
+    }
+
+    let start_time = std::time::Instant::now();
+    let rusage_start = Rusage::new();
+    let profiler = profiler(config);
+    // The output is kept where the parent asked for it with `keep_profiles`.
+    let profile_tmpdir = tempfile::Builder::new().prefix("sirun-profile").tempdir()?;
+    let profile_dir = config.profile_dir.as_deref().unwrap_or_else(|| profile_tmpdir.path());
+    let run = match &profiler {
+        Some(profiler) => profiler.wrap(&config.run, profile_dir),
+        None => config.run.clone(),
+    };
+    let cgroup = match config.cgroup_accounting {
+        true => Some(Cgroup::create()?),
+        false => None,
+    };
+    // The `run` command's strays are killed as soon as it exits, since
+    // they'd keep its stderr open.
+    let mut reaper = Reaper::start(config);
+    // One limit covers every copy: their cgroup's, or each process's own.
+    let memory_limit = config
+        .max_memory
+        .map(|bytes| MemoryLimit::new(bytes, cgroup.as_ref()));
+    let mut children = Vec::new();
+    let mut stderr_tails = Vec::new();
+    let mut stdout_scans = Vec::new();
+    let spawned_at = SystemTime::now();
+    for worker in 0..config.concurrency {
+        let mut worker_config = config.clone();
+        worker_config
+            .env
+            .insert(WORKER_INDEX_ENV.into(), worker.to_string());
+        let config = &worker_config;
+        // Stdout is only read when there are metrics to scrape from it.
+        let (stdio, stdout_copy, stderr_copy) = match &config.stdout_metrics {
+            Some(_) => {
+                let (stdout_copy, stderr_copy) =
+                    open_stdio_pipes(&config.run_stdio, "run", config)?;
+                (
+                    (Stdio::piped(), Stdio::piped()),
+                    Some(stdout_copy),
+                    stderr_copy,
+                )
+            }
+            None => {
+                let (stdout, stderr_copy) = open_stdio_tee(&config.run_stdio, "run", config)?;
+                ((stdout, Stdio::piped()), None, stderr_copy)
+            }
+        };
+        let mut command = build_cmd(&run, config, stdio);
+        die_with_parent(&mut command);
+        apply_scheduling(&mut command, config);
+        apply_disable_aslr(&mut command, config);
+        if let Some(cgroup) = &cgroup {
+            use async_std::os::unix::process::CommandExt;
+            unsafe {
+                command.pre_exec(cgroup.enter_hook());
+            }
+        }
+        if let Some(memory_limit) = &memory_limit {
+            memory_limit.restrict(&mut command);
+        }
+        let mut child =
+            spawn_reapable(&mut command, config).with_context(|| match config.nice {
+                Some(nice) if nice < 0 => {
+                    "cannot start the 'run' command; a negative 'nice' needs CAP_SYS_NICE"
+                }
+                _ => "cannot start the 'run' command",
+            })?;
+        let stderr = child.stderr.take().unwrap();
+        stderr_tails.push(spawn(tail_stderr(stderr, stderr_copy)));
+        if let Some((copy, rules)) = stdout_copy.zip(config.stdout_metrics.clone()) {
+            let stdout = child.stdout.take().unwrap();
+            stdout_scans.push(spawn(scan_stdout(stdout, copy, rules)));
+        }
+        children.push(child);
+    }
+    // How long each copy ran for, as well as how it exited.
+    let mut exits = Vec::new();
+    let mut instructions = None;
+    if let [child] = &mut children[..] {
+        let (status, counted) = run_with_instruction_count(child, config).await?;
+        exits.push((status, start_time.elapsed()));
+        instructions = counted;
+    } else {
+        let waiting: Vec<_> = children
+            .drain(..)
+            .map(|mut child| {
+                spawn(async move {
+                    let status = child.status().await?;
+                    Ok::<_, Error>((status, start_time.elapsed()))
+                })
+            })
+            .collect();
+        for waiting in waiting {
+            exits.push(waiting.await?);
+        }
+    }
+    // Killing strays can take a grace period, which isn't counted.
+    let reaped = reaper
+        .as_mut()
+        .map(|reaper| (start_time.elapsed(), reaper.reap()));
+    let duration = reaped
+        .map_or_else(|| start_time.elapsed(), |(elapsed, _)| elapsed)
+        .as_micros();
+    let mut rusage_result = Rusage::new() - rusage_start;
+    // Anything the command started may still hold its pipes open, so they're
+    // only drained for so long once it has exited.
+    let drain_deadline = Instant::now() + PIPE_DRAIN_TIMEOUT;
+    let mut stderr_tails = {
+        let mut tails = Vec::new();
+        for stderr_tail in stderr_tails {
+            let remaining = drain_deadline.saturating_duration_since(Instant::now());
+            tails.push(future::timeout(remaining, stderr_tail).await.unwrap_or_default());
+        }
+        tails
+    };
+    for stdout_scan in stdout_scans {
+        let remaining = drain_deadline.saturating_duration_since(Instant::now());
+        match future::timeout(remaining, stdout_scan).await {
+            Ok(scanned) => {
+                for (name, value) in scanned? {
+                    metrics.insert(name, value.into());
+                }
+            }
+            Err(_) => warn!(
+                "the 'run' command's stdout is still open after it exited, so its metrics are skipped"
+            ),
+        }
+    }
+    // The first copy that failed is the one reported.
+    let worker = exits
+        .iter()
+        .position(|(status, _)| !status.success())
+        .unwrap_or(0);
+    let status = exits[worker].0;
+    let stderr_tail = stderr_tails.swap_remove(worker);
+    let test = match config.concurrency {
+        1 => "Test".to_owned(),
+        _ => format!("Test worker {}", worker),
+    };
+    let out_of_memory = memory_limit
+        .as_ref()
+        .is_some_and(|limit| limit.exceeded(&status, &stderr_tail, cgroup.as_ref()));
+    // Read and removed before an exit below could leave it behind.
+    if let Some(cgroup) = cgroup {
+        cgroup.account(&mut rusage_result, metrics)?;
+    }
+    metrics.insert("wall.time".to_owned(), (duration as f64).into());
+    if config.concurrency > 1 {
+        let sum: u128 = exits.iter().map(|(_, elapsed)| elapsed.as_micros()).sum();
+        metrics.insert("wall.time.sum".to_owned(), (sum as f64).into());
+    }
+    metrics.insert(SPAWN_EPOCH_US_KEY.to_owned(), epoch_us(spawned_at).into());
+    if let Some((_, orphans)) = reaped {
+        metrics.insert("orphans.killed".to_owned(), (orphans as u64).into());
+    }
+    if let Some(instructions) = instructions {
+        metrics.insert("instructions".to_owned(), (instructions as f64).into());
+    }
+    if let Some(profiler) = &profiler {
+        if status.success() {
+            profiler.add_metrics(profile_dir, metrics)?;
+        }
+    }
+    if out_of_memory {
+        // However the command died of it, going over the limit is a failure.
+        let limit = format_byte_size(config.max_memory.unwrap_or_default());
+        if let Some(code) = status.code() {
+            metrics.insert("exit.code".to_owned(), code.into());
+        }
+        if let Some(signal) = status.signal() {
+            metrics.insert("exit.signal".to_owned(), signal.into());
+        }
+        if config.continue_on_failure {
+            eprintln!(
+                "{} exceeded its memory limit of {}, so recording a failed iteration.{}",
+                test, limit, stderr_report(&stderr_tail)
+            );
+            metrics.insert("oom_killed".to_owned(), 1.into());
+            get_kernel_metrics(duration as f64, rusage_result, metrics);
+            return Ok(1);
+        }
+        eprintln!(
+            "Memory limit {} exceeded, so aborting test.\n\nTest Config:\n{}{}",
+            limit, config, stderr_report(&stderr_tail)
+        );
+        exit(1);
+    }
+    if let Some(status) = status.code() {
+        metrics.insert("exit.code".to_owned(), status.into());
+        if status != 0 && status <= 128 {
+            if config.continue_on_failure {
+                eprintln!(
+                    "{} exited with code {}, so recording a failed iteration.{}",
+                    test, status, stderr_report(&stderr_tail)
+                );
+                get_kernel_metrics(duration as f64, rusage_result, metrics);
+                return Ok(status);
+            }
+            eprintln!(
+                "{} exited with code {}, so aborting test.\n\nTest Config:\n{}{}",
+                test, status, config, stderr_report(&stderr_tail)
+            );
+            exit(status);
+        }
+    } else {
+        if let Some(status) = status.signal() {
+            if config.continue_on_failure {
+                eprintln!(
+                    "{} was terminated via signal {}, so recording a failed iteration.{}",
+                    test, status, stderr_report(&stderr_tail)
+                );
+                metrics.insert("exit.signal".to_owned(), status.into());
+                get_kernel_metrics(duration as f64, rusage_result, metrics);
+                return Ok(1);
+            }
+            eprintln!(
+                "{} was terminated via signal {}, so aborting test.\n\nTest Config:\n{}{}",
+                test, status, config, stderr_report(&stderr_tail)
+            );
+            exit(1);
+        }
+    }
+    get_kernel_metrics(duration as f64, rusage_result, metrics);
+    Ok(0)
+}
+
+/// A running `service` command.
+struct Service {
+    command: Vec<String>,
+    child: Child,
+    stderr_tail: JoinHandle<Vec<u8>>,
+}
+
+/// Starts every service. If one can't be started, the ones already running
+/// are killed before the error is returned.
+fn run_services(config: &Config) -> Result<Vec<Service>> {
+    let mut services = Vec::new();
+    for command_arr in config.service.iter().flatten() {
+        let service = open_stdio_tee(&config.service_stdio, "service", config).and_then(
+            |(stdout, stderr_copy)| {
+                let mut command = build_cmd(command_arr, config, (stdout, Stdio::piped()));
+                if config.pin_services {
+                    apply_scheduling(&mut command, config);
+                }
+                let mut child = spawn_reapable(&mut command, config)?;
+                let stderr_tail = spawn(tail_stderr(child.stderr.take().unwrap(), stderr_copy));
+                Ok(Service {
+                    command: command_arr.clone(),
+                    child,
+                    stderr_tail,
+                })
+            },
+        );
+        match service {
+            Ok(service) => services.push(service),
+            Err(e) => {
+                let _ = kill_services(services);
+                return Err(e.context(format!("cannot start service {:?}", command_arr)));
+            }
+        }
+    }
+    Ok(services)
+}
+
+/// Kills services in the reverse of the order they were started in.
+fn kill_services(services: Vec<Service>) -> Result<()> {
+    for mut service in services.into_iter().rev() {
+        service.child.kill()?;
+    }
+    Ok(())
+}
+
+/// Waits for the first of the services to exit, returning its index. Never
+/// returns if there are no services.
+async fn service_exit(services: &mut [Service]) -> (usize, io::Result<ExitStatus>) {
+    type Exit<'a> = Pin<Box<dyn Future<Output = (usize, io::Result<ExitStatus>)> + Send + 'a>>;
+    let mut exits = services
+        .iter_mut()
+        .enumerate()
+        .map(|(i, service)| -> Exit { Box::pin(async move { (i, service.child.status().await) }) });
+    match exits.next() {
+        Some(first) => exits.fold(first, |a, b| Box::pin(a.race(b))).await,
+        None => future::pending().await,
+    }
+}
+
+/// Builds the error for a service that exited while `run` was still going,
+/// with whatever it wrote to stderr.
+async fn premature_exit_error(service: Service, status: io::Result<ExitStatus>) -> Error {
+    let status = match status {
+        Ok(status) => match (status.code(), status.signal()) {
+            (Some(code), _) => format!("exit code {}", code),
+            (None, Some(signal)) => format!("signal {}", signal),
+            (None, None) => status.to_string(),
+        },
+        Err(e) => e.to_string(),
+    };
+    // Anything the service started may still hold its stderr open.
+    let tail = future::timeout(Duration::from_secs(1), service.stderr_tail)
+        .await
+        .unwrap_or_default();
+    anyhow!(
+        "service {:?} exited with {} before the run command finished{}",
+        service.command,
+        status,
+        stderr_report(&tail)
+    )
+}
+
+/// Path used to re-run ourselves, unless `SIRUN_EXE` says otherwise. argv[0]
+/// may be relative or only resolvable through a PATH the children don't
+/// share, so it's just a fallback.
+fn sirun_exe() -> String {
+    if let Ok(exe) = env::var("SIRUN_EXE") {
+        return exe;
+    }
+    env::current_exe()
+        .ok()
+        .map(|path| path.to_string_lossy().into_owned())
+        .or_else(|| env::args().next())
+        .expect("cannot determine sirun executable")
+}
+
+/// The test failed with an exit code that sirun exits with too.
+#[derive(Debug)]
+struct TestFailed(i32);
+
+impl std::fmt::Display for TestFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "test exited with code {}", self.0)
+    }
+}
+
+impl std::error::Error for TestFailed {}
+
+/// Outcome of one attempt at running an iteration.
+enum Attempt {
+    Done(MetricMap),
+    /// The iteration child exited with this code, and can be retried.
+    Failed(i32),
+}
+
+async fn run_iteration(
+    config: &Config,
+    index: u64,
+    statsd_buf: Arc<RwLock<StatsdBuffer>>,
+) -> Result<MetricMap> {
+    let mut retries = 0;
+    loop {
+        let may_retry = retries < config.iteration_retries;
+        info!("starting iteration {} (attempt {})", index, retries + 1);
+        let started_at = SystemTime::now();
+        let start = Instant::now();
+        match run_attempt(config, index, statsd_buf.clone(), may_retry).await? {
+            Attempt::Done(mut metrics) => {
+                metrics.insert(START_EPOCH_MS_KEY.into(), epoch_ms(started_at).into());
+                metrics.insert(
+                    "duration.ms".into(),
+                    (start.elapsed().as_secs_f64() * 1000.0).into(),
+                );
+                if config.iteration_retries > 0 {
+                    metrics.insert("retries".into(), retries.into());
+                }
+                forward_statsd(config, &metrics).await;
+                return Ok(metrics);
+            }
+            Attempt::Failed(status) => {
+                retries += 1;
+                eprintln!(
+                    "Iteration {} exited with code {}, retrying ({} of {}).",
+                    index, status, retries, config.iteration_retries
+                );
+                // Drop anything the failed attempt managed to send.
+                statsd_buf.write().await.clear();
+            }
+        }
+    }
+}
+
+/// Runs an iteration once. A failure ends the run with a `TestFailed` error
+/// unless `may_retry` is set, in which case it's cleaned up after and reported
+/// instead.
+async fn run_attempt(
+    config: &Config,
+    index: u64,
+    statsd_buf: Arc<RwLock<StatsdBuffer>>,
+    may_retry: bool,
+) -> Result<Attempt> {
+    let mut sub_config: Config = config.clone();
+    sub_config.iteration = Some(index);
+    sub_config
+        .env
+        .insert("SIRUN_ITERATION_INDEX".into(), index.to_string());
+    // Set or removed either way, so that an outer sirun's don't leak through.
+    for (var, value) in [
+        ("SIRUN_BENCH_NAME", &config.name),
+        ("SIRUN_BENCH_VARIANT", &config.variant),
+    ] {
+        match value {
+            Some(value) => {
+                sub_config.env_remove.remove(var);
+                sub_config.env.insert(var.into(), value.clone());
+            }
+            None => {
+                sub_config.env.remove(var);
+                sub_config.env_remove.insert(var.into());
+            }
+        }
+    }
+    // With a `duration` the number of iterations isn't known in advance.
+    if config.duration.is_none() {
+        sub_config
+            .env
+            .insert("SIRUN_ITERATION_COUNT".into(), config.iterations.to_string());
+    }
+    let tmpdir = IterationTmpdir::create(&mut sub_config)?;
+    let results_file = IterationResultsFile::create(&mut sub_config)?;
+    // Declared after the scratch directory, so that strays are gone before
+    // it's removed.
+    let mut reaper = Reaper::start(&sub_config);
+    // Unlike the iteration's scratch directory, this outlives the run.
+    if config.keep_profiles && profiler(config).is_some() {
+        let dir = tempfile::Builder::new().prefix("sirun-profile").tempdir()?;
+        sub_config.profile_dir = Some(dir.keep());
+    }
+    // The child deletes this once read; dropping it cleans up if it couldn't.
+    let mut config_file = tempfile::Builder::new()
+        .prefix("sirun-iteration")
+        .tempfile()?;
+    config_file.write_all(serde_yaml::to_string(&sub_config)?.as_bytes())?;
+    let mut child_env = sub_config.env.clone();
+    child_env.insert(
+        ITERATION_CONFIG_ENV.into(),
+        config_file.path().to_string_lossy().into_owned(),
+    );
+    statsd_buf.write().await.open_window();
+    let mut services = run_services(&sub_config)?;
+    run_setup(&sub_config).await?;
+    if let Err(e) = wait_for(&sub_config).await {
+        let _ = kill_services(services);
+        return Err(e);
+    }
+    // After `setup`, so that whatever it read isn't left in the page cache.
+    let mut cache_metrics = MetricMap::new();
+    if let Some(drop_caches) = &sub_config.drop_caches {
+        if let Err(e) = drop_caches.drop(&mut cache_metrics).await {
+            let _ = kill_services(services);
+            return Err(e);
+        }
+    }
+    let disk_before = DiskUsage::measure(&sub_config);
+    let pressure_before = Pressure::measure();
+    let system_state = if sub_config.collect_system_state {
+        system_state()
+    } else {
+        MetricMap::new()
+    };
+    // The child decides where the output of `run` goes, and its own error
+    // reports should always be seen, so its stdio is inherited.
+    debug!(
+        "starting iteration {} child with config {}",
+        index,
+        config_file.path().display()
+    );
+    let mut child = spawn_reapable(Command::new(sirun_exe()).envs(&child_env), &sub_config)?;
+    let status = if sub_config.service_may_exit {
+        child.status().await?
+    } else {
+        // If `run` and a service finish together, `run` wins.
+        let finished = async { Ok(child.status().await) }
+            .race(async { Err(service_exit(&mut services).await) })
+            .await;
+        match finished {
+            Ok(status) => status?,
+            Err((index, status)) => {
+                let _ = child.kill();
+                let _ = child.status().await;
+                let service = services.remove(index);
+                let _ = kill_services(services);
+                return Err(premature_exit_error(service, status).await);
+            }
+        }
+    };
+    let pressure_after = Pressure::measure();
+    let mut status = status.code().expect("no exit code");
+    let mut failed = status != 0 && status <= 128;
+    // A results file that can't be used fails the iteration, like the
+    // command failing would.
+    let written = match results_file
+        .as_ref()
+        .map(IterationResultsFile::read)
+        .transpose()
+    {
+        Ok(written) => written,
+        Err(e) => {
+            eprintln!("Iteration {} failed: {:#}", index, e);
+            if !failed {
+                status = 1;
+                failed = true;
+            }
+            None
+        }
+    };
+    if failed && may_retry {
+        run_teardown(&sub_config).await?;
+        kill_services(services)?;
+        return Ok(Attempt::Failed(status));
+    }
+    if failed && !sub_config.continue_on_failure {
+        let _ = kill_services(services);
+        return Err(TestFailed(status).into());
+    }
+    await_statsd(&statsd_buf).await;
+    let mut metrics = get_statsd_metrics(statsd_buf.clone(), sub_config.nest_metrics).await?;
+    if let Some((results_file, written)) = results_file.as_ref().zip(written) {
+        if let Err(e) = results_file.merge_into(written, &mut metrics, sub_config.nest_metrics) {
+            eprintln!("Iteration {} failed: {:#}", index, e);
+            if may_retry {
+                statsd_buf.write().await.recount_out_of_window(&metrics);
+                run_teardown(&sub_config).await?;
+                kill_services(services)?;
+                return Ok(Attempt::Failed(1));
+            }
+            if !sub_config.continue_on_failure {
+                let _ = kill_services(services);
+                return Err(TestFailed(1).into());
+            }
+            if !failed {
+                status = 1;
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        // A timed out child never got to send its exit code.
+        metrics
+            .entry("exit.code".into())
+            .or_insert_with(|| status.into());
+        metrics.insert(FAILED_KEY.into(), 1.into());
+    }
+    metrics.insert(ITERATION_KEY.into(), index.into());
+    metrics.extend(system_state);
+    metrics.extend(cache_metrics);
+    if let Some(dir) = &sub_config.profile_dir {
+        metrics.insert(PROFILE_DIR_KEY.into(), dir.to_string_lossy().into_owned().into());
+    }
+    DiskUsage::measure(&sub_config).add_deltas(&disk_before, &sub_config, &mut metrics);
+    pressure_after.add_deltas(&pressure_before, &mut metrics);
+
+    run_teardown(&sub_config).await?;
+    kill_services(services)?;
+    if let Some(reaper) = &mut reaper {
+        // Added to those the child killed when `run` exited.
+        let orphans = metrics
+            .get("orphans.killed")
+            .and_then(|orphans| orphans.as_f64().ok())
+            .unwrap_or_default();
+        metrics.insert(
+            "orphans.killed".into(),
+            (orphans + reaper.reap() as f64).into(),
+        );
+    }
+    metrics.insert("tmp.bytes".into(), tmpdir.disk_usage().into());
+
+    Ok(Attempt::Done(metrics))
+}
+
+/// Runs each variant in a child process of its own, `--jobs` at a time, and
+/// then prints how each went and how its means compare with the first's.
+async fn run_all_variants(
+    variants: Vec<String>,
+    config: &Config,
+    cli: &Cli,
+    source: &ConfigSource,
+) -> Result<()> {
+    // Stdin has already been consumed, so variants are given a copy of the
+    // config on theirs, keeping relative paths relative to the current
+    // directory.
+    let stdin_config = if source.from_stdin {
+        Some(Arc::new(source.contents.clone()))
+    } else {
+        None
+    };
+    let jobs = cli.jobs.min(variants.len()).max(1);
+    let queue = Arc::new(Mutex::new(
+        variants.iter().cloned().enumerate().collect::<VecDeque<_>>(),
+    ));
+    let results = Arc::new(Mutex::new(Vec::new()));
+    let stop = Arc::new(AtomicBool::new(false));
+    let workers: Vec<_> = (0..jobs)
+        .map(|_| {
+            let queue = queue.clone();
+            let results = results.clone();
+            let stop = stop.clone();
+            let stdin_config = stdin_config.clone();
+            let fail_fast = cli.fail_fast;
+            spawn(async move {
+                while !stop.load(Ordering::SeqCst) {
+                    let next = queue.lock().unwrap().pop_front();
+                    let (index, variant) = match next {
+                        Some(next) => next,
+                        None => break,
+                    };
+                    let (status, output) =
+                        run_selected("SIRUN_VARIANT", &variant, stdin_config.clone(), jobs > 1)
+                            .await?;
+                    if fail_fast && !status.success() {
+                        stop.store(true, Ordering::SeqCst);
+                    }
+                    results.lock().unwrap().push((index, variant, status, output));
+                }
+                Result::<()>::Ok(())
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.await?;
+    }
+    let mut results = std::mem::take(&mut *results.lock().unwrap());
+    results.sort_by_key(|(index, _, _, _)| *index);
+    let mut lines = Vec::new();
+    let results: Vec<_> = results
+        .into_iter()
+        .map(|(_, variant, status, mut output)| {
+            lines.append(&mut output);
+            (variant, status)
+        })
+        .collect();
+    // With an output file, the variants' results are written there instead.
+    if let Some(output_file) = &config.output_file {
+        if let Ok(contents) = fs::read_to_string(output_file) {
+            lines.extend(contents.lines().map(str::to_owned));
+        }
+    }
+
+    eprintln!("Variant results:");
+    for (variant, status) in &results {
+        match (status.success(), status.code()) {
+            (true, _) => eprintln!("  {}: passed", variant),
+            (false, Some(code)) => eprintln!("  {}: failed (exit code {})", variant, code),
+            (false, None) => eprintln!("  {}: failed ({})", variant, status),
+        }
+    }
+    // Earlier results in the output file may be another benchmark's, with
+    // the same variants, so they're told apart by name too.
+    let benchmarks: Vec<_> = variants
+        .iter()
+        .filter_map(|variant| {
+            let mut config = get_config(source, Some(variant)).ok()?;
+            cli.apply(&mut config).ok()?;
+            Some((config.name, variant.clone()))
+        })
+        .collect();
+    print_variant_comparison(config, &variant_results(&benchmarks, &lines));
+    let failed = results.iter().filter(|(_, status)| !status.success()).count();
+    ensure!(failed == 0, "{} of {} variants failed", failed, results.len());
+    Ok(())
+}
+
+/// Runs each config file in a directory in turn, with each of its variants
+/// run separately, and then prints how each went. A config that can't be read
+/// counts as failed, but doesn't stop the rest from running unless
+/// `--fail-fast` is given.
+async fn run_suite(cli: &Cli) -> Result<()> {
+    if cli.list_variants {
+        return Err(UsageError("--list-variants can't be used with a directory".into()).into());
+    }
+    let dir = Path::new(&cli.config_file);
+    let configs = discover_configs(dir, &cli.patterns)?;
+    let child_args = suite_child_args(env::args().skip(1));
+    let mut output = match &cli.output {
+        Some(path) => {
+            Some(File::create(path).with_context(|| format!("cannot write results to {}", path))?)
+        }
+        None => None,
+    };
+    let mut results = Vec::new();
+    let mut stop = false;
+    for path in configs {
+        let config = path.strip_prefix(dir).unwrap_or(&path).display().to_string();
+        let variants = match suite_variants(cli, &path) {
+            Ok(variants) => variants,
+            Err(e) => {
+                error!("{}: {:#}", config, e);
+                results.push(SuiteResult {
+                    config,
+                    variant: None,
+                    status: SuiteStatus::Failed("invalid config".into()),
+                    wall_time: None,
+                });
+                stop |= cli.fail_fast;
+                continue;
+            }
+        };
+        for variant in variants {
+            if stop {
+                results.push(SuiteResult {
+                    config: config.clone(),
+                    variant,
+                    status: SuiteStatus::Skipped,
+                    wall_time: None,
+                });
+                continue;
+            }
+            let start = Instant::now();
+            let status =
+                run_suite_config(&path, variant.as_deref(), &child_args, &mut output).await?;
+            let status = match (status.success(), status.code()) {
+                (true, _) => SuiteStatus::Passed,
+                (false, Some(code)) => SuiteStatus::Failed(format!("exit code {}", code)),
+                (false, None) => SuiteStatus::Failed(status.to_string()),
+            };
+            let result = SuiteResult {
+                config: config.clone(),
+                variant,
+                status,
+                wall_time: Some(start.elapsed()),
+            };
+            stop |= cli.fail_fast && result.failed();
+            results.push(result);
+        }
+    }
+    print_suite_table(&results);
+    let failed = results.iter().filter(|result| result.failed()).count();
+    ensure!(
+        failed == 0,
+        "{} of {} configs and variants in the suite failed",
+        failed,
+        results.len()
+    );
+    Ok(())
+}
+
+/// The variants of a config in a suite that are to be run, or just `None`
+/// if it has none.
+fn suite_variants(cli: &Cli, path: &Path) -> Result<Vec<Option<String>>> {
+    let mut source = read_config(&path.to_string_lossy())?;
+    source.package_json |= cli.package;
+    let mut config = get_config(&source, None)?;
+    cli.apply(&mut config)?;
+    Ok(match config.variants {
+        Some(variants) => variants
+            .into_iter()
+            .filter(|variant| cli.variant.as_ref().is_none_or(|only| only == variant))
+            .map(Some)
+            .collect(),
+        None => vec![None],
+    })
+}
+
+/// Runs one config, or one variant of it, from a suite, passing on its
+/// results.
+async fn run_suite_config(
+    path: &Path,
+    variant: Option<&str>,
+    args: &[String],
+    output: &mut Option<File>,
+) -> Result<ExitStatus> {
+    let mut command = Command::new(sirun_exe());
+    command
+        .arg(path)
+        .args(args)
+        .env_remove("SIRUN_OUTPUT_FILE")
+        .env_remove("SIRUN_VARIANT")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit());
+    if let Some(variant) = variant {
+        command.env("SIRUN_VARIANT", variant);
+    }
+    let mut child = command.spawn()?;
+    let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+    while let Some(line) = lines.next().await {
+        let line = line?;
+        match output {
+            Some(file) => writeln!(file, "{}", line)?,
+            None => println!("{}", line),
+        }
+    }
+    Ok(child.status().await?)
+}
+
+/// Runs each benchmark in turn, each in its own sirun, which runs all of the
+/// benchmark's variants.
+async fn run_all_benchmarks(
+    benchmarks: Vec<String>,
+    cli: &Cli,
+    source: &ConfigSource,
+) -> Result<()> {
+    let stdin_config = if source.from_stdin {
+        Some(Arc::new(source.contents.clone()))
+    } else {
+        None
+    };
+    let mut results = Vec::new();
+    for benchmark in benchmarks {
+        let (status, _) =
+            run_selected("SIRUN_BENCHMARK", &benchmark, stdin_config.clone(), false).await?;
+        let failed = !status.success();
+        results.push((benchmark, status));
+        if failed && cli.fail_fast {
+            break;
+        }
+    }
+
+    eprintln!("Benchmark results:");
+    for (benchmark, status) in &results {
+        match (status.success(), status.code()) {
+            (true, _) => eprintln!("  {}: passed", benchmark),
+            (false, Some(code)) => eprintln!("  {}: failed (exit code {})", benchmark, code),
+            (false, None) => eprintln!("  {}: failed ({})", benchmark, status),
+        }
+    }
+    let failed = results.iter().filter(|(_, status)| !status.success()).count();
+    ensure!(failed == 0, "{} of {} benchmarks failed", failed, results.len());
+    Ok(())
+}
+
+/// Prints the fully resolved config for the selected variant, or for each
+/// variant (of each benchmark) if none is selected, without running anything.
+fn dry_run(cli: &Cli, source: &ConfigSource, config: Config) -> Result<()> {
+    for config in resolve_all(cli, source, config)? {
+        for (key, source) in &cli.sources {
+            println!("# '{}' set by {}", key, source);
+        }
+        print!("{}", config);
+    }
+    Ok(())
+}
+
+/// Resolves a config for each of its benchmarks and variants.
+fn resolve_all(cli: &Cli, source: &ConfigSource, config: Config) -> Result<Vec<Config>> {
+    if let Some(benchmarks) = &config.benchmarks {
+        let mut configs = Vec::new();
+        for benchmark in benchmarks {
+            let source = ConfigSource {
+                benchmark: Some(benchmark.clone()),
+                ..source.clone()
+            };
+            let mut config = get_config(&source, cli.variant.as_deref())?;
+            cli.apply(&mut config)?;
+            configs.extend(resolve_all(cli, &source, config)?);
+        }
+        return Ok(configs);
+    }
+    Ok(match &config.variants {
+        Some(variants) => variants
+            .iter()
+            .map(|variant| {
+                let mut config = get_config(source, Some(variant))?;
+                cli.apply(&mut config)?;
+                Ok(config)
+            })
+            .collect::<Result<Vec<_>>>()?,
+        None => vec![config],
+    })
+}
+
+async fn main_main() -> Result<()> {
+    if let Some(first_arg) = env::args().nth(1) {
+        if first_arg == "--summarize" {
+            let options = SummarizeOptions::from_args(env::args().skip(2)).map_err(usage_error)?;
+            return summarize_main(options).await;
+        }
+        if first_arg == "history" {
+            let options = HistoryOptions::from_args(env::args().skip(2)).map_err(usage_error)?;
+            return history_main(options);
+        }
+        if first_arg == "--schema" {
+            println!("{}", serde_json::to_string_pretty(&config_schema())?);
+            return Ok(());
+        }
+        if first_arg == "exec" {
+            let source = exec_config(env::args().skip(2)).map_err(usage_error)?;
+            return run_config(get_config(&source, None)?).await;
+        }
+    }
+    let cli = Cli::from_args(env::args().skip(1))?;
+    if Path::new(&cli.config_file).is_dir() {
+        return run_suite(&cli).await;
+    }
+    let mut source = read_config(&cli.config_file)?;
+    source.package_json |= cli.package;
+    source.benchmark = cli.benchmark.clone();
+    if cli.list_variants {
+        let mut config = get_config(&source, None)?;
+        cli.apply(&mut config)?;
+        if let Some(benchmarks) = config.benchmarks {
+            bail!(
+                "pick one of the benchmarks with --benchmark to list its variants: {}",
+                benchmarks.join(", ")
+            );
+        }
+        for variant in config.variants.unwrap_or_default() {
+            println!("{}", variant);
+        }
+        return Ok(());
+    }
+    let mut config = get_config(&source, cli.variant.as_deref())?;
+    cli.apply(&mut config)?;
+
+    if cli.dry_run {
+        return dry_run(&cli, &source, config);
+    }
+
+    if let Some(benchmarks) = config.benchmarks {
+        if let Some(output_file) = &config.output_file {
+            // Each benchmark appends its own lines, so start from an empty file.
+            File::create(output_file)?;
+        }
+        return run_all_benchmarks(benchmarks, &cli, &source).await;
+    }
+
+    if let Some(variants) = config.variants.take() {
+        // Each variant appends its own line, so start from an empty file,
+        // unless it's already been started for earlier benchmarks.
+        if let (Some(output_file), None) = (&config.output_file, &config.benchmark) {
+            File::create(output_file)?;
+        }
+        if config.interleave {
+            return run_interleaved(variants, &cli, &source).await;
+        }
+        run_all_variants(variants, &config, &cli, &source).await?;
+        return Ok(());
+    }
+
+    run_config(config).await
+}
+
+/// Runs all the iterations of a config without variants.
+async fn run_config(config: Config) -> Result<()> {
+    check_profiler(&config)?;
+    let _lock = lock_machine(&config).await?;
+    let noisy = check_noise(&config).await?;
+    let statsd_buf = Arc::new(RwLock::new(StatsdBuffer::default()));
+    let statsd_addr = start_statsd(&config, statsd_buf.clone()).await?;
+    let mut run = Run::new(config, statsd_addr);
+    run.noisy = noisy;
+    while !run.done() {
+        run.iterate(statsd_buf.clone()).await?;
+    }
+    if run.finish().await? {
+        exit(TRUNCATED_EXIT_CODE);
+    }
+    Ok(())
+}
+
+/// Runs all the iterations of a config, and returns the results instead of
+/// writing them out. Fails if an iteration did (unless `continue_on_failure`
+/// is set), or if any of the config's `assertions` did. Progress isn't
+/// reported, and `output_file` and `stream` are left alone.
+///
+/// Each iteration is run in a child process of the current executable, which
+/// has to call [`run_iteration_if_child`] before doing anything else, unless
+/// `SIRUN_EXE` is set to the path of a `sirun` executable to use instead.
+pub fn run_benchmark(mut config: Config) -> Result<BenchmarkResult> {
+    if let Some(variants) = &config.variants {
+        bail!(
+            "pick one of the config's variants to run: {}",
+            variants.join(", ")
+        );
+    }
+    ensure!(
+        config.benchmarks.is_none(),
+        "configs with benchmarks can only be run by the sirun command"
+    );
+    ensure!(!config.run.is_empty(), "'run' must not be empty");
+    ensure!(config.iterations > 0, "iterations must be an integer >=1");
+    check_profiler(&config)?;
+    config.quiet = true;
+    config.stream = false;
+    block_on(async {
+        let _lock = lock_machine(&config).await?;
+        let noisy = check_noise(&config).await?;
+        let statsd_buf = Arc::new(RwLock::new(StatsdBuffer::default()));
+        let statsd_addr = start_statsd(&config, statsd_buf.clone()).await?;
+        let mut run = Run::new(config, statsd_addr);
+        run.noisy = noisy;
+        while !run.done() {
+            run.iterate(statsd_buf.clone()).await?;
+        }
+        let (_, metrics, _, outcome) = run.results().await?;
+        outcome.check()?;
+        BenchmarkResult::from_metric_map(metrics)
+    })
+}
+
+/// Fails if the tool the config's profiler needs isn't available, before any
+/// iteration is run.
+fn check_profiler(config: &Config) -> Result<()> {
+    match profiler(config) {
+        Some(profiler) => profiler.check(),
+        None => Ok(()),
+    }
+}
+
+
+/// Binds the statsd socket and starts listening on it, returning the address
+/// commands should send metrics to.
+async fn start_statsd(
+    config: &Config,
+    statsd_buf: Arc<RwLock<StatsdBuffer>>,
+) -> Result<SocketAddr> {
+    let socket = bind_statsd(config).await?;
+    let bound = socket.local_addr()?;
+    debug!("statsd listening on port {}", bound.port());
+    spawn(statsd_listener(socket, statsd_buf));
+    Ok(statsd_addr(bound))
+}
+
+/// Re-runs sirun for a single variant or benchmark, selected by setting
+/// `var` to its name. When variants run in parallel, each picks its own
+/// statsd port. Its output is passed on a line at a time, so that lines from
+/// different variants don't get mixed up, and returned too.
+async fn run_selected(
+    var: &str,
+    name: &str,
+    stdin_config: Option<Arc<String>>,
+    parallel: bool,
+) -> Result<(ExitStatus, Vec<String>)> {
+    let mut command = Command::new(sirun_exe());
+    command
+        .args(env::args().skip(1))
+        .env(var, name)
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit());
+    if stdin_config.is_some() {
+        command.stdin(Stdio::piped());
+    }
+    if parallel {
+        command.env_remove("SIRUN_STATSD_PORT");
+    }
+    let mut child = command.spawn()?;
+    if let Some(contents) = stdin_config {
+        let mut stdin = child.stdin.take().unwrap();
+        stdin.write_all(contents.as_bytes()).await?;
+    }
+    let mut output = Vec::new();
+    let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+    while let Some(line) = lines.next().await {
+        let line = line?;
+        println!("{}", line);
+        output.push(line);
+    }
+    Ok((child.status().await?, output))
+}
+
+/// The iterations of each variant that has results among the given lines, in
+/// the order the variants were given, along with the name of the benchmark
+/// they're a variant of. Streamed iterations are gathered up, and a later
+/// line of results for a variant replaces an earlier one.
+fn variant_results(
+    variants: &[(Option<String>, String)],
+    lines: &[String],
+) -> Vec<(String, Vec<MetricValue>)> {
+    let mut found: HashMap<(Option<String>, String), Vec<MetricValue>> = HashMap::new();
+    for line in lines {
+        let mut results = match serde_json::from_str::<MetricMap>(line) {
+            Ok(results) => results,
+            Err(_) => continue,
+        };
+        let variant = match results.get("variant").map(MetricValue::as_string) {
+            Some(Ok(variant)) => variant,
+            _ => continue,
+        };
+        let name = match results.get("name").map(MetricValue::as_string) {
+            Some(Ok(name)) => Some(name),
+            Some(Err(_)) => continue,
+            None => None,
+        };
+        let streamed = matches!(
+            results.get(STREAM_TYPE_KEY),
+            Some(MetricValue::Str(t)) if t == "iteration"
+        );
+        if streamed {
+            if let Some(metrics) = results.remove("metrics") {
+                found.entry((name, variant)).or_default().push(metrics);
+            }
+        } else if let Some(Ok(iterations)) = results.get("iterations").map(MetricValue::as_vec) {
+            found.insert((name, variant), iterations);
+        }
+    }
+    variants
+        .iter()
+        .filter_map(|benchmark| Some((benchmark.1.clone(), found.remove(benchmark)?)))
+        .collect()
+}
+
+/// Runs every variant in this process, taking turns one iteration at a time
+/// so that drift in the machine's performance affects them all alike.
+async fn run_interleaved(variants: Vec<String>, cli: &Cli, source: &ConfigSource) -> Result<()> {
+    let mut configs = Vec::new();
+    for variant in &variants {
+        let mut config = get_config(source, Some(variant))?;
+        cli.apply(&mut config)?;
+        configs.push(config);
+    }
+    // The variants share the machine, so it only needs to be locked, and to
+    // settle, once.
+    let _lock = lock_machine(&configs[0]).await?;
+    let noisy = check_noise(&configs[0]).await?;
+    let statsd_buf = Arc::new(RwLock::new(StatsdBuffer::default()));
+    let statsd_addr = start_statsd(&configs[0], statsd_buf.clone()).await?;
+    // They also share the listener, and so its protocol.
+    let statsd_protocol = configs[0].statsd_protocol;
+    let mut runs: Vec<_> = configs
+        .into_iter()
+        .map(|mut config| {
+            config.statsd_protocol = statsd_protocol;
+            Run::new(config, statsd_addr)
+        })
+        .collect();
+    for run in runs.iter_mut() {
+        run.noisy = noisy;
+    }
+    while runs.iter().any(|run| !run.done()) {
+        for run in runs.iter_mut().filter(|run| !run.done()) {
+            run.iterate(statsd_buf.clone()).await?;
+        }
+    }
+    // Every variant's results are written even if an earlier one failed.
+    let mut result = Ok(false);
+    for run in runs {
+        let finished = run.finish().await;
+        result = match (result, finished) {
+            (Ok(truncated), Ok(finished)) => Ok(truncated || finished),
+            (Err(e), _) | (_, Err(e)) => Err(e),
+        };
+    }
+    if result? {
+        exit(TRUNCATED_EXIT_CODE);
+    }
+    Ok(())
+}
+
+/// Exit code for a run cut short by `total_timeout`, as used by `timeout(1)`.
+const TRUNCATED_EXIT_CODE: i32 = 124;
+
+/// The iterations of one config, and what's been learned from them so far.
+struct Run {
+    config: Config,
+    detectors: Detectors,
+    flagged: i32,
+    failed: i32,
+    /// How CPU time was accounted for, when `cgroup_accounting` asked for
+    /// cgroups: `cgroup`, or `rusage` if they can't be used here.
+    accounting: Option<&'static str>,
+    /// Whether the machine was still too busy for the `noise_check` when the
+    /// first iteration started.
+    noisy: bool,
+    iterations: Vec<MetricValue>,
+    start: Instant,
+    started_at: SystemTime,
+    progress: Option<Progress>,
+}
+
+impl Run {
+    fn new(mut config: Config, statsd_addr: SocketAddr) -> Self {
+        // Every command sirun spawns learns the address through its
+        // environment.
+        config
+            .env
+            .insert("SIRUN_STATSD_PORT".into(), statsd_addr.port().to_string());
+        config
+            .env
+            .insert(STATSD_ADDR_ENV.into(), statsd_addr.to_string());
+        config.env.insert(
+            STATSD_PROTOCOL_ENV.into(),
+            config.statsd_protocol.name().to_owned(),
+        );
+        let accounting = match config.cgroup_accounting {
+            false => None,
+            true => match check_cgroups() {
+                Ok(()) => Some("cgroup"),
+                Err(e) => {
+                    warn!(
+                        "'cgroup_accounting' falls back to getrusage, since cgroups can't be used here: {:#}",
+                        e
+                    );
+                    config.cgroup_accounting = false;
+                    Some("rusage")
+                }
+            },
+        };
+        Run {
+            accounting,
+            detectors: Detectors::new(),
+            flagged: 0,
+            failed: 0,
+            noisy: false,
+            iterations: Vec::new(),
+            start: Instant::now(),
+            started_at: SystemTime::now(),
+            progress: Progress::new(&config),
+            config,
+        }
+    }
+
+    fn done(&self) -> bool {
+        self.complete() || self.out_of_time()
+    }
+
+    /// Whether enough iterations have been run, going by either `iterations`
+    /// or `duration`. An iteration that's already running when the
+    /// `duration` is up is still counted.
+    fn complete(&self) -> bool {
+        let count = self.iterations.len() as u64;
+        match self.config.duration {
+            Some(duration) => {
+                count >= self.config.max_iterations.unwrap_or(u64::MAX)
+                    || (count >= self.config.min_iterations.unwrap_or(1)
+                        && self.start.elapsed() >= Duration::from_secs(duration))
+            }
+            None => count >= self.config.iterations,
+        }
+    }
+
+    /// Once `total_timeout` has run out no new iterations are started, though
+    /// one already running is allowed to finish.
+    fn out_of_time(&self) -> bool {
+        self.config
+            .total_timeout
+            .is_some_and(|timeout| self.start.elapsed() >= Duration::from_secs(timeout))
+    }
+
+    async fn iterate(&mut self, statsd_buf: Arc<RwLock<StatsdBuffer>>) -> Result<()> {
+        let index = self.iterations.len() as u64;
+        self.detectors.begin();
+        let mut iteration = run_iteration(&self.config, index, statsd_buf).await?;
+        if self.detectors.end(&mut iteration) {
+            self.flagged += 1;
+        }
+        if is_failed(&iteration) {
+            self.failed += 1;
+        }
+        if self.config.stream {
+            let mut line = self.stream_line("iteration");
+            let mut metrics = iteration.clone();
+            if let Some(precision) = &self.config.metric_precision {
+                precision.round_metrics(&mut metrics);
+            }
+            line.insert("metrics".into(), metrics.into());
+            write_results(&self.config, &json!(line).to_string(), self.iterations.is_empty())?;
+        }
+        self.iterations.push(MetricValue::Map(iteration));
+        if let Some(progress) = &mut self.progress {
+            progress.report(&self.config, &self.iterations, self.start.elapsed());
+        }
+        Ok(())
+    }
+
+    /// Start of a line of `--stream` output, identifying what it's about.
+    fn stream_line(&self, stream_type: &str) -> MetricMap {
+        let mut line = MetricMap::new();
+        line.insert(STREAM_TYPE_KEY.into(), stream_type.to_owned().into());
+        if let Some(name) = &self.config.name {
+            line.insert("name".into(), name.clone().into());
+        }
+        if let Some(variant) = &self.config.variant {
+            line.insert("variant".into(), variant.clone().into());
+        }
+        line
+    }
+
+    /// Writes out the results. Fails afterwards if any iteration did. Returns
+    /// whether `total_timeout` cut the run short.
+    async fn finish(self) -> Result<bool> {
+        let (config, metrics, mut rounded, outcome) = self.results().await?;
+        let last_run = match &config.history {
+            Some(path) => record_history(path, &metrics).unwrap_or_else(|e| {
+                error!("cannot add results to history {}: {:#}", path, e);
+                None
+            }),
+            None => None,
+        };
+        if let (Some(last_run), Some(MetricValue::Arr(iterations))) =
+            (&last_run, metrics.get("iterations"))
+        {
+            let compared = last_run.to_metric(iterations);
+            rounded.insert(LAST_RUN_KEY.into(), compared);
+        }
+        if config.stream {
+            // The iterations have been written already.
+            rounded.remove("iterations");
+            rounded.insert(STREAM_TYPE_KEY.into(), "summary".to_owned().into());
+            write_results(&config, &json!(rounded).to_string(), outcome.total == 0)?;
+        } else {
+            write_results(&config, &json!(rounded).to_string(), true)?;
+        }
+        let compared = match metrics.get("iterations") {
+            Some(MetricValue::Arr(iterations)) => {
+                print_summary_table(&config, iterations, last_run.as_ref());
+                compare_with_baseline(&config, iterations)
+            }
+            _ => Ok(()),
+        };
+        let exported = export_results(&config, &metrics);
+        outcome.check()?;
+        compared?;
+        exported?;
+        Ok(outcome.truncated)
+    }
+
+    /// Adds the metrics that don't belong to any one iteration to the results.
+    async fn results(mut self) -> Result<(Config, MetricMap, MetricMap, Outcome)> {
+        let completed_at = SystemTime::now();
+        if let Some(progress) = &mut self.progress {
+            progress.finish();
+        }
+        let truncated = !self.complete();
+        let config = self.config;
+        let failed = self.failed;
+        let total = self.iterations.len();
+        let violations = match &config.assertions {
+            Some(assertions) => assertions.check(&self.iterations),
+            None => Vec::new(),
+        };
+        let mut metrics = MetricMap::new();
+        if truncated {
+            eprintln!(
+                "Total timeout of {} seconds exceeded after {} iterations.",
+                config.total_timeout.unwrap_or_default(),
+                total
+            );
+            metrics.insert(TRUNCATED_KEY.into(), 1.into());
+        }
+        if config.duration.is_some() {
+            metrics.insert("iteration_count".into(), (total as u64).into());
+        }
+        metrics.insert("started_at".into(), iso8601(self.started_at).into());
+        metrics.insert("completed_at".into(), iso8601(completed_at).into());
+        metrics.insert("iterations".into(), MetricValue::Arr(self.iterations));
+        metrics.insert(FLAGGED_KEY.into(), self.flagged.into());
+        if self.noisy {
+            metrics.insert(NOISY_ENVIRONMENT_KEY.into(), 1.into());
+        }
+
+        if config.cachegrind && which("valgrind").is_ok() {
+            let command = "valgrind";
+            let mut args = vec![
+                "--tool=cachegrind".to_owned(),
+                "--trace-children=yes".to_owned(),
+                // Set some reasonable L1 and LL values. It is important that
+                // these values are consistent across runs, instead of the
+                // default.
+                "--I1=32768,8,64".to_owned(),
+                "--D1=32768,8,64".to_owned(),
+                "--LL=8388608,16,64".to_owned(),
+            ];
+            args.append(&mut config.run.clone());
+            let mut config = config.clone();
+            let _tmpdir = IterationTmpdir::create(&mut config)?;
+            run_setup(&config).await?;
+            let mut command = Command::new(command);
+            configure_command(&mut command, &config);
+            let output = command.args(args).output().await?;
+            run_teardown(&config).await?;
+            let stderr = String::from_utf8_lossy(&output.stderr);
+
+            let lines = stderr.trim().lines().filter(|x| x.contains("I   refs:"));
+            let mut instructions: f64 = 0.0;
+            for line in lines {
+                instructions += line
+                    .split_whitespace()
+                    .last()
+                    .expect("Bad cachegrind output: invalid instruction ref line")
+                    .replace(",", "")
+                    .parse::<f64>()
+                    .expect("Bad cachegrind output: invalid number");
+            }
+            ensure!(instructions > 0.0, "bad cachegrind output: no instructions parsed");
+            metrics.insert("instructions".into(), instructions.into());
+        }
+
+        match env::var("GIT_COMMIT_HASH") {
+            Ok(hash) => {
+                metrics.insert("version".into(), hash.into());
+            }
+            Err(_) => {
+                if let Some(git) = GitInfo::detect(&config.config_dir) {
+                    git.add_to(&mut metrics);
+                }
+            }
+        }
+        if let Some(name) = &config.name {
+            metrics.insert("name".into(), name.clone().into());
+        }
+        if let Some(variant) = &config.variant {
+            metrics.insert("variant".into(), variant.clone().into());
+        }
+        metrics.insert(CONFIG_HASH_KEY.into(), config_hash(&config)?.into());
+        metrics.insert(RUN_COMMAND_KEY.into(), run_command(&config).into());
+
+        // What's written out is rounded, while statistics are computed from
+        // the exact values.
+        let mut rounded = metrics.clone();
+        if let Some(precision) = &config.metric_precision {
+            precision.round_metrics(&mut rounded);
+        }
+
+        // Added after rounding, which is meant for measurements only.
+        let mut settings = MetricMap::new();
+        if !config.cpu_affinity.is_empty() {
+            let cpus = config.cpu_affinity.iter().map(|cpu| (*cpu as u64).into());
+            settings.insert("cpu_affinity".into(), MetricValue::Arr(cpus.collect()));
+        }
+        if let Some(nice) = config.nice {
+            settings.insert("nice".into(), nice.into());
+        }
+        if config.disable_aslr {
+            settings.insert("disable_aslr".into(), 1.into());
+        }
+        if let Some(accounting) = self.accounting {
+            settings.insert("accounting".into(), accounting.to_owned().into());
+        }
+        if config.collect_host_info && env::var("SIRUN_NO_HOST_INFO").is_err() {
+            settings.insert("host".into(), host_info().into());
+        }
+
+        metrics.extend(settings.clone());
+        rounded.extend(settings);
+
+        let outcome = Outcome {
+            truncated,
+            failed,
+            total,
+            violations,
+        };
+        Ok((config, metrics, rounded, outcome))
+    }
+}
+
+/// How a run went, beyond its metrics.
+struct Outcome {
+    truncated: bool,
+    failed: i32,
+    total: usize,
+    violations: Vec<String>,
+}
+
+impl Outcome {
+    /// Fails if any iteration or assertion did.
+    fn check(&self) -> Result<()> {
+        for violation in &self.violations {
+            eprintln!("Assertion failed: {}", violation);
+        }
+        ensure!(
+            self.failed == 0,
+            "{} of {} iterations failed",
+            self.failed,
+            self.total
+        );
+        ensure!(
+            self.violations.is_empty(),
+            "{} assertion(s) failed",
+            self.violations.len()
+        );
+        Ok(())
+    }
+}
+
+/// Prints a line of results, or writes it to the configured output file. The
+/// file is emptied before the `first` line only when neither `variant` nor
+/// `benchmark` is set, and appended to otherwise, since other runs write to it
+/// too.
+fn write_results(config: &Config, line: &str, first: bool) -> Result<()> {
+    let truncate = first && config.variant.is_none() && config.benchmark.is_none();
+    match &config.output_file {
+        Some(output_file) => {
+            let mut file = OpenOptions::new()
+                .create(true)
+                .write(true)
+                .append(!truncate)
+                .truncate(truncate)
+                .open(output_file)
+                .with_context(|| format!("cannot write results to {}", output_file))?;
+            writeln!(file, "{}", line)?;
+        }
+        None => println!("{}", line),
+    }
+    Ok(())
+}
+
+fn read_iteration_config() -> Result<Config> {
+    let yaml = match env::var(ITERATION_CONFIG_ENV) {
+        Ok(path) => {
+            let yaml = fs::read_to_string(&path)
+                .with_context(|| format!("cannot read iteration config {}", path))?;
+            fs::remove_file(&path)?;
+            yaml
+        }
+        Err(_) => env::var(LEGACY_ITERATION_ENV)?,
+    };
+    Ok(serde_yaml::from_str(&yaml)?)
+}
+
+async fn iteration_main() -> Result<()> {
+    let config = read_iteration_config()?;
+
+    let mut metrics = MetricMap::new();
+
+    let status = run_test(&config, &mut metrics).await?;
+
+    let mut buf = String::new();
+    for name in &["max.res.size", "user.time", "system.time", "wall.time", "cpu.pct.wall.time"] {
+        let value = metrics
+            .remove(*name)
+            .ok_or_else(|| anyhow!("no {} was measured", name))?;
+        buf.push_str(&format!(
+            "{}:{}|g|{}\n",
+            timing_key(&config, name),
+            value.as_f64()?,
+            OWN_METRIC_TAG
+        ));
+    }
+    // Anything left over is optional: instruction counts and exit status.
+    for (name, value) in metrics {
+        buf.push_str(&format!("{}:{}|g|{}\n", name, value.as_f64()?, OWN_METRIC_TAG));
+    }
+    let statsd_addr: SocketAddr = match env::var(STATSD_ADDR_ENV) {
+        Ok(addr) => addr.parse()?,
+        Err(_) => format!("127.0.0.1:{}", env::var("SIRUN_STATSD_PORT")?).parse()?,
+    };
+    match config.statsd_protocol {
+        // A single packet, so that the parent knows it has them all once any
+        // have arrived.
+        StatsdProtocol::Udp => {
+            let sock = match statsd_addr {
+                SocketAddr::V4(_) => UdpSocket::bind("0.0.0.0:0").await?,
+                SocketAddr::V6(_) => UdpSocket::bind("[::]:0").await?,
+            };
+            sock.send_to(buf.as_bytes(), statsd_addr).await?;
+        }
+        // A single connection, which the parent only takes them from once
+        // it's closed.
+        StatsdProtocol::Tcp => {
+            let mut stream = TcpStream::connect(statsd_addr).await?;
+            stream.write_all(buf.as_bytes()).await?;
+            stream.flush().await?;
+            stream.shutdown(Shutdown::Write)?;
+        }
+    }
+    if status != 0 {
+        exit(status);
+    }
+    Ok(())
+}
+
+fn is_iteration_child() -> bool {
+    env::var(ITERATION_CONFIG_ENV).is_ok() || env::var(LEGACY_ITERATION_ENV).is_ok()
+}
+
+/// If this process was started by [`run_benchmark`] to run an iteration,
+/// runs it and exits. Otherwise, does nothing.
+pub fn run_iteration_if_child() {
+    if !is_iteration_child() {
+        return;
+    }
+    if let Err(e) = block_on(iteration_main()) {
+        eprintln!("Error: {:?}", e);
+        exit(1);
+    }
+    exit(0);
+}
+
+/// Runs the `sirun` command line tool with this process's arguments. Exits
+/// with code 2 if they're invalid, and with the test's own exit code if it
+/// fails.
+pub fn run_cli() -> Result<()> {
+    block_on(cli_main())
+}
+
+async fn cli_main() -> Result<()> {
+    init_logging()?;
+    if is_iteration_child() {
+        return iteration_main().await;
+    }
+    for (i, arg) in env::args().skip(1).enumerate() {
+        match arg.as_str() {
+            // What follows is the command's, whose own flags are passed on.
+            "--" => break,
+            "exec" if i == 0 => break,
+            "-h" | "--help" => {
+                print!("{}", USAGE);
+                return Ok(());
+            }
+            "-V" | "--version" => {
+                println!("{}", version());
+                return Ok(());
+            }
+            _ => {}
+        }
+    }
+    let result = main_main().await;
+    if let Err(e) = &result {
+        if let Some(e) = e.downcast_ref::<UsageError>() {
+            eprintln!("Error: {}\n\n{}", e, USAGE);
+            exit(2);
+        }
+        // The test's own output says what went wrong.
+        if let Some(TestFailed(code)) = e.downcast_ref() {
+            exit(*code);
+        }
+        if e.downcast_ref::<NoisyEnvironment>().is_some() {
+            eprintln!("Error: {}", e);
+            exit(NOISY_EXIT_CODE);
+        }
+    }
+    result
+}
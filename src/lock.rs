@@ -0,0 +1,116 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+//! Keeping other sirun runs off the machine while benchmarking, with
+//! `exclusive`.
+
+use anyhow::*;
+use async_std::task::sleep;
+use log::warn;
+use nix::{
+    errno::Errno,
+    fcntl::{flock, FlockArg},
+};
+use std::{
+    env,
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    os::unix::io::AsRawFd,
+    path::PathBuf,
+    process,
+    time::{Duration, Instant},
+};
+
+use crate::config::*;
+
+/// How often a held lock is tried again.
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// An advisory lock on the machine, held until it's dropped. Since it's an
+/// `flock`, the kernel also releases it when the process exits in any way,
+/// so a crashed run never leaves it held.
+pub(crate) struct MachineLock {
+    _file: File,
+}
+
+/// Where the lock is taken: `SIRUN_LOCK_FILE` if it's set, or `sirun.lock`
+/// in `XDG_RUNTIME_DIR`, or else in the temporary directory.
+fn lock_path() -> PathBuf {
+    if let Some(path) = env::var_os("SIRUN_LOCK_FILE") {
+        return path.into();
+    }
+    env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(env::temp_dir)
+        .join("sirun.lock")
+}
+
+/// The process that holds the lock, as it wrote in the lock file.
+fn holder(file: &mut File) -> Option<u32> {
+    let mut contents = String::new();
+    file.seek(SeekFrom::Start(0)).ok()?;
+    file.read_to_string(&mut contents).ok()?;
+    contents.trim().parse().ok()
+}
+
+fn describe(holder: Option<u32>) -> String {
+    match holder {
+        Some(pid) => format!("pid {}", pid),
+        None => "another process".into(),
+    }
+}
+
+/// Takes the machine lock if the config is `exclusive`, waiting up to its
+/// `exclusive_timeout` for whoever holds it.
+pub(crate) async fn lock_machine(config: &Config) -> Result<Option<MachineLock>> {
+    if !config.exclusive {
+        return Ok(None);
+    }
+    let path = lock_path();
+    // The file may belong to another user, whose lock can still be waited
+    // for even if the pid can't be written in it. It isn't truncated until
+    // it's locked, since it has the holder's pid in it.
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .or_else(|_| File::open(&path))
+        .with_context(|| format!("cannot open lock file {}", path.display()))?;
+    let timeout = Duration::from_secs(config.exclusive_timeout);
+    let start = Instant::now();
+    let mut waiting = false;
+    loop {
+        match flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+            Ok(()) => break,
+            Err(nix::Error::Sys(Errno::EAGAIN)) => {}
+            Err(e) => bail!("cannot lock {}: {}", path.display(), e),
+        }
+        let holder = describe(holder(&mut file));
+        ensure!(
+            start.elapsed() < timeout,
+            "gave up waiting for {} to release {} after {}s ('exclusive_timeout')",
+            holder,
+            path.display(),
+            config.exclusive_timeout
+        );
+        if !waiting {
+            warn!(
+                "waiting up to {}s for {} to release {}, since 'exclusive' is set",
+                config.exclusive_timeout,
+                holder,
+                path.display()
+            );
+            waiting = true;
+        }
+        sleep(RETRY_INTERVAL.min(timeout.saturating_sub(start.elapsed()))).await;
+    }
+    let _ = file.set_len(0);
+    let _ = file
+        .seek(SeekFrom::Start(0))
+        .and_then(|_| write!(file, "{}", process::id()));
+    Ok(Some(MachineLock { _file: file }))
+}
@@ -0,0 +1,54 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+//! sirun's own diagnostics, written to stderr. How much is written is set
+//! with `SIRUN_LOG`, to one of `off`, `error`, `warn` (the default), `info`,
+//! `debug` or `trace`.
+
+use anyhow::*;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::env;
+
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        // Dependencies log too, which is only noise here.
+        metadata.target().starts_with("sirun") && metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let label = match record.level() {
+            Level::Error => "Error",
+            Level::Warn => "Warning",
+            Level::Info => "Info",
+            Level::Debug => "Debug",
+            Level::Trace => "Trace",
+        };
+        eprintln!("{}: {}", label, record.args());
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+pub(crate) fn init_logging() -> Result<()> {
+    let level = match env::var("SIRUN_LOG") {
+        Ok(level) => level.parse().map_err(|_| {
+            anyhow!(
+                "SIRUN_LOG must be one of off, error, warn, info, debug or trace, got '{}'",
+                level
+            )
+        })?,
+        Err(_) => LevelFilter::Warn,
+    };
+    log::set_logger(&LOGGER).map_err(|e| anyhow!("cannot set up logging: {}", e))?;
+    log::set_max_level(level);
+    Ok(())
+}
@@ -0,0 +1,139 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+//! Summaries as Markdown tables, for `--summarize --format markdown`, such as
+//! for posting in pull requests.
+
+use anyhow::*;
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::metric_value::*;
+use crate::summarize::*;
+use crate::units::*;
+
+/// A metric's `mean ± stddev`, in the most readable unit for it.
+fn cell(metric: &str, stats: &MetricMap) -> String {
+    let stat = |name: &str| match stats.get(name) {
+        Some(MetricValue::Num(value)) => Some(*value),
+        _ => None,
+    };
+    let mean = match stat("mean") {
+        Some(mean) => mean,
+        None => return String::new(),
+    };
+    let readable = Readable::new(metric, mean);
+    match stat("stddev") {
+        Some(stddev) => format!(
+            "{} ± {}{}",
+            readable.number(mean),
+            readable.number(stddev),
+            readable.suffix
+        ),
+        None => readable.format(mean),
+    }
+}
+
+/// Keeps names with pipes in them from ending their table cells early.
+fn escape(text: &str) -> String {
+    text.replace('|', "\\|")
+}
+
+/// Writes a table for each name, with a row for each of its variants and a
+/// column for each of the given metrics, or else of all of them.
+pub(crate) fn summary_markdown(result_data: &MetricMap, only: Option<&[String]>) -> Result<String> {
+    let mut tables = Vec::new();
+    for (name, variants) in result_data {
+        let mut rows = BTreeMap::new();
+        for (variant, data) in variants.as_map()? {
+            rows.insert(variant, summary_metrics(data.as_map()?)?);
+        }
+        let columns: Vec<&String> = match only {
+            Some(metrics) => metrics.iter().collect(),
+            None => rows
+                .values()
+                .flat_map(|metrics| metrics.keys())
+                .collect::<BTreeSet<_>>()
+                .into_iter()
+                .collect(),
+        };
+
+        let mut table = vec![
+            format!("### {}", escape(name)),
+            String::new(),
+            format!(
+                "| variant |{}",
+                columns
+                    .iter()
+                    .map(|metric| format!(" {} |", escape(metric)))
+                    .collect::<String>()
+            ),
+            format!("| --- |{}", " ---: |".repeat(columns.len())),
+        ];
+        for (variant, metrics) in &rows {
+            let cells: String = columns
+                .iter()
+                .map(|metric| match metrics.get(*metric) {
+                    Some(stats) => format!(" {} |", cell(metric, stats)),
+                    None => " |".to_owned(),
+                })
+                .collect();
+            table.push(format!("| {} |{}", escape(variant), cells));
+        }
+        tables.push(table.join("\n"));
+    }
+    Ok(tables.join("\n\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(mean: f64, stddev: f64) -> MetricMap {
+        let mut stats = MetricMap::new();
+        stats.insert("mean".to_owned(), mean.into());
+        stats.insert("stddev".to_owned(), stddev.into());
+        stats
+    }
+
+    #[test]
+    fn units() {
+        assert_eq!(cell("wall.time", &stats(812.0, 9.5)), "812 ± 10 µs");
+        assert_eq!(cell("wall.time", &stats(102_345.0, 1_520.0)), "102 ± 2 ms");
+        assert_eq!(
+            cell("user.time", &stats(2_345_678.0, 46_000.0)),
+            "2.35 ± 0.05 s"
+        );
+        assert_eq!(cell("duration.ms", &stats(12.345, 0.5)), "12.3 ± 0.5 ms");
+        assert_eq!(
+            cell("max.res.size", &stats(51_200.0, 1_024.0)),
+            "50.0 ± 1.0 MiB"
+        );
+        assert_eq!(cell("max.res.size", &stats(900.0, 10.0)), "900 ± 10 KiB");
+        assert_eq!(
+            cell("tmp.bytes", &stats(3.0 * 1024.0 * 1024.0 * 1024.0, 0.0)),
+            "3.00 ± 0.00 GiB"
+        );
+        assert_eq!(
+            cell("disk.out.bytes.delta", &stats(512.0, 0.0)),
+            "512 ± 0 B"
+        );
+        assert_eq!(
+            cell("cpu.pct.wall.time", &stats(98.765, 1.2)),
+            "98.8 ± 1.2%"
+        );
+        assert_eq!(
+            cell("gc.pauses", &stats(0.001234, 0.0001)),
+            "0.00123 ± 0.00010"
+        );
+        assert_eq!(
+            cell("instructions", &stats(34_884_985_032.0, 1e6)),
+            "34884985032 ± 1000000"
+        );
+        let mut mean_only = MetricMap::new();
+        mean_only.insert("mean".to_owned(), 3.0.into());
+        assert_eq!(cell("requests", &mean_only), "3.00");
+        assert_eq!(cell("requests", &MetricMap::new()), "");
+    }
+}
@@ -0,0 +1,131 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+//! Enforcing the `max_memory` budget of the `run` command, so that a
+//! benchmark that runs away fails rather than making the machine swap.
+
+use async_std::process::Command;
+use std::process::ExitStatus;
+
+use crate::cgroup::*;
+
+const UNITS: &[(char, u64)] = &[
+    ('T', 1 << 40),
+    ('G', 1 << 30),
+    ('M', 1 << 20),
+    ('K', 1 << 10),
+];
+
+/// Reads a size like `512M`, with an optional `K`, `M`, `G` or `T` suffix
+/// for a power of 1024, or a plain number of bytes.
+pub(crate) fn parse_byte_size(size: &str) -> Option<u64> {
+    let size = size.trim();
+    let (number, multiplier) = match UNITS
+        .iter()
+        .find(|(suffix, _)| size.ends_with(*suffix) || size.ends_with(suffix.to_ascii_lowercase()))
+    {
+        Some((_, multiplier)) => (&size[..size.len() - 1], *multiplier),
+        None => (size, 1),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .filter(|bytes| *bytes > 0)
+}
+
+/// Writes a size the way it'd be given, with the largest suffix that fits it
+/// exactly, e.g. `512M`.
+pub(crate) fn format_byte_size(bytes: u64) -> String {
+    UNITS
+        .iter()
+        .find(|(_, multiplier)| bytes.is_multiple_of(*multiplier))
+        .map_or_else(
+            || bytes.to_string(),
+            |(suffix, multiplier)| format!("{}{}", bytes / multiplier, suffix),
+        )
+}
+
+/// What runtimes commonly write to stderr when an allocation fails, in lower
+/// case.
+const OUT_OF_MEMORY_ERRORS: &[&str] = &[
+    "out of memory",
+    "memoryerror",
+    "cannot allocate",
+    "memory allocation",
+    "memory exhausted",
+    "bad_alloc",
+];
+
+/// How the `max_memory` of a command is enforced.
+pub(crate) enum MemoryLimit {
+    /// The command's cgroup has it as its `memory.max`, and has no swap, so
+    /// it's shared by every copy of the command.
+    Cgroup,
+    /// Each of the command's processes has it as its `RLIMIT_AS`.
+    AddressSpace(u64),
+}
+
+impl MemoryLimit {
+    /// Limits the command to `bytes`, through its cgroup if it has one that
+    /// can do it, and otherwise through `RLIMIT_AS`, which `restrict` sets on
+    /// each copy of the command.
+    pub(crate) fn new(bytes: u64, cgroup: Option<&Cgroup>) -> Self {
+        if cgroup.is_some_and(|cgroup| cgroup.limit_memory(bytes)) {
+            return MemoryLimit::Cgroup;
+        }
+        MemoryLimit::AddressSpace(bytes)
+    }
+
+    /// Applies the limit to a copy of the command before it's spawned.
+    pub(crate) fn restrict(&self, command: &mut Command) {
+        if let MemoryLimit::AddressSpace(bytes) = self {
+            limit_address_space(command, *bytes);
+        }
+    }
+
+    /// Whether the command was stopped for going over the limit. A cgroup
+    /// counts the processes its OOM killer killed. Running out of address
+    /// space only makes allocations fail, so it takes the command failing and
+    /// reporting that on stderr, since dying of a signal alone is as likely to
+    /// be a crash.
+    pub(crate) fn exceeded(
+        &self,
+        status: &ExitStatus,
+        stderr_tail: &[u8],
+        cgroup: Option<&Cgroup>,
+    ) -> bool {
+        match self {
+            MemoryLimit::Cgroup => cgroup.is_some_and(Cgroup::oom_killed),
+            MemoryLimit::AddressSpace(_) => {
+                let stderr = String::from_utf8_lossy(stderr_tail).to_lowercase();
+                !status.success()
+                    && OUT_OF_MEMORY_ERRORS
+                        .iter()
+                        .any(|error| stderr.contains(error))
+            }
+        }
+    }
+}
+
+/// Sets `RLIMIT_AS` once the command is forked, so that its children inherit
+/// it.
+fn limit_address_space(command: &mut Command, bytes: u64) {
+    use async_std::os::unix::process::CommandExt;
+    use nix::libc;
+    use std::io;
+    let limit = libc::rlimit {
+        rlim_cur: bytes as libc::rlim_t,
+        rlim_max: bytes as libc::rlim_t,
+    };
+    unsafe {
+        command.pre_exec(move || {
+            if libc::setrlimit(libc::RLIMIT_AS, &limit) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
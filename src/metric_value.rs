@@ -1,9 +1,13 @@
+use anyhow::*;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 
-#[derive(Serialize, Deserialize, Clone)]
+/// A value in sirun's results, as it appears in the JSON sirun writes. Most
+/// are numbers, such as measurements, which are always floating point. Flags
+/// that are on or off are numbers too, `1` or `0`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 #[serde(untagged)]
-pub(crate) enum MetricValue {
+pub enum MetricValue {
     Str(String),
     Num(f64),
     Arr(Vec<MetricValue>),
@@ -11,38 +15,52 @@ pub(crate) enum MetricValue {
 }
 
 impl MetricValue {
-    pub(crate) fn as_f64(&self) -> f64 {
+    fn describe(&self) -> &'static str {
         match self {
-            Self::Num(x) => *x,
-            _ => panic!("not an f64"),
+            Self::Str(_) => "a string",
+            Self::Num(_) => "a number",
+            Self::Arr(_) => "an array",
+            Self::Map(_) => "an object",
         }
     }
 
-    pub(crate) fn as_map_mut(&mut self) -> &mut MetricMap {
+    /// The value as a number, or an error if it isn't one.
+    pub fn as_f64(&self) -> Result<f64> {
         match self {
-            Self::Map(x) => x,
-            _ => panic!("not a map"),
+            Self::Num(x) => Ok(*x),
+            _ => bail!("expected a number, got {}", self.describe()),
         }
     }
 
-    pub(crate) fn as_map(&self) -> &MetricMap {
+    /// The value as an object, or an error if it isn't one.
+    pub fn as_map_mut(&mut self) -> Result<&mut MetricMap> {
         match self {
-            Self::Map(x) => x,
-            _ => panic!("not a map"),
+            Self::Map(x) => Ok(x),
+            _ => bail!("expected an object, got {}", self.describe()),
         }
     }
 
-    pub(crate) fn as_string(&self) -> String {
+    /// The value as an object, or an error if it isn't one.
+    pub fn as_map(&self) -> Result<&MetricMap> {
         match self {
-            Self::Str(x) => x.clone(),
-            _ => panic!("not a string"),
+            Self::Map(x) => Ok(x),
+            _ => bail!("expected an object, got {}", self.describe()),
         }
     }
 
-    pub(crate) fn as_vec(&self) -> Vec<MetricValue> {
+    /// A copy of the value as a string, or an error if it isn't one.
+    pub fn as_string(&self) -> Result<String> {
         match self {
-            Self::Arr(x) => x.clone(),
-            _ => panic!("not a string"),
+            Self::Str(x) => Ok(x.clone()),
+            _ => bail!("expected a string, got {}", self.describe()),
+        }
+    }
+
+    /// A copy of the value as an array, or an error if it isn't one.
+    pub fn as_vec(&self) -> Result<Vec<MetricValue>> {
+        match self {
+            Self::Arr(x) => Ok(x.clone()),
+            _ => bail!("expected an array, got {}", self.describe()),
         }
     }
 }
@@ -70,6 +88,84 @@ macro_rules! num_type {
 }
 num_type!(i32);
 num_type!(i64);
+num_type!(u64);
 num_type!(f64);
 
-pub(crate) type MetricMap = HashMap<String, MetricValue>;
+/// Adds `value` under a dotted `name` as a leaf of nested maps, e.g.
+/// `http.p50` as `p50` in the `http` map. Fails rather than lose either if a
+/// name is both a metric and a prefix of others, like `http` and `http.p50`.
+pub(crate) fn insert_nested(metrics: &mut MetricMap, name: &str, value: MetricValue) -> Result<()> {
+    let mut group = metrics;
+    let mut parts = name.split('.').peekable();
+    let mut prefix = 0;
+    while let Some(part) = parts.next() {
+        prefix += part.len();
+        if parts.peek().is_none() {
+            ensure!(
+                !matches!(group.get(part), Some(MetricValue::Map(_))),
+                "'{}' is both a metric and a group of metrics",
+                name
+            );
+            group.insert(part.into(), value);
+            return Ok(());
+        }
+        group = match group
+            .entry(part.into())
+            .or_insert_with(|| MetricMap::new().into())
+        {
+            MetricValue::Map(inner) => inner,
+            _ => bail!(
+                "'{}' is both a metric and a group of metrics, such as '{}'",
+                &name[..prefix],
+                name
+            ),
+        };
+        prefix += 1;
+    }
+    Ok(())
+}
+
+/// The metric with a dotted `name`, whether it's stored under that name or
+/// nested as with [`insert_nested`].
+pub(crate) fn get_nested<'a>(metrics: &'a MetricMap, name: &str) -> Option<&'a MetricValue> {
+    if let Some(value) = metrics.get(name) {
+        return Some(value);
+    }
+    name.match_indices('.').find_map(|(i, _)| match metrics.get(&name[..i]) {
+        Some(MetricValue::Map(group)) => get_nested(group, &name[i + 1..]),
+        _ => None,
+    })
+}
+
+/// Key under which each iteration records its own 0-based index.
+pub(crate) const ITERATION_KEY: &str = "iteration";
+
+/// Key under which each iteration records when it started, in milliseconds
+/// since the Unix epoch.
+pub(crate) const START_EPOCH_MS_KEY: &str = "start.epoch.ms";
+
+/// Key under which an iteration child reports when it started the `run`
+/// command, in microseconds since the Unix epoch, for the parent to work out
+/// `TIME_TO_FIRST_METRIC_KEY`. It isn't kept in the results.
+pub(crate) const SPAWN_EPOCH_US_KEY: &str = "run.spawn.epoch.us";
+
+/// Key under which each iteration records how long after the `run` command
+/// was started the first metric it sent arrived, in microseconds.
+pub(crate) const TIME_TO_FIRST_METRIC_KEY: &str = "time.to.first.metric";
+
+/// Key under which each iteration records how many statsd metrics arrived
+/// between the last iteration's being read and it starting, which are left
+/// out of both.
+pub(crate) const OUT_OF_WINDOW_KEY: &str = "statsd.out_of_window";
+
+/// Key under which each iteration records the spans the benchmark reported,
+/// as a map of each span's total milliseconds and count.
+pub(crate) const SPANS_KEY: &str = "spans";
+
+/// Key telling apart the kinds of lines written with `--stream`: one with
+/// `iteration` as its type for every iteration, then a `summary`.
+pub(crate) const STREAM_TYPE_KEY: &str = "type";
+
+/// Metrics or other results by name. Sorted by key, so that output is stable
+/// from run to run.
+pub type MetricMap = BTreeMap<String, MetricValue>;
@@ -0,0 +1,267 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+//! Waiting for a busy machine to quiet down before benchmarking on it, as set
+//! up by the `noise_check` config key.
+
+use anyhow::*;
+use async_std::task::sleep;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use serde_yaml::Value;
+use std::{
+    fmt, fs,
+    time::{Duration, Instant},
+};
+
+use crate::config::*;
+use crate::sysmetrics::*;
+
+const DEFAULT_SETTLE_SECS: u64 = 30;
+
+/// How often the machine is sampled while waiting for it to settle.
+const SETTLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Key marking results benchmarked on a machine that never settled.
+pub(crate) const NOISY_ENVIRONMENT_KEY: &str = "noisy_environment";
+
+/// Exit code for a `strict` noise check that failed, which, like
+/// `EX_TEMPFAIL`, means it's worth trying again later.
+pub(crate) const NOISY_EXIT_CODE: i32 = 75;
+
+pub(crate) const NOISE_CHECK_KEYS: &[&str] =
+    &["max_load1", "max_cpu_pressure", "settle_seconds", "strict"];
+
+/// How busy the machine may be for benchmarking on it, as given in the
+/// `noise_check` config key.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub(crate) struct NoiseCheck {
+    max_load1: Option<f64>,
+    max_cpu_pressure: Option<f64>,
+    settle_seconds: u64,
+    strict: bool,
+}
+
+impl NoiseCheck {
+    pub(crate) fn parse(value: &Value) -> Result<Self> {
+        let value = value
+            .as_mapping()
+            .ok_or_else(|| anyhow!("must be an object"))?;
+        let get = |key: &str| value.get(&key.into());
+        let threshold = |key: &str| -> Result<Option<f64>> {
+            get(key)
+                .map(|val| {
+                    val.as_f64()
+                        .filter(|threshold| *threshold >= 0.0)
+                        .ok_or_else(|| anyhow!("'.{}' must be a non-negative number", key))
+                })
+                .transpose()
+        };
+        for (key, _) in value.iter() {
+            let key = key.as_str().unwrap_or_default();
+            ensure!(NOISE_CHECK_KEYS.contains(&key), "has unknown key '{}'", key);
+        }
+        let check = NoiseCheck {
+            max_load1: threshold("max_load1")?,
+            max_cpu_pressure: threshold("max_cpu_pressure")?,
+            settle_seconds: match get("settle_seconds") {
+                Some(secs) => secs
+                    .as_u64()
+                    .ok_or_else(|| anyhow!("'.settle_seconds' must be a non-negative integer"))?,
+                None => DEFAULT_SETTLE_SECS,
+            },
+            strict: match get("strict") {
+                Some(strict) => strict
+                    .as_bool()
+                    .ok_or_else(|| anyhow!("'.strict' must be a boolean"))?,
+                None => false,
+            },
+        };
+        ensure!(
+            check.max_load1.is_some() || check.max_cpu_pressure.is_some(),
+            "needs a 'max_load1' or a 'max_cpu_pressure'"
+        );
+        Ok(check)
+    }
+
+    /// What makes the machine too busy, if anything. A threshold can only be
+    /// exceeded by a measurement that could be taken.
+    fn excess(&self, noise: &Noise) -> Option<String> {
+        let exceeded = |name: &str, value: Option<f64>, max: Option<f64>| match (value, max) {
+            (Some(value), Some(max)) if value > max => Some(format!(
+                "{} is {}, over 'max_{}' of {}",
+                name, value, name, max
+            )),
+            _ => None,
+        };
+        let reasons: Vec<String> = vec![
+            exceeded("load1", noise.load1, self.max_load1),
+            exceeded("cpu_pressure", noise.cpu_pressure, self.max_cpu_pressure),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        if reasons.is_empty() {
+            None
+        } else {
+            Some(reasons.join(" and "))
+        }
+    }
+}
+
+/// How busy the machine is right now.
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+struct Noise {
+    /// The load average over the last minute.
+    load1: Option<f64>,
+    /// The percentage of the last 10 seconds that some task was waiting for a
+    /// CPU, from Linux's pressure stall information.
+    cpu_pressure: Option<f64>,
+}
+
+impl Noise {
+    fn sample() -> Self {
+        let read = |path: &str| fs::read_to_string(path).ok();
+        Noise {
+            load1: read("/proc/loadavg").and_then(|loadavg| load1(&loadavg)),
+            cpu_pressure: read("/proc/pressure/cpu").and_then(|psi| some_avg10(&psi)),
+        }
+    }
+}
+
+/// The first field of `/proc/loadavg`.
+fn load1(loadavg: &str) -> Option<f64> {
+    loadavg.split_whitespace().next()?.parse().ok()
+}
+
+/// A `strict` noise check found the machine too busy for too long.
+#[derive(Debug)]
+pub(crate) struct NoisyEnvironment {
+    reason: String,
+    settle_seconds: u64,
+}
+
+impl fmt::Display for NoisyEnvironment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "the machine is too busy to benchmark on: {}, even after waiting {}s for it to settle",
+            self.reason, self.settle_seconds
+        )
+    }
+}
+
+impl std::error::Error for NoisyEnvironment {}
+
+/// Waits up to `settle_seconds` for the machine to be as quiet as the
+/// config's `noise_check` asks, if it has one. Returns whether it never got
+/// there, in which case the results are marked `noisy_environment`, unless
+/// the check is `strict`, which makes it a `NoisyEnvironment` error instead.
+pub(crate) async fn check_noise(config: &Config) -> Result<bool> {
+    let check = match &config.noise_check {
+        Some(check) => check,
+        None => return Ok(false),
+    };
+    let first = Noise::sample();
+    if check.max_load1.is_some() && first.load1.is_none() {
+        warn!("'noise_check' can't check 'max_load1', since the load average can't be read");
+    }
+    if check.max_cpu_pressure.is_some() && first.cpu_pressure.is_none() {
+        warn!(
+            "'noise_check' can't check 'max_cpu_pressure', since /proc/pressure/cpu can't be read"
+        );
+    }
+    let start = Instant::now();
+    let deadline = Duration::from_secs(check.settle_seconds);
+    let mut noise = first;
+    loop {
+        let reason = match check.excess(&noise) {
+            Some(reason) => reason,
+            None => return Ok(false),
+        };
+        if start.elapsed() >= deadline {
+            if check.strict {
+                return Err(NoisyEnvironment {
+                    reason,
+                    settle_seconds: check.settle_seconds,
+                }
+                .into());
+            }
+            warn!(
+                "benchmarking anyway, though the machine is too busy: {}, even after waiting {}s",
+                reason, check.settle_seconds
+            );
+            return Ok(true);
+        }
+        debug!("waiting for the machine to settle: {}", reason);
+        sleep(SETTLE_INTERVAL.min(deadline.saturating_sub(start.elapsed()))).await;
+        noise = Noise::sample();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn noise_check(yaml: &str) -> NoiseCheck {
+        NoiseCheck::parse(&serde_yaml::from_str(yaml).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn thresholds() {
+        let check = noise_check("{max_load1: 1.0, max_cpu_pressure: 5}");
+        let noise = |load1, cpu_pressure| Noise {
+            load1,
+            cpu_pressure,
+        };
+        assert_eq!(check.excess(&noise(Some(0.5), Some(2.0))), None);
+        assert_eq!(check.excess(&noise(Some(1.0), Some(5.0))), None);
+        assert_eq!(
+            check.excess(&noise(Some(2.5), Some(2.0))).unwrap(),
+            "load1 is 2.5, over 'max_load1' of 1"
+        );
+        assert_eq!(
+            check.excess(&noise(Some(2.5), Some(7.5))).unwrap(),
+            "load1 is 2.5, over 'max_load1' of 1 and cpu_pressure is 7.5, over 'max_cpu_pressure' of 5"
+        );
+        // What can't be measured can't be too high.
+        assert_eq!(check.excess(&noise(None, None)), None);
+        let load_only = noise_check("{max_load1: 1.0}");
+        assert_eq!(load_only.excess(&noise(Some(0.5), Some(90.0))), None);
+    }
+
+    #[test]
+    fn parsing() {
+        assert_eq!(load1("0.26 0.29 0.23 2/72 5175\n"), Some(0.26));
+        assert_eq!(
+            some_avg10(
+                "some avg10=1.23 avg60=0.50 avg300=0.10 total=123456\n\
+                 full avg10=0.00 avg60=0.00 avg300=0.00 total=0\n"
+            ),
+            Some(1.23)
+        );
+        assert_eq!(some_avg10(""), None);
+        let defaults = noise_check("{max_load1: 2}");
+        assert_eq!(defaults.settle_seconds, DEFAULT_SETTLE_SECS);
+        assert!(!defaults.strict);
+        let parse = |yaml: &str| {
+            NoiseCheck::parse(&serde_yaml::from_str(yaml).unwrap())
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            parse("{settle_seconds: 5}"),
+            "needs a 'max_load1' or a 'max_cpu_pressure'"
+        );
+        assert_eq!(
+            parse("{max_load1: -1}"),
+            "'.max_load1' must be a non-negative number"
+        );
+        assert_eq!(
+            parse("{max_load1: 1, quiet: true}"),
+            "has unknown key 'quiet'"
+        );
+    }
+}
@@ -0,0 +1,101 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+//! Counting hardware events by running the `run` command under `perf stat`,
+//! which counts its child processes too and doesn't need the capabilities
+//! that attaching counters to another process does.
+
+use anyhow::*;
+use std::{fs, path::Path, process::Command};
+use which::which;
+
+use crate::metric_value::*;
+use crate::profilers::*;
+
+/// Names of the events to count.
+pub(crate) struct PerfStat(pub(crate) Vec<String>);
+
+/// File in the output directory that `perf stat` writes its counts to, as CSV.
+const PERF_STAT_FILE: &str = "perf-stat.csv";
+
+impl Profiler for PerfStat {
+    /// Also counts the events for `true`, to find out whether they can be.
+    fn check(&self) -> Result<()> {
+        which("perf").map_err(|_| {
+            anyhow!("'perf_stat' needs the 'perf' executable, but it isn't on the PATH")
+        })?;
+        let output = Command::new("perf")
+            .args(["stat", "-x,", "-e", &self.0.join(","), "--", "true"])
+            .output()?;
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        ensure!(
+            output.status.success(),
+            "perf can't count 'perf_stat' events {}: {}",
+            self.0.join(", "),
+            stderr.lines().next().unwrap_or_default()
+        );
+        perf_stat_counts(&self.0, &stderr)?;
+        Ok(())
+    }
+
+    fn wrap(&self, run: &[String], out_dir: &Path) -> Vec<String> {
+        let mut command = vec![
+            "perf".to_owned(),
+            "stat".to_owned(),
+            "-x,".to_owned(),
+            "-o".to_owned(),
+            out_dir.join(PERF_STAT_FILE).to_string_lossy().into_owned(),
+            "-e".to_owned(),
+            self.0.join(","),
+            "--".to_owned(),
+        ];
+        command.extend_from_slice(run);
+        command
+    }
+
+    /// Records the count of each event as a metric named after the event.
+    fn add_metrics(&self, out_dir: &Path, metrics: &mut MetricMap) -> Result<()> {
+        let output = fs::read_to_string(out_dir.join(PERF_STAT_FILE))?;
+        for (event, count) in perf_stat_counts(&self.0, &output)? {
+            metrics.insert(event, count.into());
+        }
+        Ok(())
+    }
+}
+
+/// Finds each event's count in the CSV output of `perf stat -x,`, whose lines
+/// start with the count and have the event's name in the third field. Events
+/// that can't be counted have a count like `<not supported>` instead.
+fn perf_stat_counts(events: &[String], output: &str) -> Result<Vec<(String, f64)>> {
+    events
+        .iter()
+        .map(|event| {
+            let fields = output
+                .lines()
+                .map(|line| line.split(',').collect::<Vec<_>>())
+                .find(|fields| {
+                    fields.len() > 2
+                        && (fields[2] == event || fields[2].starts_with(&format!("{}:", event)))
+                })
+                .ok_or_else(|| anyhow!("perf stat didn't report 'perf_stat' event '{}'", event))?;
+            if fields[0].starts_with('<') {
+                bail!(
+                    "'perf_stat' event '{}' can't be counted here: perf reports it as {}. \
+                     `perf list` shows the events that can be",
+                    event,
+                    fields[0]
+                );
+            }
+            let count = fields[0].parse::<f64>().map_err(|_| {
+                anyhow!(
+                    "perf stat reported '{}' for 'perf_stat' event '{}'",
+                    fields[0],
+                    event
+                )
+            })?;
+            Ok((event.clone(), count))
+        })
+        .collect()
+}
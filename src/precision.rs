@@ -0,0 +1,154 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+use anyhow::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::glob::*;
+use crate::metric_value::*;
+use crate::statistics::*;
+
+/// Significant digits used for metrics not matched by any pattern.
+const DEFAULT_DIGITS: u32 = 6;
+
+/// Beyond this many significant digits an f64 can't be told apart anyway.
+const MAX_DIGITS: u32 = 17;
+
+/// Number of significant digits to keep per metric, keyed by glob pattern.
+/// Rounding only happens when output is serialized, never before statistics
+/// are computed.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub(crate) struct Precision {
+    rules: Vec<(String, u32)>,
+}
+
+impl Precision {
+    pub(crate) fn add_rule(&mut self, pattern: &str, digits: u64) -> Result<()> {
+        validate_glob(pattern)?;
+        ensure!(
+            digits >= 1 && digits <= MAX_DIGITS as u64,
+            "precision for '{}' must be an integer from 1 to {}",
+            pattern,
+            MAX_DIGITS
+        );
+        self.rules.push((pattern.to_owned(), digits as u32));
+        Ok(())
+    }
+
+    /// Parses a `glob=digits` pair, or plain `digits` meaning all metrics.
+    pub(crate) fn add_rule_str(&mut self, spec: &str) -> Result<()> {
+        let (pattern, digits) = match spec.rfind('=') {
+            Some(i) => (&spec[..i], &spec[i + 1..]),
+            None => ("*", spec),
+        };
+        let digits = digits
+            .parse()
+            .map_err(|_| anyhow!("precision for '{}' must be an integer", pattern))?;
+        self.add_rule(pattern, digits)
+    }
+
+    /// The most specific (longest) matching pattern wins.
+    fn digits_for(&self, metric: &str) -> u32 {
+        self.rules
+            .iter()
+            .filter(|(pattern, _)| glob_match(pattern, metric))
+            .max_by_key(|(pattern, _)| pattern.len())
+            .map_or(DEFAULT_DIGITS, |(_, digits)| *digits)
+    }
+
+    /// Rounds a value, or a statistic, of the given metric.
+    pub(crate) fn round(&self, metric: &str, value: f64) -> f64 {
+        round_significant(value, self.digits_for(metric))
+    }
+
+    /// Rounds statistics once they've been computed from unrounded values,
+    /// given as `stats` of each metric by its dotted name.
+    pub(crate) fn round_statistics(
+        &self,
+        stats: &[Statistic],
+        statistics: &mut BTreeMap<String, Vec<Option<f64>>>,
+    ) {
+        for (name, values) in statistics.iter_mut() {
+            for (stat, value) in stats.iter().zip(values) {
+                // A count is exact, however precise the rest are.
+                if *stat != Statistic::Count {
+                    *value = value.map(|value| self.round(name, value));
+                }
+            }
+        }
+    }
+
+    /// Rounds every number in `metrics` according to the name it's stored
+    /// under, descending into iteration arrays and nested maps.
+    pub(crate) fn round_metrics(&self, metrics: &mut MetricMap) {
+        for (name, value) in metrics.iter_mut() {
+            // A timestamp with its digits rounded off is just wrong.
+            if name != START_EPOCH_MS_KEY {
+                self.round_value(name, value);
+            }
+        }
+    }
+
+    fn round_value(&self, name: &str, value: &mut MetricValue) {
+        match value {
+            MetricValue::Num(x) => *x = round_significant(*x, self.digits_for(name)),
+            MetricValue::Arr(items) => {
+                for item in items {
+                    match item {
+                        // Such as iterations, whose metrics go by their own names.
+                        MetricValue::Map(map) => self.round_metrics(map),
+                        _ => self.round_value(name, item),
+                    }
+                }
+            }
+            // Nested metrics go by their dotted names.
+            MetricValue::Map(map) => {
+                for (key, value) in map.iter_mut() {
+                    self.round_value(&format!("{}.{}", name, key), value);
+                }
+            }
+            MetricValue::Str(_) => {}
+        }
+    }
+
+    /// Rounds a summary, where each metric maps to a set of statistics that
+    /// should all be rounded according to the metric's name.
+    pub(crate) fn round_summary(&self, summary: &mut MetricMap) {
+        self.round_summary_group("", summary);
+    }
+
+    /// Rounds the statistics of each metric in a group of nested ones, whose
+    /// names start with the group's `prefix`.
+    fn round_summary_group(&self, prefix: &str, summary: &mut MetricMap) {
+        for (name, stats) in summary.iter_mut() {
+            let name = format!("{}{}", prefix, name);
+            if let MetricValue::Map(stats) = stats {
+                if stats.values().any(|stat| matches!(stat, MetricValue::Map(_))) {
+                    self.round_summary_group(&format!("{}.", name), stats);
+                    continue;
+                }
+                // A count is exact, however precise the rest are.
+                for (_, stat) in stats.iter_mut().filter(|(stat, _)| *stat != "count") {
+                    if let MetricValue::Num(x) = stat {
+                        *x = round_significant(*x, self.digits_for(&name));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Rounds to the given number of significant digits, with ties going to the
+/// even digit.
+fn round_significant(value: f64, digits: u32) -> f64 {
+    if value == 0.0 || !value.is_finite() {
+        return value;
+    }
+    // Float formatting rounds the exact binary value half-to-even.
+    format!("{:.*e}", digits as usize - 1, value)
+        .parse()
+        .unwrap_or(value)
+}
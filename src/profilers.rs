@@ -0,0 +1,236 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+//! Tools that the `run` command can be run under to measure it, such as
+//! valgrind or `perf stat`. Each one wraps the command, has it write its
+//! output into a directory, and reads metrics back out of that directory once
+//! the command is done.
+
+use anyhow::*;
+use serde::{Deserialize, Serialize};
+use std::{fs, path::Path, process::Command};
+use which::which;
+
+use crate::config::*;
+use crate::metric_value::*;
+use crate::perf_stat::*;
+use crate::valgrind::*;
+
+/// Key under which an iteration records where the raw output of its profiler
+/// was kept, with `keep_profiles`.
+pub(crate) const PROFILE_DIR_KEY: &str = "profile.dir";
+
+pub(crate) trait Profiler {
+    /// Fails unless the tool is installed and can measure what it's asked to,
+    /// so that a run doesn't get as far as its first iteration without it.
+    fn check(&self) -> Result<()>;
+
+    /// The `run` command wrapped in the tool, which writes its output into
+    /// `out_dir`.
+    fn wrap(&self, run: &[String], out_dir: &Path) -> Vec<String>;
+
+    /// Reads the tool's output in `out_dir` into metrics, once the `run`
+    /// command has succeeded.
+    fn add_metrics(&self, out_dir: &Path, metrics: &mut MetricMap) -> Result<()>;
+
+    /// What's appended to the names of the `TIMING_KEYS` when the tool slows
+    /// the command down too much for them to be compared with those of a
+    /// normal run.
+    fn timing_suffix(&self) -> Option<&'static str> {
+        None
+    }
+}
+
+/// Metrics of how long the `run` command took.
+pub(crate) const TIMING_KEYS: &[&str] =
+    &["wall.time", "user.time", "system.time", "cpu.pct.wall.time"];
+
+/// The name the metric `name` is recorded under, which for one of the
+/// `TIMING_KEYS` depends on the profiler, so that timings distorted by one
+/// are never summarized along with normal ones.
+pub(crate) fn timing_key(config: &Config, name: &str) -> String {
+    match profiler(config).and_then(|profiler| profiler.timing_suffix()) {
+        Some(suffix) if TIMING_KEYS.contains(&name) => format!("{}{}", name, suffix),
+        _ => name.to_owned(),
+    }
+}
+
+/// The profiler the config's `run` command is run under, if any. There can
+/// only be one, which `get_config` makes sure of.
+pub(crate) fn profiler(config: &Config) -> Option<Box<dyn Profiler>> {
+    if let Some(tool) = config.valgrind {
+        return Some(Box::new(tool));
+    }
+    if !config.perf_stat.is_empty() {
+        return Some(Box::new(PerfStat(config.perf_stat.clone())));
+    }
+    if config.syscalls {
+        return Some(Box::new(Strace));
+    }
+    config
+        .heap_profile
+        .map(|profiler| Box::new(profiler) as Box<dyn Profiler>)
+}
+
+/// The tool that the `heap_profile` setting measures peak heap usage with.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum HeapProfiler {
+    Massif,
+}
+
+impl HeapProfiler {
+    pub(crate) fn parse(name: &str) -> Option<Self> {
+        match name {
+            "massif" => Some(HeapProfiler::Massif),
+            _ => None,
+        }
+    }
+}
+
+impl Profiler for HeapProfiler {
+    fn check(&self) -> Result<()> {
+        which("valgrind").map_err(|_| {
+            anyhow!("'heap_profile' needs the 'valgrind' executable to run massif, but it isn't on the PATH")
+        })?;
+        Ok(())
+    }
+
+    fn wrap(&self, run: &[String], out_dir: &Path) -> Vec<String> {
+        let mut command = vec![
+            "valgrind".to_owned(),
+            "--tool=massif".to_owned(),
+            format!(
+                "--massif-out-file={}",
+                out_dir.join("massif.out.%p").display()
+            ),
+            "--trace-children=yes".to_owned(),
+        ];
+        command.extend_from_slice(run);
+        command
+    }
+
+    /// Records the highest peak of any of the command's processes, since a
+    /// command is often a shell or a launcher in front of the process that
+    /// does the work.
+    fn add_metrics(&self, out_dir: &Path, metrics: &mut MetricMap) -> Result<()> {
+        let mut peak = None;
+        for entry in fs::read_dir(out_dir)? {
+            let path = entry?.path();
+            let contents = fs::read_to_string(&path)?;
+            let process_peak = massif_peak(&contents)
+                .with_context(|| format!("bad massif output in {}", path.display()))?;
+            peak = Some(peak.map_or(process_peak, |peak: u64| peak.max(process_peak)));
+        }
+        let peak = peak.ok_or_else(|| anyhow!("massif didn't write any output"))?;
+        metrics.insert("heap.peak.bytes".into(), (peak as f64).into());
+        Ok(())
+    }
+
+    fn timing_suffix(&self) -> Option<&'static str> {
+        Some(UNDER_VALGRIND)
+    }
+}
+
+/// The heap size in the peak snapshot of a massif output file. Each snapshot
+/// lists its `mem_heap_B` before its `heap_tree`, which is `peak` for the
+/// peak one. A process that never allocated may not have one, in which case
+/// the largest snapshot is used.
+fn massif_peak(contents: &str) -> Result<u64> {
+    let mut heap = None;
+    let mut largest = None;
+    for line in contents.lines() {
+        if let Some(bytes) = line.strip_prefix("mem_heap_B=") {
+            let bytes = bytes
+                .trim()
+                .parse::<u64>()
+                .map_err(|_| anyhow!("invalid heap size '{}'", bytes))?;
+            heap = Some(bytes);
+            largest = largest.max(Some(bytes));
+        } else if line.trim() == "heap_tree=peak" {
+            return heap.ok_or_else(|| anyhow!("peak snapshot has no heap size"));
+        }
+    }
+    largest.ok_or_else(|| anyhow!("no snapshots"))
+}
+
+/// Counts the system calls of the command and its child processes with
+/// `strace -c`, as `syscalls.NAME` and `syscalls.total`.
+pub(crate) struct Strace;
+
+/// File in the output directory that `strace -c` writes its table to.
+const STRACE_FILE: &str = "strace.txt";
+
+impl Profiler for Strace {
+    /// Also traces `true`, since containers often don't allow ptrace.
+    fn check(&self) -> Result<()> {
+        which("strace").map_err(|_| {
+            anyhow!("'syscalls' needs the 'strace' executable, but it isn't on the PATH")
+        })?;
+        let output = Command::new("strace")
+            .args(["-c", "-o", "/dev/null", "--", "true"])
+            .output()?;
+        ensure!(
+            output.status.success(),
+            "strace can't trace commands here, which 'syscalls' needs: {}",
+            String::from_utf8_lossy(&output.stderr)
+                .lines()
+                .next()
+                .unwrap_or_default()
+        );
+        Ok(())
+    }
+
+    fn wrap(&self, run: &[String], out_dir: &Path) -> Vec<String> {
+        let mut command = vec![
+            "strace".to_owned(),
+            "-f".to_owned(),
+            "-c".to_owned(),
+            "-o".to_owned(),
+            out_dir.join(STRACE_FILE).to_string_lossy().into_owned(),
+            "--".to_owned(),
+        ];
+        command.extend_from_slice(run);
+        command
+    }
+
+    fn add_metrics(&self, out_dir: &Path, metrics: &mut MetricMap) -> Result<()> {
+        let table = fs::read_to_string(out_dir.join(STRACE_FILE))?;
+        let counts = syscall_counts(&table).context("bad strace output")?;
+        let total: f64 = counts.iter().map(|(_, calls)| calls).sum();
+        for (syscall, calls) in counts {
+            metrics.insert(format!("syscalls.{}", syscall), calls.into());
+        }
+        metrics.insert("syscalls.total".into(), total.into());
+        Ok(())
+    }
+
+    fn timing_suffix(&self) -> Option<&'static str> {
+        Some(".under.strace")
+    }
+}
+
+/// The number of calls of each system call in the table that `strace -c`
+/// writes. Its rows start with the time spent, in percent, seconds and
+/// microseconds per call, followed by the number of calls, the number of
+/// errors if there were any, and the name of the system call. The `total`
+/// row is left out, since its columns differ between versions of strace.
+fn syscall_counts(table: &str) -> Result<Vec<(String, f64)>> {
+    table
+        .lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .filter(|columns| {
+            columns.len() >= 5
+                && columns[0].parse::<f64>().is_ok()
+                && columns.last() != Some(&"total")
+        })
+        .map(|columns| {
+            let calls = columns[3]
+                .parse::<f64>()
+                .map_err(|_| anyhow!("invalid number of calls '{}'", columns[3]))?;
+            Ok((columns[columns.len() - 1].to_owned(), calls))
+        })
+        .collect()
+}
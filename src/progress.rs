@@ -0,0 +1,305 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+use nix::unistd::isatty;
+use std::{collections::BTreeMap, env, time::Duration};
+
+use crate::config::*;
+use crate::flags::*;
+use crate::history::*;
+use crate::metric_value::*;
+use crate::statistics::*;
+use crate::summarize::*;
+use crate::units::*;
+
+/// Reports on stderr how far along a run is after each iteration. On a
+/// terminal each report replaces the last; otherwise each is its own line, as
+/// suits CI logs.
+pub(crate) struct Progress {
+    tty: bool,
+    /// Whether a report is left on the current terminal line.
+    pending: bool,
+}
+
+impl Progress {
+    /// Returns `None` when the config has `quiet` set, or `SIRUN_NO_STDIO`
+    /// is set.
+    pub(crate) fn new(config: &Config) -> Option<Self> {
+        if config.quiet || env::var("SIRUN_NO_STDIO").is_ok() {
+            return None;
+        }
+        Some(Progress {
+            tty: isatty(2).unwrap_or(false),
+            pending: false,
+        })
+    }
+
+    pub(crate) fn report(
+        &mut self,
+        config: &Config,
+        iterations: &[MetricValue],
+        elapsed: Duration,
+    ) {
+        let count = iterations.len();
+        let mut line = String::new();
+        if let Some(variant) = &config.variant {
+            line.push_str(&format!("[{}] ", variant));
+        }
+        match config.duration {
+            Some(duration) => line.push_str(&format!(
+                "iteration {} ({}s of {}s)",
+                count,
+                elapsed.as_secs(),
+                duration
+            )),
+            None => line.push_str(&format!("iteration {}/{}", count, config.iterations)),
+        }
+        let last = iterations
+            .last()
+            .and_then(|iteration| iteration.as_map().ok());
+        match last {
+            Some(last) if is_failed(last) => line.push_str(": failed"),
+            Some(last) => {
+                if let Some(MetricValue::Num(wall)) = last.get("wall.time") {
+                    line.push_str(&format!(": wall.time {}", format_micros(*wall)));
+                }
+            }
+            None => {}
+        }
+        let walls: Vec<f64> = iterations
+            .iter()
+            .filter_map(|iteration| iteration.as_map().ok())
+            .filter(|iteration| !is_failed(iteration))
+            .filter_map(|iteration| match iteration.get("wall.time") {
+                Some(MetricValue::Num(wall)) => Some(*wall),
+                _ => None,
+            })
+            .collect();
+        if !walls.is_empty() {
+            let mean = walls.iter().sum::<f64>() / walls.len() as f64;
+            line.push_str(&format!(", mean {}", format_micros(mean)));
+        }
+        if self.tty {
+            // Back to the start of the line, and clear it.
+            eprint!("\r\x1b[K{}", line);
+            self.pending = true;
+        } else {
+            eprintln!("{}", line);
+        }
+    }
+
+    /// Ends the last report's line, so that whatever's printed next starts on
+    /// a line of its own.
+    pub(crate) fn finish(&mut self) {
+        if self.pending {
+            eprintln!();
+            self.pending = false;
+        }
+    }
+}
+
+/// Formats a `wall.time`, which is in microseconds, in a readable unit.
+fn format_micros(micros: f64) -> String {
+    if micros >= 1_000_000.0 {
+        format!("{:.2}s", micros / 1_000_000.0)
+    } else if micros >= 1_000.0 {
+        format!("{:.2}ms", micros / 1_000.0)
+    } else {
+        format!("{:.0}µs", micros)
+    }
+}
+
+/// Metric names longer than this are shortened in the summary table.
+const MAX_NAME_WIDTH: usize = 40;
+
+/// Numbers longer than this are shown in scientific notation instead.
+const MAX_NUMBER_WIDTH: usize = 12;
+
+/// Shortens a name by cutting out its middle, where the parts of dotted
+/// names that tell them apart are least likely to be.
+fn shorten(name: &str) -> String {
+    let chars: Vec<char> = name.chars().collect();
+    if chars.len() <= MAX_NAME_WIDTH {
+        return name.to_owned();
+    }
+    let head = (MAX_NAME_WIDTH - 1) / 2;
+    let tail = MAX_NAME_WIDTH - 1 - head;
+    let mut short: String = chars[..head].iter().collect();
+    short.push('…');
+    short.extend(&chars[chars.len() - tail..]);
+    short
+}
+
+/// A value in the unit that suits the metric's mean.
+fn table_cell(readable: &Readable, value: Option<f64>) -> String {
+    let value = match value {
+        Some(value) => value,
+        None => return String::new(),
+    };
+    let number = readable.number(value);
+    if number.chars().count() <= MAX_NUMBER_WIDTH {
+        format!("{}{}", number, readable.suffix)
+    } else {
+        format!("{:.2e}", value)
+    }
+}
+
+/// A table of the mean, stddev, min and max of each metric over the
+/// iterations that didn't fail, with the names left-aligned and the numbers
+/// right-aligned, rounded to `metric_precision`. Given the last run from
+/// `--history`, it also shows by how much each mean changed since.
+pub(crate) fn summary_table(
+    config: &Config,
+    iterations: &[MetricValue],
+    last_run: Option<&LastRun>,
+) -> Option<String> {
+    const STATS: [Statistic; 4] = [
+        Statistic::Mean,
+        Statistic::Stddev,
+        Statistic::Min,
+        Statistic::Max,
+    ];
+    let mut statistics = statistics(iterations, &STATS).ok()?;
+    if let Some(precision) = &config.metric_precision {
+        precision.round_statistics(&STATS, &mut statistics);
+    }
+    if statistics.is_empty() {
+        return None;
+    }
+    let mut rows = vec![vec![
+        "metric".to_owned(),
+        "mean".to_owned(),
+        "stddev".to_owned(),
+        "min".to_owned(),
+        "max".to_owned(),
+    ]];
+    let changes = last_run.map(|last_run| last_run.changes(iterations));
+    if changes.is_some() {
+        rows[0].push("vs last".to_owned());
+    }
+    for (name, values) in &statistics {
+        let readable = Readable::new(name, values[0].unwrap_or_default());
+        let mut row = vec![shorten(name)];
+        row.extend(values.iter().map(|value| table_cell(&readable, *value)));
+        if let Some(changes) = &changes {
+            row.push(match changes.get(name) {
+                Some(change) => format!("{:+.1}%", change),
+                None => String::new(),
+            });
+        }
+        rows.push(row);
+    }
+    let failed = iterations
+        .iter()
+        .filter(|iteration| iteration.as_map().is_ok_and(is_failed))
+        .count();
+    let mut table = String::new();
+    if let Some(variant) = &config.variant {
+        table.push_str(&format!("[{}] ", variant));
+    }
+    table.push_str(&format!(
+        "Statistics over {} iterations",
+        iterations.len() - failed
+    ));
+    if failed > 0 {
+        table.push_str(&format!(", leaving out {} that failed", failed));
+    }
+    table.push_str(":\n");
+    table.push_str(&align(&rows));
+    Some(table)
+}
+
+/// Lays out rows of cells as lines, with the first column left-aligned and
+/// the rest right-aligned.
+pub(crate) fn align(rows: &[Vec<String>]) -> String {
+    let widths: Vec<usize> = (0..rows[0].len())
+        .map(|column| {
+            rows.iter()
+                .map(|row| row[column].chars().count())
+                .max()
+                .unwrap_or_default()
+        })
+        .collect();
+    let mut lines = String::new();
+    for row in rows {
+        let mut line = format!("{:<width$}", row[0], width = widths[0]);
+        for (cell, width) in row.iter().zip(&widths).skip(1) {
+            line.push_str(&format!("  {:>width$}", cell, width = width));
+        }
+        lines.push_str(line.trim_end());
+        lines.push('\n');
+    }
+    lines
+}
+
+/// Prints the summary table on stderr, unless the config has `quiet` set,
+/// `SIRUN_NO_STDIO` is set, or stderr isn't a terminal and the table wasn't
+/// asked for with `--summary-table`.
+pub(crate) fn print_summary_table(
+    config: &Config,
+    iterations: &[MetricValue],
+    last_run: Option<&LastRun>,
+) {
+    if config.quiet || env::var("SIRUN_NO_STDIO").is_ok() {
+        return;
+    }
+    if !config.summary_table && !isatty(2).unwrap_or(false) {
+        return;
+    }
+    if let Some(table) = summary_table(config, iterations, last_run) {
+        eprint!("{}", table);
+    }
+}
+
+/// A table of the mean of each metric for the first variant, and by how much
+/// each other variant's mean differs from it. Only metrics that the first
+/// variant shares with another are shown.
+pub(crate) fn variant_comparison(results: &[(String, Vec<MetricValue>)]) -> Option<String> {
+    let ((baseline, baseline_iterations), others) = results.split_first()?;
+    if others.is_empty() {
+        return None;
+    }
+    let baseline_means = means(baseline_iterations).ok()?;
+    let others: Vec<(&String, BTreeMap<String, f64>)> = others
+        .iter()
+        .filter_map(|(variant, iterations)| Some((variant, means(iterations).ok()?)))
+        .collect();
+
+    let mut rows = vec![vec!["metric".to_owned(), baseline.clone()]];
+    rows[0].extend(others.iter().map(|(variant, _)| variant.to_string()));
+    for (name, mean) in &baseline_means {
+        if !others.iter().any(|(_, means)| means.contains_key(name)) {
+            continue;
+        }
+        let readable = Readable::new(name, *mean);
+        let mut row = vec![shorten(name), table_cell(&readable, Some(*mean))];
+        row.extend(others.iter().map(|(_, means)| match means.get(name) {
+            None => String::new(),
+            Some(other) if *mean == 0.0 && *other == 0.0 => "+0.0%".to_owned(),
+            Some(_) if *mean == 0.0 => "n/a".to_owned(),
+            Some(other) => format!("{:+.1}%", (other - mean) / mean.abs() * 100.0),
+        }));
+        rows.push(row);
+    }
+    if rows.len() == 1 {
+        return None;
+    }
+    Some(format!(
+        "Means compared with variant {:?}:\n{}",
+        baseline,
+        align(&rows)
+    ))
+}
+
+/// Prints the comparison of the variants on stderr, unless the config has
+/// `quiet` set or `SIRUN_NO_STDIO` is set.
+pub(crate) fn print_variant_comparison(config: &Config, results: &[(String, Vec<MetricValue>)]) {
+    if config.quiet || env::var("SIRUN_NO_STDIO").is_ok() {
+        return;
+    }
+    if let Some(table) = variant_comparison(results) {
+        eprint!("{}", table);
+    }
+}
@@ -0,0 +1,369 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+//! Summaries in the Prometheus exposition format, for node_exporter's
+//! textfile collector and the Pushgateway.
+
+use anyhow::*;
+use nix::fcntl::{flock, FlockArg};
+use std::{
+    collections::BTreeMap,
+    fs::{self, OpenOptions},
+    io::Write,
+    os::unix::io::AsRawFd,
+    path::Path,
+};
+use tempfile::NamedTempFile;
+
+use crate::metric_value::*;
+use crate::precision::*;
+use crate::statistics::*;
+use crate::summarize::*;
+
+const PREFIX: &str = "sirun_";
+
+/// The statistics given for each metric.
+const STATS: [Statistic; 4] = [
+    Statistic::Mean,
+    Statistic::Stddev,
+    Statistic::Min,
+    Statistic::Max,
+];
+
+/// The labels that tell one run's samples apart from another's, which are
+/// those a run replaces when it writes a textfile.
+const RUN_LABELS: [&str; 2] = ["name", "variant"];
+
+/// One line of a metric family, with its labels as written.
+#[derive(Debug, Clone, PartialEq)]
+struct Sample {
+    family: String,
+    labels: String,
+    value: String,
+}
+
+/// A metric name, with anything Prometheus doesn't allow in one, like the
+/// dots of dotted names, replaced by underscores.
+fn family_name(metric: &str, stat: &Statistic) -> String {
+    format!("{}{}_{}", PREFIX, metric, stat.name())
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == ':' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Labels for the results' name, variant and version, as written between
+/// braces.
+fn run_labels(results: &MetricMap) -> String {
+    ["name", "variant", "version"]
+        .iter()
+        .filter_map(|key| match results.get(*key) {
+            Some(MetricValue::Str(value)) => Some(format!("{}=\"{}\"", key, escape_label(value))),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// The values of labels as written between braces, unescaped.
+fn parse_labels(labels: &str) -> BTreeMap<String, String> {
+    let mut parsed = BTreeMap::new();
+    let mut chars = labels.chars();
+    loop {
+        let name: String = chars
+            .by_ref()
+            .take_while(|c| *c != '=')
+            .collect::<String>()
+            .trim_start_matches(',')
+            .trim()
+            .to_owned();
+        if name.is_empty() || chars.next() != Some('"') {
+            return parsed;
+        }
+        let mut value = String::new();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => break,
+                '\\' => match chars.next() {
+                    Some('n') => value.push('\n'),
+                    Some(c) => value.push(c),
+                    None => {}
+                },
+                c => value.push(c),
+            }
+        }
+        parsed.insert(name, value);
+    }
+}
+
+/// The samples of a textfile, leaving out comments.
+fn parse_samples(text: &str) -> Vec<Sample> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let (series, value) = line.rsplit_once(' ')?;
+            let (family, labels) = match series.split_once('{') {
+                Some((family, labels)) => (family, labels.strip_suffix('}')?),
+                None => (series, ""),
+            };
+            Some(Sample {
+                family: family.to_owned(),
+                labels: labels.to_owned(),
+                value: value.to_owned(),
+            })
+        })
+        .collect()
+}
+
+/// A gauge for each statistic of each metric over the iterations that
+/// didn't fail, rounded to `precision` once computed.
+fn samples(results: &MetricMap, precision: Option<&Precision>) -> Result<Vec<Sample>> {
+    let iterations = match results.get("iterations") {
+        Some(iterations) => iterations.as_vec()?,
+        None => Vec::new(),
+    };
+    let labels = run_labels(results);
+    let mut samples = Vec::new();
+    let mut statistics = statistics(&iterations, &STATS)?;
+    if let Some(precision) = precision {
+        precision.round_statistics(&STATS, &mut statistics);
+    }
+    for (metric, values) in statistics {
+        for (stat, value) in STATS.iter().zip(values) {
+            if let Some(value) = value.filter(|value| value.is_finite()) {
+                samples.push(Sample {
+                    family: family_name(&metric, stat),
+                    labels: labels.clone(),
+                    value: value.to_string(),
+                });
+            }
+        }
+    }
+    Ok(samples)
+}
+
+/// Writes samples as families of gauges, sorted by name and then labels.
+fn render(samples: &[Sample]) -> String {
+    let mut families: BTreeMap<&str, Vec<&Sample>> = BTreeMap::new();
+    for sample in samples {
+        families.entry(&sample.family).or_default().push(sample);
+    }
+    let mut text = String::new();
+    for (family, mut samples) in families {
+        samples.sort_by(|a, b| a.labels.cmp(&b.labels));
+        text.push_str(&format!("# TYPE {} gauge\n", family));
+        for sample in samples {
+            if sample.labels.is_empty() {
+                text.push_str(&format!("{} {}\n", family, sample.value));
+            } else {
+                text.push_str(&format!(
+                    "{}{{{}}} {}\n",
+                    family, sample.labels, sample.value
+                ));
+            }
+        }
+    }
+    text
+}
+
+/// The summary of a run in the exposition format.
+pub(crate) fn exposition(results: &MetricMap, precision: Option<&Precision>) -> Result<String> {
+    Ok(render(&samples(results, precision)?))
+}
+
+/// Replaces a run's samples in a textfile with its new ones, keeping those
+/// of other runs, such as other variants, which write to the same file.
+fn merge(existing: &str, results: &MetricMap, precision: Option<&Precision>) -> Result<String> {
+    let run: Vec<Option<String>> = RUN_LABELS
+        .iter()
+        .map(|key| match results.get(*key) {
+            Some(MetricValue::Str(value)) => Some(value.clone()),
+            _ => None,
+        })
+        .collect();
+    let mut kept: Vec<Sample> = parse_samples(existing)
+        .into_iter()
+        .filter(|sample| {
+            let mut labels = parse_labels(&sample.labels);
+            let sample_run: Vec<Option<String>> =
+                RUN_LABELS.iter().map(|key| labels.remove(*key)).collect();
+            sample_run != run
+        })
+        .collect();
+    kept.extend(samples(results, precision)?);
+    Ok(render(&kept))
+}
+
+/// Writes a run's summary to a textfile for node_exporter, which is replaced
+/// in one go, so that it's never read half written. Runs of variants at the
+/// same time take turns, with a lock file next to it.
+pub(crate) fn write_textfile(
+    path: &str,
+    results: &MetricMap,
+    precision: Option<&Precision>,
+) -> Result<()> {
+    let path = Path::new(path);
+    let lock_path = format!("{}.lock", path.display());
+    let lock = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&lock_path)
+        .with_context(|| format!("cannot open {}", lock_path))?;
+    flock(lock.as_raw_fd(), FlockArg::LockExclusive)
+        .with_context(|| format!("cannot lock {}", lock_path))?;
+    let existing = match fs::read_to_string(path) {
+        Ok(existing) => existing,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).with_context(|| format!("cannot read {}", path.display())),
+    };
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut file =
+        NamedTempFile::new_in(dir).with_context(|| format!("cannot write to {}", dir.display()))?;
+    file.write_all(merge(&existing, results, precision)?.as_bytes())?;
+    file.persist(path)
+        .with_context(|| format!("cannot replace {}", path.display()))?;
+    Ok(())
+}
+
+/// URL-safe base64 without padding, which the Pushgateway takes label
+/// values in when they're marked with `@base64`.
+fn base64_url(value: &str) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+    let mut encoded = String::new();
+    for chunk in value.as_bytes().chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, byte)| {
+            bits | (*byte as u32) << (16 - 8 * i)
+        });
+        for i in 0..=chunk.len() {
+            encoded.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+        }
+    }
+    encoded
+}
+
+/// Where to put a run's summary on the Pushgateway at `base`: the group of
+/// the `sirun` job for its name and variant, so that variants don't replace
+/// each other's.
+pub(crate) fn pushgateway_url(base: &str, results: &MetricMap) -> String {
+    let mut url = format!("{}/metrics/job/sirun", base.trim_end_matches('/'));
+    for key in RUN_LABELS.iter() {
+        if let Some(MetricValue::Str(value)) = results.get(*key) {
+            // An empty value can only be given in base64, as `=`.
+            let encoded = if value.is_empty() {
+                "=".to_owned()
+            } else {
+                base64_url(value)
+            };
+            url.push_str(&format!("/{}@base64/{}", key, encoded));
+        }
+    }
+    url
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn results(variant: &str, walls: &[f64]) -> MetricMap {
+        let mut results = MetricMap::new();
+        results.insert("name".to_owned(), "startup".to_owned().into());
+        results.insert("variant".to_owned(), variant.to_owned().into());
+        results.insert("version".to_owned(), "abc123".to_owned().into());
+        let iterations = walls
+            .iter()
+            .map(|wall| {
+                let mut iteration = MetricMap::new();
+                iteration.insert("wall.time".to_owned(), (*wall).into());
+                iteration.insert("http-requests".to_owned(), 3.into());
+                MetricValue::Map(iteration)
+            })
+            .collect();
+        results.insert("iterations".to_owned(), MetricValue::Arr(iterations));
+        results
+    }
+
+    #[test]
+    fn golden() {
+        assert_eq!(
+            exposition(&results("fast", &[10.0, 20.0]), None).unwrap(),
+            "\
+# TYPE sirun_http_requests_max gauge
+sirun_http_requests_max{name=\"startup\",variant=\"fast\",version=\"abc123\"} 3
+# TYPE sirun_http_requests_mean gauge
+sirun_http_requests_mean{name=\"startup\",variant=\"fast\",version=\"abc123\"} 3
+# TYPE sirun_http_requests_min gauge
+sirun_http_requests_min{name=\"startup\",variant=\"fast\",version=\"abc123\"} 3
+# TYPE sirun_http_requests_stddev gauge
+sirun_http_requests_stddev{name=\"startup\",variant=\"fast\",version=\"abc123\"} 0
+# TYPE sirun_wall_time_max gauge
+sirun_wall_time_max{name=\"startup\",variant=\"fast\",version=\"abc123\"} 20
+# TYPE sirun_wall_time_mean gauge
+sirun_wall_time_mean{name=\"startup\",variant=\"fast\",version=\"abc123\"} 15
+# TYPE sirun_wall_time_min gauge
+sirun_wall_time_min{name=\"startup\",variant=\"fast\",version=\"abc123\"} 10
+# TYPE sirun_wall_time_stddev gauge
+sirun_wall_time_stddev{name=\"startup\",variant=\"fast\",version=\"abc123\"} 5
+"
+        );
+    }
+
+    #[test]
+    fn labels() {
+        let mut results = MetricMap::new();
+        results.insert("name".to_owned(), "say \"hi\"\\\n".to_owned().into());
+        let labels = run_labels(&results);
+        assert_eq!(labels, "name=\"say \\\"hi\\\"\\\\\\n\"");
+        assert_eq!(parse_labels(&labels)["name"], "say \"hi\"\\\n");
+        assert_eq!(
+            family_name("gc.pause-time", &Statistic::Mean),
+            "sirun_gc_pause_time_mean"
+        );
+    }
+
+    #[test]
+    fn merged() {
+        let fast = exposition(&results("fast", &[10.0]), None).unwrap();
+        let both = merge(&fast, &results("slow", &[30.0]), None).unwrap();
+        assert!(both.contains(
+            "sirun_wall_time_mean{name=\"startup\",variant=\"fast\",version=\"abc123\"} 10\n"
+        ));
+        assert!(both.contains(
+            "sirun_wall_time_mean{name=\"startup\",variant=\"slow\",version=\"abc123\"} 30\n"
+        ));
+        assert_eq!(both.matches("# TYPE sirun_wall_time_mean gauge").count(), 1);
+        // A run replaces its own samples.
+        let replaced = merge(&both, &results("fast", &[40.0]), None).unwrap();
+        assert!(replaced.contains("variant=\"fast\",version=\"abc123\"} 40\n"));
+        assert!(!replaced.contains("variant=\"fast\",version=\"abc123\"} 10\n"));
+        assert!(replaced.contains("variant=\"slow\",version=\"abc123\"} 30\n"));
+    }
+
+    #[test]
+    fn pushgateway() {
+        assert_eq!(base64_url("startup"), "c3RhcnR1cA");
+        assert_eq!(base64_url("a/b"), "YS9i");
+        assert_eq!(
+            pushgateway_url("http://localhost:9091/", &results("fast", &[])),
+            "http://localhost:9091/metrics/job/sirun/name@base64/c3RhcnR1cA/variant@base64/ZmFzdA"
+        );
+    }
+}
@@ -0,0 +1,1275 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+//! Parsing a layer of config (a config file, a variant, a benchmark or the
+//! `--set` overrides) into a `RawConfig`, and merging layers into a `Config`.
+
+use anyhow::*;
+use serde::{
+    de::{self, value::StrDeserializer, DeserializeSeed, IntoDeserializer, MapAccess, Visitor},
+    forward_to_deserialize_any, Deserialize, Deserializer,
+};
+use serde_json::json;
+use serde_yaml::{mapping, Mapping, Value};
+use std::{collections::BTreeMap, convert::TryFrom, env, fmt};
+
+use crate::assertions::*;
+use crate::caches::*;
+use crate::config::*;
+use crate::export::*;
+use crate::glob::*;
+use crate::memory_limit::*;
+use crate::noise::*;
+use crate::precision::*;
+use crate::profilers::*;
+use crate::results_file::*;
+use crate::schema::*;
+use crate::statsd::*;
+use crate::stdout_metrics::*;
+use crate::subproc::*;
+use crate::valgrind::*;
+use crate::wait::*;
+
+/// Declares `RawConfig`, along with the JSON Schema of each of its keys: the
+/// schema of the type the key's value is parsed into, described by the
+/// field's doc comment.
+macro_rules! raw_config {
+    (
+        $(#[$attr:meta])*
+        $vis:vis struct $name:ident {
+            $($(#[doc = $doc:literal])+ $key:ident: Option<$value:ty>,)*
+        }
+    ) => {
+        $(#[$attr])*
+        $vis struct $name {
+            $($(#[doc = $doc])+ $key: Option<$value>,)*
+        }
+
+        impl $name {
+            /// Each key with its schema, for `sirun --schema`.
+            pub(crate) fn key_schemas() -> Vec<(&'static str, serde_json::Value)> {
+                vec![$((
+                    stringify!($key),
+                    described(
+                        &[$($doc),+].iter().map(|line| line.trim()).collect::<Vec<_>>().join(" "),
+                        <$value as ValueSchema>::schema(),
+                    ),
+                )),*]
+            }
+        }
+    };
+}
+
+raw_config! {
+    /// One layer of config, with only what it sets. Every key in `KNOWN_KEYS`
+    /// other than those `get_config` handles itself has a field here.
+    #[derive(Deserialize, Default, Clone)]
+    #[serde(deny_unknown_fields, default)]
+    pub(crate) struct RawConfig {
+        /// The name given to the results.
+        name: Option<Text>,
+        /// The command that's measured.
+        run: Option<CommandSpec>,
+        /// Commands run in the background during each iteration.
+        service: Option<Commands>,
+        /// Commands run before each iteration.
+        setup: Option<Commands>,
+        /// Commands run after each iteration.
+        teardown: Option<Commands>,
+        /// Seconds before an iteration fails.
+        timeout: Option<Number>,
+        /// Count instructions with cachegrind.
+        cachegrind: Option<Flag>,
+        /// How many times to run the command.
+        iterations: Option<Count>,
+        /// How many copies of the command each iteration runs at once.
+        concurrency: Option<Count>,
+        /// Count instructions, on Linux.
+        instructions: Option<Flag>,
+        /// Count each iteration's instructions and cache misses under valgrind:
+        /// true or 'cachegrind', or 'callgrind'.
+        valgrind: Option<Valgrind>,
+        /// Events to count by running the command under 'perf stat'.
+        perf_stat: Option<PerfEvents>,
+        /// Measure peak heap usage with the given tool.
+        heap_profile: Option<HeapProfile>,
+        /// Count system calls with strace.
+        syscalls: Option<Flag>,
+        /// Keep the raw output of 'valgrind', 'perf_stat', 'heap_profile' or
+        /// 'syscalls'.
+        keep_profiles: Option<Flag>,
+        /// Significant digits to round metrics matching each glob pattern to.
+        metric_precision: Option<PrecisionRules>,
+        /// File to write results to.
+        output_file: Option<Text>,
+        /// Make unknown config keys an error.
+        strict: Option<Flag>,
+        /// Run string commands with sh, or with the given shell.
+        shell: Option<Shell>,
+        /// Directory to run commands in.
+        cwd: Option<Text>,
+        /// Make commands starting with ./ or ../ relative to the config file.
+        paths_relative_to_config: Option<Flag>,
+        /// Alternate between variants each iteration.
+        interleave: Option<Flag>,
+        /// Point TMPDIR at the iteration's scratch directory.
+        manage_tmpdir: Option<Flag>,
+        /// Paths whose disk usage is measured.
+        measure_disk: Option<Strings>,
+        /// Conditions to wait for before running the command.
+        wait_for: Option<WaitFor>,
+        /// Allow services to exit before the command is done.
+        service_may_exit: Option<Flag>,
+        /// How many times to retry a failed iteration.
+        iteration_retries: Option<Number>,
+        /// Record failed iterations and keep going.
+        continue_on_failure: Option<Flag>,
+        /// Seconds after which no more iterations are started.
+        total_timeout: Option<Number>,
+        /// Seconds to keep starting iterations for, instead of 'iterations'.
+        duration: Option<Count>,
+        /// Fewest iterations to run with 'duration'.
+        min_iterations: Option<Count>,
+        /// Most iterations to run with 'duration'.
+        max_iterations: Option<Count>,
+        /// Limits on metrics, which fail the run when they're exceeded.
+        assertions: Option<AssertionRules>,
+        /// Regexes with one capture group, to read metrics from the lines the
+        /// 'run' command prints.
+        stdout_metrics: Option<StdoutRules>,
+        /// Read metrics the 'run' command writes to SIRUN_RESULTS_FILE: true or
+        /// 'statsd' to keep what statsd sent when both report a metric, or
+        /// 'file'.
+        results_file: Option<ResultsFile>,
+        /// Glob patterns of metrics for which a lower value is a regression.
+        higher_is_better: Option<Globs>,
+        /// Describe the machine in the results.
+        collect_host_info: Option<Flag>,
+        /// Record the load and CPU frequencies before each iteration.
+        collect_system_state: Option<Flag>,
+        /// How quiet the machine has to be before the first iteration.
+        noise_check: Option<NoiseLimits>,
+        /// Account for the command's CPU time and memory through a cgroup, on
+        /// Linux.
+        cgroup_accounting: Option<Flag>,
+        /// Memory the command may use, in bytes or like '512M': shared by all
+        /// its copies with 'cgroup_accounting', and otherwise each process's
+        /// own address space limit.
+        max_memory: Option<ByteSize>,
+        /// Run the command without address space layout randomization, on
+        /// Linux.
+        disable_aslr: Option<Flag>,
+        /// Drop the page cache before each iteration, directly or with a helper
+        /// command.
+        drop_caches: Option<CacheDropping>,
+        /// Nest the metrics the benchmark sends by the parts of their dotted
+        /// names.
+        nest_metrics: Option<Flag>,
+        /// Kill whatever the commands leave running at the end of each
+        /// iteration.
+        reap_orphans: Option<Flag>,
+        /// Wait for other exclusive sirun runs on this machine to finish before
+        /// running.
+        exclusive: Option<Flag>,
+        /// Seconds to wait for other exclusive sirun runs before failing.
+        exclusive_timeout: Option<Number>,
+        /// Where to send the results once the run is done.
+        export: Option<Exports>,
+        /// The host:port of a DogStatsD agent to relay each iteration's metrics
+        /// to.
+        statsd_forward: Option<Address>,
+        /// What the names of relayed metrics start with. Defaults to 'sirun.'.
+        statsd_prefix: Option<Text>,
+        /// The address the statsd listener binds to, such as 0.0.0.0. Defaults
+        /// to 127.0.0.1.
+        statsd_host: Option<Text>,
+        /// Whether the statsd listener takes UDP packets or line-delimited
+        /// metrics over TCP connections. Defaults to 'udp'.
+        statsd_protocol: Option<Protocol>,
+        /// More parts of env names whose values are hidden wherever the config
+        /// is shown.
+        redact_env: Option<Strings>,
+        /// Where the setup commands' output goes.
+        setup_stdio: Option<Stdio>,
+        /// Where the 'run' command's output goes.
+        run_stdio: Option<Stdio>,
+        /// Where the services' output goes.
+        service_stdio: Option<Stdio>,
+        /// Where the teardown commands' output goes.
+        teardown_stdio: Option<Stdio>,
+        /// Environment variables for the commands. null removes an inherited
+        /// one.
+        env: Option<Env>,
+        /// Don't inherit sirun's environment.
+        clear_env: Option<Flag>,
+        /// CPUs to pin the command to, on Linux.
+        cpu_affinity: Option<Cpus>,
+        /// Scheduling priority of the command, on Linux.
+        nice: Option<Nice>,
+        /// Apply 'cpu_affinity' and 'nice' to services too.
+        pin_services: Option<Flag>,
+    }
+}
+
+/// Keys of a layer that `get_config` deals with before it's parsed.
+pub(crate) const LAYERING_KEYS: &[&str] = &["extends", "matrix", "benchmarks", "variants"];
+
+impl RawConfig {
+    /// Parses a layer of config found at `path` in its file (empty for the
+    /// top level), which errors name keys by. Unknown keys are warned about,
+    /// or are an error if the layer sets `strict`, or doesn't and `strict` is
+    /// inherited from the layers below.
+    pub(crate) fn parse(value: &Value, strict: bool, path: &str) -> Result<Self> {
+        let mapping = match value.as_mapping() {
+            Some(mapping) => mapping,
+            None if path.is_empty() => bail!("invalid json"),
+            None => bail!("'{}' must be an object", path),
+        };
+        let raw = RawConfig::deserialize(Layer { mapping, path })?;
+        check_keys(raw.strict(strict), mapping, path)?;
+        ensure!(
+            raw.iterations.is_none() || raw.duration.is_none(),
+            "'{}' and '{}' can't be used together; use 'min_iterations' and \
+             'max_iterations' to bound a duration",
+            join_path(path, "iterations"),
+            join_path(path, "duration")
+        );
+        Ok(raw)
+    }
+
+    /// Whether unknown keys are an error, given the `strict` of the layers
+    /// below.
+    pub(crate) fn strict(&self, inherited: bool) -> bool {
+        self.strict
+            .as_ref()
+            .map_or(inherited, |Flag(strict)| *strict)
+    }
+
+    /// Puts `layer` on top of this one. Whatever `layer` sets replaces what's
+    /// set here, except for `env`, which is merged variable by variable.
+    /// `iterations` and `duration` replace each other, so whichever is set
+    /// last wins.
+    pub(crate) fn merge(self, layer: RawConfig) -> RawConfig {
+        let (iterations, duration) = match (layer.iterations, layer.duration) {
+            (Some(iterations), _) => (Some(iterations), None),
+            (None, Some(duration)) => (self.iterations, Some(duration)),
+            (None, None) => (self.iterations, self.duration),
+        };
+        let env = match (self.env, layer.env) {
+            (Some(Env(mut env)), Some(Env(layer_env))) => {
+                env.extend(layer_env);
+                Some(Env(env))
+            }
+            (env, layer_env) => layer_env.or(env),
+        };
+        RawConfig {
+            name: layer.name.or(self.name),
+            run: layer.run.or(self.run),
+            service: layer.service.or(self.service),
+            setup: layer.setup.or(self.setup),
+            teardown: layer.teardown.or(self.teardown),
+            timeout: layer.timeout.or(self.timeout),
+            cachegrind: layer.cachegrind.or(self.cachegrind),
+            iterations,
+            concurrency: layer.concurrency.or(self.concurrency),
+            instructions: layer.instructions.or(self.instructions),
+            valgrind: layer.valgrind.or(self.valgrind),
+            perf_stat: layer.perf_stat.or(self.perf_stat),
+            heap_profile: layer.heap_profile.or(self.heap_profile),
+            syscalls: layer.syscalls.or(self.syscalls),
+            keep_profiles: layer.keep_profiles.or(self.keep_profiles),
+            metric_precision: layer.metric_precision.or(self.metric_precision),
+            output_file: layer.output_file.or(self.output_file),
+            strict: layer.strict.or(self.strict),
+            shell: layer.shell.or(self.shell),
+            cwd: layer.cwd.or(self.cwd),
+            paths_relative_to_config: layer
+                .paths_relative_to_config
+                .or(self.paths_relative_to_config),
+            interleave: layer.interleave.or(self.interleave),
+            manage_tmpdir: layer.manage_tmpdir.or(self.manage_tmpdir),
+            measure_disk: layer.measure_disk.or(self.measure_disk),
+            wait_for: layer.wait_for.or(self.wait_for),
+            service_may_exit: layer.service_may_exit.or(self.service_may_exit),
+            iteration_retries: layer.iteration_retries.or(self.iteration_retries),
+            continue_on_failure: layer.continue_on_failure.or(self.continue_on_failure),
+            total_timeout: layer.total_timeout.or(self.total_timeout),
+            duration,
+            min_iterations: layer.min_iterations.or(self.min_iterations),
+            max_iterations: layer.max_iterations.or(self.max_iterations),
+            assertions: layer.assertions.or(self.assertions),
+            stdout_metrics: layer.stdout_metrics.or(self.stdout_metrics),
+            results_file: layer.results_file.or(self.results_file),
+            higher_is_better: layer.higher_is_better.or(self.higher_is_better),
+            collect_host_info: layer.collect_host_info.or(self.collect_host_info),
+            collect_system_state: layer.collect_system_state.or(self.collect_system_state),
+            noise_check: layer.noise_check.or(self.noise_check),
+            cgroup_accounting: layer.cgroup_accounting.or(self.cgroup_accounting),
+            max_memory: layer.max_memory.or(self.max_memory),
+            disable_aslr: layer.disable_aslr.or(self.disable_aslr),
+            drop_caches: layer.drop_caches.or(self.drop_caches),
+            nest_metrics: layer.nest_metrics.or(self.nest_metrics),
+            reap_orphans: layer.reap_orphans.or(self.reap_orphans),
+            exclusive: layer.exclusive.or(self.exclusive),
+            exclusive_timeout: layer.exclusive_timeout.or(self.exclusive_timeout),
+            export: layer.export.or(self.export),
+            statsd_forward: layer.statsd_forward.or(self.statsd_forward),
+            statsd_prefix: layer.statsd_prefix.or(self.statsd_prefix),
+            statsd_host: layer.statsd_host.or(self.statsd_host),
+            statsd_protocol: layer.statsd_protocol.or(self.statsd_protocol),
+            redact_env: layer.redact_env.or(self.redact_env),
+            setup_stdio: layer.setup_stdio.or(self.setup_stdio),
+            run_stdio: layer.run_stdio.or(self.run_stdio),
+            service_stdio: layer.service_stdio.or(self.service_stdio),
+            teardown_stdio: layer.teardown_stdio.or(self.teardown_stdio),
+            env,
+            clear_env: layer.clear_env.or(self.clear_env),
+            cpu_affinity: layer.cpu_affinity.or(self.cpu_affinity),
+            nice: layer.nice.or(self.nice),
+            pin_services: layer.pin_services.or(self.pin_services),
+        }
+    }
+
+    /// Sets everything this layer sets on `config`. `SIRUN_NAME` takes
+    /// precedence over `name`, and commands given as strings are handed to
+    /// the `shell`, if there is one, rather than split into arguments.
+    pub(crate) fn apply(self, config: &mut Config) {
+        if let Ok(name) = env::var("SIRUN_NAME") {
+            config.name = Some(name);
+        } else if let Some(Text(name)) = self.name {
+            config.name = Some(name);
+        }
+        if let Some(Shell(shell)) = self.shell {
+            config.shell = shell;
+        }
+        let shell = config.shell.as_deref();
+        if let Some(run) = self.run {
+            config.run = run.argv(shell);
+        }
+        let argvs = |Commands(commands): Commands| {
+            commands.iter().map(|command| command.argv(shell)).collect()
+        };
+        if let Some(service) = self.service {
+            config.service = Some(argvs(service));
+        }
+        if let Some(setup) = self.setup {
+            config.setup = Some(argvs(setup));
+        }
+        if let Some(teardown) = self.teardown {
+            config.teardown = Some(argvs(teardown));
+        }
+        if let Some(Count(iterations)) = self.iterations {
+            config.iterations = iterations;
+            config.duration = None;
+        }
+        if let Some(Count(concurrency)) = self.concurrency {
+            config.concurrency = concurrency;
+        }
+        if let Some(Count(duration)) = self.duration {
+            config.duration = Some(duration);
+        }
+        if let Some(Env(env)) = self.env {
+            for (name, value) in env {
+                match value {
+                    Some(value) => {
+                        config.env_remove.remove(&name);
+                        config.env.insert(name, value);
+                    }
+                    None => {
+                        config.env.remove(&name);
+                        config.env_remove.insert(name);
+                    }
+                }
+            }
+        }
+        let set = |target: &mut bool, flag: Option<Flag>| {
+            if let Some(Flag(value)) = flag {
+                *target = value;
+            }
+        };
+        set(&mut config.cachegrind, self.cachegrind);
+        set(&mut config.instructions, self.instructions);
+        set(&mut config.syscalls, self.syscalls);
+        set(&mut config.keep_profiles, self.keep_profiles);
+        set(&mut config.strict, self.strict);
+        set(
+            &mut config.paths_relative_to_config,
+            self.paths_relative_to_config,
+        );
+        set(&mut config.interleave, self.interleave);
+        set(&mut config.manage_tmpdir, self.manage_tmpdir);
+        set(&mut config.service_may_exit, self.service_may_exit);
+        set(&mut config.continue_on_failure, self.continue_on_failure);
+        set(&mut config.collect_host_info, self.collect_host_info);
+        set(&mut config.collect_system_state, self.collect_system_state);
+        set(&mut config.cgroup_accounting, self.cgroup_accounting);
+        set(&mut config.disable_aslr, self.disable_aslr);
+        set(&mut config.clear_env, self.clear_env);
+        set(&mut config.pin_services, self.pin_services);
+        if let Some(Cpus(cpus)) = self.cpu_affinity {
+            config.cpu_affinity = cpus;
+        }
+        if let Some(Nice(nice)) = self.nice {
+            config.nice = Some(nice);
+        }
+        if let Some(Valgrind(tool)) = self.valgrind {
+            config.valgrind = tool;
+        }
+        if let Some(Number(timeout)) = self.timeout {
+            config.timeout = Some(timeout);
+        }
+        if let Some(Number(retries)) = self.iteration_retries {
+            config.iteration_retries = retries;
+        }
+        if let Some(Number(total_timeout)) = self.total_timeout {
+            config.total_timeout = Some(total_timeout);
+        }
+        if let Some(Count(min)) = self.min_iterations {
+            config.min_iterations = Some(min);
+        }
+        if let Some(Count(max)) = self.max_iterations {
+            config.max_iterations = Some(max);
+        }
+        if let Some(PrecisionRules(precision)) = self.metric_precision {
+            config.metric_precision = Some(precision);
+        }
+        if let Some(AssertionRules(assertions)) = self.assertions {
+            config.assertions = Some(assertions);
+        }
+        if let Some(StdoutRules(stdout_metrics)) = self.stdout_metrics {
+            config.stdout_metrics = Some(stdout_metrics);
+        }
+        if let Some(ResultsFile(precedence)) = self.results_file {
+            config.results_file = precedence;
+        }
+        if let Some(Text(output_file)) = self.output_file {
+            config.output_file = Some(output_file);
+        }
+        if let Some(Text(cwd)) = self.cwd {
+            config.cwd = Some(cwd);
+        }
+        if let Some(Strings(paths)) = self.measure_disk {
+            config.measure_disk = paths;
+        }
+        if let Some(PerfEvents(events)) = self.perf_stat {
+            config.perf_stat = events;
+        }
+        if let Some(HeapProfile(profiler)) = self.heap_profile {
+            config.heap_profile = profiler;
+        }
+        if let Some(Globs(patterns)) = self.higher_is_better {
+            config.higher_is_better = patterns;
+        }
+        if let Some(WaitFor(conditions)) = self.wait_for {
+            config.wait_for = conditions;
+        }
+        if let Some(NoiseLimits(check)) = self.noise_check {
+            config.noise_check = Some(check);
+        }
+        if let Some(ByteSize(bytes)) = self.max_memory {
+            config.max_memory = Some(bytes);
+        }
+        set(&mut config.nest_metrics, self.nest_metrics);
+        set(&mut config.reap_orphans, self.reap_orphans);
+        set(&mut config.exclusive, self.exclusive);
+        if let Some(Number(timeout)) = self.exclusive_timeout {
+            config.exclusive_timeout = timeout;
+        }
+        if let Some(Exports(export)) = self.export {
+            config.export = export;
+        }
+        if let Some(Address(address)) = self.statsd_forward {
+            config.statsd_forward = Some(address);
+        }
+        if let Some(Text(prefix)) = self.statsd_prefix {
+            config.statsd_prefix = Some(prefix);
+        }
+        if let Some(Text(host)) = self.statsd_host {
+            config.statsd_host = Some(host);
+        }
+        if let Some(Protocol(protocol)) = self.statsd_protocol {
+            config.statsd_protocol = protocol;
+        }
+        if let Some(Strings(patterns)) = self.redact_env {
+            config.redact_env = patterns;
+        }
+        if let Some(CacheDropping(drop_caches)) = self.drop_caches {
+            config.drop_caches = drop_caches;
+        }
+        for (target, stdio) in [
+            (&mut config.setup_stdio, self.setup_stdio),
+            (&mut config.run_stdio, self.run_stdio),
+            (&mut config.service_stdio, self.service_stdio),
+            (&mut config.teardown_stdio, self.teardown_stdio),
+        ] {
+            if let Some(Stdio(stdio)) = stdio {
+                *target = Some(stdio);
+            }
+        }
+    }
+}
+
+/// The path of `key` within the value at `path`.
+pub(crate) fn join_path(path: &str, key: &str) -> String {
+    match path {
+        "" => key.to_owned(),
+        _ => format!("{}.{}", path, key),
+    }
+}
+
+/// Names `path` in an error about the value there. Errors about a part of
+/// the value start with the part's path relative to it, in quotes, like
+/// `'.ITERS'` or `'[1]'`, which is appended to `path`. Other errors are put
+/// after `path`, so that "must be a string" becomes "'name' must be a string".
+pub(crate) fn at_path(path: &str, e: impl fmt::Display) -> Error {
+    let message = e.to_string();
+    match message.strip_prefix('\'') {
+        Some(rest) if rest.starts_with('.') || rest.starts_with('[') => {
+            anyhow!("'{}{}", path, rest)
+        }
+        _ => anyhow!("'{}' {}", path, message),
+    }
+}
+
+/// Deserializes a layer of config from its mapping, leaving out unknown keys
+/// (which `check_keys` reports) and `LAYERING_KEYS`. Errors in a value are
+/// given the path of its key.
+struct Layer<'a> {
+    mapping: &'a Mapping,
+    path: &'a str,
+}
+
+impl<'de, 'a> Deserializer<'de> for Layer<'a> {
+    type Error = serde_yaml::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_map(LayerEntries {
+            entries: self.mapping.iter(),
+            path: self.path,
+            value: None,
+        })
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+struct LayerEntries<'a> {
+    entries: mapping::Iter<'a>,
+    path: &'a str,
+    value: Option<(&'a str, &'a Value)>,
+}
+
+impl<'de, 'a> MapAccess<'de> for LayerEntries<'a> {
+    type Error = serde_yaml::Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Self::Error> {
+        for (key, value) in &mut self.entries {
+            let key = match key.as_str() {
+                Some(key) if KNOWN_KEYS.contains(&key) && !LAYERING_KEYS.contains(&key) => key,
+                _ => continue,
+            };
+            self.value = Some((key, value));
+            let key: StrDeserializer<Self::Error> = key.into_deserializer();
+            return seed.deserialize(key).map(Some);
+        }
+        Ok(None)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, Self::Error> {
+        let (key, value) = self.value.take().unwrap();
+        seed.deserialize(value.clone())
+            .map_err(|e| de::Error::custom(at_path(&join_path(self.path, key), e)))
+    }
+}
+
+/// Values are checked by hand after being read as YAML, so that errors say
+/// what was expected rather than what serde was given. Each comes with the
+/// JSON Schema of what it accepts, which `sirun --schema` describes it by.
+macro_rules! config_value {
+    ($(#[$attr:meta])* $name:ident($inner:ty), $schema:expr, |$value:ident| $parse:expr) => {
+        $(#[$attr])*
+        #[derive(Deserialize, Clone)]
+        #[serde(try_from = "Value")]
+        struct $name($inner);
+
+        impl TryFrom<Value> for $name {
+            type Error = Error;
+
+            fn try_from($value: Value) -> Result<Self> {
+                $parse.map($name)
+            }
+        }
+
+        impl ValueSchema for $name {
+            fn schema() -> serde_json::Value {
+                $schema
+            }
+        }
+    };
+}
+
+config_value!(Text(String), string(), |value| match value {
+    Value::String(text) => Ok(text),
+    _ => Err(anyhow!("must be a string")),
+});
+
+config_value!(Flag(bool), boolean(), |value| value
+    .as_bool()
+    .ok_or_else(|| anyhow!("must be a boolean")));
+
+config_value!(Number(u64), integer(0), |value| value
+    .as_u64()
+    .ok_or_else(|| anyhow!("must be a non-negative integer")));
+
+config_value!(Count(u64), integer(1), |value| value
+    .as_u64()
+    .filter(|count| *count > 0)
+    .ok_or_else(|| anyhow!("must be an integer >=1")));
+
+config_value!(Strings(Vec<String>), string_array(), |value| strings(
+    &value
+));
+
+config_value!(
+    /// Indices of CPUs, which `get_config` checks against those online.
+    Cpus(Vec<usize>),
+    json!({"type": "array", "items": integer(0), "minItems": 1}),
+    |value| {
+        let cpus = value
+            .as_sequence()
+            .and_then(|cpus| {
+                cpus.iter()
+                    .map(|cpu| cpu.as_u64().map(|cpu| cpu as usize))
+                    .collect::<Option<Vec<_>>>()
+            })
+            .ok_or_else(|| anyhow!("must be an array of CPU indices"))?;
+        ensure!(!cpus.is_empty(), "must not be empty");
+        Ok(cpus)
+    }
+);
+
+config_value!(
+    /// A number of bytes, or a size like `512M`.
+    ByteSize(u64),
+    json!({"anyOf": [integer(1), {"type": "string", "pattern": "^[0-9]+[KMGTkmgt]?$"}]}),
+    |value| match value {
+        Value::Number(bytes) => bytes.as_u64().filter(|bytes| *bytes > 0),
+        Value::String(size) => parse_byte_size(&size),
+        _ => None,
+    }
+    .ok_or_else(|| anyhow!("must be a number of bytes, or a size like '512M'"))
+);
+
+config_value!(
+    Nice(i32),
+    json!({"type": "integer", "minimum": -20, "maximum": 19}),
+    |value| value
+        .as_i64()
+        .filter(|nice| (-20..=19).contains(nice))
+        .map(|nice| nice as i32)
+        .ok_or_else(|| anyhow!("must be an integer from -20 to 19"))
+);
+
+config_value!(
+    /// Glob patterns of metric names.
+    Globs(Vec<String>),
+    string_array(),
+    |value| {
+        let patterns = strings(&value)?;
+        for (i, pattern) in patterns.iter().enumerate() {
+            validate_glob(pattern).map_err(|e| at_path(&format!("[{}]", i), e))?;
+        }
+        Ok(patterns)
+    }
+);
+
+config_value!(
+    /// Names of events for `perf stat -e`, which takes them comma-separated.
+    PerfEvents(Vec<String>),
+    json!({"type": "array", "items": {"type": "string", "pattern": "^[^,]+$"}, "minItems": 1}),
+    |value| {
+        let events = strings(&value)?;
+        ensure!(!events.is_empty(), "must not be empty");
+        for (i, event) in events.iter().enumerate() {
+            ensure!(
+                !event.is_empty() && !event.contains(','),
+                "'[{}]' must be a single event name",
+                i
+            );
+        }
+        Ok(events)
+    }
+);
+
+config_value!(
+    /// `true` for `sh`, `false` for none, or the shell to use.
+    Shell(Option<String>),
+    json!({"type": ["boolean", "string"]}),
+    |value| match value {
+        Value::Bool(true) => Ok(Some("sh".into())),
+        Value::Bool(false) => Ok(None),
+        Value::String(shell) => Ok(Some(shell)),
+        _ => Err(anyhow!("must be a boolean or the shell to use")),
+    }
+);
+
+config_value!(
+    /// `true` for cachegrind, `false` for neither, or the tool's name.
+    Valgrind(Option<ValgrindTool>),
+    json!({"anyOf": [boolean(), {"enum": ["cachegrind", "callgrind"]}]}),
+    |value| match value {
+        Value::Bool(true) => Ok(Some(ValgrindTool::Cachegrind)),
+        Value::Bool(false) => Ok(None),
+        Value::String(tool) => ValgrindTool::parse(&tool)
+            .map(Some)
+            .ok_or_else(|| anyhow!("must be a boolean, 'cachegrind' or 'callgrind'")),
+        _ => Err(anyhow!("must be a boolean, 'cachegrind' or 'callgrind'")),
+    }
+);
+
+config_value!(
+    /// `true` to keep what statsd sent when both report a metric, `false`
+    /// for no results file, or which of them wins.
+    ResultsFile(Option<ResultsPrecedence>),
+    json!({"anyOf": [boolean(), {"enum": ["statsd", "file"]}]}),
+    |value| match value {
+        Value::Bool(true) => Ok(Some(ResultsPrecedence::Statsd)),
+        Value::Bool(false) => Ok(None),
+        Value::String(precedence) => ResultsPrecedence::parse(&precedence)
+            .map(Some)
+            .ok_or_else(|| anyhow!("must be a boolean, 'statsd' or 'file'")),
+        _ => Err(anyhow!("must be a boolean, 'statsd' or 'file'")),
+    }
+);
+
+config_value!(
+    /// Which transport the statsd listener uses.
+    Protocol(StatsdProtocol),
+    json!({"enum": ["udp", "tcp"]}),
+    |value| match value {
+        Value::String(protocol) => StatsdProtocol::parse(&protocol)
+            .ok_or_else(|| anyhow!("must be 'udp' or 'tcp'")),
+        _ => Err(anyhow!("must be 'udp' or 'tcp'")),
+    }
+);
+
+config_value!(
+    /// `false` for none, or the heap profiler's name.
+    HeapProfile(Option<HeapProfiler>),
+    json!({"enum": ["massif", false]}),
+    |value| match value {
+        Value::Bool(false) => Ok(None),
+        Value::String(profiler) => HeapProfiler::parse(&profiler)
+            .map(Some)
+            .ok_or_else(|| anyhow!("must be 'massif' or false")),
+        _ => Err(anyhow!("must be 'massif' or false")),
+    }
+);
+
+config_value!(
+    /// `true` to write to `drop_caches` directly, `false` not to drop caches,
+    /// or a helper command to write to it with.
+    CacheDropping(Option<DropCaches>),
+    json!({"anyOf": [boolean(), string(), {"type": "array", "items": string(), "minItems": 1}]}),
+    |value| match value {
+        Value::Bool(drop) => Ok(Some(DropCaches::Direct).filter(|_| drop)),
+        Value::String(helper) => helper_command(shlex::split(&helper)),
+        Value::Sequence(_) => helper_command(strings(&value).ok()),
+        _ => Err(anyhow!("must be a boolean or a helper command")),
+    }
+);
+
+fn helper_command(helper: Option<Vec<String>>) -> Result<Option<DropCaches>> {
+    helper
+        .filter(|helper| !helper.is_empty())
+        .map(|helper| Some(DropCaches::Helper(helper)))
+        .ok_or_else(|| anyhow!("must be a boolean or a helper command"))
+}
+
+config_value!(Stdio(StdioTarget), definition("stdio"), |value| value
+    .as_str()
+    .and_then(StdioTarget::parse)
+    .ok_or_else(|| anyhow!("must be 'inherit', 'null' or 'file:<path>'")));
+
+config_value!(
+    /// A variable set to `None` is removed from the environment commands
+    /// inherit.
+    Env(BTreeMap<String, Option<String>>),
+    json!({"type": "object", "additionalProperties": {"type": ["string", "number", "boolean", "null"]}}),
+    |value| {
+        let env = value.as_mapping().ok_or_else(|| anyhow!("must be an object"))?;
+        env.iter()
+            .map(|(name, value)| {
+                let name = name
+                    .as_str()
+                    .ok_or_else(|| anyhow!("must have strings as names"))?;
+                let value = match value {
+                    Value::Null => None,
+                    _ => Some(env_value(value).ok_or_else(|| {
+                        anyhow!("'.{}' must be a string, number, boolean or null", name)
+                    })?),
+                };
+                Ok((name.to_owned(), value))
+            })
+            .collect::<Result<_>>()
+    }
+);
+
+config_value!(
+    WaitFor(Vec<WaitCondition>),
+    json!({"anyOf": [definition("wait_condition"), {"type": "array", "items": definition("wait_condition")}]}),
+    |value| match value.as_sequence() {
+        Some(conditions) => conditions
+            .iter()
+            .enumerate()
+            .map(|(i, condition)| {
+                WaitCondition::parse(condition).map_err(|e| at_path(&format!("[{}]", i), e))
+            })
+            .collect(),
+        None => Ok(vec![WaitCondition::parse(&value)?]),
+    }
+);
+
+config_value!(
+    NoiseLimits(NoiseCheck),
+    json!({
+        "type": "object",
+        "properties": {
+            "max_load1": {"type": "number", "minimum": 0},
+            "max_cpu_pressure": {"type": "number", "minimum": 0},
+            "settle_seconds": integer(0),
+            "strict": boolean()
+        },
+        "additionalProperties": false,
+        "anyOf": [{"required": ["max_load1"]}, {"required": ["max_cpu_pressure"]}]
+    }),
+    |value| NoiseCheck::parse(&value)
+);
+
+config_value!(
+    Exports(Export),
+    json!({
+        "type": "object",
+        "properties": {
+            "datadog": {
+                "description": "Send metrics to Datadog, with the API key in DD_API_KEY.",
+                "anyOf": [
+                    boolean(),
+                    {
+                        "type": "object",
+                        "properties": {
+                            "site": string(),
+                            "prefix": string(),
+                            "tags": string_array(),
+                            "send": {"enum": ["summary", "iterations", "both"]},
+                            "strict": boolean()
+                        },
+                        "additionalProperties": false
+                    }
+                ]
+            },
+            "prometheus_textfile": {
+                "description": "A file for node_exporter's textfile collector to read.",
+                "anyOf": [{"const": false}, string()]
+            },
+            "pushgateway": {
+                "description": "The URL of a Prometheus Pushgateway to PUT metrics to.",
+                "anyOf": [{"const": false}, string()]
+            }
+        },
+        "additionalProperties": false
+    }),
+    |value| Export::parse(&value)
+);
+
+config_value!(
+    /// A `host:port` to send UDP packets to.
+    Address(String),
+    json!({"type": "string", "pattern": "^.+:[0-9]+$"}),
+    |value| value
+        .as_str()
+        .filter(|address| {
+            address
+                .rsplit_once(':')
+                .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok())
+        })
+        .map(str::to_owned)
+        .ok_or_else(|| anyhow!("must be a 'host:port' string"))
+);
+
+config_value!(
+    PrecisionRules(Precision),
+    json!({"type": "object", "additionalProperties": integer(0)}),
+    |value| {
+        let rules = value
+            .as_mapping()
+            .ok_or_else(|| anyhow!("must be an object"))?;
+        let mut precision = Precision::default();
+        for (pattern, digits) in rules {
+            let pattern = pattern
+                .as_str()
+                .ok_or_else(|| anyhow!("must have strings as keys"))?;
+            let digits = digits
+                .as_u64()
+                .ok_or_else(|| anyhow!("'.{}' must be an integer", pattern))?;
+            precision
+                .add_rule(pattern, digits)
+                .map_err(|e| at_path(&format!(".{}", pattern), e))?;
+        }
+        Ok(precision)
+    }
+);
+
+config_value!(
+    AssertionRules(Assertions),
+    json!({
+        "type": "object",
+        "additionalProperties": {
+            "type": "object",
+            "properties": {
+                "min": {"type": "number"},
+                "max": {"type": "number"},
+                "over": {"enum": ["iteration", "mean"]}
+            },
+            "additionalProperties": false
+        }
+    }),
+    |value| {
+        let rules = value
+            .as_mapping()
+            .ok_or_else(|| anyhow!("must be an object"))?;
+        let mut assertions = Assertions::default();
+        for (metric, limits) in rules {
+            let metric = metric
+                .as_str()
+                .ok_or_else(|| anyhow!("must have strings as keys"))?;
+            let limits = limits
+                .as_mapping()
+                .ok_or_else(|| anyhow!("'.{}' must be an object", metric))?;
+            let limit = |key: &str, value: &Value| {
+                value
+                    .as_f64()
+                    .ok_or_else(|| anyhow!("'.{}.{}' must be a number", metric, key))
+            };
+            let mut min = None;
+            let mut max = None;
+            let mut over = Over::Iteration;
+            for (key, value) in limits {
+                match key.as_str() {
+                    Some("min") => min = Some(limit("min", value)?),
+                    Some("max") => max = Some(limit("max", value)?),
+                    Some("over") => {
+                        over = value.as_str().and_then(Over::parse).ok_or_else(|| {
+                            anyhow!("'.{}.over' must be 'iteration' or 'mean'", metric)
+                        })?
+                    }
+                    _ => bail!(
+                        "'.{}' has unknown key {:?}; expected 'min', 'max' or 'over'",
+                        metric,
+                        key
+                    ),
+                }
+            }
+            assertions
+                .add(metric, min, max, over)
+                .map_err(|e| at_path(&format!(".{}", metric), e))?;
+        }
+        Ok(assertions)
+    }
+);
+
+config_value!(
+    StdoutRules(StdoutMetrics),
+    json!({
+        "type": "object",
+        "additionalProperties": {
+            "anyOf": [
+                string(),
+                {
+                    "type": "object",
+                    "properties": {"pattern": string(), "sum": boolean()},
+                    "required": ["pattern"],
+                    "additionalProperties": false
+                }
+            ]
+        }
+    }),
+    |value| {
+        let rules = value
+            .as_mapping()
+            .ok_or_else(|| anyhow!("must be an object"))?;
+        let mut stdout_metrics = StdoutMetrics::default();
+        for (metric, rule) in rules {
+            let metric = metric
+                .as_str()
+                .ok_or_else(|| anyhow!("must have strings as keys"))?;
+            let (pattern, sum) = match rule {
+                Value::String(pattern) => (pattern.as_str(), false),
+                Value::Mapping(rule) => {
+                    let mut pattern = None;
+                    let mut sum = false;
+                    for (key, value) in rule {
+                        match key.as_str() {
+                            Some("pattern") => {
+                                pattern = Some(value.as_str().ok_or_else(|| {
+                                    anyhow!("'.{}.pattern' must be a string", metric)
+                                })?)
+                            }
+                            Some("sum") => {
+                                sum = value
+                                    .as_bool()
+                                    .ok_or_else(|| anyhow!("'.{}.sum' must be a boolean", metric))?
+                            }
+                            _ => bail!(
+                                "'.{}' has unknown key {:?}; expected 'pattern' or 'sum'",
+                                metric,
+                                key
+                            ),
+                        }
+                    }
+                    let pattern =
+                        pattern.ok_or_else(|| anyhow!("'.{}' needs a 'pattern'", metric))?;
+                    (pattern, sum)
+                }
+                _ => bail!("'.{}' must be a regex or an object", metric),
+            };
+            stdout_metrics
+                .add(metric, pattern, sum)
+                .map_err(|e| at_path(&format!(".{}", metric), e))?;
+        }
+        Ok(stdout_metrics)
+    }
+);
+
+fn strings(value: &Value) -> Result<Vec<String>> {
+    value
+        .as_sequence()
+        .and_then(|items| {
+            items
+                .iter()
+                .map(|item| item.as_str().map(String::from))
+                .collect()
+        })
+        .ok_or_else(|| anyhow!("must be an array of strings"))
+}
+
+/// A command as given in a config: a line to split into arguments the way a
+/// shell would (or to hand to the `shell`), or the arguments themselves.
+#[derive(Deserialize, Clone)]
+#[serde(try_from = "Value")]
+enum CommandSpec {
+    Line(String),
+    Argv(Vec<String>),
+}
+
+impl TryFrom<Value> for CommandSpec {
+    type Error = Error;
+
+    fn try_from(value: Value) -> Result<Self> {
+        match value {
+            Value::String(line) => {
+                let argv = shlex::split(&line).ok_or_else(|| {
+                    anyhow!("could not be split into arguments (check its quoting)")
+                })?;
+                ensure!(!argv.is_empty(), "must not be empty");
+                Ok(CommandSpec::Line(line))
+            }
+            Value::Sequence(argv) => {
+                ensure!(!argv.is_empty(), "must not be an empty array");
+                argv.iter()
+                    .map(|arg| arg.as_str().map(String::from))
+                    .collect::<Option<_>>()
+                    .map(CommandSpec::Argv)
+                    .ok_or_else(|| {
+                        anyhow!("must be an array of strings, but has a non-string element")
+                    })
+            }
+            _ => bail!("must be a string or an array of strings"),
+        }
+    }
+}
+
+impl ValueSchema for CommandSpec {
+    fn schema() -> serde_json::Value {
+        definition("command")
+    }
+}
+
+impl CommandSpec {
+    fn argv(&self, shell: Option<&str>) -> Vec<String> {
+        match (self, shell) {
+            (CommandSpec::Line(line), Some(shell)) => {
+                vec![shell.to_owned(), "-c".into(), line.clone()]
+            }
+            (CommandSpec::Line(line), None) => shlex::split(line).unwrap(),
+            (CommandSpec::Argv(argv), _) => argv.clone(),
+        }
+    }
+}
+
+/// A single command, or a list of them. A list is told apart from the
+/// arguments of a single command by containing arrays.
+#[derive(Deserialize, Clone)]
+#[serde(try_from = "Value")]
+struct Commands(Vec<CommandSpec>);
+
+impl TryFrom<Value> for Commands {
+    type Error = Error;
+
+    fn try_from(value: Value) -> Result<Self> {
+        let is_list =
+            matches!(&value, Value::Sequence(items) if items.iter().any(Value::is_sequence));
+        if !is_list {
+            return Ok(Commands(vec![CommandSpec::try_from(value)?]));
+        }
+        match value {
+            Value::Sequence(commands) => commands
+                .into_iter()
+                .enumerate()
+                .map(|(i, command)| {
+                    CommandSpec::try_from(command).map_err(|e| at_path(&format!("[{}]", i), e))
+                })
+                .collect::<Result<_>>()
+                .map(Commands),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl ValueSchema for Commands {
+    fn schema() -> serde_json::Value {
+        definition("commands")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layer(yaml: &str) -> RawConfig {
+        RawConfig::parse(&serde_yaml::from_str(yaml).unwrap(), false, "").unwrap()
+    }
+
+    fn resolve(layers: &[&str]) -> Config {
+        let raw = layers
+            .iter()
+            .fold(RawConfig::default(), |raw, yaml| raw.merge(layer(yaml)));
+        let mut config = Config::new(Vec::<String>::new());
+        raw.apply(&mut config);
+        config
+    }
+
+    #[test]
+    fn later_layers_win() {
+        let config = resolve(&["run: a\ntimeout: 5\ncachegrind: true", "run: b\ntimeout: 7"]);
+        assert_eq!(config.run, ["b"]);
+        assert_eq!(config.timeout, Some(7));
+        assert!(config.cachegrind);
+    }
+
+    #[test]
+    fn env_is_merged_by_variable() {
+        let config = resolve(&[
+            "run: a\nenv: {A: '1', B: '2', C: '3'}",
+            "env: {B: 20, C: null, D: true}",
+        ]);
+        let env: Vec<_> = config
+            .env
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        assert_eq!(env, [("A", "1"), ("B", "20"), ("D", "true")]);
+        assert!(config.env_remove.contains("C"));
+
+        let config = resolve(&["run: a\nenv: {A: null}", "env: {A: back}"]);
+        assert_eq!(config.env["A"], "back");
+        assert!(config.env_remove.is_empty());
+    }
+
+    #[test]
+    fn iterations_and_duration_replace_each_other() {
+        let config = resolve(&["run: a\niterations: 5", "duration: 10"]);
+        assert_eq!((config.iterations, config.duration), (5, Some(10)));
+        let config = resolve(&["run: a\nduration: 10", "iterations: 3"]);
+        assert_eq!((config.iterations, config.duration), (3, None));
+
+        let both = serde_yaml::from_str("{iterations: 3, duration: 10}").unwrap();
+        let e = RawConfig::parse(&both, false, "").err().unwrap();
+        assert!(e.to_string().contains("can't be used together"));
+    }
+
+    #[test]
+    fn shell_applies_to_string_commands_from_any_layer() {
+        let config = resolve(&[
+            "run: echo a | cat\nsetup: [[echo, b], echo c]",
+            "shell: bash\nteardown: echo d",
+        ]);
+        assert_eq!(config.run, ["bash", "-c", "echo a | cat"]);
+        let setup = config.setup.unwrap();
+        assert_eq!(setup, [vec!["echo", "b"], vec!["bash", "-c", "echo c"]]);
+        assert_eq!(config.teardown.unwrap(), [["bash", "-c", "echo d"]]);
+
+        let config = resolve(&["run: \"echo 'a b'\"\nshell: true", "shell: false"]);
+        assert_eq!(config.run, ["echo", "a b"]);
+    }
+
+    #[test]
+    fn strict_is_inherited() {
+        let unknown = serde_yaml::from_str("{tear_down: x}").unwrap();
+        assert!(RawConfig::parse(&unknown, false, "").is_ok());
+        assert!(RawConfig::parse(&unknown, true, "").is_err());
+        let relaxed = serde_yaml::from_str("{tear_down: x, strict: false}").unwrap();
+        assert!(RawConfig::parse(&relaxed, true, "").is_ok());
+    }
+
+    #[test]
+    fn errors_name_the_key() {
+        let error = |yaml: &str| {
+            let value = serde_yaml::from_str(yaml).unwrap();
+            RawConfig::parse(&value, false, "")
+                .err()
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(
+            error("timeout: soon"),
+            "'timeout' must be a non-negative integer"
+        );
+        assert_eq!(
+            error("setup: [[a], 3]"),
+            "'setup[1]' must be a string or an array of strings"
+        );
+        assert_eq!(
+            error("env: {LIST: [1]}"),
+            "'env.LIST' must be a string, number, boolean or null"
+        );
+        assert_eq!(
+            error("assertions: {wall.time: {over: all}}"),
+            "'assertions.wall.time.over' must be 'iteration' or 'mean'"
+        );
+        assert_eq!(
+            error("stdout_metrics: {ops: 'ops/sec'}"),
+            "'stdout_metrics.ops' must have exactly one capture group, but has 0"
+        );
+        assert_eq!(
+            error("stdout_metrics: {ops: {pattern: '(a)', sum: 1}}"),
+            "'stdout_metrics.ops.sum' must be a boolean"
+        );
+        assert_eq!(
+            error("wait_for: [{file: a}, {port: 70000}]"),
+            "'wait_for[1].port' must be a valid port number"
+        );
+    }
+}
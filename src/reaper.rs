@@ -0,0 +1,247 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+//! Cleaning up after commands that leave processes behind, with
+//! `reap_orphans`. On Linux, sirun becomes a child subreaper, so that
+//! processes that double-forked away from the commands it ran are reparented
+//! to it rather than to init, and can be found as its descendants. Elsewhere,
+//! each command it spawns gets a process group of its own, which strays stay
+//! in unless they start a new session.
+
+use async_std::process::{Child, Command};
+use log::warn;
+use nix::{
+    sys::signal::{self, Signal},
+    unistd::Pid,
+};
+use std::{
+    io, thread,
+    time::{Duration, Instant},
+};
+
+use crate::config::*;
+
+/// How long strays get to exit after `SIGTERM` before they're sent `SIGKILL`.
+const GRACE: Duration = Duration::from_millis(500);
+
+/// Cleans up what the commands of an iteration left running, when it's
+/// reaped or dropped, so that aborted iterations are cleaned up after too.
+pub(crate) struct Reaper {
+    /// Descendants from before the iteration, which aren't its to kill.
+    #[cfg(target_os = "linux")]
+    existing: Vec<i32>,
+    done: bool,
+}
+
+impl Reaper {
+    /// Starts keeping track of the iteration's processes, if the config has
+    /// `reap_orphans`.
+    pub(crate) fn start(config: &Config) -> Option<Self> {
+        if !config.reap_orphans {
+            return None;
+        }
+        #[cfg(target_os = "linux")]
+        {
+            use nix::libc;
+            if unsafe { libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1, 0, 0, 0) } == -1 {
+                warn!(
+                    "cannot become a child subreaper, so only descendants that weren't \
+                     reparented can be reaped: {}",
+                    io::Error::last_os_error()
+                );
+            }
+            Some(Reaper {
+                existing: linux::descendants()
+                    .into_iter()
+                    .map(|(pid, _)| pid)
+                    .collect(),
+                done: false,
+            })
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            groups::GROUPS.lock().unwrap().clear();
+            Some(Reaper { done: false })
+        }
+    }
+
+    /// Terminates everything the iteration left running, returning how many
+    /// processes (or, without subreapers, process groups) there were.
+    pub(crate) fn reap(&mut self) -> usize {
+        self.done = true;
+        #[cfg(target_os = "linux")]
+        let killed = linux::reap(&self.existing);
+        #[cfg(not(target_os = "linux"))]
+        let killed = groups::reap();
+        killed
+    }
+}
+
+impl Drop for Reaper {
+    fn drop(&mut self) {
+        if !self.done {
+            self.reap();
+        }
+    }
+}
+
+/// Spawns a command, in a process group of its own that's killed at the end
+/// of the iteration where there are no subreapers.
+pub(crate) fn spawn_reapable(command: &mut Command, config: &Config) -> io::Result<Child> {
+    #[cfg(not(target_os = "linux"))]
+    {
+        if config.reap_orphans {
+            use async_std::os::unix::process::CommandExt;
+            unsafe {
+                command.pre_exec(|| {
+                    nix::unistd::setpgid(Pid::from_raw(0), Pid::from_raw(0))
+                        .map_err(|_| io::Error::last_os_error())
+                });
+            }
+            let child = command.spawn()?;
+            groups::GROUPS.lock().unwrap().push(child.id() as i32);
+            return Ok(child);
+        }
+    }
+    let _ = config;
+    command.spawn()
+}
+
+/// Sends `SIGTERM` to each target, waits up to the grace period for all of
+/// them to be `gone`, and then sends `SIGKILL` to those that aren't.
+fn terminate(targets: &[i32], send: impl Fn(i32, Signal), gone: impl Fn(i32) -> bool) {
+    for target in targets {
+        send(*target, Signal::SIGTERM);
+    }
+    let start = Instant::now();
+    while start.elapsed() < GRACE && !targets.iter().all(|target| gone(*target)) {
+        thread::sleep(Duration::from_millis(10));
+    }
+    for target in targets.iter().filter(|target| !gone(**target)) {
+        send(*target, Signal::SIGKILL);
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use super::*;
+    use nix::{sys::wait::waitpid, unistd::getpid};
+    use std::fs;
+
+    /// The `(ppid, state, name)` of a process, from its
+    /// `/proc/<pid>/stat`. The name is in parentheses, and can itself have
+    /// spaces and parentheses in it.
+    fn stat(pid: i32) -> Option<(i32, char, String)> {
+        let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+        let name = &stat[stat.find('(')? + 1..stat.rfind(')')?];
+        let mut fields = stat[stat.rfind(')')? + 1..].split_whitespace();
+        let state = fields.next()?.chars().next()?;
+        let ppid = fields.next()?.parse().ok()?;
+        Some((ppid, state, name.to_owned()))
+    }
+
+    /// This process's descendants that are still running, with their names.
+    pub(super) fn descendants() -> Vec<(i32, String)> {
+        let processes: Vec<(i32, i32, char, String)> = match fs::read_dir("/proc") {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+                .filter_map(|pid| {
+                    let (ppid, state, name) = stat(pid)?;
+                    Some((pid, ppid, state, name))
+                })
+                .collect(),
+            Err(_) => return Vec::new(),
+        };
+        let mut parents = vec![getpid().as_raw()];
+        let mut descendants = Vec::new();
+        while let Some(parent) = parents.pop() {
+            for (pid, ppid, state, name) in &processes {
+                if *ppid == parent {
+                    parents.push(*pid);
+                    if *state != 'Z' {
+                        descendants.push((*pid, name.clone()));
+                    }
+                }
+            }
+        }
+        descendants
+    }
+
+    /// Whether a process has exited, which leaves those that were
+    /// reparented to sirun as zombies until they're waited for.
+    fn gone(pid: i32) -> bool {
+        stat(pid).is_none_or(|(_, state, _)| state == 'Z')
+    }
+
+    pub(super) fn reap(existing: &[i32]) -> usize {
+        let strays: Vec<(i32, String)> = descendants()
+            .into_iter()
+            .filter(|(pid, _)| !existing.contains(pid))
+            .collect();
+        if strays.is_empty() {
+            return 0;
+        }
+        for (pid, name) in &strays {
+            warn!(
+                "killing stray process {} ({}) left behind by the iteration",
+                pid, name
+            );
+        }
+        let pids: Vec<i32> = strays.iter().map(|(pid, _)| *pid).collect();
+        terminate(
+            &pids,
+            |pid, signal| {
+                let _ = signal::kill(Pid::from_raw(pid), signal);
+            },
+            gone,
+        );
+        // Those that were reparented to sirun are its children now, and would
+        // stay zombies until it exits. Any others are waited for by their own
+        // parents.
+        for pid in &pids {
+            let _ = waitpid(Pid::from_raw(*pid), None);
+        }
+        strays.len()
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod groups {
+    use super::*;
+    use std::sync::Mutex;
+
+    lazy_static::lazy_static! {
+        /// The process groups of the commands spawned in this iteration.
+        pub(super) static ref GROUPS: Mutex<Vec<i32>> = Mutex::new(Vec::new());
+    }
+
+    /// Whether a process group has any processes left in it.
+    fn exists(pgid: i32) -> bool {
+        signal::killpg(Pid::from_raw(pgid), None).is_ok()
+    }
+
+    pub(super) fn reap() -> usize {
+        let groups: Vec<i32> = GROUPS
+            .lock()
+            .unwrap()
+            .drain(..)
+            .filter(|pgid| exists(*pgid))
+            .collect();
+        for pgid in &groups {
+            warn!(
+                "killing process group {} left behind by the iteration",
+                pgid
+            );
+        }
+        terminate(
+            &groups,
+            |pgid, signal| {
+                let _ = signal::killpg(Pid::from_raw(pgid), signal);
+            },
+            |pgid| !exists(pgid),
+        );
+        groups.len()
+    }
+}
@@ -0,0 +1,74 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+//! Keeping the values of secret-looking environment variables out of
+//! anything that echoes a config, like failure reports and `--dry-run`.
+
+use crate::config::*;
+
+/// Parts of environment variable names whose values are likely to be
+/// secrets, matched regardless of case. `redact_env` adds to them.
+const SECRET_PATTERNS: &[&str] = &[
+    "TOKEN",
+    "SECRET",
+    "PASSWORD",
+    "PASSWD",
+    "API_KEY",
+    "ACCESS_KEY",
+    "PRIVATE_KEY",
+    "CREDENTIAL",
+];
+
+/// What the values of secret-looking variables are replaced by.
+pub(crate) const REDACTED: &str = "***";
+
+/// Whether an environment variable's name suggests its value is a secret,
+/// like `DD_API_KEY` or `GITHUB_TOKEN`, or matches one of the config's
+/// `redact_env` patterns.
+pub(crate) fn is_secret(config: &Config, name: &str) -> bool {
+    let name = name.to_ascii_uppercase();
+    SECRET_PATTERNS
+        .iter()
+        .copied()
+        .chain(config.redact_env.iter().map(String::as_str))
+        .any(|pattern| name.contains(&pattern.to_ascii_uppercase()))
+}
+
+/// A copy of the config to show, with the values of secret-looking
+/// environment variables replaced. Commands must still be given the real
+/// ones, from the config itself.
+pub(crate) fn redacted(config: &Config) -> Config {
+    let mut redacted = config.clone();
+    for (name, value) in redacted.env.iter_mut() {
+        if is_secret(config, name) {
+            *value = REDACTED.to_owned();
+        }
+    }
+    redacted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secrets() {
+        let mut config = Config::new(vec!["node", "bench.js"]);
+        config.env.insert("DD_API_KEY".into(), "abc123".into());
+        config.env.insert("github_token".into(), "ghp_abc".into());
+        config.env.insert("MY_CRED".into(), "hunter2".into());
+        config.env.insert("NODE_ENV".into(), "production".into());
+        assert!(!is_secret(&config, "MY_CRED"));
+        config.redact_env = vec!["my_cred".into()];
+
+        let shown = redacted(&config);
+        assert_eq!(shown.env["DD_API_KEY"], REDACTED);
+        assert_eq!(shown.env["github_token"], REDACTED);
+        assert_eq!(shown.env["MY_CRED"], REDACTED);
+        assert_eq!(shown.env["NODE_ENV"], "production");
+        assert_eq!(config.env["DD_API_KEY"], "abc123");
+        assert!(!config.to_string().contains("hunter2"));
+    }
+}
@@ -0,0 +1,62 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+use anyhow::*;
+
+use crate::metric_value::*;
+
+/// The results of running a config: the same as the line of JSON `sirun`
+/// writes, split up.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct BenchmarkResult {
+    pub name: Option<String>,
+    pub variant: Option<String>,
+    /// The metrics measured in each iteration, such as `wall.time`,
+    /// `user.time`, `system.time`, `max.res.size` and whatever was sent over
+    /// statsd.
+    pub iterations: Vec<MetricMap>,
+    /// Everything else in the results, such as `version`, `host`,
+    /// `started_at`, `completed_at` and `flagged`.
+    pub metadata: MetricMap,
+}
+
+impl BenchmarkResult {
+    pub(crate) fn from_metric_map(mut results: MetricMap) -> Result<Self> {
+        let name = results.remove("name").map(|v| v.as_string()).transpose()?;
+        let variant = results
+            .remove("variant")
+            .map(|v| v.as_string())
+            .transpose()?;
+        let iterations = match results.remove("iterations") {
+            Some(iterations) => iterations
+                .as_vec()?
+                .iter()
+                .map(|iteration| Ok(iteration.as_map()?.clone()))
+                .collect::<Result<_>>()?,
+            None => Vec::new(),
+        };
+        Ok(BenchmarkResult {
+            name,
+            variant,
+            iterations,
+            metadata: results,
+        })
+    }
+
+    /// The results as they're written by `sirun`.
+    pub fn into_metric_map(self) -> MetricMap {
+        let mut results = self.metadata;
+        if let Some(name) = self.name {
+            results.insert("name".into(), name.into());
+        }
+        if let Some(variant) = self.variant {
+            results.insert("variant".into(), variant.into());
+        }
+        let iterations = self.iterations.into_iter().map(MetricValue::Map).collect();
+        results.insert("iterations".into(), MetricValue::Arr(iterations));
+        results
+    }
+}
@@ -0,0 +1,151 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+//! Metrics a benchmark writes to a file, with the `results_file` config key,
+//! for runtimes where sending UDP packets is awkward.
+
+use anyhow::*;
+use serde::{Deserialize, Serialize};
+use std::{fs, io, path::PathBuf};
+use tempfile::TempDir;
+
+use crate::config::*;
+use crate::metric_value::*;
+
+/// Points the `run` command at the file it can write its metrics to.
+pub(crate) const RESULTS_FILE_ENV: &str = "SIRUN_RESULTS_FILE";
+
+/// Which metrics are kept when the results file and statsd both report one.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ResultsPrecedence {
+    Statsd,
+    File,
+}
+
+impl ResultsPrecedence {
+    pub(crate) fn parse(name: &str) -> Option<Self> {
+        match name {
+            "statsd" => Some(ResultsPrecedence::Statsd),
+            "file" => Some(ResultsPrecedence::File),
+            _ => None,
+        }
+    }
+}
+
+/// A fresh path for one iteration's results file, in a directory of its own
+/// so that the benchmark creates the file. It's removed when dropped.
+pub(crate) struct IterationResultsFile {
+    dir: TempDir,
+    precedence: ResultsPrecedence,
+}
+
+impl IterationResultsFile {
+    /// Creates the directory and points `RESULTS_FILE_ENV` at a file in it in
+    /// the config's env, if the config has `results_file` set.
+    pub(crate) fn create(config: &mut Config) -> Result<Option<Self>> {
+        let precedence = match config.results_file {
+            Some(precedence) => precedence,
+            None => return Ok(None),
+        };
+        let dir = tempfile::Builder::new().prefix("sirun-results").tempdir()?;
+        let results_file = IterationResultsFile { dir, precedence };
+        config.env.insert(
+            RESULTS_FILE_ENV.into(),
+            results_file.path().to_string_lossy().into_owned(),
+        );
+        Ok(Some(results_file))
+    }
+
+    fn path(&self) -> PathBuf {
+        self.dir.path().join("results.json")
+    }
+
+    /// Reads the metrics in the file, if the benchmark wrote one.
+    pub(crate) fn read(&self) -> Result<Vec<(String, f64)>> {
+        let path = self.path();
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => {
+                return Err(e).context(format!("cannot read results file {}", path.display()))
+            }
+        };
+        parse_results(&contents).with_context(|| format!("invalid results file {}", path.display()))
+    }
+
+    /// Adds the metrics read from the file to those received over statsd.
+    /// With `nest`, dotted names are folded into nested maps like the
+    /// benchmark's statsd metrics are.
+    pub(crate) fn merge_into(
+        &self,
+        written: Vec<(String, f64)>,
+        metrics: &mut MetricMap,
+        nest: bool,
+    ) -> Result<()> {
+        for (name, value) in written {
+            if self.precedence == ResultsPrecedence::Statsd && get_nested(metrics, &name).is_some()
+            {
+                continue;
+            }
+            if nest {
+                insert_nested(metrics, &name, value.into())?;
+            } else {
+                metrics.insert(name, value.into());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A flat JSON object of metric names to numbers.
+fn parse_results(contents: &str) -> Result<Vec<(String, f64)>> {
+    let object: serde_json::Map<String, serde_json::Value> = serde_json::from_str(contents)?;
+    object
+        .into_iter()
+        .map(|(name, value)| match value.as_f64() {
+            Some(value) => Ok((name, value)),
+            None => bail!("'{}' must be a number, not {}", name, value),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn written(precedence: ResultsPrecedence, contents: Option<&str>) -> Result<MetricMap> {
+        let mut config = Config::new(vec!["true"]);
+        config.results_file = Some(precedence);
+        let results_file = IterationResultsFile::create(&mut config)?.unwrap();
+        let path = PathBuf::from(&config.env[RESULTS_FILE_ENV]);
+        assert!(!path.exists());
+        if let Some(contents) = contents {
+            fs::write(&path, contents)?;
+        }
+        let mut metrics = MetricMap::new();
+        metrics.insert("ready".into(), 1.into());
+        results_file.merge_into(results_file.read()?, &mut metrics, false)?;
+        drop(results_file);
+        assert!(!path.exists());
+        Ok(metrics)
+    }
+
+    #[test]
+    fn merged() {
+        let contents = Some(r#"{"ready": 2, "load.ms": 12.5}"#);
+        let metrics = written(ResultsPrecedence::Statsd, contents).unwrap();
+        assert_eq!(metrics["ready"], 1.into());
+        assert_eq!(metrics["load.ms"], 12.5.into());
+        let metrics = written(ResultsPrecedence::File, contents).unwrap();
+        assert_eq!(metrics["ready"], 2.into());
+
+        assert_eq!(written(ResultsPrecedence::Statsd, None).unwrap().len(), 1);
+        let e = written(ResultsPrecedence::Statsd, Some("{\"ready\": ")).unwrap_err();
+        assert!(format!("{:#}", e).contains("invalid results file"));
+        let e = written(ResultsPrecedence::Statsd, Some(r#"{"ready": "yes"}"#)).unwrap_err();
+        assert!(format!("{:#}", e).contains("'ready' must be a number"));
+    }
+}
@@ -11,6 +11,7 @@ pub(crate) struct Rusage {
 
 fn μs_from_timeval(tv: timeval) -> f64 {
     let seconds = tv.tv_sec;
+    #[allow(clippy::unnecessary_cast)] // suseconds_t is i32 on some platforms
     let μs = tv.tv_usec as i64;
     let val = seconds * 1000000 + μs;
     val as f64
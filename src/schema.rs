@@ -0,0 +1,190 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+//! The JSON Schema that `sirun --schema` prints, describing config files.
+
+use serde_json::{json, Map, Value};
+
+use crate::raw_config::*;
+use crate::wait::*;
+
+/// A type config values are parsed into, which knows the JSON Schema of what
+/// it accepts.
+pub(crate) trait ValueSchema {
+    fn schema() -> Value;
+}
+
+/// A JSON Schema (draft-07) for config files. Its properties are the keys
+/// `RawConfig` parses, each with the schema of the type its value is parsed
+/// into, along with `LAYERING_KEYS`.
+pub(crate) fn config_schema() -> Value {
+    let properties: Map<String, Value> = LAYERING_KEYS
+        .iter()
+        .map(|key| (*key, layering_key_schema(key)))
+        .chain(RawConfig::key_schemas())
+        .map(|(key, schema)| (key.to_string(), schema))
+        .collect();
+    let conditions: Map<String, Value> = CONDITION_KEYS
+        .iter()
+        .map(|key| (key.to_string(), condition_key_schema(key)))
+        .collect();
+    json!({
+        "$schema": "http://json-schema.org/draft-07/schema#",
+        "title": "sirun config",
+        "$ref": "#/definitions/config",
+        "definitions": {
+            "config": {
+                "type": "object",
+                "properties": properties,
+                "additionalProperties": false,
+                "not": {
+                    "description": "'iterations' and 'duration' can't be used together.",
+                    "required": ["iterations", "duration"]
+                }
+            },
+            "command": {
+                "description": "A command, either split into arguments like a shell \
+                                would, or as an array of arguments.",
+                "anyOf": [
+                    {"type": "string", "pattern": "\\S"},
+                    {"type": "array", "items": {"type": "string"}, "minItems": 1}
+                ]
+            },
+            "commands": {
+                "description": "A command, or an array of commands run one after another.",
+                "anyOf": [
+                    {"$ref": "#/definitions/command"},
+                    {"type": "array", "items": {"$ref": "#/definitions/command"}}
+                ]
+            },
+            "scalar": {"type": ["string", "number", "boolean"]},
+            "stdio": {
+                "description": "Where the command's output goes: 'inherit', 'null' or \
+                                'file:<path>'.",
+                "anyOf": [
+                    {"enum": ["inherit", "null"]},
+                    {"type": "string", "pattern": "^file:."}
+                ]
+            },
+            "wait_condition": {
+                "type": "object",
+                "properties": conditions,
+                "additionalProperties": false,
+                "oneOf": [
+                    {"required": ["port"]},
+                    {"required": ["url"]},
+                    {"required": ["file"]}
+                ]
+            }
+        }
+    })
+}
+
+pub(crate) fn described(description: &str, mut schema: Value) -> Value {
+    schema["description"] = description.into();
+    schema
+}
+
+pub(crate) fn integer(minimum: u64) -> Value {
+    json!({"type": "integer", "minimum": minimum})
+}
+
+pub(crate) fn boolean() -> Value {
+    json!({"type": "boolean"})
+}
+
+pub(crate) fn string() -> Value {
+    json!({"type": "string"})
+}
+
+pub(crate) fn string_array() -> Value {
+    json!({"type": "array", "items": {"type": "string"}})
+}
+
+pub(crate) fn definition(name: &str) -> Value {
+    json!({ "$ref": format!("#/definitions/{}", name) })
+}
+
+/// The schemas of the keys `get_config` handles before a layer is parsed.
+fn layering_key_schema(key: &str) -> Value {
+    match key {
+        "extends" => described("Path of a config file this one builds on.", string()),
+        "matrix" => described(
+            "Dimensions whose combinations become variants. Each is an array of \
+             values, or an object of labels to values or configs.",
+            json!({
+                "type": "object",
+                "additionalProperties": {
+                    "anyOf": [
+                        {"type": "array", "items": definition("scalar"), "minItems": 1},
+                        {
+                            "type": "object",
+                            "minProperties": 1,
+                            "additionalProperties": {
+                                "anyOf": [definition("scalar"), definition("config")]
+                            }
+                        }
+                    ]
+                }
+            }),
+        ),
+        "benchmarks" => described(
+            "Separate benchmarks in one file, layered on top of the rest of it.",
+            json!({
+                "type": "object",
+                "minProperties": 1,
+                "additionalProperties": definition("config")
+            }),
+        ),
+        "variants" => described(
+            "Configs layered on top of this one, run one at a time.",
+            json!({
+                "anyOf": [
+                    {"type": "array", "items": definition("config")},
+                    {"type": "object", "additionalProperties": definition("config")}
+                ]
+            }),
+        ),
+        _ => unreachable!("layering key '{}' has no schema", key),
+    }
+}
+
+fn condition_key_schema(key: &str) -> Value {
+    match key {
+        "port" => json!({"type": "integer", "minimum": 0, "maximum": 65535}),
+        "host" | "url" | "file" => string(),
+        "status" => json!({"type": "integer", "minimum": 0, "maximum": 65535}),
+        "timeout" | "interval_ms" => integer(0),
+        _ => unreachable!("'wait_for' key '{}' has no schema", key),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::*;
+
+    #[test]
+    fn covers_known_keys() {
+        let schema = config_schema();
+        let properties = schema["definitions"]["config"]["properties"]
+            .as_object()
+            .unwrap();
+        let mut keys: Vec<&str> = properties.keys().map(String::as_str).collect();
+        let mut known = KNOWN_KEYS.to_vec();
+        keys.sort_unstable();
+        known.sort_unstable();
+        assert_eq!(keys, known);
+        for (key, property) in properties {
+            assert!(
+                property["description"]
+                    .as_str()
+                    .is_some_and(|d| !d.is_empty()),
+                "'{}' has no description",
+                key
+            );
+        }
+    }
+}
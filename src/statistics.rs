@@ -0,0 +1,360 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+//! The statistics `--summarize` can give for each metric, chosen with
+//! `--stats`.
+
+use anyhow::*;
+use log::warn;
+
+/// A statistic of a metric's values over the iterations.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Statistic {
+    Mean,
+    Stddev,
+    StddevPct,
+    Min,
+    Max,
+    Count,
+    CoveragePct,
+    Geomean,
+    /// A percentile, from 0 to 100.
+    Percentile(f64),
+}
+
+/// What's given when `--stats` isn't.
+pub(crate) const DEFAULT_STATISTICS: &[Statistic] = &[
+    Statistic::Mean,
+    Statistic::Stddev,
+    Statistic::StddevPct,
+    Statistic::Min,
+    Statistic::Max,
+    Statistic::Count,
+    Statistic::CoveragePct,
+];
+
+impl Statistic {
+    fn parse(name: &str) -> Result<Self> {
+        Ok(match name {
+            "mean" => Statistic::Mean,
+            "stddev" => Statistic::Stddev,
+            "stddev_pct" => Statistic::StddevPct,
+            "min" => Statistic::Min,
+            "max" => Statistic::Max,
+            "count" => Statistic::Count,
+            "coverage_pct" => Statistic::CoveragePct,
+            "geomean" => Statistic::Geomean,
+            _ => match name.strip_prefix('p').and_then(|p| p.parse::<f64>().ok()) {
+                Some(p) if (0.0..=100.0).contains(&p) => Statistic::Percentile(p),
+                _ => bail!(
+                    "unknown statistic '{}', expected mean, stddev, stddev_pct, min, max, \
+                     count, coverage_pct, geomean or a percentile from p0 to p100",
+                    name
+                ),
+            },
+        })
+    }
+
+    /// Parses a comma-separated list of statistics, like `mean,stddev,p95`.
+    pub(crate) fn parse_list(spec: &str) -> Result<Vec<Self>> {
+        spec.split(',')
+            .map(|name| Statistic::parse(name.trim()))
+            .collect()
+    }
+
+    /// The statistic's key in a summary.
+    pub(crate) fn name(&self) -> String {
+        match self {
+            Statistic::Mean => "mean".into(),
+            Statistic::Stddev => "stddev".into(),
+            Statistic::StddevPct => "stddev_pct".into(),
+            Statistic::Min => "min".into(),
+            Statistic::Max => "max".into(),
+            Statistic::Count => "count".into(),
+            Statistic::CoveragePct => "coverage_pct".into(),
+            Statistic::Geomean => "geomean".into(),
+            Statistic::Percentile(p) => format!("p{}", p),
+        }
+    }
+
+    /// The statistic of a metric's `items`, out of `total` iterations, or
+    /// `None` if it has none.
+    pub(crate) fn compute(&self, metric: &str, items: &[f64], total: usize) -> Option<f64> {
+        Some(match self {
+            Statistic::Mean => mean(items),
+            Statistic::Stddev => stddev(mean(items), items),
+            Statistic::StddevPct => {
+                let m = mean(items);
+                (stddev(m, items) / m) * 100.0
+            }
+            Statistic::Min => items.iter().fold(f64::INFINITY, |a, &b| a.min(b)),
+            Statistic::Max => items.iter().fold(-f64::INFINITY, |a, &b| a.max(b)),
+            Statistic::Count => items.len() as f64,
+            // Shows iterations that didn't send a metric, like when the app
+            // crashed before a statsd gauge.
+            Statistic::CoveragePct => items.len() as f64 / total as f64 * 100.0,
+            Statistic::Geomean => {
+                let (geomean, skipped) = geomean(items);
+                if skipped > 0 {
+                    warn!(
+                        "the geomean of '{}' leaves out {} of its {} values, which aren't positive",
+                        metric,
+                        skipped,
+                        items.len()
+                    );
+                }
+                geomean?
+            }
+            Statistic::Percentile(p) => percentile(items, *p),
+        })
+    }
+}
+
+pub(crate) fn mean(items: &[f64]) -> f64 {
+    let len = items.len() as f64;
+    let total: f64 = items.iter().sum();
+    total / len
+}
+
+pub(crate) fn stddev(m: f64, items: &[f64]) -> f64 {
+    mean(
+        &items
+            .iter()
+            .map(|x| f64::powf(x - m, 2.0))
+            .collect::<Vec<f64>>(),
+    )
+    .sqrt()
+}
+
+/// The geometric mean of the positive items, which are the only ones that
+/// have one, along with how many others were left out.
+fn geomean(items: &[f64]) -> (Option<f64>, usize) {
+    let positive: Vec<f64> = items.iter().copied().filter(|x| *x > 0.0).collect();
+    let skipped = items.len() - positive.len();
+    if positive.is_empty() {
+        return (None, skipped);
+    }
+    // Summing logarithms doesn't overflow the way multiplying can.
+    let logs: Vec<f64> = positive.iter().map(|x| x.ln()).collect();
+    (Some(mean(&logs).exp()), skipped)
+}
+
+/// The `p`th percentile, interpolating linearly between the closest ranks.
+fn percentile(items: &[f64], p: f64) -> f64 {
+    let mut sorted = items.to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let rank = p / 100.0 * (sorted.len() - 1) as f64;
+    let (lower, upper) = (rank.floor() as usize, rank.ceil() as usize);
+    sorted[lower] + (sorted[upper] - sorted[lower]) * (rank - lower as f64)
+}
+
+/// The two-tailed p-value of Welch's t-test of whether two samples have the
+/// same mean, or `None` if either has fewer than two values.
+pub(crate) fn welch_p_value(a: &[f64], b: &[f64]) -> Option<f64> {
+    if a.len() < 2 || b.len() < 2 {
+        return None;
+    }
+    let variance = |items: &[f64], m: f64| {
+        items.iter().map(|x| (x - m).powi(2)).sum::<f64>() / (items.len() - 1) as f64
+    };
+    let (mean_a, mean_b) = (mean(a), mean(b));
+    let error_a = variance(a, mean_a) / a.len() as f64;
+    let error_b = variance(b, mean_b) / b.len() as f64;
+    let error = error_a + error_b;
+    // Without any variance, the means are either the same or certainly not.
+    if error == 0.0 {
+        return Some(if mean_a == mean_b { 1.0 } else { 0.0 });
+    }
+    let t = (mean_a - mean_b) / error.sqrt();
+    let df = error.powi(2)
+        / (error_a.powi(2) / (a.len() - 1) as f64 + error_b.powi(2) / (b.len() - 1) as f64);
+    Some(student_t_p_value(t, df))
+}
+
+/// The two-tailed p-value of `t` in Student's t-distribution with `df`
+/// degrees of freedom.
+fn student_t_p_value(t: f64, df: f64) -> f64 {
+    incomplete_beta(df / 2.0, 0.5, df / (df + t * t))
+}
+
+/// The regularized incomplete beta function, evaluated with its continued
+/// fraction as in Numerical Recipes.
+fn incomplete_beta(a: f64, b: f64, x: f64) -> f64 {
+    if x <= 0.0 {
+        return 0.0;
+    }
+    if x >= 1.0 {
+        return 1.0;
+    }
+    let front =
+        (ln_gamma(a + b) - ln_gamma(a) - ln_gamma(b) + a * x.ln() + b * (1.0 - x).ln()).exp();
+    // The continued fraction converges quickly only on this side.
+    if x < (a + 1.0) / (a + b + 2.0) {
+        front * beta_fraction(a, b, x) / a
+    } else {
+        1.0 - front * beta_fraction(b, a, 1.0 - x) / b
+    }
+}
+
+/// The continued fraction of the incomplete beta function, with Lentz's
+/// method.
+fn beta_fraction(a: f64, b: f64, x: f64) -> f64 {
+    const TINY: f64 = 1e-300;
+    let mut c = 1.0;
+    let mut d = 1.0 - (a + b) * x / (a + 1.0);
+    if d.abs() < TINY {
+        d = TINY;
+    }
+    d = 1.0 / d;
+    let mut result = d;
+    for m in 1..=300 {
+        let m = m as f64;
+        let even = m * (b - m) * x / ((a + 2.0 * m - 1.0) * (a + 2.0 * m));
+        let odd = -(a + m) * (a + b + m) * x / ((a + 2.0 * m) * (a + 2.0 * m + 1.0));
+        for step in &[even, odd] {
+            d = 1.0 + step * d;
+            if d.abs() < TINY {
+                d = TINY;
+            }
+            c = 1.0 + step / c;
+            if c.abs() < TINY {
+                c = TINY;
+            }
+            d = 1.0 / d;
+            result *= d * c;
+        }
+        if (d * c - 1.0).abs() < 1e-15 {
+            break;
+        }
+    }
+    result
+}
+
+/// The logarithm of the gamma function, with the Lanczos approximation.
+fn ln_gamma(x: f64) -> f64 {
+    const COEFFICIENTS: [f64; 9] = [
+        0.999_999_999_999_809_9,
+        676.520_368_121_885_1,
+        -1_259.139_216_722_402_8,
+        771.323_428_777_653_1,
+        -176.615_029_162_140_6,
+        12.507_343_278_686_905,
+        -0.138_571_095_265_720_12,
+        9.984_369_578_019_572e-6,
+        1.505_632_735_149_311_6e-7,
+    ];
+    if x < 0.5 {
+        // The reflection formula covers what the approximation doesn't.
+        let pi = std::f64::consts::PI;
+        return (pi / (pi * x).sin()).ln() - ln_gamma(1.0 - x);
+    }
+    let x = x - 1.0;
+    let t = x + 7.5;
+    let sum = COEFFICIENTS[1..]
+        .iter()
+        .enumerate()
+        .fold(COEFFICIENTS[0], |sum, (i, c)| {
+            sum + c / (x + i as f64 + 1.0)
+        });
+    0.5 * (2.0 * std::f64::consts::PI).ln() + (x + 0.5) * t.ln() - t + sum.ln()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn geometric_mean() {
+        assert_eq!(geomean(&[4.0]), (Some(4.0), 0));
+        let (g, skipped) = geomean(&[1.0, 2.0, 4.0, 8.0]);
+        assert!(close(g.unwrap(), 8f64.sqrt()));
+        assert_eq!(skipped, 0);
+        let (g, skipped) = geomean(&[2.0, 0.0, 8.0, -3.0]);
+        assert!(close(g.unwrap(), 4.0));
+        assert_eq!(skipped, 2);
+        assert_eq!(geomean(&[0.0, -1.0]), (None, 2));
+        // Too big to multiply together, but not to average.
+        let (g, _) = geomean(&[1e200, 1e200, 1e200]);
+        assert!((g.unwrap() / 1e200 - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn percentiles() {
+        let items: Vec<f64> = (1..=100).rev().map(f64::from).collect();
+        assert!(close(percentile(&items, 0.0), 1.0));
+        assert!(close(percentile(&items, 50.0), 50.5));
+        assert!(close(percentile(&items, 95.0), 95.05));
+        assert!(close(percentile(&items, 100.0), 100.0));
+        assert!(close(percentile(&[3.0], 99.0), 3.0));
+        assert!(close(percentile(&[10.0, 20.0], 25.0), 12.5));
+    }
+
+    #[test]
+    fn t_test() {
+        // Closed forms for one, two and four degrees of freedom.
+        for &t in &[0.0f64, 0.5, 1.0, 3.0, 12.0] {
+            let cauchy = 1.0 - 2.0 / std::f64::consts::PI * t.atan();
+            assert!(close(student_t_p_value(t, 1.0), cauchy), "{}", t);
+            let two = 1.0 - t / (2.0 + t * t).sqrt();
+            assert!(close(student_t_p_value(-t, 2.0), two), "{}", t);
+        }
+        assert!(close(ln_gamma(1.0), 0.0));
+        assert!(close(ln_gamma(5.0), 24f64.ln()));
+        assert!(close(ln_gamma(0.5), std::f64::consts::PI.sqrt().ln()));
+        // As scipy.stats.ttest_ind(a, b, equal_var=False) has it.
+        let p = welch_p_value(&[1.0, 2.0, 3.0], &[4.0, 5.0, 6.0]).unwrap();
+        assert!(close(p, 0.021_311_641_128_756_6), "{}", p);
+        assert_eq!(welch_p_value(&[1.0, 2.0], &[3.0]), None);
+        assert_eq!(welch_p_value(&[2.0, 2.0], &[2.0, 2.0]), Some(1.0));
+        assert_eq!(welch_p_value(&[2.0, 2.0], &[3.0, 3.0]), Some(0.0));
+        // A sample compared with itself is as alike as can be.
+        let same = [3.0, 1.0, 4.0, 1.0, 5.0];
+        assert!(close(welch_p_value(&same, &same).unwrap(), 1.0));
+    }
+
+    #[test]
+    fn parsing() {
+        assert_eq!(
+            Statistic::parse_list("mean, stddev,p95,geomean,p99.9").unwrap(),
+            vec![
+                Statistic::Mean,
+                Statistic::Stddev,
+                Statistic::Percentile(95.0),
+                Statistic::Geomean,
+                Statistic::Percentile(99.9),
+            ]
+        );
+        assert_eq!(Statistic::Percentile(99.9).name(), "p99.9");
+        assert_eq!(Statistic::Percentile(95.0).name(), "p95");
+        for bad in &["median", "p101", "p", ""] {
+            assert!(Statistic::parse_list(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn defaults() {
+        let items = [1000.0, 3000.0];
+        let stats: Vec<(String, Option<f64>)> = DEFAULT_STATISTICS
+            .iter()
+            .map(|stat| (stat.name(), stat.compute("wall.time", &items, 4)))
+            .collect();
+        assert_eq!(
+            stats,
+            vec![
+                ("mean".into(), Some(2000.0)),
+                ("stddev".into(), Some(1000.0)),
+                ("stddev_pct".into(), Some(50.0)),
+                ("min".into(), Some(1000.0)),
+                ("max".into(), Some(3000.0)),
+                ("count".into(), Some(2.0)),
+                ("coverage_pct".into(), Some(50.0)),
+            ]
+        );
+    }
+}
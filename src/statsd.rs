@@ -1,51 +1,586 @@
+use crate::config::*;
 use crate::metric_value::*;
+use crate::timestamp::*;
 use anyhow::*;
 use async_std::{
-    net::UdpSocket,
-    sync::{Arc, Barrier, RwLock},
+    io::{prelude::BufReadExt, BufReader},
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::{Arc, RwLock},
+    task::{sleep, spawn},
 };
-use std::{collections::HashMap, env};
+use log::{trace, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    env,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Where the statsd listener binds unless `SIRUN_STATSD_HOST` or the config's
+/// `statsd_host` say otherwise.
+const DEFAULT_STATSD_HOST: &str = "127.0.0.1";
+
+/// Tells every command sirun spawns the `host:port` to send metrics to.
+pub(crate) const STATSD_ADDR_ENV: &str = "SIRUN_STATSD_ADDR";
+
+/// Tells every command sirun spawns whether to send metrics over UDP or TCP.
+pub(crate) const STATSD_PROTOCOL_ENV: &str = "SIRUN_STATSD_PROTOCOL";
+
+/// The transport the statsd listener takes metrics over, with the
+/// `statsd_protocol` setting.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum StatsdProtocol {
+    Udp,
+    Tcp,
+}
+
+impl StatsdProtocol {
+    pub(crate) fn parse(name: &str) -> Option<Self> {
+        match name {
+            "udp" => Some(StatsdProtocol::Udp),
+            "tcp" => Some(StatsdProtocol::Tcp),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            StatsdProtocol::Udp => "udp",
+            StatsdProtocol::Tcp => "tcp",
+        }
+    }
+}
+
+/// The bound statsd socket, for whichever protocol is used.
+pub(crate) enum StatsdSocket {
+    Udp(UdpSocket),
+    Tcp(TcpListener),
+}
+
+impl StatsdSocket {
+    pub(crate) fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(match self {
+            StatsdSocket::Udp(socket) => socket.local_addr()?,
+            StatsdSocket::Tcp(listener) => listener.local_addr()?,
+        })
+    }
+}
+
+/// Binds the statsd socket. The address is taken from `SIRUN_STATSD_HOST` if
+/// set, otherwise from `statsd_host`, and the port from `SIRUN_STATSD_PORT`
+/// if set, otherwise an available one is assigned.
+pub(crate) async fn bind_statsd(config: &Config) -> Result<StatsdSocket> {
+    let host = env::var("SIRUN_STATSD_HOST")
+        .ok()
+        .or_else(|| config.statsd_host.clone())
+        .unwrap_or_else(|| DEFAULT_STATSD_HOST.to_owned());
+    let port: u16 = env::var("SIRUN_STATSD_PORT").map_or(0, |p| p.parse().unwrap_or(0));
+    let address = match host.contains(':') {
+        true => format!("[{}]:{}", host, port),
+        false => format!("{}:{}", host, port),
+    };
+    let socket = match config.statsd_protocol {
+        StatsdProtocol::Udp => UdpSocket::bind((host.as_str(), port))
+            .await
+            .map(StatsdSocket::Udp),
+        StatsdProtocol::Tcp => TcpListener::bind((host.as_str(), port))
+            .await
+            .map(StatsdSocket::Tcp),
+    };
+    socket.with_context(|| format!("cannot bind statsd to {}", address))
+}
+
+/// The address to send metrics to for them to reach a listener bound to
+/// `bound`. That's the same address, unless it's a wildcard like 0.0.0.0,
+/// which is reached over loopback.
+pub(crate) fn statsd_addr(bound: SocketAddr) -> SocketAddr {
+    let ip = match bound.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => Ipv4Addr::LOCALHOST.into(),
+        IpAddr::V6(ip) if ip.is_unspecified() => Ipv6Addr::LOCALHOST.into(),
+        ip => ip,
+    };
+    SocketAddr::new(ip, bound.port())
+}
+
+/// What the statsd listener has received for the current iteration.
+#[derive(Default)]
+pub(crate) struct StatsdBuffer {
+    data: String,
+    /// When each packet with anything but sirun's own metrics arrived, in
+    /// microseconds since the Unix epoch. They're all kept, since only the
+    /// iteration child knows when the `run` command was spawned, and those
+    /// sent before that, such as by `setup` or a service, don't count.
+    metric_arrivals: Vec<f64>,
+    /// Whether an iteration is running, from `open_window` until its metrics
+    /// are read.
+    window_open: bool,
+    /// How many metrics arrived while no iteration was running, such as from
+    /// a process the last one left behind. They're counted rather than kept,
+    /// so that they aren't taken for the next iteration's.
+    out_of_window: u64,
+}
+
+impl StatsdBuffer {
+    fn push(&mut self, datum: &str, arrived_at: SystemTime) {
+        if !self.window_open {
+            let lines = datum.lines().filter(|line| !line.trim().is_empty()).count();
+            self.out_of_window += lines as u64;
+            return;
+        }
+        let sent_by_benchmark = datum
+            .lines()
+            .any(|line| !line.trim().is_empty() && !line.contains(OWN_METRIC_TAG));
+        if sent_by_benchmark {
+            self.metric_arrivals.push(epoch_us(arrived_at));
+        }
+        self.data.push_str(datum);
+    }
+
+    /// Drops everything received so far, such as by a failed attempt, and
+    /// closes the window until the next one is opened. What arrived out of
+    /// the window is still counted.
+    pub(crate) fn clear(&mut self) {
+        self.data.clear();
+        self.metric_arrivals.clear();
+        self.window_open = false;
+    }
+
+    /// Counts again what arrived out of the window according to metrics
+    /// already read for an attempt, so that retrying it doesn't lose them.
+    pub(crate) fn recount_out_of_window(&mut self, metrics: &MetricMap) {
+        if let Some(Ok(count)) = metrics.get(OUT_OF_WINDOW_KEY).map(MetricValue::as_f64) {
+            self.out_of_window += count as u64;
+        }
+    }
+
+    /// Starts keeping what's received, for the iteration that's starting.
+    pub(crate) fn open_window(&mut self) {
+        self.window_open = true;
+    }
+}
 
 pub(crate) async fn statsd_listener(
-    barrier: Arc<Barrier>,
-    statsd_buf: Arc<RwLock<String>>,
+    socket: StatsdSocket,
+    statsd_buf: Arc<RwLock<StatsdBuffer>>,
 ) -> Result<()> {
-    // If the env var is set, we'll use it, otherwise use 0 to grab an available port.
-    let port: u16 = env::var("SIRUN_STATSD_PORT").map_or(0, |p| p.parse().unwrap_or(0));
-    let socket = UdpSocket::bind(format!("127.0.0.1:{}", port)).await;
     let socket = match socket {
-        Ok(s) => s,
-        Err(error) => panic!("Cannot bind to 127.0.0.1:{}: {}", port, error),
+        StatsdSocket::Udp(socket) => socket,
+        // A connection that can't be accepted or read loses only its own
+        // metrics, so the listener keeps going.
+        StatsdSocket::Tcp(listener) => loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _peer)) => stream,
+                Err(e) => {
+                    warn!("cannot accept a statsd connection: {:#}", e);
+                    // Running out of file descriptors, say, doesn't pass at once.
+                    sleep(Duration::from_millis(10)).await;
+                    continue;
+                }
+            };
+            let statsd_buf = statsd_buf.clone();
+            spawn(async move {
+                if let Err(e) = statsd_connection(stream, statsd_buf).await {
+                    warn!("cannot read from a statsd connection: {:#}", e);
+                }
+            });
+        },
     };
-    let port = socket.local_addr()?.port();
-    env::set_var("SIRUN_STATSD_PORT", format!("{}", port));
-    barrier.wait().await; // indicates to main task that socket is listening
-
     loop {
-        let mut buf = vec![0u8; 4096];
+        // Big enough for the largest UDP packet.
+        let mut buf = vec![0u8; 65536];
         let (recv, _peer) = socket.recv_from(&mut buf).await?;
+        let arrived_at = SystemTime::now();
 
         let datum = String::from_utf8(buf[..recv].into()).unwrap_or_else(|_| String::new());
-        statsd_buf.write().await.push_str(&datum);
+        for line in datum.lines() {
+            trace!("statsd received {}", line);
+        }
+        statsd_buf.write().await.push(&datum, arrived_at);
+    }
+}
+
+/// Reads line-delimited metrics from a TCP connection until it's closed.
+/// Sirun's own are kept back until then, so that they arrive all at once, as
+/// they do in a single UDP packet.
+async fn statsd_connection(stream: TcpStream, statsd_buf: Arc<RwLock<StatsdBuffer>>) -> Result<()> {
+    let mut stream = BufReader::new(stream);
+    let mut own = String::new();
+    let mut line = Vec::new();
+    while stream.read_until(b'\n', &mut line).await? > 0 {
+        let arrived_at = SystemTime::now();
+        let mut datum = String::from_utf8_lossy(&line).into_owned();
+        line.clear();
+        if !datum.ends_with('\n') {
+            datum.push('\n');
+        }
+        trace!("statsd received {}", datum.trim_end());
+        if datum.contains(OWN_METRIC_TAG) {
+            own.push_str(&datum);
+        } else {
+            statsd_buf.write().await.push(&datum, arrived_at);
+        }
+    }
+    if !own.is_empty() {
+        statsd_buf.write().await.push(&own, SystemTime::now());
+    }
+    Ok(())
+}
+
+/// How long the metrics of an iteration child that has exited may take to be
+/// received.
+const STATSD_GRACE: Duration = Duration::from_millis(500);
+
+/// Waits for the metrics of an iteration child that has exited, which can
+/// still be on their way to the listener, and which can be told apart from
+/// the benchmark's by their tag. A child that died before sending any is
+/// given up on after `STATSD_GRACE`.
+pub(crate) async fn await_statsd(udp_data: &Arc<RwLock<StatsdBuffer>>) {
+    let start = Instant::now();
+    while !udp_data.read().await.data.contains(OWN_METRIC_TAG) && start.elapsed() < STATSD_GRACE {
+        sleep(Duration::from_millis(1)).await;
+    }
+}
+
+/// Tag on the metrics an iteration child measured itself, which tells them
+/// apart from those the benchmark sent.
+pub(crate) const OWN_METRIC_TAG: &str = "#sirun";
+
+/// What the names of spans start with, unless they're sent as timers.
+const SPAN_PREFIX: &str = "span.";
+
+/// The name of the span a line of the benchmark's reports, if it's a timer
+/// (`|ms`) or its name starts with `SPAN_PREFIX`.
+fn span_name<'a>(name: &'a str, kind: Option<&str>) -> Option<&'a str> {
+    match name.strip_prefix(SPAN_PREFIX) {
+        Some(span) if !span.is_empty() => Some(span),
+        Some(_) => None,
+        None if kind == Some("ms") => Some(name),
+        None => None,
     }
 }
 
+/// The metrics received for an iteration. With `nest`, dotted names of those
+/// the benchmark sent are folded into nested maps, while sirun's own stay as
+/// they are. Spans are gathered under `SPANS_KEY`, with the milliseconds of
+/// each span's reports summed, and how many there were. The time to the
+/// first metric the benchmark sent is added, if it sent any after the `run`
+/// command was started, as is how many metrics arrived before the iteration
+/// started, if any did. The window is closed, in the same write as the
+/// metrics are taken, so that none that arrive meanwhile are lost.
 pub(crate) async fn get_statsd_metrics(
-    udp_data: Arc<RwLock<String>>,
-) -> Result<HashMap<String, MetricValue>> {
-    let mut metrics = HashMap::new();
-    let udp_string = udp_data.read().await.clone();
+    udp_data: Arc<RwLock<StatsdBuffer>>,
+    nest: bool,
+) -> Result<MetricMap> {
+    let mut metrics = MetricMap::new();
+    let mut sent = Vec::new();
+    let mut spans: BTreeMap<&str, (f64, u64)> = BTreeMap::new();
+    let StatsdBuffer {
+        data: udp_string,
+        metric_arrivals,
+        out_of_window,
+        ..
+    } = std::mem::take(&mut *udp_data.write().await);
     let lines = udp_string.trim().lines();
-    udp_data.write().await.clear();
     for line in lines {
-        let metric: Vec<&str> = match line.split('|').next() {
+        let mut fields = line.split('|');
+        let metric: Vec<&str> = match fields.next() {
             None => continue,
             Some(metric) => metric.split(':').collect(),
         };
         if metric.len() < 2 {
             continue;
         }
-        metrics.insert(metric[0].into(), metric[1].parse::<f64>()?.into());
+        // Anything that isn't a number, like a build label, is kept as a
+        // string.
+        let value = match metric[1].parse::<f64>() {
+            Ok(number) => number.into(),
+            Err(_) => metric[1].to_owned().into(),
+        };
+        let kind = fields.next();
+        let own = kind == Some(OWN_METRIC_TAG) || fields.any(|field| field == OWN_METRIC_TAG);
+        if let (false, Some(span), MetricValue::Num(ms)) = (own, span_name(metric[0], kind), &value)
+        {
+            let (total, count) = spans.entry(span).or_default();
+            *total += ms;
+            *count += 1;
+        } else if nest && !own {
+            sent.push((metric[0], value));
+        } else {
+            metrics.insert(metric[0].into(), value);
+        }
+    }
+    for (name, value) in sent {
+        insert_nested(&mut metrics, name, value)?;
+    }
+    if !spans.is_empty() {
+        let spans = spans
+            .into_iter()
+            .map(|(span, (total, count))| {
+                let mut reports = MetricMap::new();
+                reports.insert("ms".into(), total.into());
+                reports.insert("count".into(), count.into());
+                (span.to_owned(), reports.into())
+            })
+            .collect::<MetricMap>();
+        metrics.insert(SPANS_KEY.into(), spans.into());
+    }
+    let spawned_at = metrics.remove(SPAWN_EPOCH_US_KEY);
+    if let Some(MetricValue::Num(spawned_at)) = spawned_at {
+        // Whatever arrived before `run` started, such as from a service,
+        // wasn't sent by it.
+        if let Some(first_metric_at) = metric_arrivals
+            .into_iter()
+            .find(|arrived_at| *arrived_at >= spawned_at)
+        {
+            metrics.insert(
+                TIME_TO_FIRST_METRIC_KEY.into(),
+                (first_metric_at - spawned_at).into(),
+            );
+        }
+    }
+    if out_of_window > 0 {
+        metrics.insert(OUT_OF_WINDOW_KEY.into(), out_of_window.into());
     }
     Ok(metrics)
 }
+
+/// What the names of forwarded metrics start with when `statsd_prefix` isn't
+/// set.
+pub(crate) const DEFAULT_STATSD_PREFIX: &str = "sirun.";
+
+/// The largest packet the Datadog agent reads by default.
+const FORWARD_PACKET_SIZE: usize = 8192;
+
+/// Adds a DogStatsD gauge for a numeric metric, or for each of those in a
+/// group, under its dotted name.
+fn gauge_lines(name: &str, value: &MetricValue, suffix: &str, lines: &mut Vec<String>) {
+    match value {
+        MetricValue::Num(value) => lines.push(format!("{}:{}|g{}", name, value, suffix)),
+        MetricValue::Map(group) => {
+            for (key, value) in group {
+                gauge_lines(&format!("{}.{}", name, key), value, suffix, lines);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// The lines for the metrics of an iteration, with the config's prefix and
+/// tags for its name and variant.
+fn forwarded_lines(config: &Config, metrics: &MetricMap) -> Vec<String> {
+    let prefix = config
+        .statsd_prefix
+        .as_deref()
+        .unwrap_or(DEFAULT_STATSD_PREFIX);
+    let mut tags = Vec::new();
+    if let Some(name) = config.name.as_ref().or(config.benchmark.as_ref()) {
+        tags.push(format!("benchmark:{}", name));
+    }
+    if let Some(variant) = &config.variant {
+        tags.push(format!("variant:{}", variant));
+    }
+    let suffix = if tags.is_empty() {
+        String::new()
+    } else {
+        format!("|#{}", tags.join(","))
+    };
+    let mut lines = Vec::new();
+    for (name, value) in metrics {
+        if name == ITERATION_KEY || name == START_EPOCH_MS_KEY {
+            continue;
+        }
+        gauge_lines(&format!("{}{}", prefix, name), value, &suffix, &mut lines);
+    }
+    lines
+}
+
+/// Groups lines into as few packets as fit them.
+fn packets(lines: Vec<String>) -> Vec<String> {
+    let mut packets: Vec<String> = Vec::new();
+    for line in lines {
+        match packets.last_mut() {
+            Some(packet) if packet.len() + 1 + line.len() <= FORWARD_PACKET_SIZE => {
+                packet.push('\n');
+                packet.push_str(&line);
+            }
+            _ => packets.push(line),
+        }
+    }
+    packets
+}
+
+async fn send_gauges(address: &str, metrics: Vec<String>) -> Result<()> {
+    let sock = UdpSocket::bind("0.0.0.0:0").await?;
+    for packet in packets(metrics) {
+        sock.send_to(packet.as_bytes(), address).await?;
+    }
+    Ok(())
+}
+
+/// Relays an iteration's metrics to the DogStatsD agent at `statsd_forward`,
+/// if it's set. The agent not being there is no reason to stop benchmarking,
+/// so failing to is only warned about.
+pub(crate) async fn forward_statsd(config: &Config, metrics: &MetricMap) {
+    let address = match &config.statsd_forward {
+        Some(address) => address,
+        None => return,
+    };
+    if let Err(e) = send_gauges(address, forwarded_lines(config, metrics)).await {
+        warn!("cannot forward metrics to statsd at {}: {:#}", address, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forwarded() {
+        let mut config = Config::new(vec!["true"]).with_name("startup");
+        config.variant = Some("fast".to_owned());
+        config.statsd_prefix = Some("bench.".to_owned());
+        let mut metrics = MetricMap::new();
+        metrics.insert("wall.time".to_owned(), 812.0.into());
+        metrics.insert(ITERATION_KEY.to_owned(), 0.into());
+        let mut http = MetricMap::new();
+        http.insert("p50".to_owned(), 1.5.into());
+        metrics.insert("http".to_owned(), http.into());
+        metrics.insert("label".to_owned(), "nightly".to_owned().into());
+        assert_eq!(
+            forwarded_lines(&config, &metrics),
+            vec![
+                "bench.http.p50:1.5|g|#benchmark:startup,variant:fast",
+                "bench.wall.time:812|g|#benchmark:startup,variant:fast",
+            ]
+        );
+    }
+
+    #[test]
+    fn packed() {
+        let line = "x".repeat(3000);
+        let packed = packets(vec![line.clone(), line.clone(), line.clone()]);
+        assert_eq!(packed, vec![format!("{}\n{}", line, line), line]);
+    }
+
+    #[test]
+    fn time_to_first_metric() {
+        let at = |micros| std::time::UNIX_EPOCH + Duration::from_micros(micros);
+        let spawned = format!("{}:1000|g|{}\n", SPAWN_EPOCH_US_KEY, OWN_METRIC_TAG);
+        let mut buffer = StatsdBuffer::default();
+        buffer.open_window();
+        buffer.push("setup.done:1|g\n", at(500));
+        buffer.push("service.up:1|g\n", at(950));
+        buffer.push(&spawned, at(1100));
+        buffer.push("ready:1|g\n", at(4000));
+        buffer.push("done:1|g\n", at(9000));
+        let udp_data = Arc::new(RwLock::new(buffer));
+        async_std::task::block_on(async {
+            let metrics = get_statsd_metrics(udp_data.clone(), false).await.unwrap();
+            assert_eq!(metrics[TIME_TO_FIRST_METRIC_KEY], 3000.0.into());
+            assert!(!metrics.contains_key(SPAWN_EPOCH_US_KEY));
+            assert!(metrics.contains_key("setup.done"));
+
+            // Nothing from the benchmark, nothing to time.
+            udp_data.write().await.open_window();
+            udp_data.write().await.push("setup.done:1|g\n", at(500));
+            udp_data.write().await.push(&spawned, at(900));
+            let metrics = get_statsd_metrics(udp_data, false).await.unwrap();
+            assert!(!metrics.contains_key(TIME_TO_FIRST_METRIC_KEY));
+        });
+    }
+
+    #[test]
+    fn spans() {
+        let mut buffer = StatsdBuffer::default();
+        buffer.open_window();
+        let now = std::time::SystemTime::now();
+        buffer.push("span.require:12|ms\nspan.require:8|g\n", now);
+        buffer.push("connect:5|ms\nrequests:3|c\nspan.:1|g\n", now);
+        buffer.push(&format!("cpu.user:7|ms|{}\n", OWN_METRIC_TAG), now);
+        let udp_data = Arc::new(RwLock::new(buffer));
+        async_std::task::block_on(async {
+            let metrics = get_statsd_metrics(udp_data, true).await.unwrap();
+            let spans: MetricValue = serde_json::from_str(
+                r#"{"connect": {"ms": 5, "count": 1}, "require": {"ms": 20, "count": 2}}"#,
+            )
+            .unwrap();
+            assert_eq!(metrics[SPANS_KEY], spans);
+            assert_eq!(metrics["requests"], 3.0.into());
+            assert!(metrics.contains_key("span"));
+            assert_eq!(metrics["cpu.user"], 7.0.into());
+        });
+    }
+
+    #[test]
+    fn out_of_window() {
+        let now = std::time::SystemTime::now();
+        let udp_data = Arc::new(RwLock::new(StatsdBuffer::default()));
+        async_std::task::block_on(async {
+            // Left behind by the last iteration, or sent before the first.
+            udp_data.write().await.push("stray:1|g\n\nstray:2|g\n", now);
+            udp_data.write().await.open_window();
+            udp_data.write().await.push("ready:1|g\n", now);
+            let metrics = get_statsd_metrics(udp_data.clone(), false).await.unwrap();
+            assert_eq!(metrics[OUT_OF_WINDOW_KEY], 2.0.into());
+            assert!(!metrics.contains_key("stray"));
+
+            udp_data.write().await.open_window();
+            udp_data.write().await.push("ready:1|g\n", now);
+            let metrics = get_statsd_metrics(udp_data.clone(), false).await.unwrap();
+            assert!(!metrics.contains_key(OUT_OF_WINDOW_KEY));
+            assert_eq!(metrics.len(), 1);
+
+            // A retry drops what the failed attempt received, but not what
+            // was counted before it.
+            udp_data.write().await.push("late:1|g\n", now);
+            udp_data.write().await.open_window();
+            udp_data.write().await.push("failed:1|g\n", now);
+            udp_data.write().await.clear();
+            udp_data.write().await.open_window();
+            udp_data.write().await.push("ready:1|g\n", now);
+            let metrics = get_statsd_metrics(udp_data, false).await.unwrap();
+            assert_eq!(metrics[OUT_OF_WINDOW_KEY], 1.0.into());
+            assert!(!metrics.contains_key("failed"));
+        });
+    }
+
+    #[test]
+    fn wildcard_addr() {
+        let addr = |a: &str| statsd_addr(a.parse().unwrap()).to_string();
+        assert_eq!(addr("0.0.0.0:8125"), "127.0.0.1:8125");
+        assert_eq!(addr("[::]:8125"), "[::1]:8125");
+        assert_eq!(addr("10.1.2.3:8125"), "10.1.2.3:8125");
+    }
+
+    #[test]
+    fn tcp_connection() {
+        use async_std::io::prelude::WriteExt;
+        let statsd_buf = Arc::new(RwLock::new(StatsdBuffer::default()));
+        async_std::task::block_on(async {
+            statsd_buf.write().await.open_window();
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            spawn(statsd_listener(
+                StatsdSocket::Tcp(listener),
+                statsd_buf.clone(),
+            ));
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let own = format!("wall.time:5|g|{}\n", OWN_METRIC_TAG);
+            stream.write_all(own.as_bytes()).await.unwrap();
+            stream.write_all(b"ready:1|g\n").await.unwrap();
+            stream.flush().await.unwrap();
+            while !statsd_buf.read().await.data.contains("ready") {
+                sleep(Duration::from_millis(1)).await;
+            }
+            // Sirun's own wait for the connection to close.
+            assert!(!statsd_buf.read().await.data.contains(OWN_METRIC_TAG));
+            drop(stream);
+            await_statsd(&statsd_buf).await;
+            let metrics = get_statsd_metrics(statsd_buf, false).await.unwrap();
+            assert_eq!(metrics["ready"], 1.0.into());
+            assert_eq!(metrics["wall.time"], 5.0.into());
+        });
+    }
+}
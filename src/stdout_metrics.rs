@@ -0,0 +1,144 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+//! Metrics scraped from what the `run` command prints, with the
+//! `stdout_metrics` config key, for benchmarks that report results as text
+//! rather than over statsd.
+
+use anyhow::*;
+use async_std::{
+    io::{prelude::BufReadExt, BufReader},
+    process::ChildStdout,
+};
+use log::warn;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, io::Write};
+
+#[derive(Serialize, Deserialize, Clone)]
+struct StdoutMetric {
+    metric: String,
+    pattern: String,
+    sum: bool,
+}
+
+/// Patterns for lines of the `run` command's stdout, each with one capture
+/// group holding the value of a metric. Matches replace each other, so the
+/// last one wins, unless the metric sums them.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub(crate) struct StdoutMetrics {
+    rules: Vec<StdoutMetric>,
+}
+
+fn compile(pattern: &str) -> Result<Regex> {
+    let regex = Regex::new(pattern)?;
+    ensure!(
+        regex.captures_len() == 2,
+        "must have exactly one capture group, but has {}",
+        regex.captures_len() - 1
+    );
+    Ok(regex)
+}
+
+impl StdoutMetrics {
+    pub(crate) fn add(&mut self, metric: &str, pattern: &str, sum: bool) -> Result<()> {
+        compile(pattern)?;
+        self.rules.push(StdoutMetric {
+            metric: metric.to_owned(),
+            pattern: pattern.to_owned(),
+            sum,
+        });
+        Ok(())
+    }
+}
+
+/// Applies the patterns to each line of a command's stdout.
+struct Scanner<'a> {
+    rules: Vec<(&'a StdoutMetric, Regex)>,
+    values: BTreeMap<String, f64>,
+}
+
+impl<'a> Scanner<'a> {
+    fn new(metrics: &'a StdoutMetrics) -> Result<Self> {
+        let rules = metrics
+            .rules
+            .iter()
+            .map(|rule| Ok((rule, compile(&rule.pattern)?)))
+            .collect::<Result<_>>()?;
+        Ok(Scanner {
+            rules,
+            values: BTreeMap::new(),
+        })
+    }
+
+    fn scan(&mut self, line: &str) {
+        for (rule, regex) in &self.rules {
+            let captured = match regex.captures(line).and_then(|captures| captures.get(1)) {
+                Some(captured) => captured.as_str(),
+                None => continue,
+            };
+            let value = match captured.trim().parse::<f64>() {
+                Ok(value) => value,
+                Err(_) => {
+                    warn!(
+                        "{} matched {:?}, which isn't a number",
+                        rule.metric, captured
+                    );
+                    continue;
+                }
+            };
+            let total = self.values.entry(rule.metric.clone()).or_insert(0.0);
+            *total = if rule.sum { *total + value } else { value };
+        }
+    }
+}
+
+/// Copies `stdout` to `copy` until it closes, returning the metrics its
+/// lines matched.
+pub(crate) async fn scan_stdout(
+    stdout: ChildStdout,
+    mut copy: Box<dyn Write + Send>,
+    metrics: StdoutMetrics,
+) -> Result<BTreeMap<String, f64>> {
+    let mut scanner = Scanner::new(&metrics)?;
+    let mut stdout = BufReader::new(stdout);
+    let mut line = Vec::new();
+    while stdout.read_until(b'\n', &mut line).await? > 0 {
+        let _ = copy.write_all(&line);
+        scanner.scan(String::from_utf8_lossy(&line).trim_end_matches(&['\r', '\n'][..]));
+        line.clear();
+    }
+    let _ = copy.flush();
+    Ok(scanner.values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scanned() {
+        let mut metrics = StdoutMetrics::default();
+        metrics
+            .add("ops.per.sec", "ops/sec: ([0-9.]+)", false)
+            .unwrap();
+        metrics.add("errors", r"errors=(\d+)", true).unwrap();
+        let mut scanner = Scanner::new(&metrics).unwrap();
+        for line in &[
+            "ops/sec: 100.5",
+            "errors=2 ops/sec: 12345.6",
+            "errors=3",
+            "ops/sec: fast",
+        ] {
+            scanner.scan(line);
+        }
+        assert_eq!(scanner.values["ops.per.sec"], 12345.6);
+        assert_eq!(scanner.values["errors"], 5.0);
+
+        assert!(metrics.add("bad", "ops/sec: ([0-9.]+", false).is_err());
+        assert!(metrics.add("none", "ops/sec", false).is_err());
+        assert!(metrics.add("two", "(a)(b)", false).is_err());
+    }
+}
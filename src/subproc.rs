@@ -1,37 +1,72 @@
 use anyhow::*;
 use async_std::{
-    process::{Command, Child, Stdio},
+    io::ReadExt,
+    process::{Command, ChildStderr, Stdio},
     task::sleep,
 };
-use std::{collections::HashMap, env, os::unix::process::ExitStatusExt, time::Duration};
+use log::{debug, log_enabled, Level};
+use serde::{Deserialize, Serialize};
+use std::{
+    env,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    os::unix::process::ExitStatusExt,
+    time::Duration,
+};
 
 use crate::config::*;
+use crate::reaper::*;
+use crate::redact::*;
 
 async fn run_setup_or_teardown(typ: &str, config: &Config) -> Result<()> {
     if env::var("SIRUN_SKIP_SETUP").is_ok() {
         return Ok(());
     }
-    let command_arr = if typ == "setup" {
-        &config.setup
+    let (commands, stdio) = if typ == "setup" {
+        (&config.setup, &config.setup_stdio)
     } else {
-        &config.teardown
+        (&config.teardown, &config.teardown_stdio)
     };
-    let command_arr = match command_arr {
-        Some(command_arr) => command_arr,
+    let commands = match commands {
+        Some(commands) => commands,
         None => return Ok(()),
     };
-    let env = &config.env;
+    for (i, command_arr) in commands.iter().enumerate() {
+        let step = if commands.len() > 1 {
+            format!("{} step {} of {}", typ, i + 1, commands.len())
+        } else {
+            typ.to_owned()
+        };
+        run_until_success(&step, command_arr, stdio, config).await?;
+    }
+    Ok(())
+}
+
+/// Runs a setup or teardown command repeatedly until it exits successfully.
+async fn run_until_success(
+    typ: &str,
+    command_arr: &[String],
+    stdio: &Option<StdioTarget>,
+    config: &Config,
+) -> Result<()> {
     let mut code: i32 = 1;
     let mut attempts: u8 = 0;
     while code != 0 {
         if attempts == 100 {
             bail!("{} script did not complete successfully. aborting.", typ);
         }
-        let mut child = run_cmd(command_arr, env)?;
+        let stdio = open_stdio(stdio, typ, config)?;
+        let mut child = spawn_reapable(&mut build_cmd(command_arr, config, stdio), config)?;
         let status = child.status().await?;
         let maybe_code = status.code();
         if let Some(maybe_code) = maybe_code {
             code = maybe_code;
+            debug!(
+                "{} attempt {} exited with code {}",
+                typ,
+                attempts + 1,
+                code
+            );
             if code != 0 {
                 sleep(Duration::from_secs(1)).await;
                 attempts += 1;
@@ -64,17 +99,297 @@ fn get_stdio() -> Stdio {
     }
 }
 
-pub(crate) fn run_cmd(
+/// Where a command's stdout and stderr go, as set by the `*_stdio` config
+/// keys.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum StdioTarget {
+    Inherit,
+    Null,
+    /// Appended to, with a header before each command's output.
+    File(String),
+}
+
+impl StdioTarget {
+    pub(crate) fn parse(value: &str) -> Option<Self> {
+        match value {
+            "inherit" => Some(StdioTarget::Inherit),
+            "null" => Some(StdioTarget::Null),
+            _ => value
+                .strip_prefix("file:")
+                .filter(|path| !path.is_empty())
+                .map(|path| StdioTarget::File(path.to_owned())),
+        }
+    }
+}
+
+fn open_file(path: &str, typ: &str, config: &Config) -> Result<File> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("cannot open {} output file {}", typ, path))?;
+    match config.iteration {
+        Some(iteration) => writeln!(file, "==> {} (iteration {}) <==", typ, iteration)?,
+        None => writeln!(file, "==> {} <==", typ)?,
+    }
+    Ok(file)
+}
+
+/// Opens the stdout and stderr for one of the commands in `config`. Without
+/// an explicit target, `SIRUN_NO_STDIO` decides.
+pub(crate) fn open_stdio(
+    target: &Option<StdioTarget>,
+    typ: &str,
+    config: &Config,
+) -> Result<(Stdio, Stdio)> {
+    Ok(match target {
+        None => (get_stdio(), get_stdio()),
+        Some(StdioTarget::Inherit) => (Stdio::inherit(), Stdio::inherit()),
+        Some(StdioTarget::Null) => (Stdio::null(), Stdio::null()),
+        Some(StdioTarget::File(path)) => {
+            let file = open_file(path, typ, config)?;
+            (file.try_clone()?.into(), file.into())
+        }
+    })
+}
+
+fn tee_target(target: &Option<StdioTarget>) -> StdioTarget {
+    match target {
+        Some(target) => target.clone(),
+        None if env::var("SIRUN_NO_STDIO").is_ok() => StdioTarget::Null,
+        None => StdioTarget::Inherit,
+    }
+}
+
+/// Like `open_stdio`, except that stderr is returned as a writer, so that the
+/// caller can pipe the command's stderr through `tail_stderr`.
+pub(crate) fn open_stdio_tee(
+    target: &Option<StdioTarget>,
+    typ: &str,
+    config: &Config,
+) -> Result<(Stdio, Box<dyn Write + Send>)> {
+    Ok(match tee_target(target) {
+        StdioTarget::Inherit => (Stdio::inherit(), Box::new(io::stderr())),
+        StdioTarget::Null => (Stdio::null(), Box::new(io::sink())),
+        StdioTarget::File(path) => {
+            let file = open_file(&path, typ, config)?;
+            (file.try_clone()?.into(), Box::new(file))
+        }
+    })
+}
+
+/// Like `open_stdio_tee`, except that stdout is returned as a writer too, so
+/// that the caller can read the command's stdout through `scan_stdout`.
+pub(crate) fn open_stdio_pipes(
+    target: &Option<StdioTarget>,
+    typ: &str,
+    config: &Config,
+) -> Result<(Box<dyn Write + Send>, Box<dyn Write + Send>)> {
+    Ok(match tee_target(target) {
+        StdioTarget::Inherit => (Box::new(io::stdout()), Box::new(io::stderr())),
+        StdioTarget::Null => (Box::new(io::sink()), Box::new(io::sink())),
+        StdioTarget::File(path) => {
+            let file = open_file(&path, typ, config)?;
+            (Box::new(file.try_clone()?), Box::new(file))
+        }
+    })
+}
+
+/// How much of the end of a failed command's stderr gets reported.
+const STDERR_TAIL_BYTES: usize = 8 * 1024;
+
+/// Copies `stderr` to `copy` until it closes, returning the last
+/// `STDERR_TAIL_BYTES` of it.
+pub(crate) async fn tail_stderr(mut stderr: ChildStderr, mut copy: Box<dyn Write + Send>) -> Vec<u8> {
+    let mut tail = Vec::new();
+    let mut buf = [0; 4096];
+    while let Ok(len) = stderr.read(&mut buf).await {
+        if len == 0 {
+            break;
+        }
+        let _ = copy.write_all(&buf[..len]);
+        tail.extend_from_slice(&buf[..len]);
+        if tail.len() > STDERR_TAIL_BYTES {
+            tail.drain(..tail.len() - STDERR_TAIL_BYTES);
+        }
+    }
+    tail
+}
+
+/// Formats captured stderr for appending to an error report.
+pub(crate) fn stderr_report(tail: &[u8]) -> String {
+    if tail.is_empty() {
+        return String::new();
+    }
+    format!(
+        "\n\nLast {} bytes of stderr:\n{}",
+        tail.len(),
+        String::from_utf8_lossy(tail)
+    )
+}
+
+/// Points an iteration child at the file holding its serialized config.
+pub(crate) const ITERATION_CONFIG_ENV: &str = "SIRUN_ITERATION_CONFIG";
+
+/// The serialized config itself, as passed by older versions of sirun.
+pub(crate) const LEGACY_ITERATION_ENV: &str = "SIRUN_ITERATION";
+
+/// Sets up the working directory and environment shared by every command in
+/// `config`.
+pub(crate) fn configure_command(command: &mut Command, config: &Config) {
+    if let Some(cwd) = &config.cwd {
+        command.current_dir(cwd);
+    }
+    if config.clear_env {
+        command.env_clear();
+    }
+    // Only the iteration child itself should see these, never the commands it
+    // runs.
+    command
+        .env_remove(ITERATION_CONFIG_ENV)
+        .env_remove(LEGACY_ITERATION_ENV);
+    for name in &config.env_remove {
+        command.env_remove(name);
+    }
+    command.envs(&config.env);
+}
+
+/// How the environment commands are run with differs from sirun's own, for
+/// logging.
+fn env_changes(config: &Config) -> String {
+    let mut changes = String::new();
+    if config.clear_env {
+        changes.push_str(" with a cleared env");
+    }
+    for name in &config.env_remove {
+        if env::var_os(name).is_some() {
+            changes.push_str(&format!(" -{}", name));
+        }
+    }
+    for (name, value) in &config.env {
+        if config.clear_env || env::var(name).ok().as_ref() != Some(value) {
+            let shown = if is_secret(config, name) {
+                REDACTED
+            } else {
+                value
+            };
+            changes.push_str(&format!(" {}={}", name, shown));
+        }
+    }
+    changes
+}
+
+pub(crate) fn build_cmd(
     command_arr: &[String],
-    env: &HashMap<String, String>,
-) -> Result<Child> {
-    let command = command_arr[0].clone();
-    let args = command_arr.iter().skip(1);
-    Command::new(command)
-        .args(args)
-        .envs(env.clone())
-        .stdout(get_stdio())
-        .stderr(get_stdio())
-        .spawn()
-        .map_err(|e| e.into())
+    config: &Config,
+    (stdout, stderr): (Stdio, Stdio),
+) -> Command {
+    if log_enabled!(Level::Debug) {
+        debug!("spawning {:?}{}", command_arr, env_changes(config));
+    }
+    let mut command = Command::new(&command_arr[0]);
+    configure_command(&mut command, config);
+    command
+        .args(&command_arr[1..])
+        .stdout(stdout)
+        .stderr(stderr);
+    command
+}
+
+/// Pins the command to the config's `cpu_affinity` and gives it its `nice`
+/// level once it's forked, so its children inherit both. This is only done on
+/// Linux, and `get_config` warns that they're ignored elsewhere.
+pub(crate) fn apply_scheduling(command: &mut Command, config: &Config) {
+    #[cfg(target_os = "linux")]
+    {
+        use async_std::os::unix::process::CommandExt;
+        use nix::{
+            libc,
+            sched::{sched_setaffinity, CpuSet},
+            unistd::Pid,
+        };
+        if config.cpu_affinity.is_empty() && config.nice.is_none() {
+            return;
+        }
+        let cpus = match config.cpu_affinity.is_empty() {
+            true => None,
+            false => {
+                let mut cpus = CpuSet::new();
+                for cpu in &config.cpu_affinity {
+                    // Out of range CPUs are rejected when the config is read.
+                    let _ = cpus.set(*cpu);
+                }
+                Some(cpus)
+            }
+        };
+        let nice = config.nice;
+        unsafe {
+            command.pre_exec(move || {
+                if let Some(cpus) = &cpus {
+                    if sched_setaffinity(Pid::from_raw(0), cpus).is_err() {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                if let Some(nice) = nice {
+                    if libc::setpriority(libc::PRIO_PROCESS, 0, nice) == -1 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = (command, config);
+}
+
+/// Turns off address space layout randomization for the command once it's
+/// forked, like `setarch -R`, so that where its code, stack and heap end up
+/// doesn't vary between iterations. Its children inherit this. Only supported
+/// on Linux, and `get_config` warns that it's ignored elsewhere.
+pub(crate) fn apply_disable_aslr(command: &mut Command, config: &Config) {
+    #[cfg(target_os = "linux")]
+    {
+        use async_std::os::unix::process::CommandExt;
+        use nix::libc;
+        if !config.disable_aslr {
+            return;
+        }
+        unsafe {
+            command.pre_exec(|| {
+                // 0xffffffff reads the current persona without changing it.
+                let persona = libc::personality(0xffffffff);
+                if persona == -1
+                    || libc::personality((persona | libc::ADDR_NO_RANDOMIZE) as libc::c_ulong) == -1
+                {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = (command, config);
+}
+
+/// Has the kernel kill the command once the sirun process that started it
+/// is gone, so that killing an iteration child doesn't leave its `run`
+/// command behind. The kernel ties this to the spawning thread rather than
+/// the process, so it's only safe for commands spawned from the main thread.
+/// Only supported on Linux.
+pub(crate) fn die_with_parent(command: &mut Command) {
+    #[cfg(target_os = "linux")]
+    unsafe {
+        use async_std::os::unix::process::CommandExt;
+        use nix::libc::{prctl, PR_SET_PDEATHSIG, SIGKILL};
+        command.pre_exec(|| {
+            if prctl(PR_SET_PDEATHSIG, SIGKILL) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = command;
 }
@@ -0,0 +1,144 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+//! Running every config file in a directory, as a suite.
+
+use anyhow::*;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::glob::*;
+
+/// Which files in a suite directory are configs, when no `--pattern` is given.
+const DEFAULT_PATTERNS: &[&str] = &["*.json", "*.yml", "*.yaml"];
+
+/// Finds the config files in `dir` matching any of `patterns`, sorted so that
+/// a suite always runs in the same order. Patterns are matched against paths
+/// relative to `dir` a component at a time, so `*` doesn't match `/`, and
+/// `*/meta.json` finds the `meta.json` in each subdirectory.
+pub(crate) fn discover_configs(dir: &Path, patterns: &[String]) -> Result<Vec<PathBuf>> {
+    let patterns: Vec<Vec<&str>> = match patterns.is_empty() {
+        true => DEFAULT_PATTERNS.iter().map(|p| vec![*p]).collect(),
+        false => patterns.iter().map(|p| p.split('/').collect()).collect(),
+    };
+    let mut found = Vec::new();
+    walk(dir, &mut Vec::new(), &patterns, &mut found)?;
+    found.sort();
+    ensure!(
+        !found.is_empty(),
+        "no config files found in {}",
+        dir.display()
+    );
+    Ok(found)
+}
+
+/// Whether the components of `path` match the first ones of `pattern`.
+fn matches_start(pattern: &[&str], path: &[String]) -> bool {
+    pattern.len() >= path.len()
+        && path
+            .iter()
+            .zip(pattern)
+            .all(|(component, p)| glob_match(p, component))
+}
+
+fn walk(
+    dir: &Path,
+    components: &mut Vec<String>,
+    patterns: &[Vec<&str>],
+    found: &mut Vec<PathBuf>,
+) -> Result<()> {
+    let entries = fs::read_dir(dir).with_context(|| format!("cannot read {}", dir.display()))?;
+    for entry in entries {
+        let path = entry?.path();
+        components.push(path.file_name().unwrap().to_string_lossy().into_owned());
+        let reachable = |pattern: &&Vec<&str>| matches_start(pattern, components);
+        if path.is_dir() {
+            // Only directories some pattern could reach into are looked at.
+            if patterns
+                .iter()
+                .filter(reachable)
+                .any(|pattern| pattern.len() > components.len())
+            {
+                walk(&path, components, patterns, found)?;
+            }
+        } else if patterns
+            .iter()
+            .filter(reachable)
+            .any(|pattern| pattern.len() == components.len())
+        {
+            found.push(path);
+        }
+        components.pop();
+    }
+    Ok(())
+}
+
+pub(crate) enum SuiteStatus {
+    Passed,
+    Failed(String),
+    /// Not run because of `--fail-fast`.
+    Skipped,
+}
+
+/// How one variant of one config in a suite went. Configs without variants
+/// have a single result, with no variant.
+pub(crate) struct SuiteResult {
+    pub(crate) config: String,
+    pub(crate) variant: Option<String>,
+    pub(crate) status: SuiteStatus,
+    pub(crate) wall_time: Option<Duration>,
+}
+
+impl SuiteResult {
+    pub(crate) fn failed(&self) -> bool {
+        matches!(self.status, SuiteStatus::Failed(_))
+    }
+}
+
+/// Prints a table of how each config and variant in a suite went to stderr.
+pub(crate) fn print_suite_table(results: &[SuiteResult]) {
+    let rows: Vec<[String; 4]> = results
+        .iter()
+        .map(|result| {
+            [
+                result.config.clone(),
+                result.variant.clone().unwrap_or_else(|| "-".into()),
+                match &result.status {
+                    SuiteStatus::Passed => "passed".into(),
+                    SuiteStatus::Failed(reason) => format!("failed ({})", reason),
+                    SuiteStatus::Skipped => "skipped".into(),
+                },
+                match result.wall_time {
+                    Some(time) => format!("{:.2}s", time.as_secs_f64()),
+                    None => "-".into(),
+                },
+            ]
+        })
+        .collect();
+    let header = ["CONFIG", "VARIANT", "STATUS", "WALL TIME"].map(String::from);
+    let mut widths = [0; 4];
+    for row in std::iter::once(&header).chain(&rows) {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    eprintln!("Suite results:");
+    for row in std::iter::once(&header).chain(&rows) {
+        let line = format!(
+            "  {:w0$}  {:w1$}  {:w2$}  {}",
+            row[0],
+            row[1],
+            row[2],
+            row[3],
+            w0 = widths[0],
+            w1 = widths[1],
+            w2 = widths[2]
+        );
+        eprintln!("{}", line.trim_end());
+    }
+}
@@ -4,106 +4,829 @@
 // This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
 
 use anyhow::*;
-use async_std::io;
-use std::collections::HashMap;
+use async_std::{io, task::sleep};
+use log::warn;
+use nix::{
+    sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal},
+    unistd::isatty,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt,
+    fs::File,
+    io::Read,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
 
+use crate::csv::*;
+use crate::fingerprint::*;
+use crate::flags::*;
+use crate::markdown::*;
+use crate::results::*;
 use crate::metric_value::*;
+use crate::precision::*;
+use crate::statistics::*;
 
-fn mean(items: &[f64]) -> f64 {
-    let len = items.len() as f64;
-    let total: f64 = items.iter().sum();
-    total / len
+/// How `--summarize` writes summaries.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum SummaryFormat {
+    Json,
+    /// One row per name, variant and metric, with a column per statistic.
+    Csv,
+    /// One row per name and variant, with a column per metric and statistic.
+    CsvWide,
+    /// A table per name, with a row per variant and a column per metric.
+    Markdown,
 }
 
-fn stddev(m: f64, items: &[f64]) -> f64 {
-    mean(
-        &items
-            .iter()
-            .map(|x| f64::powf(x - m, 2.0))
-            .collect::<Vec<f64>>(),
-    )
-    .sqrt()
+impl SummaryFormat {
+    fn parse(format: &str) -> Result<Self> {
+        Ok(match format {
+            "json" => SummaryFormat::Json,
+            "csv" => SummaryFormat::Csv,
+            "csv-wide" => SummaryFormat::CsvWide,
+            "markdown" => SummaryFormat::Markdown,
+            _ => bail!(
+                "--format must be one of json, csv, csv-wide or markdown, got '{}'",
+                format
+            ),
+        })
+    }
 }
 
-fn summary(iterations: &[MetricValue]) -> MetricValue {
-    let mut stats: HashMap<String, Vec<f64>> = HashMap::new();
-    for iteration in iterations {
-        let iteration = iteration.as_map();
-        for (k, v) in iteration {
-            let stat = match stats.get_mut(k) {
-                Some(k) => k,
-                None => {
-                    stats.insert(k.clone(), Vec::new());
-                    stats.get_mut(k).unwrap()
+#[derive(Default)]
+pub(crate) struct SummarizeOptions {
+    exclude_flagged: bool,
+    follow: Option<String>,
+    precision: Option<Precision>,
+    merge_iterations: bool,
+    require_complete: bool,
+    strict: bool,
+    stats: Option<Vec<Statistic>>,
+    compare_variants: Option<String>,
+    alpha: Option<f64>,
+    format: Option<SummaryFormat>,
+    metrics: Option<Vec<String>>,
+    files: Vec<String>,
+}
+
+impl SummarizeOptions {
+    pub(crate) fn from_args(mut args: impl Iterator<Item = String>) -> Result<Self> {
+        let mut options = SummarizeOptions::default();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--exclude-flagged" => options.exclude_flagged = true,
+                "--precision" => {
+                    let spec = args
+                        .next()
+                        .ok_or_else(|| anyhow!("--precision requires a value"))?;
+                    options
+                        .precision
+                        .get_or_insert_with(Precision::default)
+                        .add_rule_str(&spec)?;
                 }
-            };
-            stat.push(v.clone().as_f64());
-        }
-    }
-    let mut result = HashMap::new();
-    for (name, items) in stats {
-        let mut statistics = HashMap::new();
-        let m = mean(&items);
-        let s = stddev(m, &items);
-        statistics.insert("mean".to_owned(), m.into());
-        statistics.insert("stddev".to_owned(), s.into());
-        statistics.insert("stddev_pct".to_owned(), ((s / m) * 100.0).into());
-        statistics.insert(
-            "min".to_owned(),
-            items.iter().fold(f64::INFINITY, |a, &b| a.min(b)).into(),
+                "--follow" => {
+                    options.follow = Some(
+                        args.next()
+                            .ok_or_else(|| anyhow!("--follow requires a file argument"))?,
+                    )
+                }
+                "--merge-iterations" => options.merge_iterations = true,
+                "--group-by" => {
+                    let group_by = args
+                        .next()
+                        .ok_or_else(|| anyhow!("--group-by requires a value"))?;
+                    options.merge_iterations = match group_by.as_str() {
+                        "run" => false,
+                        "all" => true,
+                        _ => bail!("--group-by must be run or all, got '{}'", group_by),
+                    };
+                }
+                "--require-complete" => options.require_complete = true,
+                "--strict" => options.strict = true,
+                "--stats" => {
+                    let spec = args
+                        .next()
+                        .ok_or_else(|| anyhow!("--stats requires a value"))?;
+                    options.stats = Some(Statistic::parse_list(&spec)?);
+                }
+                "--compare-variants" => {
+                    options.compare_variants =
+                        Some(args.next().ok_or_else(|| {
+                            anyhow!("--compare-variants requires a baseline variant")
+                        })?)
+                }
+                "--format" => {
+                    let format = args
+                        .next()
+                        .ok_or_else(|| anyhow!("--format requires a value"))?;
+                    options.format = Some(SummaryFormat::parse(&format)?);
+                }
+                "--metrics" => {
+                    let metrics = args
+                        .next()
+                        .ok_or_else(|| anyhow!("--metrics requires a value"))?;
+                    options.metrics = Some(
+                        metrics
+                            .split(',')
+                            .map(|metric| metric.trim().to_owned())
+                            .collect(),
+                    );
+                }
+                "--alpha" => {
+                    let alpha = args
+                        .next()
+                        .ok_or_else(|| anyhow!("--alpha requires a value"))?;
+                    match alpha.parse::<f64>() {
+                        Ok(alpha) if alpha > 0.0 && alpha < 1.0 => options.alpha = Some(alpha),
+                        _ => bail!("--alpha must be between 0 and 1, got '{}'", alpha),
+                    }
+                }
+                _ if arg.starts_with("--") => bail!("unknown summarize option '{}'", arg),
+                _ => options.files.push(arg),
+            }
+        }
+        ensure!(
+            options.alpha.is_none() || options.compare_variants.is_some(),
+            "--alpha only applies with --compare-variants"
         );
-        statistics.insert(
-            "max".to_owned(),
-            items.iter().fold(-f64::INFINITY, |a, &b| a.max(b)).into(),
+        ensure!(
+            options.metrics.is_none() || options.format == Some(SummaryFormat::Markdown),
+            "--metrics only applies with --format markdown"
         );
+        Ok(options)
+    }
+}
 
-        result.insert(name, statistics.into());
+/// Every value of each metric across some iterations, by its path through
+/// nested maps, which is just its name unless it's been nested.
+pub(crate) type Samples = BTreeMap<Vec<String>, Vec<f64>>;
+
+/// How significant a difference from the baseline variant has to be to be
+/// flagged, unless `--alpha` says otherwise.
+const DEFAULT_ALPHA: f64 = 0.05;
+
+/// Why a line wasn't summarized.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Skip {
+    Invalid,
+    NotJson,
+    NoName,
+    NoIterations,
+}
+
+impl Skip {
+    /// Whether the line is broken, rather than results that just can't be
+    /// told apart from others.
+    fn malformed(self) -> bool {
+        self != Skip::NoName
     }
+}
 
-    result.into()
+impl fmt::Display for Skip {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Skip::Invalid => "invalid",
+            Skip::NotJson => "not JSON",
+            Skip::NoName => "without a name",
+            Skip::NoIterations => "without iterations",
+        })
+    }
+}
+
+/// How much of a skipped line its warning shows.
+const PREVIEW_CHARS: usize = 60;
+
+fn preview(line: &str) -> String {
+    let line = line.trim();
+    match line.char_indices().nth(PREVIEW_CHARS) {
+        Some((end, _)) => format!("{}…", &line[..end]),
+        None => line.to_owned(),
+    }
+}
+
+/// What results without a variant are summarized as, since benchmarks
+/// without variants are still worth summarizing.
+const DEFAULT_VARIANT: &str = "default";
+
+/// Set in a summary that combines runs with different config hashes.
+const MIXED_CONFIGS_KEY: &str = "mixed_configs";
+
+/// The samples of the iterations, which can't have a metric that's also a
+/// group of metrics.
+pub(crate) fn collect_stats(iterations: &[MetricValue]) -> Result<Samples> {
+    let mut stats = BTreeMap::new();
+    for (i, iteration) in iterations.iter().enumerate() {
+        let iteration = iteration
+            .as_map()
+            .with_context(|| format!("invalid iteration {}", i))?;
+        for (k, v) in iteration {
+            if k == FLAGS_KEY || k == ITERATION_KEY || k == START_EPOCH_MS_KEY {
+                continue;
+            }
+            collect_value(&mut stats, vec![k.clone()], v);
+        }
+    }
+    // Sorted, a group's metrics come right after a metric of the same name.
+    let paths: Vec<&Vec<String>> = stats.keys().collect();
+    for pair in paths.windows(2) {
+        ensure!(
+            !pair[1].starts_with(pair[0]),
+            "'{}' is both a metric and a group of metrics, such as '{}'",
+            pair[0].join("."),
+            pair[1].join(".")
+        );
+    }
+    Ok(stats)
+}
+
+fn collect_value(stats: &mut Samples, path: Vec<String>, value: &MetricValue) {
+    match value {
+        MetricValue::Num(v) => stats.entry(path).or_default().push(*v),
+        MetricValue::Map(group) => {
+            for (k, v) in group {
+                let mut path = path.clone();
+                path.push(k.clone());
+                collect_value(stats, path, v);
+            }
+        }
+        // Only numbers can be summarized. Others, like the CPU frequency
+        // governor or where a profile was kept, are left out.
+        _ => {}
+    }
 }
 
-pub(crate) async fn summarize() -> Result<()> {
-    let stdin = io::stdin();
-    let mut line = String::new();
-    let mut result_data: MetricMap = HashMap::new();
-    while stdin.read_line(&mut line).await? != 0 {
-        if let Ok(mut json_data) = serde_json::from_str::<MetricMap>(&line) {
-            let name = match json_data.get("name") {
-                Some(name) => name.clone().as_string(),
-                None => {
-                    line = String::new();
-                    continue;
+/// The mean of each metric over the iterations that didn't fail.
+pub(crate) fn means(iterations: &[MetricValue]) -> Result<BTreeMap<String, f64>> {
+    Ok(statistics(iterations, &[Statistic::Mean])?
+        .into_iter()
+        .map(|(name, stats)| (name, stats[0].unwrap_or_default()))
+        .collect())
+}
+
+/// The given statistics of each metric over the iterations that didn't
+/// fail, by its dotted name.
+pub(crate) fn statistics(
+    iterations: &[MetricValue],
+    stats: &[Statistic],
+) -> Result<BTreeMap<String, Vec<Option<f64>>>> {
+    let iterations: Vec<MetricValue> = iterations
+        .iter()
+        .filter(|iteration| !iteration.as_map().is_ok_and(is_failed))
+        .cloned()
+        .collect();
+    Ok(collect_stats(&iterations)?
+        .into_iter()
+        .map(|(path, items)| {
+            let name = path.join(".");
+            let values = stats
+                .iter()
+                .map(|stat| stat.compute(&name, &items, iterations.len()))
+                .collect();
+            (name, values)
+        })
+        .collect())
+}
+
+/// Inserts a metric's value where its path puts it, so that nested metrics
+/// get nested statistics.
+fn insert_at_path(result: &mut MetricMap, path: &[String], value: MetricValue) -> Result<()> {
+    let (name, groups) = path.split_last().unwrap();
+    let mut group = result;
+    for part in groups {
+        group = group
+            .entry(part.clone())
+            .or_insert_with(|| MetricMap::new().into())
+            .as_map_mut()?;
+    }
+    group.insert(name.clone(), value);
+    Ok(())
+}
+
+/// The statistics of each metric in the summary of some results, by its
+/// dotted name. As when rounding, a map with maps in it is a group of nested
+/// metrics.
+pub(crate) fn summary_metrics(data: &MetricMap) -> Result<BTreeMap<String, &MetricMap>> {
+    fn flatten<'a>(
+        prefix: &str,
+        summary: &'a MetricMap,
+        metrics: &mut BTreeMap<String, &'a MetricMap>,
+    ) {
+        for (name, stats) in summary {
+            let name = format!("{}{}", prefix, name);
+            if let MetricValue::Map(stats) = stats {
+                if stats.values().any(|stat| matches!(stat, MetricValue::Map(_))) {
+                    flatten(&format!("{}.", name), stats, metrics);
+                } else {
+                    metrics.insert(name, stats);
                 }
-            };
-            json_data.remove("name");
-            let variant = match json_data.get("variant") {
-                Some(variant) => variant.clone().as_string(),
-                None => {
-                    line = String::new();
-                    continue;
+            }
+        }
+    }
+    let mut metrics = BTreeMap::new();
+    if let Some(summary) = data.get("summary") {
+        flatten("", summary.as_map()?, &mut metrics);
+    }
+    Ok(metrics)
+}
+
+/// The given statistics of each metric over `total` iterations, along with
+/// the metrics that are missing from some of them, by name.
+fn summary(
+    samples: &Samples,
+    total: usize,
+    stats_wanted: &[Statistic],
+) -> Result<(MetricValue, Vec<String>)> {
+    let mut result = MetricMap::new();
+    let mut incomplete = Vec::new();
+    for (path, items) in samples {
+        if items.len() < total {
+            incomplete.push(path.join("."));
+        }
+        let mut statistics = MetricMap::new();
+        for stat in stats_wanted {
+            if let Some(value) = stat.compute(&path.join("."), items, total) {
+                statistics.insert(stat.name(), value.into());
+            }
+        }
+        insert_at_path(&mut result, path, statistics.into())?;
+    }
+
+    Ok((result.into(), incomplete))
+}
+
+/// How each metric of a variant differs from that of the baseline variant:
+/// the `diff_pct` of its mean, and the `p_value` of Welch's t-test between
+/// their iterations, which is `significant` if it's under `alpha`. Metrics
+/// the baseline doesn't have are left out.
+fn comparison(baseline: &Samples, samples: &Samples, alpha: f64) -> Result<MetricValue> {
+    let mut result = MetricMap::new();
+    for (path, items) in samples {
+        let baseline_items = match baseline.get(path) {
+            Some(items) => items,
+            None => continue,
+        };
+        let mut compared = MetricMap::new();
+        let baseline_mean = mean(baseline_items);
+        if baseline_mean != 0.0 {
+            let diff = (mean(items) - baseline_mean) / baseline_mean.abs() * 100.0;
+            compared.insert("diff_pct".to_owned(), diff.into());
+        }
+        // Fewer than two iterations on either side can't be tested.
+        if let Some(p) = welch_p_value(baseline_items, items) {
+            compared.insert("p_value".to_owned(), p.into());
+            compared.insert("significant".to_owned(), ((p < alpha) as u64).into());
+        }
+        insert_at_path(&mut result, path, compared.into())?;
+    }
+    Ok(result.into())
+}
+
+/// Summaries keyed by name, then variant. Each input line replaces only its own
+/// entry, so lines can be added one at a time as they arrive.
+struct Summaries {
+    options: SummarizeOptions,
+    result_data: MetricMap,
+    /// Iterations seen so far per name and variant, kept only when merging.
+    merged: HashMap<(String, String), Vec<MetricValue>>,
+    /// How many complete runs have been seen per name and variant.
+    runs: HashMap<(String, String), u64>,
+    /// The config hashes seen per name and variant, kept only when merging.
+    config_hashes: HashMap<(String, String), BTreeSet<String>>,
+    /// How many lines were skipped, by why they were.
+    skipped: BTreeMap<Skip, usize>,
+    /// Iterations written with `--stream` whose summary line hasn't been seen.
+    streamed: HashMap<(String, String), Vec<MetricValue>>,
+    /// Metrics missing from some iterations, per name and variant, kept only
+    /// with `--require-complete`.
+    incomplete: BTreeMap<(String, String), Vec<String>>,
+    /// The iterations' values per name and variant, kept only with
+    /// `--compare-variants`.
+    samples: HashMap<(String, String), Samples>,
+}
+
+impl Summaries {
+    fn new(options: SummarizeOptions) -> Self {
+        Summaries {
+            options,
+            result_data: MetricMap::new(),
+            merged: HashMap::new(),
+            runs: HashMap::new(),
+            config_hashes: HashMap::new(),
+            skipped: BTreeMap::new(),
+            streamed: HashMap::new(),
+            incomplete: BTreeMap::new(),
+            samples: HashMap::new(),
+        }
+    }
+
+    /// Lines that aren't results, or are from runs without a name, are
+    /// skipped and counted. Malformed lines, like half-written ones from a
+    /// run that crashed, also get a warning giving their number in their
+    /// `source`, and a preview of them.
+    fn add_line(&mut self, source: &str, number: usize, line: &str) {
+        if line.trim().is_empty() {
+            return;
+        }
+        let (skip, why) = match self.try_add_line(line) {
+            Ok(None) => return,
+            Ok(Some(skip)) => (skip, skip.to_string()),
+            Err(e) => (Skip::Invalid, format!("{:#}", e)),
+        };
+        if skip.malformed() {
+            warn!(
+                "skipping results on line {} of {}: {}: {}",
+                number,
+                source,
+                why,
+                preview(line)
+            );
+        }
+        *self.skipped.entry(skip).or_default() += 1;
+    }
+
+    fn try_add_line(&mut self, line: &str) -> Result<Option<Skip>> {
+        let json_data = match serde_json::from_str::<serde_json::Value>(line) {
+            Ok(json_data) => json_data,
+            Err(_) => return Ok(Some(Skip::NotJson)),
+        };
+        self.try_add(serde_json::from_value(json_data).context("invalid results")?)
+    }
+
+    /// Adds a line of results, or returns why it was skipped.
+    fn try_add(&mut self, mut json_data: MetricMap) -> Result<Option<Skip>> {
+        let stream_type = json_data.remove(STREAM_TYPE_KEY);
+        let name = match json_data.remove("name") {
+            Some(name) => name.as_string().context("invalid name")?,
+            None => return Ok(Some(Skip::NoName)),
+        };
+        let variant = match json_data.remove("variant") {
+            Some(variant) => variant.as_string().context("invalid variant")?,
+            None => DEFAULT_VARIANT.to_owned(),
+        };
+        let key = (name.clone(), variant.clone());
+        let (mut iterations, complete) = match stream_type {
+            // Until the summary line arrives, the iterations streamed so far
+            // are summarized on their own.
+            Some(MetricValue::Str(t)) if t == "iteration" => {
+                let metrics = match json_data.remove("metrics") {
+                    Some(metrics) => metrics,
+                    None => return Ok(Some(Skip::NoIterations)),
+                };
+                // Checked before it's kept, so that it can't spoil later lines.
+                metrics.as_map().context("invalid metrics")?;
+                collect_stats(std::slice::from_ref(&metrics))?;
+                let streamed = self.streamed.entry(key.clone()).or_default();
+                streamed.push(metrics);
+                json_data.insert("incomplete".to_owned(), 1.into());
+                (streamed.clone(), false)
+            }
+            Some(MetricValue::Str(t)) if t == "summary" => {
+                (self.streamed.remove(&key).unwrap_or_default(), true)
+            }
+            _ => match json_data.remove("iterations") {
+                Some(iterations) => {
+                    let iterations = iterations.as_vec().context("invalid iterations")?;
+                    collect_stats(&iterations)?;
+                    (iterations, true)
                 }
-            };
-            json_data.remove("variant");
-            let name_data: &mut MetricMap = match result_data.get_mut(&name) {
-                Some(data) => data.as_map_mut(),
-                None => {
-                    result_data.insert(name.to_owned(), HashMap::new().into());
-                    result_data.get_mut(&name).unwrap().as_map_mut()
+                None => return Ok(Some(Skip::NoIterations)),
+            },
+        };
+        let name_data: &mut MetricMap = match self.result_data.get_mut(&name) {
+            Some(data) => data.as_map_mut()?,
+            None => {
+                self.result_data.insert(name.to_owned(), MetricMap::new().into());
+                self.result_data.get_mut(&name).unwrap().as_map_mut()?
+            }
+        };
+
+        let runs = self.runs.entry(key.clone()).or_default();
+        if complete {
+            *runs += 1;
+        }
+        if self.options.merge_iterations {
+            let merged = self.merged.entry(key.clone()).or_default();
+            if complete {
+                merged.append(&mut iterations);
+                iterations = merged.clone();
+            } else {
+                iterations = merged.iter().cloned().chain(iterations).collect();
+            }
+            // A run that's still streaming counts too.
+            let runs = *runs + if complete { 0 } else { 1 };
+            json_data.insert("runs".to_owned(), runs.into());
+            // Runs of different definitions of a benchmark aren't really
+            // samples of the same thing.
+            let hashes = self.config_hashes.entry(key.clone()).or_default();
+            if let Some(MetricValue::Str(hash)) = json_data.get(CONFIG_HASH_KEY) {
+                if hashes.insert(hash.clone()) && hashes.len() == 2 {
+                    warn!(
+                        "results for name {:?} and variant {:?} come from different configs; \
+                         check config.hash before combining them",
+                        name, variant
+                    );
                 }
-            };
+            }
+            if hashes.len() > 1 {
+                json_data.insert(MIXED_CONFIGS_KEY.to_owned(), 1.into());
+            }
+        } else if complete && *runs > 1 {
+            warn!(
+                "results for name {:?} and variant {:?} replace earlier ones; pass \
+                 --group-by all to combine them instead",
+                name, variant
+            );
+        }
+        // Whatever a failed iteration managed to measure isn't comparable to
+        // a complete one, so they're only counted.
+        let total = iterations.len();
+        iterations.retain(|iteration| !iteration.as_map().is_ok_and(is_failed));
+        if iterations.len() < total {
+            json_data.insert(
+                FAILED_KEY.to_owned(),
+                ((total - iterations.len()) as i64).into(),
+            );
+        }
+        if self.options.exclude_flagged {
+            let total = iterations.len();
+            iterations.retain(|iteration| !iteration.as_map().is_ok_and(is_flagged));
+            json_data.insert(
+                "excluded".to_owned(),
+                ((total - iterations.len()) as i64).into(),
+            );
+        }
+        let stats = self.options.stats.as_deref().unwrap_or(DEFAULT_STATISTICS);
+        let samples = collect_stats(&iterations)?;
+        let (summary, incomplete) = summary(&samples, iterations.len(), stats)?;
+        if self.options.require_complete {
+            self.incomplete.insert(key.clone(), incomplete);
+        }
+        if self.options.compare_variants.is_some() {
+            self.samples.insert(key, samples);
+        }
+        json_data.insert("summary".to_owned(), summary);
+        name_data.insert(variant, json_data.into());
+        Ok(None)
+    }
 
-            if let Some((_, iterations)) = json_data.remove_entry("iterations") {
-                json_data.insert("summary".to_owned(), summary(&iterations.as_vec()));
+    /// Notes how many lines were skipped, if any were, so that it's never a
+    /// surprise that some results are missing from the summary.
+    fn report_skipped(&self) {
+        let total: usize = self.skipped.values().sum();
+        if total == 0 {
+            return;
+        }
+        let reasons: Vec<String> = self
+            .skipped
+            .iter()
+            .map(|(reason, count)| format!("{} {}", count, reason))
+            .collect();
+        warn!(
+            "skipped {} summarized: {}",
+            if total == 1 {
+                "1 line that wasn't".to_owned()
             } else {
-                line = String::new();
-                continue;
+                format!("{} lines that weren't", total)
+            },
+            reasons.join(", ")
+        );
+    }
+
+    /// Fails with `--strict` if any lines were malformed, and with
+    /// `--require-complete` if any metrics are missing from some iterations.
+    fn check(&self) -> Result<()> {
+        let malformed: usize = self
+            .skipped
+            .iter()
+            .filter(|(skip, _)| skip.malformed())
+            .map(|(_, count)| count)
+            .sum();
+        ensure!(
+            !self.options.strict || malformed == 0,
+            "{} malformed, with --strict",
+            if malformed == 1 {
+                "1 line was".to_owned()
+            } else {
+                format!("{} lines were", malformed)
             }
-            json_data.remove("iterations");
-            name_data.insert(variant, json_data.into());
+        );
+        self.check_complete()
+    }
+
+    /// Fails, naming them, if any metrics are missing from some iterations.
+    fn check_complete(&self) -> Result<()> {
+        let missing: Vec<String> = self
+            .incomplete
+            .iter()
+            .flat_map(|((name, variant), metrics)| {
+                metrics
+                    .iter()
+                    .map(move |metric| format!("'{}' of {}/{}", metric, name, variant))
+            })
+            .collect();
+        ensure!(
+            missing.is_empty(),
+            "some iterations are missing metrics, with --require-complete: {}",
+            missing.join(", ")
+        );
+        Ok(())
+    }
+
+    fn render(&self) -> Result<String> {
+        let mut result_data = self.result_data.clone();
+        if let Some(precision) = &self.options.precision {
+            round_summaries(precision, &mut result_data)?;
+        }
+        if let Some(baseline) = &self.options.compare_variants {
+            self.compare_variants(baseline, &mut result_data)?;
+        }
+        let stats = || -> Vec<String> {
+            let stats = self.options.stats.as_deref().unwrap_or(DEFAULT_STATISTICS);
+            stats.iter().map(Statistic::name).collect()
         };
-        line = String::new();
+        match self.options.format.unwrap_or(SummaryFormat::Json) {
+            SummaryFormat::Json => Ok(serde_json::to_string_pretty(&result_data)?),
+            SummaryFormat::Csv => summary_csv(&result_data, &stats(), false),
+            SummaryFormat::CsvWide => summary_csv(&result_data, &stats(), true),
+            SummaryFormat::Markdown => {
+                summary_markdown(&result_data, self.options.metrics.as_deref())
+            }
+        }
+    }
+
+    /// Adds a `comparison` with the baseline variant to each other variant of
+    /// a name that has one.
+    fn compare_variants(&self, baseline: &str, result_data: &mut MetricMap) -> Result<()> {
+        let alpha = self.options.alpha.unwrap_or(DEFAULT_ALPHA);
+        for (name, variants) in result_data.iter_mut() {
+            let baseline_samples = match self.samples.get(&(name.clone(), baseline.to_owned())) {
+                Some(samples) => samples,
+                None => continue,
+            };
+            for (variant, data) in variants.as_map_mut()? {
+                let samples = match self.samples.get(&(name.clone(), variant.clone())) {
+                    Some(samples) if variant != baseline => samples,
+                    _ => continue,
+                };
+                let data = data.as_map_mut()?;
+                data.insert("compared_with".to_owned(), baseline.to_owned().into());
+                data.insert(
+                    "comparison".to_owned(),
+                    comparison(baseline_samples, samples, alpha)?,
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Rounds each summary, and the other measurements of its results.
+fn round_summaries(precision: &Precision, result_data: &mut MetricMap) -> Result<()> {
+    for variants in result_data.values_mut() {
+        for data in variants.as_map_mut()?.values_mut() {
+            let data = data.as_map_mut()?;
+            if let Some(mut summary) = data.remove("summary") {
+                // The host description isn't a measurement, so it's left as is.
+                let host = data.remove("host");
+                precision.round_metrics(data);
+                precision.round_summary(summary.as_map_mut()?);
+                data.insert("summary".to_owned(), summary);
+                if let Some(host) = host {
+                    data.insert("host".to_owned(), host);
+                }
+            }
+        }
     }
-    println!("{}", serde_json::to_string_pretty(&result_data).unwrap());
     Ok(())
 }
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sigint(_: nix::libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Tails `path`, re-rendering the summary whenever new lines are appended,
+/// until interrupted with SIGINT. On a terminal the previous render is
+/// replaced in place; otherwise each update is printed as a new block.
+async fn follow(path: &str, mut summaries: Summaries) -> Result<()> {
+    let handler = SigAction::new(
+        SigHandler::Handler(on_sigint),
+        SaFlags::empty(),
+        SigSet::empty(),
+    );
+    unsafe { sigaction(Signal::SIGINT, &handler)? };
+    let tty = isatty(1).unwrap_or(false);
+
+    let mut file = File::open(path).with_context(|| format!("cannot open {}", path))?;
+    let mut pending = String::new();
+    let mut number = 0;
+    let mut rendered = false;
+    loop {
+        let mut buf = String::new();
+        file.read_to_string(&mut buf)?;
+        pending.push_str(&buf);
+        let mut changed = false;
+        while let Some(end) = pending.find('\n') {
+            let line: String = pending.drain(..=end).collect();
+            number += 1;
+            summaries.add_line(path, number, &line);
+            changed = true;
+        }
+        if changed || !rendered {
+            if tty {
+                print!("\x1b[H\x1b[2J");
+            }
+            println!("{}", summaries.render()?);
+            rendered = true;
+        }
+        if INTERRUPTED.load(Ordering::SeqCst) {
+            summaries.report_skipped();
+            return summaries.check();
+        }
+        sleep(FOLLOW_INTERVAL).await;
+    }
+}
+
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Summaries of results, as `sirun --summarize` gives them: for each name and
+/// variant, the `mean`, `stddev`, `stddev_pct`, `min`, `max`, `count` and
+/// `coverage_pct` of each metric under `summary`, along with the rest of the results other than their
+/// iterations.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Summary {
+    summaries: MetricMap,
+}
+
+impl Summary {
+    /// The summary of the results with the given name and variant.
+    pub fn get(&self, name: &str, variant: &str) -> Option<&MetricMap> {
+        self.summaries.get(name)?.as_map().ok()?.get(variant)?.as_map().ok()
+    }
+
+    /// Every summary, by name and then variant.
+    pub fn as_map(&self) -> &MetricMap {
+        &self.summaries
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let json = serde_json::to_string_pretty(&self.summaries).map_err(|_| fmt::Error)?;
+        f.write_str(&json)
+    }
+}
+
+/// Summarizes results. Only those with a name can be told apart, so others
+/// are left out, as are malformed ones, with a warning. Results without a
+/// variant are summarized as the `default` variant. Results with the same
+/// name and variant replace earlier ones.
+pub fn summarize(results: impl IntoIterator<Item = BenchmarkResult>) -> Summary {
+    let mut summaries = Summaries::new(SummarizeOptions::default());
+    for result in results {
+        match summaries.try_add(result.into_metric_map()) {
+            Ok(None) => {}
+            Ok(Some(reason)) => *summaries.skipped.entry(reason).or_default() += 1,
+            Err(e) => {
+                warn!("skipping results: {:#}", e);
+                *summaries.skipped.entry(Skip::Invalid).or_default() += 1;
+            }
+        }
+    }
+    summaries.report_skipped();
+    Summary {
+        summaries: summaries.result_data,
+    }
+}
+
+pub(crate) async fn summarize_main(options: SummarizeOptions) -> Result<()> {
+    let follow_path = options.follow.clone();
+    let mut summaries = Summaries::new(options);
+    if let Some(path) = follow_path {
+        return follow(&path, summaries).await;
+    }
+    let files = summaries.options.files.clone();
+    if files.is_empty() {
+        let stdin = io::stdin();
+        let mut line = String::new();
+        let mut number = 0;
+        while stdin.read_line(&mut line).await? != 0 {
+            number += 1;
+            summaries.add_line("stdin", number, &line);
+            line = String::new();
+        }
+    }
+    for path in files {
+        let contents = std::fs::read_to_string(&path)
+            .with_context(|| format!("cannot read results file {}", path))?;
+        for (i, line) in contents.lines().enumerate() {
+            summaries.add_line(&path, i + 1, line);
+        }
+    }
+    println!("{}", summaries.render()?);
+    summaries.report_skipped();
+    summaries.check()
+}
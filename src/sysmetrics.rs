@@ -0,0 +1,57 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+//! Measurements of the machine as a whole, rather than of the benchmark, that
+//! show when it was competing with something else.
+
+use std::fs;
+
+use crate::metric_value::*;
+
+/// The resources Linux reports pressure stall information for.
+const PSI_RESOURCES: &[&str] = &["cpu", "memory", "io"];
+
+/// The `avg10` of the `some` line of a file in `/proc/pressure`: the
+/// percentage of the last 10 seconds that some task was stalled waiting for
+/// the resource. The line looks like
+/// `some avg10=1.23 avg60=0.50 avg300=0.10 total=123456`.
+pub(crate) fn some_avg10(psi: &str) -> Option<f64> {
+    psi.lines()
+        .find_map(|line| line.strip_prefix("some "))?
+        .split_whitespace()
+        .find_map(|field| field.strip_prefix("avg10="))?
+        .parse()
+        .ok()
+}
+
+/// The `some` avg10 of each of the `PSI_RESOURCES`, taken before and after
+/// `run`. Kernels without `/proc/pressure` have none.
+pub(crate) struct Pressure(Vec<Option<f64>>);
+
+impl Pressure {
+    pub(crate) fn measure() -> Self {
+        Pressure(
+            PSI_RESOURCES
+                .iter()
+                .map(|resource| {
+                    fs::read_to_string(format!("/proc/pressure/{}", resource))
+                        .ok()
+                        .and_then(|psi| some_avg10(&psi))
+                })
+                .collect(),
+        )
+    }
+
+    /// Records how much the pressure on each resource has changed since
+    /// `before`, as `psi.RESOURCE.some`. Those that couldn't be read both
+    /// times are left out.
+    pub(crate) fn add_deltas(&self, before: &Pressure, metrics: &mut MetricMap) {
+        for ((resource, after), before) in PSI_RESOURCES.iter().zip(&self.0).zip(&before.0) {
+            if let (Some(after), Some(before)) = (after, before) {
+                metrics.insert(format!("psi.{}.some", resource), (after - before).into());
+            }
+        }
+    }
+}
@@ -0,0 +1,55 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+fn since_epoch(time: SystemTime) -> Duration {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default()
+}
+
+/// Milliseconds since the Unix epoch.
+pub(crate) fn epoch_ms(time: SystemTime) -> f64 {
+    since_epoch(time).as_millis() as f64
+}
+
+/// Microseconds since the Unix epoch.
+pub(crate) fn epoch_us(time: SystemTime) -> f64 {
+    since_epoch(time).as_micros() as f64
+}
+
+/// Formats a time as ISO-8601 in UTC with millisecond precision, e.g.
+/// `2021-03-04T05:06:07.089Z`.
+pub(crate) fn iso8601(time: SystemTime) -> String {
+    let since = since_epoch(time);
+    let secs = since.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let secs_of_day = secs % 86400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60,
+        since.subsec_millis()
+    )
+}
+
+/// Converts days since 1970-01-01 into a year, month and day of the
+/// proleptic Gregorian calendar. See
+/// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
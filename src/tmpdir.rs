@@ -0,0 +1,57 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+use anyhow::*;
+use std::{fs, os::unix::fs::PermissionsExt, path::Path};
+use tempfile::TempDir;
+
+use crate::config::*;
+use crate::disk::*;
+
+/// A fresh scratch directory for one iteration. It's removed when dropped,
+/// along with anything the benchmark left behind in it.
+pub(crate) struct IterationTmpdir(TempDir);
+
+impl IterationTmpdir {
+    /// Creates the directory and points `SIRUN_TMPDIR` at it in the config's
+    /// env, as well as `TMPDIR` if the config has `manage_tmpdir` set.
+    pub(crate) fn create(config: &mut Config) -> Result<Self> {
+        let dir = tempfile::Builder::new().prefix("sirun-tmp").tempdir()?;
+        let path = dir.path().to_string_lossy().into_owned();
+        if config.manage_tmpdir {
+            config.env.insert("TMPDIR".into(), path.clone());
+        }
+        config.env.insert("SIRUN_TMPDIR".into(), path);
+        Ok(IterationTmpdir(dir))
+    }
+
+    /// Total size in bytes of the files currently in the directory.
+    pub(crate) fn disk_usage(&self) -> u64 {
+        disk_usage(self.0.path())
+    }
+}
+
+impl Drop for IterationTmpdir {
+    fn drop(&mut self) {
+        // Files in a read-only directory can't be removed, so make sure every
+        // directory is writable before `TempDir` deletes the tree.
+        make_writable(self.0.path());
+    }
+}
+
+fn make_writable(dir: &Path) {
+    if let Ok(metadata) = fs::symlink_metadata(dir) {
+        let mut permissions = metadata.permissions();
+        permissions.set_mode(permissions.mode() | 0o700);
+        let _ = fs::set_permissions(dir, permissions);
+    }
+    if let Ok(entries) = fs::read_dir(dir) {
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            if entry.file_type().is_ok_and(|typ| typ.is_dir()) {
+                make_writable(&entry.path());
+            }
+        }
+    }
+}
@@ -0,0 +1,97 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+//! Showing metrics to people, in units that suit them.
+
+/// The unit a metric is measured in, as far as its name tells.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Unit {
+    Micros,
+    Millis,
+    Bytes,
+    Kibibytes,
+    Percent,
+    Unknown,
+}
+
+fn unit(metric: &str) -> Unit {
+    let parts: Vec<&str> = metric.split('.').collect();
+    match metric {
+        "wall.time" | "wall.time.sum" | "user.time" | "system.time" | "time.to.first.metric" => {
+            Unit::Micros
+        }
+        "max.res.size" => Unit::Kibibytes,
+        _ if metric.ends_with(".us") => Unit::Micros,
+        _ if metric.ends_with(".ms") => Unit::Millis,
+        _ if parts.contains(&"bytes") => Unit::Bytes,
+        _ if parts.contains(&"pct") || metric.ends_with("_pct") => Unit::Percent,
+        _ => Unit::Unknown,
+    }
+}
+
+const BYTE_UNITS: [&str; 5] = [" B", " KiB", " MiB", " GiB", " TiB"];
+
+/// Scales a metric's values to the largest unit that keeps its mean at least
+/// 1, returning the divisor and the unit's suffix.
+fn scale(unit: Unit, mean: f64) -> (f64, &'static str) {
+    let mean = mean.abs();
+    match unit {
+        Unit::Micros if mean >= 1e6 => (1e6, " s"),
+        Unit::Micros if mean >= 1e3 => (1e3, " ms"),
+        Unit::Micros => (1.0, " µs"),
+        Unit::Millis if mean >= 1e3 => (1e3, " s"),
+        Unit::Millis => (1.0, " ms"),
+        Unit::Bytes | Unit::Kibibytes => {
+            let mut index = if unit == Unit::Kibibytes { 1 } else { 0 };
+            let mut divisor = 1.0;
+            while mean / divisor >= 1024.0 && index < BYTE_UNITS.len() - 1 {
+                divisor *= 1024.0;
+                index += 1;
+            }
+            (divisor, BYTE_UNITS[index])
+        }
+        Unit::Percent => (1.0, "%"),
+        Unit::Unknown => (1.0, ""),
+    }
+}
+
+/// How many decimals give a value three significant digits.
+fn decimals(value: f64) -> usize {
+    if value == 0.0 || !value.is_finite() {
+        return 0;
+    }
+    (2 - value.abs().log10().floor() as i32).clamp(0, 6) as usize
+}
+
+/// How to show the values of a metric: scaled to the unit that suits its
+/// mean, with three significant digits of it, which is plenty to tell
+/// results apart without the noise of all of them.
+pub(crate) struct Readable {
+    divisor: f64,
+    places: usize,
+    /// The unit, with the space before it if it needs one.
+    pub(crate) suffix: &'static str,
+}
+
+impl Readable {
+    pub(crate) fn new(metric: &str, mean: f64) -> Self {
+        let (divisor, suffix) = scale(unit(metric), mean);
+        Readable {
+            divisor,
+            places: decimals(mean / divisor),
+            suffix,
+        }
+    }
+
+    /// A value in the unit, without the unit.
+    pub(crate) fn number(&self, value: f64) -> String {
+        format!("{:.*}", self.places, value / self.divisor)
+    }
+
+    /// A value in the unit.
+    pub(crate) fn format(&self, value: f64) -> String {
+        format!("{}{}", self.number(value), self.suffix)
+    }
+}
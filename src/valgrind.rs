@@ -0,0 +1,129 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+//! Counting instructions and cache misses by running the `run` command under
+//! valgrind, for machines without usable hardware counters.
+
+use anyhow::*;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fs, path::Path};
+use which::which;
+
+use crate::metric_value::*;
+use crate::profilers::*;
+
+/// Suffix of the timings of a command run under valgrind, which slows it down
+/// by an order of magnitude.
+pub(crate) const UNDER_VALGRIND: &str = ".under.valgrind";
+
+/// The valgrind tool that the `valgrind` setting runs the `run` command
+/// under.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ValgrindTool {
+    Cachegrind,
+    Callgrind,
+}
+
+impl ValgrindTool {
+    pub(crate) fn parse(name: &str) -> Option<Self> {
+        match name {
+            "cachegrind" => Some(ValgrindTool::Cachegrind),
+            "callgrind" => Some(ValgrindTool::Callgrind),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            ValgrindTool::Cachegrind => "cachegrind",
+            ValgrindTool::Callgrind => "callgrind",
+        }
+    }
+}
+
+impl Profiler for ValgrindTool {
+    fn check(&self) -> Result<()> {
+        which("valgrind").map_err(|_| {
+            anyhow!(
+                "'valgrind' needs the 'valgrind' executable to run {}, but it isn't on the PATH",
+                self.name()
+            )
+        })?;
+        Ok(())
+    }
+
+    /// Valgrind writes one output file per process into `out_dir`.
+    fn wrap(&self, run: &[String], out_dir: &Path) -> Vec<String> {
+        let out_file = out_dir.join(format!("{}.out.%p", self.name()));
+        let mut command = vec![
+            "valgrind".to_owned(),
+            format!("--tool={}", self.name()),
+            format!("--{}-out-file={}", self.name(), out_file.display()),
+            "--trace-children=yes".to_owned(),
+            "--cache-sim=yes".to_owned(),
+            // It is important that the cache sizes are the same across
+            // machines, instead of the default of detecting them.
+            "--I1=32768,8,64".to_owned(),
+            "--D1=32768,8,64".to_owned(),
+            "--LL=8388608,16,64".to_owned(),
+        ];
+        command.extend_from_slice(run);
+        command
+    }
+
+    /// Adds up the event counts of every process.
+    fn add_metrics(&self, out_dir: &Path, metrics: &mut MetricMap) -> Result<()> {
+        let mut totals = BTreeMap::new();
+        for entry in fs::read_dir(out_dir)? {
+            let contents = fs::read_to_string(entry?.path())?;
+            for (event, count) in
+                event_totals(&contents).with_context(|| format!("bad {} output", self.name()))?
+            {
+                *totals.entry(event).or_insert(0.0) += count;
+            }
+        }
+        let sum =
+            |events: &[&str]| -> f64 { events.iter().filter_map(|event| totals.get(*event)).sum() };
+        let instructions = sum(&["Ir"]);
+        ensure!(
+            instructions > 0.0,
+            "bad {} output: no instructions counted",
+            self.name()
+        );
+        metrics.insert("instructions".into(), instructions.into());
+        metrics.insert("cache.d1.miss".into(), sum(&["D1mr", "D1mw"]).into());
+        metrics.insert(
+            "cache.ll.miss".into(),
+            sum(&["ILmr", "DLmr", "DLmw"]).into(),
+        );
+        Ok(())
+    }
+
+    fn timing_suffix(&self) -> Option<&'static str> {
+        Some(UNDER_VALGRIND)
+    }
+}
+
+/// Reads the totals of an output file, which has the names of the events on
+/// an `events:` line and their totals on a `summary:` line, in the same order.
+fn event_totals(contents: &str) -> Result<Vec<(String, f64)>> {
+    let line = |prefix: &str| {
+        contents
+            .lines()
+            .find_map(|line| line.strip_prefix(prefix))
+            .ok_or_else(|| anyhow!("no '{}' line", prefix))
+    };
+    let events = line("events:")?.split_whitespace();
+    let counts = line("summary:")?
+        .split_whitespace()
+        .map(|count| {
+            count
+                .parse::<f64>()
+                .map_err(|_| anyhow!("invalid count '{}'", count))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(events.map(str::to_owned).zip(counts).collect())
+}
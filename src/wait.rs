@@ -0,0 +1,216 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
+
+use anyhow::*;
+use async_std::{
+    future::timeout,
+    io::{prelude::WriteExt, ReadExt},
+    net::TcpStream,
+    task::sleep,
+};
+use serde::{Deserialize, Serialize};
+use serde_yaml::Value;
+use std::{
+    fmt,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use crate::config::*;
+
+const DEFAULT_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_INTERVAL_MS: u64 = 100;
+
+pub(crate) const CONDITION_KEYS: &[&str] = &[
+    "port",
+    "host",
+    "url",
+    "status",
+    "file",
+    "timeout",
+    "interval_ms",
+];
+
+/// Something that has to become true before `run` can start, as given in the
+/// `wait_for` config key.
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct WaitCondition {
+    check: Check,
+    timeout: u64,
+    interval_ms: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "lowercase")]
+enum Check {
+    /// Something accepts TCP connections on this port.
+    Port { host: String, port: u16 },
+    /// A GET of this URL answers with the status, or any 2xx if none is given.
+    Url { url: String, status: Option<u16> },
+    /// This file exists.
+    File(String),
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Check::Port { host, port } => write!(f, "port {}:{}", host, port),
+            Check::Url {
+                url,
+                status: Some(status),
+            } => write!(f, "url {} (status {})", url, status),
+            Check::Url { url, status: None } => write!(f, "url {}", url),
+            Check::File(path) => write!(f, "file {}", path),
+        }
+    }
+}
+
+impl WaitCondition {
+    pub(crate) fn parse(value: &Value) -> Result<Self> {
+        let value = value
+            .as_mapping()
+            .ok_or_else(|| anyhow!("must be an object"))?;
+        let get = |key: &str| value.get(&key.into());
+        let number = |key: &str| -> Result<Option<u64>> {
+            get(key)
+                .map(|val| {
+                    val.as_u64()
+                        .ok_or_else(|| anyhow!("'.{}' must be a non-negative integer", key))
+                })
+                .transpose()
+        };
+        for (key, _) in value.iter() {
+            let key = key.as_str().unwrap_or_default();
+            ensure!(CONDITION_KEYS.contains(&key), "has unknown key '{}'", key);
+        }
+        let check = match (get("port"), get("url"), get("file")) {
+            (Some(_), None, None) => Check::Port {
+                host: match get("host") {
+                    Some(host) => host
+                        .as_str()
+                        .ok_or_else(|| anyhow!("'.host' must be a string"))?
+                        .to_owned(),
+                    None => "localhost".to_owned(),
+                },
+                port: number("port")?
+                    .filter(|port| *port <= u16::MAX as u64)
+                    .ok_or_else(|| anyhow!("'.port' must be a valid port number"))?
+                    as u16,
+            },
+            (None, Some(url), None) => {
+                let url = url
+                    .as_str()
+                    .ok_or_else(|| anyhow!("'.url' must be a string"))?;
+                parse_url(url)?;
+                Check::Url {
+                    url: url.to_owned(),
+                    status: number("status")?.map(|status| status as u16),
+                }
+            }
+            (None, None, Some(file)) => Check::File(
+                file.as_str()
+                    .ok_or_else(|| anyhow!("'.file' must be a string"))?
+                    .to_owned(),
+            ),
+            _ => bail!("needs exactly one of 'port', 'url' or 'file'"),
+        };
+        Ok(WaitCondition {
+            check,
+            timeout: number("timeout")?.unwrap_or(DEFAULT_TIMEOUT_SECS),
+            interval_ms: number("interval_ms")?.unwrap_or(DEFAULT_INTERVAL_MS),
+        })
+    }
+
+    async fn is_met(&self, config: &Config) -> bool {
+        match &self.check {
+            Check::Port { host, port } => TcpStream::connect((host.as_str(), *port)).await.is_ok(),
+            Check::Url { url, status } => match http_status(url).await {
+                Ok(actual) => match status {
+                    Some(status) => actual == *status,
+                    None => (200..300).contains(&actual),
+                },
+                Err(_) => false,
+            },
+            Check::File(path) => match &config.cwd {
+                Some(cwd) => Path::new(cwd).join(path).exists(),
+                None => Path::new(path).exists(),
+            },
+        }
+    }
+}
+
+/// Waits until every one of the config's `wait_for` conditions holds. Each
+/// condition's timeout counts from when the waiting started.
+pub(crate) async fn wait_for(config: &Config) -> Result<()> {
+    let start = Instant::now();
+    for condition in &config.wait_for {
+        let deadline = Duration::from_secs(condition.timeout);
+        let interval = Duration::from_millis(condition.interval_ms);
+        loop {
+            let remaining = deadline
+                .checked_sub(start.elapsed())
+                .filter(|remaining| *remaining > Duration::from_millis(0))
+                .ok_or_else(|| {
+                    anyhow!(
+                        "wait_for condition never became true within {}s: {}",
+                        condition.timeout,
+                        condition.check
+                    )
+                })?;
+            // A single check can hang, e.g. connecting to a port nothing
+            // answers on, so it can't be allowed to outlast the deadline.
+            if let Ok(true) = timeout(remaining, condition.is_met(config)).await {
+                break;
+            }
+            sleep(interval.min(deadline.saturating_sub(start.elapsed()))).await;
+        }
+    }
+    Ok(())
+}
+
+/// Splits an `http://` URL into host, port and path.
+fn parse_url(url: &str) -> Result<(&str, u16, &str)> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| anyhow!("'.url' must start with http://, got '{}'", url))?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rfind(':') {
+        Some(i) => (
+            &authority[..i],
+            authority[i + 1..]
+                .parse()
+                .map_err(|_| anyhow!("'.url' has an invalid port in '{}'", url))?,
+        ),
+        None => (authority, 80),
+    };
+    ensure!(!host.is_empty(), "'.url' has no host in '{}'", url);
+    Ok((host, port, path))
+}
+
+async fn http_status(url: &str) -> Result<u16> {
+    let (host, port, path) = parse_url(url)?;
+    let mut stream = TcpStream::connect((host, port)).await?;
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        path, host
+    );
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = Vec::new();
+    let mut buf = [0; 256];
+    while !response.contains(&b'\n') {
+        let len = stream.read(&mut buf).await?;
+        ensure!(len > 0, "connection closed before a status line was sent");
+        response.extend_from_slice(&buf[..len]);
+    }
+    // e.g. `HTTP/1.1 200 OK`
+    String::from_utf8_lossy(&response)
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| anyhow!("invalid HTTP response"))
+}
@@ -3,7 +3,6 @@
 //
 // This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2021 Datadog, Inc.
 
-use assert_cmd;
 use predicates::prelude::*;
 use serial_test::serial;
 use std::path::PathBuf;
@@ -28,6 +27,12 @@ macro_rules! json_has {
     };
 }
 
+/// The results `sirun` printed, which come after anything the benchmark did.
+fn last_results(output: &assert_cmd::assert::Assert) -> serde_json::Value {
+    let stdout = std::str::from_utf8(&output.get_output().stdout).unwrap();
+    serde_json::from_str(stdout.lines().last().unwrap()).unwrap()
+}
+
 #[test]
 #[serial]
 fn simple_json() {
@@ -43,7 +48,7 @@ fn wall_and_cpu_pct() {
             .unwrap()
             .as_sequence()
             .unwrap();
-        let map = map.get(0).unwrap().as_mapping().unwrap();
+        let map = map.first().unwrap().as_mapping().unwrap();
         let wall_time = map.get(&"wall.time".into()).unwrap().as_f64().unwrap();
         let stime = map.get(&"system.time".into()).unwrap().as_f64().unwrap();
         let utime = map.get(&"user.time".into()).unwrap().as_f64().unwrap();
@@ -72,6 +77,23 @@ fn simple_name_env() {
         .stdout(predicate::str::contains("\"name\":\"test test\""));
 }
 
+#[test]
+#[serial]
+fn sorted_keys() {
+    run!("examples/simple.json")
+        .env("SIRUN_NAME", "test test")
+        .env("SIRUN_NO_STDIO", "1")
+        .assert()
+        .success()
+        .stdout(predicate::function(|out: &str| {
+            let top = out.find("\"iterations\"").unwrap() < out.find("\"name\"").unwrap();
+            let iteration = out.find("\"cpu.pct.wall.time\"").unwrap()
+                < out.find("\"user.time\"").unwrap()
+                && out.find("\"user.time\"").unwrap() < out.find("\"wall.time\"").unwrap();
+            top && iteration
+        }));
+}
+
 #[test]
 #[serial]
 fn simple_name() {
@@ -138,6 +160,58 @@ fn all_variants() {
         .stdout(predicate::str::contains("variant 0").and(predicate::str::contains("variant 1")));
 }
 
+#[test]
+#[serial]
+fn variant_comparison() {
+    let table = predicate::function(|err: &str| {
+        err.contains("Means compared with variant \"0\":\nmetric ")
+            && err.lines().any(|line| {
+                line.split_whitespace().collect::<Vec<_>>() == ["udp.data", "50.0", "+0.0%"]
+            })
+    });
+    run!("./examples/variants.json")
+        .assert()
+        .success()
+        .stderr(table)
+        .stdout(predicate::str::contains("\"variant\":\"1\""));
+    // Results written to a file are compared too.
+    let output = std::env::temp_dir().join("sirun-variant-comparison.ndjson");
+    run!("--output")
+        .args([output.to_str().unwrap(), "./examples/variants.json"])
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "Means compared with variant \"0\":",
+        ));
+    std::fs::remove_file(&output).unwrap();
+    // Another benchmark's streamed iterations of the same variants are
+    // left out.
+    let config = format!(
+        "output_file: {}\nvariants:\n  fast: {{env: {{N: '1'}}}}\n  slow: {{env: {{N: '2'}}}}\nbenchmarks:\n  a:\n    run: bash -c \"echo udp.data:$((N*10))\\\\|g > /dev/udp/127.0.0.1/$SIRUN_STATSD_PORT\"\n  b:\n    run: bash -c \"echo udp.data:$((N*1000))\\\\|g > /dev/udp/127.0.0.1/$SIRUN_STATSD_PORT\"\n",
+        output.display()
+    );
+    let means = predicate::function(|err: &str| {
+        let means: Vec<_> = err
+            .lines()
+            .filter(|line| line.starts_with("udp.data"))
+            .map(|line| line.split_whitespace().nth(1).unwrap())
+            .collect();
+        means == ["10.0", "1000"]
+    });
+    run!("--stream")
+        .arg("-")
+        .write_stdin(config)
+        .assert()
+        .success()
+        .stderr(means);
+    std::fs::remove_file(output).unwrap();
+    run!("--quiet")
+        .arg("./examples/variants.json")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Means compared").not());
+}
+
 #[test]
 #[serial]
 fn timeout() {
@@ -194,6 +268,154 @@ fn cachegrind() {
     }
 }
 
+#[test]
+#[serial]
+fn valgrind() {
+    if which::which("valgrind").is_err() {
+        run!("./examples/valgrind.json")
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains(
+                "'valgrind' needs the 'valgrind' executable to run cachegrind",
+            ));
+        return;
+    }
+    json_has!("./examples/valgrind.json", |map: &serde_yaml::Mapping| {
+        let iterations = map.get(&"iterations".into()).unwrap().as_sequence().unwrap();
+        iterations.len() == 2
+            && iterations.iter().all(|iteration| {
+                ["instructions", "cache.d1.miss", "cache.ll.miss", "wall.time.under.valgrind"]
+                    .iter()
+                    .all(|key| iteration.get(key).is_some())
+                    && iteration.get("wall.time").is_none()
+            })
+    });
+    run!("-")
+        .write_stdin("run: 'true'\nvalgrind: callgrind\n")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("\"cache.ll.miss\":"));
+}
+
+#[test]
+#[serial]
+fn perf_stat() {
+    if which::which("perf").is_err() {
+        run!("./examples/perf-stat.json")
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains(
+                "'perf_stat' needs the 'perf' executable",
+            ));
+    } else if std::process::Command::new("perf")
+        .args(["stat", "-e", "instructions,cycles", "true"])
+        .output()
+        .is_ok_and(|output| output.status.success())
+    {
+        json_has!("./examples/perf-stat.json", |map: &serde_yaml::Mapping| {
+            let iterations = map
+                .get(&"iterations".into())
+                .unwrap()
+                .as_sequence()
+                .unwrap();
+            iterations.iter().all(|iteration| {
+                iteration.get("instructions").is_some() && iteration.get("cycles").is_some()
+            })
+        });
+    }
+    run!("-")
+        .write_stdin("run: 'true'\nperf_stat: [instructions]\nvalgrind: true\n")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "'valgrind' and 'perf_stat' can't be used together",
+        ));
+    run!("-")
+        .write_stdin("run: 'true'\nperf_stat: ['cycles,instructions']\n")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "'perf_stat[0]' must be a single event name",
+        ));
+}
+
+#[test]
+#[serial]
+fn heap_profile() {
+    if which::which("valgrind").is_err() {
+        run!("./examples/heap-profile.json")
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains(
+                "'heap_profile' needs the 'valgrind' executable to run massif",
+            ));
+    } else {
+        json_has!(
+            "./examples/heap-profile.json",
+            |map: &serde_yaml::Mapping| {
+                let iterations = map
+                    .get(&"iterations".into())
+                    .unwrap()
+                    .as_sequence()
+                    .unwrap();
+                iterations[0].get("heap.peak.bytes").is_some()
+                    && iterations[0].get("wall.time.under.valgrind").is_some()
+            }
+        );
+    }
+    run!("-")
+        .write_stdin("run: 'true'\nheap_profile: massif\nvalgrind: true\n")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "'valgrind' and 'heap_profile' can't be used together",
+        ));
+    run!("-")
+        .write_stdin("run: 'true'\nheap_profile: dhat\n")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "'heap_profile' must be 'massif' or false",
+        ));
+}
+
+#[test]
+#[serial]
+fn syscalls() {
+    if which::which("strace").is_err() {
+        run!("./examples/syscalls.json")
+            .assert()
+            .failure()
+            .stderr(predicate::str::contains(
+                "'syscalls' needs the 'strace' executable",
+            ));
+    } else if std::process::Command::new("strace")
+        .args(["-c", "-o", "/dev/null", "true"])
+        .output()
+        .is_ok_and(|output| output.status.success())
+    {
+        json_has!("./examples/syscalls.json", |map: &serde_yaml::Mapping| {
+            let iterations = map
+                .get(&"iterations".into())
+                .unwrap()
+                .as_sequence()
+                .unwrap();
+            let iteration = &iterations[0];
+            iteration.get("syscalls.total").is_some()
+                && iteration.get("wall.time.under.strace").is_some()
+                && iteration.get("user.time.under.strace").is_some()
+                && iteration.get("wall.time").is_none()
+        });
+    }
+    run!("-")
+        .write_stdin("run: 'true'\nsyscalls: true\nperf_stat: [cycles]\n")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "'perf_stat' and 'syscalls' can't be used together",
+        ));
+}
+
 #[test]
 #[serial]
 fn iterations() {
@@ -273,10 +495,48 @@ fn summarize() {
     in_path.push("tests/fixtures/summary/in.ndjson");
     let mut out_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
     out_path.push("tests/fixtures/summary/out.json");
+    let expected = std::fs::read_to_string(&out_path).unwrap();
     run!("--summarize")
-        .write_stdin(std::fs::read(in_path).unwrap())
-        .output()
-        .expect(&std::fs::read_to_string(out_path).unwrap());
+        .write_stdin(std::fs::read(&in_path).unwrap())
+        .assert()
+        .success()
+        .stdout(expected.clone());
+    run!("--summarize")
+        .arg(&in_path)
+        .assert()
+        .success()
+        .stdout(expected);
+}
+
+#[test]
+#[serial]
+fn summarize_files() {
+    run!("--summarize")
+        .args(["tests/fixtures/summary/in.ndjson", "tests/fixtures/summary/flagged.ndjson"])
+        .assert()
+        .success()
+        .stdout(predicate::function(|out: &str| {
+            let val = serde_yaml::from_str::<serde_yaml::Value>(out).unwrap();
+            val["encoders"]["0.5"]["summary"]["wall.time"]["mean"].is_f64()
+                && val["flagged"]["b"]["summary"]["wall.time"]["mean"].as_f64() == Some(4000.0)
+        }));
+    run!("--summarize")
+        .args(["tests/fixtures/summary/missing.ndjson"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "cannot read results file tests/fixtures/summary/missing.ndjson",
+        ));
+    run!("--summarize")
+        .args(["--merge-iterations", "--exclude-flagged"])
+        .args(["tests/fixtures/summary/flagged.ndjson", "tests/fixtures/summary/flagged.ndjson"])
+        .assert()
+        .success()
+        .stdout(predicate::function(|out: &str| {
+            let val = serde_yaml::from_str::<serde_yaml::Value>(out).unwrap();
+            val["flagged"]["a"]["excluded"].as_f64() == Some(2.0)
+                && val["flagged"]["b"]["summary"]["wall.time"]["mean"].as_f64() == Some(4000.0)
+        }));
 }
 
 #[test]
@@ -290,6 +550,40 @@ fn assigned_port() {
         .stdout(predicate::str::contains("\"udp.data\":8125"));
 }
 
+#[test]
+#[serial]
+fn statsd_host() {
+    let output = run!("./examples/statsd-host.json")
+        .env("SIRUN_STATSD_PORT", "8125")
+        .env("SIRUN_NO_STDIO", "1")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout.matches("\"udp.data\":8125").count(), 2);
+
+    // Binding to one of the machine's own non-loopback addresses, if it has
+    // one. Connecting a UDP socket sends nothing, it only picks the address.
+    let sock = std::net::UdpSocket::bind("0.0.0.0:0").unwrap();
+    let host = match sock.connect("192.0.2.1:9").and_then(|_| sock.local_addr()) {
+        Ok(addr) if !addr.ip().is_loopback() && !addr.ip().is_unspecified() => addr.ip(),
+        _ => return,
+    };
+    run!("--variant")
+        .args(["loopback", "./examples/statsd-host.json"])
+        .env("SIRUN_STATSD_HOST", host.to_string())
+        .env("SIRUN_NO_STDIO", "1")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("\"udp.data\":"));
+    run!("--variant")
+        .args(["loopback", "./examples/statsd-host.json"])
+        .env("SIRUN_STATSD_HOST", "256.0.0.1")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("cannot bind statsd to 256.0.0.1:0"));
+}
+
 #[test]
 #[serial]
 #[cfg(target_os = "linux")]
@@ -307,6 +601,3222 @@ fn insctrution_counts() {
 
 #[test]
 #[serial]
-fn service() {
-    run!("./examples/service.json").assert().success();
+#[cfg(target_os = "linux")]
+fn cgroup_accounting() {
+    json_has!(
+        "./examples/cgroup-accounting.yml",
+        |map: &serde_yaml::Mapping| {
+            // Iterations whose metrics were lost over statsd have nothing to check.
+            let mut iterations = map[&"iterations".into()]
+                .as_sequence()
+                .unwrap()
+                .iter()
+                .filter(|iteration| iteration["flags"].as_sequence().unwrap().is_empty());
+            match map[&"accounting".into()].as_str() {
+                Some("cgroup") => iterations.all(|iteration| {
+                    iteration["cpu.pct.wall.time"].as_f64().unwrap() > 20.0
+                }),
+                // Where cgroups can't be used, nothing else changes.
+                Some("rusage") => iterations.all(|iteration| iteration["user.time"].is_f64()),
+                _ => false,
+            }
+        }
+    );
+    json_has!("./examples/simple.json", |map: &serde_yaml::Mapping| {
+        !map.contains_key(&"accounting".into())
+    });
+}
+
+#[test]
+#[serial]
+fn max_memory() {
+    run!("./examples/max-memory.yml")
+        .env("SIRUN_NO_STDIO", "1")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "Test exceeded its memory limit of 100M, so recording a failed iteration.",
+        ))
+        .stdout(predicate::str::contains("\"oom_killed\":1.0"));
+    run!("-")
+        .write_stdin(
+            "run: \"bash -c 'x=$(head -c 300000000 /dev/zero | tr \\\"\\\\0\\\" a)'\"\nmax_memory: 104857600\n",
+        )
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Memory limit 100M exceeded, so aborting test."));
+    run!("-")
+        .write_stdin("run: 'true'\nmax_memory: 1G\n")
+        .assert()
+        .success();
+    // Without a cgroup, every copy is limited, not just the last one started.
+    run!("-")
+        .write_stdin(
+            "run: \"bash -c 'test $SIRUN_WORKER_INDEX = 1 || x=$(head -c 300000000 /dev/zero | tr \\\"\\\\0\\\" a)'\"\nmax_memory: 100M\nconcurrency: 2\ncontinue_on_failure: true\n",
+        )
+        .env("SIRUN_NO_STDIO", "1")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "Test worker 0 exceeded its memory limit of 100M, so recording a failed iteration.",
+        ))
+        .stdout(predicate::str::contains("\"oom_killed\":1.0"));
+    // A crash isn't taken for running out of memory.
+    run!("-")
+        .write_stdin("run: bash -c 'kill -SEGV $$'\nmax_memory: 1G\ncontinue_on_failure: true\n")
+        .env("SIRUN_NO_STDIO", "1")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("was terminated via signal 11"))
+        .stdout(predicate::str::contains("oom_killed").not());
+    run!("-")
+        .write_stdin("run: 'true'\nmax_memory: lots\n")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "'max_memory' must be a number of bytes, or a size like '512M'",
+        ));
+}
+
+#[test]
+#[serial]
+#[cfg(target_os = "linux")]
+fn disable_aslr() {
+    run!("./examples/disable-aslr.yml")
+        .assert()
+        .success()
+        .stdout(predicate::function(|out: &str| {
+            let lines: Vec<&str> = out.lines().collect();
+            let results = serde_yaml::from_str::<serde_yaml::Value>(lines[4]).unwrap();
+            // Both iterations' stacks are in the same place.
+            lines[0] == "00040000"
+                && lines[2] == "00040000"
+                && lines[1] == lines[3]
+                && results["disable_aslr"].as_f64() == Some(1.0)
+        }));
+    json_has!("./examples/simple.json", |map: &serde_yaml::Mapping| {
+        !map.contains_key(&"disable_aslr".into())
+    });
+}
+
+#[test]
+#[serial]
+fn drop_caches() {
+    json_has!("./examples/drop-caches.yml", |map: &serde_yaml::Mapping| {
+        let iterations = map
+            .get(&"iterations".into())
+            .unwrap()
+            .as_sequence()
+            .unwrap();
+        iterations.iter().all(|iteration| {
+            iteration["caches.dropped"].as_f64() == Some(1.0)
+                && iteration["caches.drop.ms"].as_f64().is_some()
+        })
+    });
+    json_has!("./examples/simple.json", |map: &serde_yaml::Mapping| {
+        let iterations = map
+            .get(&"iterations".into())
+            .unwrap()
+            .as_sequence()
+            .unwrap();
+        iterations[0].get("caches.dropped").is_none()
+    });
+    run!("-")
+        .write_stdin("run: 'true'\ndrop_caches: 'false'\n")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "'drop_caches' helper [\"false\"] failed",
+        ));
+    run!("-")
+        .write_stdin("run: 'true'\ndrop_caches: []\n")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "'drop_caches' must be a boolean or a helper command",
+        ));
+    let direct = run!("-")
+        .write_stdin("run: 'true'\ndrop_caches: true\n")
+        .assert();
+    if std::fs::OpenOptions::new()
+        .write(true)
+        .open("/proc/sys/vm/drop_caches")
+        .is_ok()
+    {
+        direct.success();
+    } else {
+        direct
+            .failure()
+            .stderr(predicate::str::contains("needs root or CAP_SYS_ADMIN"));
+    }
+}
+
+#[test]
+#[serial]
+fn exclusive() {
+    let lock_file = std::env::temp_dir().join(format!("sirun-{}.lock", std::process::id()));
+    let hold = || {
+        let child = std::process::Command::new(assert_cmd::cargo::cargo_bin("sirun"))
+            .arg("./examples/exclusive.yml")
+            .env("SIRUN_LOCK_FILE", &lock_file)
+            .env("SIRUN_NO_STDIO", "1")
+            .stdout(std::process::Stdio::null())
+            .spawn()
+            .unwrap();
+        // The lock file gets the holder's pid once it's locked.
+        let pid = child.id().to_string();
+        while std::fs::read_to_string(&lock_file).unwrap_or_default() != pid {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        child
+    };
+
+    let mut holder = hold();
+    run!("./examples/simple.json")
+        .args(["--exclusive", "--set", "exclusive_timeout=0"])
+        .env("SIRUN_LOCK_FILE", &lock_file)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(format!(
+            "gave up waiting for pid {} to release",
+            holder.id()
+        )));
+    // Runs that aren't exclusive don't wait.
+    run!("./examples/simple.json")
+        .env("SIRUN_LOCK_FILE", &lock_file)
+        .assert()
+        .success();
+    run!("./examples/simple.json")
+        .arg("--exclusive")
+        .env("SIRUN_LOCK_FILE", &lock_file)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(format!(
+            "waiting up to 600s for pid {} to release",
+            holder.id()
+        )));
+    assert!(holder.wait().unwrap().success());
+
+    // A holder that's killed leaves the lock free, though its pid is left in
+    // the file.
+    let mut holder = hold();
+    holder.kill().unwrap();
+    holder.wait().unwrap();
+    run!("./examples/simple.json")
+        .args(["--exclusive", "--set", "exclusive_timeout=0"])
+        .env("SIRUN_LOCK_FILE", &lock_file)
+        .assert()
+        .success();
+    std::fs::remove_file(lock_file).unwrap();
+}
+
+#[test]
+#[serial]
+fn reap_orphans() {
+    run!("./examples/reap-orphans.yml")
+        .env("SIRUN_NO_STDIO", "1")
+        .timeout(std::time::Duration::from_secs(30))
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("killing stray process"))
+        .stdout(predicate::function(|out: &str| {
+            let results = serde_yaml::from_str::<serde_yaml::Value>(out).unwrap();
+            let iterations = results["iterations"].as_sequence().unwrap();
+            iterations.iter().all(|iteration| {
+                iteration["orphans.killed"].as_f64() == Some(2.0)
+                    && iteration["wall.time"].as_f64().unwrap() < 10_000_000.0
+            })
+        }));
+    // Strays of services are killed at the end of the iteration, along with
+    // the service's own child, which outlives it.
+    let pid_file = std::env::temp_dir().join(format!("sirun-stray-{}", std::process::id()));
+    run!("-")
+        .env("SIRUN_NO_STDIO", "1")
+        .env("STRAY_PID_FILE", &pid_file)
+        .write_stdin(
+            "service: \"sh -c '(sleep 60 & echo $! > $STRAY_PID_FILE); sleep 60'\"\n\
+             setup: \"sh -c 'test -s $STRAY_PID_FILE'\"\n\
+             run: 'true'\n\
+             reap_orphans: true\n",
+        )
+        .timeout(std::time::Duration::from_secs(30))
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("\"orphans.killed\":2.0"));
+    let stray = std::fs::read_to_string(&pid_file).unwrap();
+    assert!(!std::path::Path::new(&format!("/proc/{}", stray.trim())).exists());
+    std::fs::remove_file(pid_file).unwrap();
+}
+
+#[test]
+#[serial]
+fn nest_metrics() {
+    let output = run!("./examples/nest-metrics.yml")
+        .env("SIRUN_NO_STDIO", "1")
+        .env("SIRUN_VARIANT", "nested")
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let results = serde_yaml::from_slice::<serde_yaml::Value>(&output).unwrap();
+    let iteration = &results["iterations"][0];
+    assert_eq!(iteration["http"]["p50"].as_f64(), Some(5.0));
+    assert_eq!(iteration["db"]["p99"].as_f64(), Some(5.0));
+    // sirun's own metrics keep their names.
+    assert!(iteration["wall.time"].as_f64().is_some());
+    assert!(iteration["http.p50"].is_null());
+
+    run!("--summarize")
+        .write_stdin(output)
+        .assert()
+        .success()
+        .stdout(predicate::function(|out: &str| {
+            let summary = serde_yaml::from_str::<serde_yaml::Value>(out).unwrap();
+            let summary = &summary["nest-metrics"]["nested"]["summary"];
+            summary["http"]["p99"]["mean"].as_f64() == Some(20.0)
+                && summary["wall.time"]["mean"].as_f64().is_some()
+        }));
+
+    run!("./examples/nest-metrics.yml")
+        .env("SIRUN_NO_STDIO", "1")
+        .env("SIRUN_VARIANT", "collision")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "'http' is both a metric and a group of metrics",
+        ));
+}
+
+#[test]
+#[serial]
+fn mixed_metrics() {
+    run!("--summarize")
+        .arg("tests/fixtures/summary/mixed.ndjson")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "Warning: skipping results on line 2 of tests/fixtures/summary/mixed.ndjson: \
+             'wall.time' is both a metric and a group of metrics",
+        ))
+        .stderr(predicate::str::contains(
+            "Warning: skipping results on line 3 of tests/fixtures/summary/mixed.ndjson: \
+             invalid results",
+        ))
+        .stderr(predicate::str::contains(
+            "Warning: skipping results on line 4 of tests/fixtures/summary/mixed.ndjson: \
+             not JSON: not json",
+        ))
+        .stdout(predicate::function(|out: &str| {
+            let val = serde_yaml::from_str::<serde_yaml::Value>(out).unwrap();
+            let summary = &val["mixed"]["types"]["summary"];
+            summary["wall.time"]["mean"].as_f64() == Some(2000.0)
+                && summary["http"]["p99"]["mean"].as_f64() == Some(5.0)
+                && summary["build"].is_null()
+                && summary["phases"].is_null()
+                && val["mixed"].get("clash").is_none()
+                && val["mixed"].get("flag").is_none()
+        }));
+}
+
+#[test]
+#[serial]
+fn require_complete() {
+    let summary = |out: &str| {
+        let val = serde_yaml::from_str::<serde_yaml::Value>(out).unwrap();
+        let crashy = &val["crashy"]["gc"]["summary"];
+        let steady = &val["steady"]["gc"]["summary"];
+        crashy["gc.pause"]["count"].as_f64() == Some(1.0)
+            && crashy["gc.pause"]["coverage_pct"].as_f64() == Some(25.0)
+            && crashy["wall.time"]["count"].as_f64() == Some(4.0)
+            && crashy["wall.time"]["coverage_pct"].as_f64() == Some(100.0)
+            && steady["gc.pause"]["coverage_pct"].as_f64() == Some(100.0)
+    };
+    run!("--summarize")
+        .arg("tests/fixtures/summary/incomplete.ndjson")
+        .assert()
+        .success()
+        .stdout(predicate::function(summary));
+    run!("--summarize")
+        .args([
+            "--require-complete",
+            "tests/fixtures/summary/incomplete.ndjson",
+        ])
+        .assert()
+        .failure()
+        .stdout(predicate::function(summary))
+        .stderr(predicate::str::contains(
+            "some iterations are missing metrics, with --require-complete: 'gc.pause' of crashy/gc",
+        ))
+        .stderr(predicate::str::contains("steady").not());
+}
+
+#[test]
+#[serial]
+fn summary_stats() {
+    run!("--summarize")
+        .args([
+            "--stats",
+            "mean,p50,geomean",
+            "tests/fixtures/summary/incomplete.ndjson",
+        ])
+        .assert()
+        .success()
+        .stdout(predicate::function(|out: &str| {
+            let val = serde_yaml::from_str::<serde_yaml::Value>(out).unwrap();
+            let wall = val["crashy"]["gc"]["summary"]["wall.time"]
+                .as_mapping()
+                .unwrap();
+            wall.len() == 3
+                && wall[&"mean".into()].as_f64() == Some(2500.0)
+                && wall[&"p50".into()].as_f64() == Some(2500.0)
+                && (wall[&"geomean".into()].as_f64().unwrap() - 24f64.powf(0.25) * 1000.0).abs()
+                    < 1e-6
+        }));
+    run!("--summarize")
+        .args(["--stats", "mean,median"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("unknown statistic 'median'"));
+}
+
+#[test]
+#[serial]
+fn compare_variants() {
+    let fixture = "tests/fixtures/summary/variants.ndjson";
+    run!("--summarize")
+        .args(["--compare-variants", "before", fixture])
+        .assert()
+        .success()
+        .stdout(predicate::function(|out: &str| {
+            let val = serde_yaml::from_str::<serde_yaml::Value>(out).unwrap();
+            let server = &val["server"];
+            let faster = &server["after"]["comparison"]["wall.time"];
+            let cpu = &server["after"]["comparison"]["cpu"]["pct"];
+            let noisy = &server["noisy"]["comparison"]["wall.time"];
+            server["after"]["compared_with"].as_str() == Some("before")
+                && faster["diff_pct"].as_f64() == Some(-20.0)
+                && faster["p_value"].as_f64().unwrap() < 0.001
+                && faster["significant"].as_f64() == Some(1.0)
+                && cpu["diff_pct"].as_f64() == Some(0.0)
+                && cpu["significant"].as_f64() == Some(0.0)
+                && noisy["diff_pct"].as_f64() == Some(1.0)
+                && noisy["p_value"].as_f64().unwrap() > 0.5
+                && noisy["significant"].as_f64() == Some(0.0)
+                && server["before"].get("comparison").is_none()
+                && val["client"]["after"].get("comparison").is_none()
+        }));
+    // Even the slightest chance of a fluke is too much for a tiny alpha.
+    run!("--summarize")
+        .args(["--compare-variants", "before", "--alpha", "1e-9", fixture])
+        .assert()
+        .success()
+        .stdout(predicate::function(|out: &str| {
+            let val = serde_yaml::from_str::<serde_yaml::Value>(out).unwrap();
+            val["server"]["after"]["comparison"]["wall.time"]["significant"].as_f64() == Some(0.0)
+        }));
+    run!("--summarize")
+        .args(["--compare-variants", "before", "--alpha", "5"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "--alpha must be between 0 and 1, got '5'",
+        ));
+    run!("--summarize")
+        .args(["--alpha", "0.01"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "--alpha only applies with --compare-variants",
+        ));
+}
+
+#[test]
+#[serial]
+fn summary_csv() {
+    let fixture = "tests/fixtures/summary/csv.ndjson";
+    run!("--summarize")
+        .args(["--format", "csv", fixture])
+        .assert()
+        .success()
+        .stdout(
+            "name,variant,metric,mean,stddev,stddev_pct,min,max,count,coverage_pct\n\
+             \"a,b\",x,http.p99,5,1,20,4,6,2,100\n\
+             \"a,b\",x,\"say \"\"hi\"\", twice\",3,1,33.33333333333333,2,4,2,100\n\
+             \"a,b\",x,wall.time,2000,1000,50,1000,3000,2,100\n\
+             c,y,wall.time,10,0,0,10,10,1,100\n",
+        );
+    run!("--summarize")
+        .args(["--format", "csv-wide", "--stats", "mean,p50", fixture])
+        .assert()
+        .success()
+        .stdout(
+            "name,variant,http.p99.mean,http.p99.p50,\
+             \"say \"\"hi\"\", twice.mean\",\"say \"\"hi\"\", twice.p50\",\
+             wall.time.mean,wall.time.p50\n\
+             \"a,b\",x,5,5,3,3,2000,2000\n\
+             c,y,,,,,10,10\n",
+        );
+    run!("--summarize")
+        .args(["--format", "xml"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "--format must be one of json, csv, csv-wide or markdown, got 'xml'",
+        ));
+}
+
+#[test]
+#[serial]
+fn summary_markdown() {
+    let fixture = "tests/fixtures/summary/variants.ndjson";
+    run!("--summarize")
+        .args([
+            "--format",
+            "markdown",
+            "--metrics",
+            "wall.time,cpu.pct",
+            fixture,
+        ])
+        .assert()
+        .success()
+        .stdout(
+            "### client\n\
+             \n\
+             | variant | wall.time | cpu.pct |\n\
+             | --- | ---: | ---: |\n\
+             | after | 52.5 ± 2.5 µs | |\n\
+             \n\
+             ### server\n\
+             \n\
+             | variant | wall.time | cpu.pct |\n\
+             | --- | ---: | ---: |\n\
+             | after | 80.0 ± 1.4 µs | 50.0 ± 2.0% |\n\
+             | before | 100 ± 1 µs | 50.0 ± 1.3% |\n\
+             | noisy | 101 ± 3 µs | |\n",
+        );
+    run!("--summarize")
+        .args(["--format", "markdown", fixture])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "| variant | cpu.pct | wall.time |\n",
+        ));
+    run!("--summarize")
+        .args(["--metrics", "wall.time", fixture])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "--metrics only applies with --format markdown",
+        ));
+}
+
+#[test]
+#[serial]
+fn summary_table() {
+    let table = predicate::function(|err: &str| {
+        err.contains("Statistics over 3 iterations:\nmetric ")
+            && err.lines().any(|line| line.starts_with("wall.time "))
+    });
+    run!("--summary-table")
+        .args(["--iterations", "3", "./examples/simple.yml"])
+        .assert()
+        .success()
+        .stderr(table)
+        .stdout(predicate::function(|out: &str| {
+            let last = out.lines().last().unwrap_or_default();
+            serde_json::from_str::<serde_json::Value>(last).is_ok()
+        }));
+    // Only on a terminal unless it's asked for, and never when quiet.
+    run!("--iterations")
+        .args(["3", "./examples/simple.yml"])
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Statistics over").not());
+    run!("--summary-table")
+        .args(["--quiet", "./examples/simple.yml"])
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Statistics over").not());
+    run!("--summary-table")
+        .arg("./examples/simple.yml")
+        .env("SIRUN_NO_STDIO", "1")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Statistics over").not());
+}
+
+#[test]
+#[serial]
+fn group_by() {
+    let twice = [
+        "tests/fixtures/summary/flagged.ndjson",
+        "tests/fixtures/summary/flagged.ndjson",
+    ];
+    run!("--summarize")
+        .args(twice)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "Warning: results for name \"flagged\" and variant \"a\" replace earlier ones; \
+             pass --group-by all to combine them instead",
+        ))
+        .stdout(predicate::function(|out: &str| {
+            let val = serde_yaml::from_str::<serde_yaml::Value>(out).unwrap();
+            let a = &val["flagged"]["a"];
+            a["summary"]["wall.time"]["count"].as_f64() == Some(3.0) && a.get("runs").is_none()
+        }));
+    run!("--summarize")
+        .args(["--group-by", "all"])
+        .args(twice)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("replace earlier ones").not())
+        .stdout(predicate::function(|out: &str| {
+            let val = serde_yaml::from_str::<serde_yaml::Value>(out).unwrap();
+            let a = &val["flagged"]["a"];
+            a["summary"]["wall.time"]["count"].as_f64() == Some(6.0)
+                && a["runs"].as_f64() == Some(2.0)
+        }));
+    // Each line is a run of its own, even from a single file.
+    run!("--summarize")
+        .args(["--group-by", "run", "tests/fixtures/summary/flagged.ndjson"])
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("replace earlier ones").not());
+    run!("--summarize")
+        .args(["--group-by", "day"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "--group-by must be run or all, got 'day'",
+        ));
+}
+
+#[test]
+#[serial]
+fn summarize_without_variant() {
+    run!("--summarize")
+        .arg("tests/fixtures/summary/no-variant.ndjson")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "Warning: skipped 4 lines that weren't summarized: 1 invalid, 1 not JSON, \
+             1 without a name, 1 without iterations",
+        ))
+        .stdout(predicate::function(|out: &str| {
+            let val = serde_yaml::from_str::<serde_yaml::Value>(out).unwrap();
+            let plain = val["plain"].as_mapping().unwrap();
+            plain.len() == 2
+                && plain[&"default".into()]["summary"]["wall.time"]["mean"].as_f64() == Some(2000.0)
+                && plain[&"fast".into()]["summary"]["wall.time"]["mean"].as_f64() == Some(5000.0)
+        }));
+    run!("--summarize")
+        .arg("tests/fixtures/summary/in.ndjson")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("skipped").not());
+}
+
+#[test]
+#[serial]
+fn summarize_strict() {
+    let fixture = "tests/fixtures/summary/truncated.ndjson";
+    let complete = std::fs::read_to_string(fixture)
+        .unwrap()
+        .lines()
+        .next()
+        .unwrap()
+        .to_owned();
+    let expected = run!("--summarize")
+        .write_stdin(complete)
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    // The half-written line of a run that crashed is reported, but the
+    // summary of the rest is just as it would be without it.
+    run!("--summarize")
+        .arg(fixture)
+        .assert()
+        .success()
+        .stdout(expected)
+        .stderr(predicate::str::contains(
+            "Warning: skipping results on line 2 of tests/fixtures/summary/truncated.ndjson: \
+             not JSON: {\"iterations\":[{\"wall.time\":1200,\"user.time\":810},{\"wall.tim…\n",
+        ));
+    run!("--summarize")
+        .args(["--strict", fixture])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "1 line was malformed, with --strict",
+        ));
+    // Results that only lack a name aren't malformed.
+    run!("--summarize")
+        .arg("--strict")
+        .write_stdin("{\"iterations\":[{\"wall.time\":1}]}\n")
+        .assert()
+        .success();
+}
+
+#[test]
+#[serial]
+fn export_datadog() {
+    run!("--export-dry-run")
+        .arg("./examples/export-datadog.yml")
+        .env_remove("DD_SITE")
+        .assert()
+        .success()
+        .stderr(
+            predicate::str::contains("Would send to https://api.datadoghq.com/api/v2/series:")
+                .and(predicate::str::contains("\"metric\": \"bench.wall.time\""))
+                .and(predicate::str::contains(
+                    "\"metric\": \"bench.wall.time.mean\"",
+                ))
+                .and(predicate::str::contains("\"name:export\""))
+                .and(predicate::str::contains("\"team:apm\"")),
+        )
+        .stdout(predicate::str::contains("\"name\":\"export\""));
+    // A failed export is logged, but doesn't fail the run unless it's strict.
+    run!("--export")
+        .args(["datadog", "./examples/simple.json"])
+        .env_remove("DD_API_KEY")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "cannot export results to Datadog: DD_API_KEY isn't set",
+        ));
+    run!("-")
+        .write_stdin("run: 'true'\nexport: {datadog: {site: invalid, strict: true}}\n")
+        .env("DD_API_KEY", "not-a-key")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("cannot export results to Datadog"));
+    run!("--export-dry-run")
+        .arg("./examples/simple.json")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "--export-dry-run needs --export or the 'export' config key",
+        ));
+    run!("--export")
+        .args(["statsd", "./examples/simple.json"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "unknown export target 'statsd', expected datadog, prometheus-textfile=PATH or \
+             pushgateway=URL",
+        ));
+}
+
+#[test]
+#[serial]
+fn statsd_forward() {
+    let agent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    agent
+        .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+        .unwrap();
+    let config = format!(
+        r#"name: forwarded
+run: bash -c "echo udp.data:50\|g > /dev/udp/127.0.0.1/$SIRUN_STATSD_PORT"
+statsd_forward: '{}'
+statsd_prefix: bench.
+"#,
+        agent.local_addr().unwrap()
+    );
+    run!("-").write_stdin(config).assert().success();
+    let mut buf = [0; 8192];
+    let len = agent.recv(&mut buf).unwrap();
+    let packet = String::from_utf8_lossy(&buf[..len]);
+    assert!(packet
+        .lines()
+        .any(|line| line == "bench.udp.data:50|g|#benchmark:forwarded"));
+    assert!(packet
+        .lines()
+        .any(|line| line.starts_with("bench.wall.time:")));
+    // Nothing listening there doesn't fail the run.
+    run!("-")
+        .write_stdin("run: 'true'\nstatsd_forward: 'nowhere.invalid:8125'\n")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "cannot forward metrics to statsd at nowhere.invalid:8125",
+        ));
+    run!("-")
+        .write_stdin("run: 'true'\nstatsd_forward: 8125\n")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "'statsd_forward' must be a 'host:port' string",
+        ));
+}
+
+#[test]
+#[serial]
+fn export_prometheus() {
+    let dir = tempfile::tempdir().unwrap();
+    let textfile = dir.path().join("sirun.prom");
+    run!("--export")
+        .arg(format!("prometheus-textfile={}", textfile.display()))
+        .arg("./examples/variants.json")
+        .assert()
+        .success();
+    // Each variant's run keeps the samples the others wrote.
+    let written = std::fs::read_to_string(&textfile).unwrap();
+    assert!(written.contains("# TYPE sirun_wall_time_mean gauge\n"));
+    assert!(written.contains("sirun_udp_data_mean{variant=\"0\","));
+    assert!(written.contains("sirun_udp_data_mean{variant=\"1\","));
+    let mut files: Vec<_> = std::fs::read_dir(dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    files.sort();
+    assert_eq!(files, ["sirun.prom", "sirun.prom.lock"]);
+    run!("--export-dry-run")
+        .args(["--export", "pushgateway=http://127.0.0.1:9/"])
+        .arg("./examples/simple.json")
+        .assert()
+        .success()
+        .stderr(
+            predicate::str::contains("Would send to http://127.0.0.1:9/metrics/job/sirun:").and(
+                predicate::str::contains("# TYPE sirun_wall_time_stddev gauge"),
+            ),
+        );
+    // An unreachable Pushgateway is logged, but doesn't fail the run.
+    run!("--export")
+        .args(["pushgateway=http://127.0.0.1:9", "./examples/simple.json"])
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "cannot export results to the Pushgateway at http://127.0.0.1:9",
+        ));
+}
+
+#[test]
+#[serial]
+fn history() {
+    let dir = tempfile::tempdir().unwrap();
+    let history = dir.path().join("history.ndjson");
+    let config = r#"name: trend
+run: bash -c "echo udp.data:50\|g > /dev/udp/127.0.0.1/$SIRUN_STATSD_PORT"
+"#;
+    let first = run!("--history")
+        .arg(&history)
+        .arg("-")
+        .write_stdin(config)
+        .assert()
+        .success();
+    let first = String::from_utf8(first.get_output().stdout.clone()).unwrap();
+    assert!(!first.contains("last_run"));
+    let second = run!("--history")
+        .arg(&history)
+        .args(["--summary-table", "-"])
+        .write_stdin(config)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("vs last"));
+    let second: serde_json::Value = serde_json::from_slice(&second.get_output().stdout).unwrap();
+    assert_eq!(second["last_run"]["changes"]["udp.data"], 0.0);
+    assert!(second["last_run"]["started_at"].is_string());
+    run!("history")
+        .args(["show", "trend", "--history"])
+        .arg(&history)
+        .assert()
+        .success()
+        .stdout(predicate::function(|out: &str| {
+            out.lines().count() == 3 && out.starts_with("started_at")
+        }));
+    run!("history")
+        .args(["show", "other", "--history"])
+        .arg(&history)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("no runs of \"other\" in history"));
+}
+
+#[test]
+#[serial]
+fn fingerprint() {
+    let run_with = |env: &str| {
+        let config = format!(
+            "name: fingerprint\nrun: [bash, -c, 'exit 0']\nenv: {{{}}}\n",
+            env
+        );
+        let output = run!("-").write_stdin(config).assert().success();
+        String::from_utf8(output.get_output().stdout.clone()).unwrap()
+    };
+    let hash = |line: &str| {
+        let results: serde_json::Value = serde_json::from_str(line).unwrap();
+        assert_eq!(results["run.command"], "bash -c \"exit 0\"");
+        results["config.hash"].as_str().unwrap().to_owned()
+    };
+    let first = run_with("API_TOKEN: one, MODE: fast");
+    let rotated = run_with("MODE: fast, API_TOKEN: two");
+    let changed = run_with("API_TOKEN: one, MODE: slow");
+    assert_eq!(hash(&first).len(), 16);
+    assert_eq!(hash(&first), hash(&rotated));
+    assert_ne!(hash(&first), hash(&changed));
+
+    let dir = tempfile::tempdir().unwrap();
+    let results = dir.path().join("results.ndjson");
+    std::fs::write(&results, format!("{}{}", first, changed)).unwrap();
+    run!("--summarize")
+        .args(["--group-by", "all"])
+        .arg(&results)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "results for name \"fingerprint\" and variant \"default\" come from different configs",
+        ))
+        .stdout(predicate::function(|out: &str| {
+            let val = serde_yaml::from_str::<serde_yaml::Value>(out).unwrap();
+            val["fingerprint"]["default"]["mixed_configs"].as_f64() == Some(1.0)
+        }));
+    run!("--baseline")
+        .arg(&results)
+        .arg("-")
+        .write_stdin("name: fingerprint\nrun: [bash, -c, 'exit 0']\nenv: {MODE: fast}\n")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(format!(
+            "was run with a different config (hash {}, now ",
+            hash(&changed)
+        )));
+}
+
+#[test]
+#[serial]
+fn redact_env() {
+    run!("./examples/redact-env.yml")
+        .assert()
+        .failure()
+        // The command still gets the real values.
+        .stdout(predicate::str::contains("got hunter2 fake-key-123"))
+        .stderr(
+            predicate::str::contains("Test Config:")
+                .and(predicate::str::contains("DD_API_KEY: \"***\""))
+                .and(predicate::str::contains("MY_CRED: \"***\""))
+                .and(predicate::str::contains("MODE: fast"))
+                .and(predicate::str::contains("fake-key-123").not())
+                .and(predicate::str::contains("hunter2").not()),
+        );
+    run!("--dry-run")
+        .arg("./examples/redact-env.yml")
+        .assert()
+        .success()
+        .stdout(
+            predicate::str::contains("DD_API_KEY: \"***\"")
+                .and(predicate::str::contains("fake-key-123").not()),
+        );
+}
+
+#[test]
+#[serial]
+fn time_to_first_metric() {
+    let output = run!("./examples/simple.json").assert().success();
+    let results = last_results(&output);
+    let iteration = &results["iterations"][0];
+    assert!(iteration["time.to.first.metric"].as_f64().unwrap() > 0.0);
+    assert!(iteration.get("run.spawn.epoch.us").is_none());
+    // Nothing sent, nothing to time.
+    run!("exec")
+        .args(["--", "true"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("time.to.first.metric").not());
+}
+
+#[test]
+#[serial]
+fn spans() {
+    let output = run!("./examples/spans.yml").assert().success();
+    let results = last_results(&output);
+    for iteration in results["iterations"].as_array().unwrap() {
+        assert_eq!(
+            iteration["spans"],
+            serde_json::json!({
+                "require": {"ms": 20.0, "count": 2.0},
+                "connect": {"ms": 5.0, "count": 1.0},
+            })
+        );
+        assert!(iteration.get("span.require").is_none());
+    }
+    run!("--summarize")
+        .write_stdin(format!("{}\n", results))
+        .assert()
+        .success()
+        .stdout(predicate::function(|out: &str| {
+            let val = serde_yaml::from_str::<serde_yaml::Value>(out).unwrap();
+            let require = &val["spans"]["default"]["summary"]["spans"]["require"];
+            require["ms"]["mean"].as_f64() == Some(20.0)
+                && require["count"]["mean"].as_f64() == Some(2.0)
+        }));
+}
+
+#[test]
+#[serial]
+fn stdout_metrics() {
+    let output = run!("./examples/stdout-metrics.yml")
+        .env_remove("SIRUN_NO_STDIO")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("errors=2 ops/sec: 12345.6\n"));
+    let results = last_results(&output);
+    for iteration in results["iterations"].as_array().unwrap() {
+        assert_eq!(iteration["ops.per.sec"], 12345.6);
+        assert_eq!(iteration["errors"], 5.0);
+    }
+    run!("./examples/stdout-metrics.yml")
+        .env("SIRUN_NO_STDIO", "1")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("errors=3\n").not());
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("bad-regex.yml");
+    std::fs::write(
+        &path,
+        "run: 'true'\nstdout_metrics: {ops: 'ops/sec: ([0-9.]+'}\n",
+    )
+    .unwrap();
+    run!(&path)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "'stdout_metrics.ops' regex parse error",
+        ));
+}
+
+#[test]
+#[serial]
+fn results_file() {
+    for (variant, ready) in [("statsd", 1.0), ("file", 2.0)] {
+        let output = run!("./examples/results-file.yml")
+            .env("SIRUN_VARIANT", variant)
+            .assert()
+            .success();
+        let results = last_results(&output);
+        for iteration in results["iterations"].as_array().unwrap() {
+            assert_eq!(iteration["ready"], ready);
+            assert_eq!(iteration["load.ms"], 12.5);
+        }
+    }
+    let dir = tempfile::tempdir().unwrap();
+    // No file, no metrics.
+    let path = dir.path().join("missing.yml");
+    std::fs::write(&path, "results_file: true\nrun: 'true'\n").unwrap();
+    run!(&path)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("load.ms").not());
+    let path = dir.path().join("malformed.yml");
+    std::fs::write(
+        &path,
+        "results_file: true\nrun: bash -c 'echo \"{\" > \"$SIRUN_RESULTS_FILE\"'\n",
+    )
+    .unwrap();
+    run!(&path)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("invalid results file /"))
+        .stderr(predicate::str::contains("results.json"));
+    // It's a failed iteration, which can be retried or recorded.
+    let marker = dir.path().join("retried");
+    let path = dir.path().join("retried.yml");
+    std::fs::write(
+        &path,
+        format!(
+            "results_file: true\niteration_retries: 1\nrun: bash -c 'test -e {0} || {{ touch {0}; echo \"{{\" > \"$SIRUN_RESULTS_FILE\"; }}'\n",
+            marker.display()
+        ),
+    )
+    .unwrap();
+    run!(&path)
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Iteration 0 failed: invalid results file"))
+        .stdout(predicate::str::contains("\"retries\":1.0"));
+    let path = dir.path().join("recorded.yml");
+    std::fs::write(
+        &path,
+        "results_file: true\ncontinue_on_failure: true\nrun: bash -c 'echo \"{\" > \"$SIRUN_RESULTS_FILE\"'\n",
+    )
+    .unwrap();
+    run!(&path)
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains("\"failed\":1.0"));
+}
+
+#[test]
+#[serial]
+fn concurrency() {
+    let output = run!("./examples/concurrency.yml")
+        .args(["--variant", "ok"])
+        .env_remove("SIRUN_NO_STDIO")
+        .assert()
+        .success();
+    let stdout = String::from_utf8(output.get_output().stdout.clone()).unwrap();
+    for worker in 0..4 {
+        assert!(
+            stdout.contains(&format!("worker {}\n", worker)),
+            "{}",
+            stdout
+        );
+    }
+    let results = last_results(&output);
+    let iteration = &results["iterations"][0];
+    let wall = iteration["wall.time"].as_f64().unwrap();
+    let sum = iteration["wall.time.sum"].as_f64().unwrap();
+    // The copies sleep for 0.1 to 0.4 seconds, all at once.
+    assert!((400000.0..1000000.0).contains(&wall), "{}", wall);
+    assert!(sum >= 1000000.0 && sum > wall, "{}", sum);
+
+    run!("./examples/concurrency.yml")
+        .args(["--variant", "failing"])
+        .assert()
+        .code(3)
+        .stderr(predicate::str::contains(
+            "Test worker 2 exited with code 3, so aborting test.",
+        ));
+    run!("-")
+        .write_stdin("run: 'true'\nconcurrency: 2\ninstructions: true\n")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "'concurrency' and 'instructions' can't be used together",
+        ));
+    run!("-")
+        .write_stdin("run: 'true'\nconcurrency: 0\n")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "'concurrency' must be an integer >=1",
+        ));
+}
+
+#[test]
+#[serial]
+fn out_of_window() {
+    let output = run!("./examples/out-of-window.yml").assert().success();
+    let results = last_results(&output);
+    let iterations = results["iterations"].as_array().unwrap();
+    assert!(iterations
+        .iter()
+        .all(|iteration| iteration["ready"] == 1.0 && iteration.get("stray").is_none()));
+    assert!(iterations[0].get("statsd.out_of_window").is_none());
+    assert_eq!(iterations[1]["statsd.out_of_window"], 1.0);
+}
+
+#[test]
+#[serial]
+fn statsd_tcp() {
+    let output = run!("./examples/statsd-tcp.yml").assert().success();
+    let results = last_results(&output);
+    let iterations = results["iterations"].as_array().unwrap();
+    assert_eq!(iterations.len(), 3);
+    for iteration in iterations {
+        assert_eq!(iteration["requests"], 10.0);
+        assert_eq!(iteration["protocol.tcp"], 1.0);
+        assert_eq!(iteration["spans"]["handle"]["ms"], 2.5);
+        assert!(iteration["user.time"].is_number());
+        assert!(iteration["wall.time"].is_number());
+    }
+    run!("-")
+        .write_stdin("run: 'true'\nstatsd_protocol: quic\n")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("must be 'udp' or 'tcp'"));
+}
+
+#[test]
+#[serial]
+fn stray_holds_pipes() {
+    // The `sleep` keeps the command's stdout and stderr open after it exits.
+    let config =
+        "run: bash -c 'sleep 5 & echo count=3'\nstdout_metrics:\n  count: 'count=(\\d+)'\n";
+    let start = std::time::Instant::now();
+    let output = run!("-").write_stdin(config).assert().success();
+    assert!(start.elapsed() < std::time::Duration::from_secs(4));
+    let results = last_results(&output);
+    let wall = results["iterations"][0]["wall.time"].as_f64().unwrap();
+    assert!(wall < 1000000.0, "wall.time {} includes the stray", wall);
+}
+
+#[test]
+#[serial]
+fn service() {
+    run!("./examples/service.json").assert().success();
+}
+
+#[test]
+#[serial]
+fn flags() {
+    json_has!("examples/iterations.json", |map: &serde_yaml::Mapping| {
+        let iterations = map
+            .get(&"iterations".into())
+            .unwrap()
+            .as_sequence()
+            .unwrap();
+        let flagged = iterations
+            .iter()
+            .filter(|i| {
+                !i["flags"].as_sequence().unwrap().is_empty()
+            })
+            .count();
+        map.get(&"flagged".into()).unwrap().as_f64().unwrap() == flagged as f64
+    });
+}
+
+#[test]
+#[serial]
+fn summarize_exclude_flagged() {
+    let mut in_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    in_path.push("tests/fixtures/summary/flagged.ndjson");
+    run!("--summarize")
+        .arg("--exclude-flagged")
+        .write_stdin(std::fs::read(&in_path).unwrap())
+        .assert()
+        .success()
+        .stdout(predicate::function(|out: &str| {
+            let val = serde_yaml::from_str::<serde_yaml::Value>(out).unwrap();
+            let a = &val["flagged"]["a"];
+            let b = &val["flagged"]["b"];
+            a["excluded"].as_f64() == Some(1.0)
+                && b["excluded"].as_f64() == Some(0.0)
+                && a["summary"]["wall.time"]["max"].as_f64() == Some(4000.0)
+                && a["summary"]["user.time"]["mean"].as_f64() == Some(2000.0)
+        }));
+    run!("--summarize")
+        .write_stdin(std::fs::read(&in_path).unwrap())
+        .assert()
+        .success()
+        .stdout(predicate::function(|out: &str| {
+            let val = serde_yaml::from_str::<serde_yaml::Value>(out).unwrap();
+            val["flagged"]["a"]["summary"]["wall.time"]["max"].as_f64() == Some(90000.0)
+        }));
+}
+
+#[test]
+#[serial]
+fn summarize_follow() {
+    use std::io::{BufRead, BufReader, Write};
+
+    fn next_block(lines: &mut impl Iterator<Item = std::io::Result<String>>) -> String {
+        let mut block = String::new();
+        for line in lines {
+            let line = line.unwrap();
+            block.push_str(&line);
+            block.push('\n');
+            if line == "}" {
+                break;
+            }
+        }
+        block
+    }
+
+    let fixture = std::fs::read_to_string("tests/fixtures/summary/in.ndjson").unwrap();
+    let mut fixture = fixture.lines();
+    let path = std::env::temp_dir().join(format!("sirun-follow-{}.ndjson", std::process::id()));
+    let mut file = std::fs::File::create(&path).unwrap();
+    writeln!(file, "{}", fixture.next().unwrap()).unwrap();
+
+    let mut child = std::process::Command::new(assert_cmd::cargo::cargo_bin("sirun"))
+        .args(["--summarize", "--follow"])
+        .arg(&path)
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+
+    let first = next_block(&mut lines);
+    assert!(first.contains("no-hooks"));
+    assert!(!first.contains("init-only"));
+
+    writeln!(file, "{}", fixture.next().unwrap()).unwrap();
+    let second = next_block(&mut lines);
+    assert!(second.contains("no-hooks"));
+    assert!(second.contains("init-only"));
+
+    nix::sys::signal::kill(
+        nix::unistd::Pid::from_raw(child.id() as i32),
+        nix::sys::signal::Signal::SIGINT,
+    )
+    .unwrap();
+    assert!(child.wait().unwrap().success());
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+#[serial]
+fn exit_status() {
+    json_has!("./examples/sigint.json", |map: &serde_yaml::Mapping| {
+        let iteration = &map.get(&"iterations".into()).unwrap()[0];
+        iteration["exit.code"].as_f64() == Some(130.0)
+            && iteration.get("exit.signal").is_none()
+    });
+    json_has!("./examples/simple.json", |map: &serde_yaml::Mapping| {
+        let iteration = &map.get(&"iterations".into()).unwrap()[0];
+        iteration["exit.code"].as_f64() == Some(0.0)
+            && iteration.get("exit.signal").is_none()
+    });
+    let output = run!("-")
+        .write_stdin("run: sh -c 'kill -TERM $$'\ncontinue_on_failure: true\n")
+        .assert()
+        .failure();
+    let iteration = &last_results(&output)["iterations"][0];
+    assert_eq!(iteration["exit.signal"], 15.0);
+}
+
+#[test]
+#[serial]
+fn iterations_flag() {
+    run!("./examples/iterations.json")
+        .args(["--iterations", "3"])
+        .env("SIRUN_NO_STDIO", "1")
+        .assert()
+        .success()
+        .stdout(predicate::function(|out: &str| {
+            let val = serde_yaml::from_str::<serde_yaml::Value>(out).unwrap();
+            val["iterations"].as_sequence().unwrap().len() == 3
+        }));
+    run!("./examples/iterations.json")
+        .env("SIRUN_ITERATIONS", "2")
+        .env("SIRUN_NO_STDIO", "1")
+        .assert()
+        .success()
+        .stdout(predicate::function(|out: &str| {
+            let val = serde_yaml::from_str::<serde_yaml::Value>(out).unwrap();
+            val["iterations"].as_sequence().unwrap().len() == 2
+        }));
+    run!("./examples/iterations.json")
+        .args(["--iterations", "0"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("iterations must be an integer >=1"));
+}
+
+#[test]
+#[serial]
+fn iterations_flag_variants() {
+    run!("./examples/variants.json")
+        .args(["--iterations", "2"])
+        .env("SIRUN_NO_STDIO", "1")
+        .assert()
+        .success()
+        .stdout(predicate::function(|out: &str| {
+            out.lines().count() == 2
+                && out.lines().all(|line| {
+                    let val = serde_yaml::from_str::<serde_yaml::Value>(line).unwrap();
+                    val["iterations"].as_sequence().unwrap().len() == 2
+                })
+        }));
+}
+
+fn round_significant(value: f64, digits: usize) -> f64 {
+    format!("{:.*e}", digits - 1, value).parse().unwrap()
+}
+
+#[test]
+#[serial]
+fn metric_precision() {
+    json_has!("./examples/precision.json", |map: &serde_yaml::Mapping| {
+        let iteration = &map.get(&"iterations".into()).unwrap()[0];
+        let pct = iteration["cpu.pct.wall.time"].as_f64().unwrap();
+        iteration["udp.data"].as_f64() == Some(3.1) && round_significant(pct, 4) == pct
+    });
+    run!("./examples/precision-invalid.json")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("unclosed '['"));
+}
+
+#[test]
+#[serial]
+fn summarize_precision() {
+    let input = std::fs::read_to_string("tests/fixtures/summary/in.ndjson").unwrap();
+    let pre_rounded: String = input
+        .lines()
+        .map(|line| {
+            let mut val: serde_json::Value = serde_json::from_str(line).unwrap();
+            for iteration in val["iterations"].as_array_mut().unwrap() {
+                for metric in iteration.as_object_mut().unwrap().values_mut() {
+                    *metric = round_significant(metric.as_f64().unwrap(), 6).into();
+                }
+            }
+            format!("{}\n", val)
+        })
+        .collect();
+    let summarize = |input: String| {
+        let out = run!("--summarize")
+            .args(["--precision", "6"])
+            .write_stdin(input)
+            .output()
+            .unwrap();
+        assert!(out.status.success());
+        serde_json::from_slice::<serde_json::Value>(&out.stdout).unwrap()
+    };
+    let post = summarize(input);
+    let pre = summarize(pre_rounded);
+    for (name, variants) in post.as_object().unwrap() {
+        for (variant, data) in variants.as_object().unwrap() {
+            for (metric, stats) in data["summary"].as_object().unwrap() {
+                let a = stats["mean"].as_f64().unwrap();
+                assert_eq!(round_significant(a, 6), a);
+                let b = pre[name][variant]["summary"][metric]["mean"].as_f64().unwrap();
+                assert!(((a - b) / a).abs() < 1e-5, "{} {} {}", name, variant, metric);
+            }
+        }
+    }
+}
+
+#[test]
+#[serial]
+fn variant_flag() {
+    run!("./examples/variants.json")
+        .args(["--variant", "1"])
+        .env("SIRUN_VARIANT", "0")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("variant 1").and(predicate::str::contains("variant 0").not()));
+    run!("./examples/variants.json")
+        .args(["--variant", "7"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("variant index 7 does not exist"));
+}
+
+#[test]
+#[serial]
+fn list_variants() {
+    run!("./examples/variants.json")
+        .arg("--list-variants")
+        .assert()
+        .success()
+        .stdout("0\n1\n");
+    run!("./examples/simple.json")
+        .arg("--list-variants")
+        .assert()
+        .success()
+        .stdout("");
+}
+
+#[test]
+#[serial]
+fn output_file() {
+    let path = std::env::temp_dir().join(format!("sirun-output-{}.ndjson", std::process::id()));
+    run!("./examples/teardown.json")
+        .arg("--output")
+        .arg(&path)
+        .assert()
+        .success()
+        .stdout("the test was run\na teardown was run\n");
+    let results = std::fs::read_to_string(&path).unwrap();
+    assert_eq!(results.lines().count(), 1);
+    assert!(results.contains("\"iterations\""));
+
+    run!("./examples/variants.json")
+        .env("SIRUN_OUTPUT_FILE", &path)
+        .env("SIRUN_NO_STDIO", "1")
+        .assert()
+        .success()
+        .stdout("");
+    let results = std::fs::read_to_string(&path).unwrap();
+    assert_eq!(results.lines().count(), 2);
+    assert!(results.contains("\"variant\":\"0\"") && results.contains("\"variant\":\"1\""));
+    std::fs::remove_file(&path).unwrap();
+
+    run!("./examples/output-file.json")
+        .assert()
+        .success()
+        .stdout("the test was run\n");
+    let results = std::fs::read_to_string("target/output-file.ndjson").unwrap();
+    assert!(results.contains("\"iterations\""));
+}
+
+#[test]
+#[serial]
+fn unknown_keys() {
+    run!("./examples/unknown-key.json")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "unknown config key 'iteration' (did you mean 'iterations'?)",
+        ));
+    run!("./examples/unknown-key-strict.json")
+        .args(["--variant", "typo"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "unknown config key 'variants.typo.tear_down' (did you mean 'teardown'?)",
+        ));
+}
+
+#[test]
+#[serial]
+fn dry_run() {
+    run!("./examples/env.json")
+        .arg("--dry-run")
+        .env("SIRUN_NAME", "dry")
+        .assert()
+        .success()
+        .stdout(predicate::function(|out: &str| {
+            let docs: Vec<&str> = out.split("---\n").skip(1).collect();
+            docs.len() == 2
+                && out.starts_with("# 'name' set by SIRUN_NAME\n")
+                && docs.iter().enumerate().all(|(i, doc)| {
+                    let config = serde_yaml::from_str::<serde_yaml::Value>(doc).unwrap();
+                    config["name"].as_str() == Some("dry")
+                        && config["variant"].as_str() == Some(&i.to_string())
+                })
+                && docs[1].contains("MY_ENV: something one")
+        }))
+        .stdout(predicate::function(|out: &str| {
+            !out.lines().any(|line| line == "something zero")
+        }));
+    run!("./examples/variants.json")
+        .args(["--dry-run", "--variant", "1", "--iterations", "3"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("# 'iterations' set by --iterations"))
+        .stdout(predicate::str::contains("# 'variant' set by --variant"))
+        .stdout(predicate::str::contains("iterations: 3"))
+        .stdout(predicate::str::contains("variant: \"1\""));
+}
+
+#[test]
+#[serial]
+fn config_from_stdin() {
+    run!("-")
+        .write_stdin(std::fs::read("./examples/env.json").unwrap())
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("something zero").and(predicate::str::contains("something one")))
+        .stdout(predicate::str::contains("\"variant\":\"1\""));
+    run!("-")
+        .write_stdin("run: [")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("invalid config in stdin"));
+}
+
+#[test]
+#[serial]
+fn failing_variant() {
+    run!("./examples/variants-failing.json")
+        .env("SIRUN_NO_STDIO", "1")
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains("\"variant\":\"last\""))
+        .stderr(predicate::str::contains("broken: failed (exit code 1)"))
+        .stderr(predicate::str::contains("first: passed"))
+        .stderr(predicate::str::contains("1 of 3 variants failed"));
+    run!("./examples/variants-failing.json")
+        .arg("--fail-fast")
+        .env("SIRUN_NO_STDIO", "1")
+        .assert()
+        .failure()
+        .stdout(predicate::str::contains("\"variant\":\"last\"").not())
+        .stderr(predicate::str::contains("1 of 2 variants failed"));
+}
+
+#[test]
+#[serial]
+fn variant_env() {
+    run!("./examples/variant-env.json")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("variant=a\n").and(predicate::str::contains("variant=b\n")))
+        .stdout(predicate::function(|out: &str| {
+            // Each variant's children see the port of that variant's own listener.
+            out.lines().filter(|line| line.starts_with('{')).all(|line| {
+                let val = serde_yaml::from_str::<serde_yaml::Value>(line).unwrap();
+                val["iterations"][0]["udp.data"].as_f64().unwrap() > 0.0
+            })
+        }));
+}
+
+#[test]
+#[serial]
+fn relative_exe_path() {
+    let exe = assert_cmd::cargo::cargo_bin("sirun");
+    let config = std::fs::canonicalize("examples/simple.json").unwrap();
+    assert_cmd::Command::new(format!("./{}", exe.file_name().unwrap().to_string_lossy()))
+        .current_dir(exe.parent().unwrap())
+        .arg(config)
+        .env("SIRUN_NO_STDIO", "1")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("\"iterations\":[{"));
+}
+
+#[test]
+#[serial]
+fn iteration_env() {
+    run!("./examples/iteration-env.json")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("no iteration env"))
+        // Only the results' `run.command` mentions them.
+        .stdout(predicate::function(|out: &str| {
+            out.lines()
+                .filter(|line| !line.starts_with('{'))
+                .all(|line| !line.contains("SIRUN_ITERATION"))
+        }));
+}
+
+#[test]
+#[serial]
+fn legacy_iteration_env() {
+    let statsd = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let config = run!("examples/simple.json").arg("--dry-run").output().unwrap();
+    run!("unused")
+        .env("SIRUN_ITERATION", String::from_utf8(config.stdout).unwrap())
+        .env("SIRUN_STATSD_PORT", statsd.local_addr().unwrap().port().to_string())
+        .assert()
+        .success();
+    statsd
+        .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+        .unwrap();
+    let mut buf = [0; 1024];
+    let mut received = String::new();
+    while !received.contains("wall.time:") {
+        let len = statsd.recv(&mut buf).unwrap();
+        received.push_str(&String::from_utf8_lossy(&buf[..len]));
+    }
+}
+
+#[test]
+#[serial]
+fn iteration_index() {
+    run!("./examples/iteration-index.yml")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "setup 0 of 3\nrun 0 of 3\nteardown 0 of 3\n\
+             setup 1 of 3\nrun 1 of 3\nteardown 1 of 3\n\
+             setup 2 of 3\nrun 2 of 3\nteardown 2 of 3\n",
+        ));
+    json_has!(
+        "./examples/iteration-index.yml",
+        move |map: &serde_yaml::Mapping| {
+            let iterations = map
+                .get(&"iterations".into())
+                .unwrap()
+                .as_sequence()
+                .unwrap();
+            iterations.iter().enumerate().all(|(i, iteration)| {
+                iteration.get("iteration").and_then(|x| x.as_f64()) == Some(i as f64)
+            })
+        }
+    );
+}
+
+#[test]
+#[serial]
+fn bench_identity() {
+    // The service runs alongside the rest, so the order of the lines varies.
+    let mut assert = run!("./examples/bench-identity.yml").assert().success();
+    for command in &["setup", "service", "bench", "teardown"] {
+        for variant in &["fast", "slow"] {
+            assert = assert.stdout(predicate::str::contains(format!(
+                "{} identity/{}\n",
+                command, variant
+            )));
+        }
+    }
+    run!("./examples/bench-identity.yml")
+        .env("SIRUN_NAME", "renamed")
+        .env("SIRUN_VARIANT", "slow")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("bench renamed/slow\n"));
+    // Without a name or variants, nothing leaks in from outside.
+    run!("exec")
+        .env("SIRUN_BENCH_NAME", "outer")
+        .env("SIRUN_BENCH_VARIANT", "outer")
+        .args([
+            "--",
+            "bash",
+            "-c",
+            "echo \"bench $SIRUN_BENCH_NAME/${SIRUN_BENCH_VARIANT-unset}\"",
+        ])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("bench /unset\n"));
+}
+
+#[test]
+#[serial]
+fn tmpdir() {
+    let tmpdir = |output: &[u8]| {
+        let output = String::from_utf8_lossy(output);
+        let line = output.lines().find(|line| line.starts_with("tmpdir ")).unwrap();
+        PathBuf::from(line.trim_start_matches("tmpdir "))
+    };
+    let output = run!("./examples/tmpdir.yml")
+        .arg("--variant")
+        .arg("ok")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("\"tmp.bytes\":11.0"))
+        .get_output()
+        .stdout
+        .clone();
+    assert!(!tmpdir(&output).exists());
+    let output = run!("./examples/tmpdir.yml")
+        .arg("--variant")
+        .arg("failing")
+        .assert()
+        .code(3)
+        .get_output()
+        .stdout
+        .clone();
+    assert!(!tmpdir(&output).exists());
+}
+
+#[test]
+#[serial]
+fn measure_disk() {
+    run!("./examples/measure-disk.yml")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "Warning: skipping target/measure-disk/logs/loop, a symlink loop",
+        ))
+        .stdout(predicate::str::contains(
+            "\"disk.target_measure-disk_created.bytes.delta\":7.0",
+        ))
+        .stdout(predicate::str::contains(
+            "\"disk.target_measure-disk_logs.bytes.delta\":100.0",
+        ));
+}
+
+#[test]
+#[serial]
+fn wait_for() {
+    for variant in &["file", "http"] {
+        run!("./examples/wait-for.yml")
+            .arg("--variant")
+            .arg(variant)
+            .assert()
+            .success()
+            .stdout(predicate::str::contains("ready\n"));
+    }
+    run!("./examples/wait-for.yml")
+        .arg("--variant")
+        .arg("never")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "wait_for condition never became true within 1s: file target/wait-for/never",
+        ));
+}
+
+#[test]
+#[serial]
+fn service_exit() {
+    run!("./examples/service-exit.yml")
+        .arg("--variant")
+        .arg("crash")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "exited with exit code 3 before the run command finished",
+        ))
+        .stderr(predicate::str::contains(
+            "Last 20 bytes of stderr:\nport already in use",
+        ));
+    // The run command would have finished by now if it hadn't been killed.
+    std::thread::sleep(std::time::Duration::from_millis(1500));
+    assert!(!PathBuf::from("target/service-exit/finished").exists());
+    for variant in &["may-exit", "survives"] {
+        run!("./examples/service-exit.yml")
+            .arg("--variant")
+            .arg(variant)
+            .assert()
+            .success()
+            .stdout(predicate::str::contains("finished\n"));
+    }
+}
+
+#[test]
+#[serial]
+fn iteration_retries() {
+    let _ = std::fs::remove_dir_all("target/retries");
+    std::fs::create_dir_all("target/retries").unwrap();
+    run!("./examples/retries.yml")
+        .arg("--variant")
+        .arg("enough")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "Iteration 0 exited with code 1, retrying (2 of 2).",
+        ))
+        .stdout(predicate::str::contains("\"retries\":2.0"));
+    run!("./examples/retries.yml")
+        .arg("--variant")
+        .arg("too-few")
+        .assert()
+        .code(1)
+        .stdout(predicate::str::contains("attempt 1\n"))
+        .stdout(predicate::str::contains("attempt 2").not());
+    run!("./examples/retries.yml")
+        .arg("--variant")
+        .arg("timeout")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("\"retries\":1.0"));
+}
+
+#[test]
+#[serial]
+fn continue_on_failure() {
+    run!("./examples/continue-on-failure.yml")
+        .env("SIRUN_NO_STDIO", "1")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("2 of 4 iterations failed"))
+        .stdout(predicate::function(|out: &str| {
+            let val = serde_yaml::from_str::<serde_yaml::Value>(out).unwrap();
+            let iterations = val["iterations"].as_sequence().unwrap();
+            iterations.len() == 4
+                && iterations[0].get("failed").is_none()
+                && iterations[1]["failed"].as_f64() == Some(1.0)
+                && iterations[1]["exit.code"].as_f64() == Some(7.0)
+                && iterations[1]["partial"].as_f64() == Some(5.0)
+        }));
+}
+
+#[test]
+#[serial]
+fn summarize_failed() {
+    run!("--summarize")
+        .arg("tests/fixtures/summary/failed.ndjson")
+        .assert()
+        .success()
+        .stdout(predicate::function(|out: &str| {
+            let val = serde_yaml::from_str::<serde_yaml::Value>(out).unwrap();
+            let a = &val["failed"]["a"];
+            a["failed"].as_f64() == Some(1.0)
+                && a["summary"]["wall.time"]["min"].as_f64() == Some(2000.0)
+                && a["summary"]["exit.code"]["max"].as_f64() == Some(0.0)
+        }));
+}
+
+#[test]
+#[serial]
+fn total_timeout() {
+    run!("./examples/total-timeout.yml")
+        .assert()
+        .code(124)
+        .stderr(predicate::str::contains("Total timeout of 1 seconds exceeded"))
+        .stdout(predicate::function(|out: &str| {
+            let val = serde_yaml::from_str::<serde_yaml::Value>(out).unwrap();
+            let iterations = val["iterations"].as_sequence().unwrap().len();
+            val["truncated"].as_f64() == Some(1.0) && (1..10).contains(&iterations)
+        }));
+}
+
+#[test]
+#[serial]
+fn duration() {
+    let iterations = |variant: &str, extra: &[&str]| {
+        let output = run!("./examples/duration.yml")
+            .arg("--variant")
+            .arg(variant)
+            .args(extra)
+            .env("SIRUN_NO_STDIO", "1")
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let val: serde_yaml::Value = serde_yaml::from_slice(&output.stdout).unwrap();
+        val["iterations"].as_sequence().unwrap().len()
+    };
+    // Iterations take about 0.2 seconds each.
+    assert!((3..=6).contains(&iterations("plain", &[])));
+    assert_eq!(iterations("capped", &[]), 2);
+    assert_eq!(iterations("minimum", &[]), 8);
+    assert_eq!(iterations("overridden", &[]), 3);
+    assert_eq!(iterations("plain", &["--iterations", "2"]), 2);
+    run!("-")
+        .write_stdin("run: 'true'\niterations: 2\nduration: 3\n")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "'iterations' and 'duration' can't be used together",
+        ));
+}
+
+#[test]
+#[serial]
+fn assertions() {
+    run!("./examples/assertions.yml")
+        .arg("--variant")
+        .arg("pass")
+        .assert()
+        .success();
+    run!("./examples/assertions.yml")
+        .arg("--variant")
+        .arg("fail")
+        .env("SIRUN_NO_STDIO", "1")
+        .assert()
+        .failure()
+        .stdout(predicate::function(|out: &str| {
+            let val = serde_yaml::from_str::<serde_yaml::Value>(out).unwrap();
+            val["iterations"].as_sequence().unwrap().len() == 3
+        }))
+        .stderr(predicate::str::contains(
+            "Assertion failed: custom was 20 in iteration 2, above the max of 15\n\
+             Assertion failed: mean exit.code was 0, above the max of -1\n\
+             Assertion failed: missing was never reported\n\
+             Error: 3 assertion(s) failed",
+        ));
+}
+
+#[test]
+#[serial]
+fn baseline() {
+    let compare = |args: &[&str]| {
+        run!("./examples/baseline.yml")
+            .env("SIRUN_NO_STDIO", "1")
+            .arg("--baseline")
+            .arg("tests/fixtures/baseline.ndjson")
+            .args(args)
+            .assert()
+    };
+    compare(&[])
+        .success()
+        .stderr(predicate::str::contains("custom: 9 -> 10 (+11.1%)\n"))
+        .stderr(predicate::str::contains("score: 120 -> 100 (-16.7%)\n"))
+        .stderr(predicate::str::is_match("wall.time: [0-9.]+ \\(not in baseline\\)").unwrap());
+    compare(&["--fail-on-regression", "20"]).success();
+    compare(&["--fail-on-regression", "15"])
+        .failure()
+        .stdout(predicate::str::contains("\"iterations\""))
+        .stderr(predicate::str::contains(
+            "Error: 1 metric regressed by more than 15% from the baseline: score",
+        ));
+    compare(&["--fail-on-regression", "10"])
+        .failure()
+        .stderr(predicate::str::contains(
+            "Error: metrics regressed by more than 10% from the baseline: custom, score",
+        ));
+    run!("./examples/baseline.yml")
+        .arg("--fail-on-regression")
+        .arg("10")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "--fail-on-regression requires --baseline",
+        ));
+}
+
+#[test]
+#[serial]
+fn host_info() {
+    json_has!("./examples/simple.json", |map: &serde_yaml::Mapping| {
+        let host = &map[&"host".into()];
+        host["cpu_count"].as_f64().unwrap() >= 1.0
+            && host["memory_bytes"].as_f64().unwrap() > 0.0
+            && host["os"].as_str().is_some()
+            && host["kernel"].as_str().is_some()
+            && host["hostname"].as_str().is_some()
+            && host["sirun_version"].as_str() == Some(env!("CARGO_PKG_VERSION"))
+    });
+    json_has!("./examples/no-host-info.yml", |map: &serde_yaml::Mapping| {
+        !map.contains_key(&"host".into())
+    });
+    run!("./examples/simple.json")
+        .env("SIRUN_NO_STDIO", "1")
+        .env("SIRUN_NO_HOST_INFO", "1")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("\"host\"").not());
+}
+
+#[test]
+#[serial]
+fn system_state() {
+    json_has!("./examples/simple.json", |map: &serde_yaml::Mapping| {
+        let iteration = &map[&"iterations".into()][0];
+        iteration["sys.load1"]
+            .as_f64()
+            .is_some_and(|load| load >= 0.0)
+            && iteration["sys.procs.total"]
+                .as_f64()
+                .is_some_and(|procs| procs >= 1.0)
+    });
+    json_has!(
+        "./examples/no-system-state.yml",
+        |map: &serde_yaml::Mapping| {
+            let iteration = map[&"iterations".into()][0].as_mapping().unwrap();
+            !iteration
+                .iter()
+                .any(|(key, _)| key.as_str().unwrap().starts_with("sys."))
+        }
+    );
+    run!("--summarize")
+        .arg("tests/fixtures/summary/system-state.ndjson")
+        .assert()
+        .success()
+        .stdout(predicate::function(|out: &str| {
+            let val = serde_yaml::from_str::<serde_yaml::Value>(out).unwrap();
+            let summary = &val["system"]["state"]["summary"];
+            summary["sys.load1"]["mean"].as_f64() == Some(1.0)
+                && summary["sys.cpufreq.governor"].is_null()
+        }));
+}
+
+#[test]
+#[serial]
+fn noise_check() {
+    json_has!("./examples/noise-check.yml", |map: &serde_yaml::Mapping| {
+        !map.contains_key(&"noisy_environment".into())
+    });
+    run!("-")
+        .write_stdin("run: 'true'\nnoise_check: {settle_seconds: 5}\n")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "'noise_check' needs a 'max_load1' or a 'max_cpu_pressure'",
+        ));
+}
+
+#[test]
+#[serial]
+fn pressure_stalls() {
+    let has_psi = std::fs::read_to_string("/proc/pressure/cpu").is_ok();
+    // Without PSI the metrics are left out, rather than reported as zero.
+    json_has!("./examples/simple.json", |map: &serde_yaml::Mapping| {
+        let iteration = &map[&"iterations".into()][0];
+        ["psi.cpu.some", "psi.memory.some", "psi.io.some"]
+            .iter()
+            .all(|key| if has_psi { iteration[*key].is_f64() } else { iteration[*key].is_null() })
+    });
+    run!("--summarize")
+        .arg("tests/fixtures/summary/psi.ndjson")
+        .assert()
+        .success()
+        .stdout(predicate::function(|out: &str| {
+            let val = serde_yaml::from_str::<serde_yaml::Value>(out).unwrap();
+            let summary = &val["psi"]["deltas"]["summary"];
+            summary["psi.cpu.some"]["mean"].as_f64() == Some(1.0)
+                && summary["psi.io.some"]["mean"].as_f64() == Some(0.0)
+        }));
+}
+
+#[test]
+#[serial]
+fn summarize_host() {
+    run!("--summarize")
+        .args(["--precision", "2", "tests/fixtures/summary/host.ndjson"])
+        .assert()
+        .success()
+        .stdout(predicate::function(|out: &str| {
+            let val = serde_yaml::from_str::<serde_yaml::Value>(out).unwrap();
+            let small = &val["host"]["small"];
+            let large = &val["host"]["large"];
+            small["host"]["hostname"].as_str() == Some("runner-1")
+                && small["host"]["memory_bytes"].as_f64() == Some(16777216123.0)
+                && small["summary"]["wall.time"]["mean"].as_f64() == Some(1200000.0)
+                && large["host"]["cpu_count"].as_f64() == Some(32.0)
+        }));
+}
+
+#[test]
+#[serial]
+fn git_metadata() {
+    let repo = tempfile::tempdir().unwrap();
+    let git = |args: &[&str]| {
+        let output = std::process::Command::new("git")
+            .args(["-c", "user.name=sirun", "-c", "user.email=sirun@example.com"])
+            .args(args)
+            .current_dir(repo.path())
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap().trim().to_owned()
+    };
+    git(&["init", "-q"]);
+    git(&["checkout", "-q", "-b", "feature"]);
+    std::fs::create_dir(repo.path().join("bench")).unwrap();
+    let config = repo.path().join("bench/config.yml");
+    std::fs::write(&config, "run: \"true\"\n").unwrap();
+    git(&["add", "."]);
+    git(&["commit", "-q", "-m", "initial"]);
+    let hash = git(&["rev-parse", "HEAD"]);
+
+    let results = |env_hash: Option<&str>| {
+        let mut cmd = assert_cmd::Command::cargo_bin("sirun").unwrap();
+        cmd.arg(&config).env_remove("GIT_COMMIT_HASH");
+        if let Some(env_hash) = env_hash {
+            cmd.env("GIT_COMMIT_HASH", env_hash);
+        }
+        let output = cmd.output().unwrap();
+        assert!(output.status.success());
+        serde_yaml::from_slice::<serde_yaml::Value>(&output.stdout).unwrap()
+    };
+    let val = results(None);
+    assert_eq!(val["version"].as_str(), Some(hash.as_str()));
+    assert_eq!(val["git.branch"].as_str(), Some("feature"));
+    assert_eq!(val["git.dirty"].as_f64(), Some(0.0));
+
+    // Packed refs are read too, and only tracked files make the tree dirty.
+    git(&["pack-refs", "--all"]);
+    std::fs::write(repo.path().join("untracked"), "").unwrap();
+    let val = results(None);
+    assert_eq!(val["version"].as_str(), Some(hash.as_str()));
+    assert_eq!(val["git.dirty"].as_f64(), Some(0.0));
+
+    std::fs::write(&config, "run: \"true\"\niterations: 1\n").unwrap();
+    assert_eq!(results(None)["git.dirty"].as_f64(), Some(1.0));
+
+    // The environment variable takes precedence.
+    let val = results(Some("123abc"));
+    assert_eq!(val["version"].as_str(), Some("123abc"));
+    assert!(val.get("git.branch").is_none());
+
+    std::fs::remove_dir_all(repo.path().join(".git")).unwrap();
+    let val = results(None);
+    assert!(val.get("version").is_none() && val.get("git.branch").is_none());
+}
+
+#[test]
+#[serial]
+fn timestamps() {
+    let output = run!("./examples/baseline.yml")
+        .env("SIRUN_NO_STDIO", "1")
+        .output()
+        .unwrap();
+    let out = String::from_utf8(output.stdout).unwrap();
+    let val = serde_yaml::from_str::<serde_yaml::Value>(&out).unwrap();
+    let started_at = val["started_at"].as_str().unwrap();
+    let completed_at = val["completed_at"].as_str().unwrap();
+    // e.g. 2021-03-04T05:06:07.089Z, which sorts chronologically.
+    assert_eq!(started_at.len(), 24);
+    assert!(started_at.ends_with('Z') && started_at <= completed_at);
+    let iterations = val["iterations"].as_sequence().unwrap();
+    let first = &iterations[0];
+    let second = &iterations[1];
+    assert!(first["duration.ms"].as_f64().unwrap() > 0.0);
+    assert!(
+        second["start.epoch.ms"].as_f64().unwrap()
+            >= first["start.epoch.ms"].as_f64().unwrap()
+                + first["duration.ms"].as_f64().unwrap().floor()
+    );
+
+    // Timestamps aren't averaged when summarizing.
+    let line = format!("{{\"variant\":\"a\",{}", &out[1..]);
+    run!("--summarize")
+        .write_stdin(line)
+        .assert()
+        .success()
+        .stdout(predicate::function(|out: &str| {
+            let val = serde_yaml::from_str::<serde_yaml::Value>(out).unwrap();
+            let a = &val["baseline"]["a"];
+            a["summary"].get("start.epoch.ms").is_none()
+                && a["summary"]["duration.ms"]["mean"].as_f64().is_some()
+                && a["started_at"].as_str().is_some()
+        }));
+}
+
+#[test]
+#[serial]
+fn stream() {
+    let output = run!("./examples/stream.yml")
+        .args(["--stream", "--variant", "a"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let out = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<serde_yaml::Value> = out
+        .lines()
+        .map(|line| serde_yaml::from_str(line).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 3);
+    for (i, line) in lines[..2].iter().enumerate() {
+        assert_eq!(line["type"].as_str(), Some("iteration"));
+        assert_eq!(line["name"].as_str(), Some("stream"));
+        assert_eq!(line["variant"].as_str(), Some("a"));
+        assert_eq!(line["metrics"]["iteration"].as_f64(), Some(i as f64));
+    }
+    assert_eq!(lines[2]["type"].as_str(), Some("summary"));
+    assert_eq!(lines[2]["variant"].as_str(), Some("a"));
+    assert!(lines[2].get("iterations").is_none());
+    assert!(lines[2]["version"].as_str().is_some());
+
+    // Streamed results can be summarized, even from a run that never finished.
+    let partial: String = out.lines().take(2).map(|line| format!("{}\n", line)).collect();
+    run!("--summarize")
+        .write_stdin(partial)
+        .assert()
+        .success()
+        .stdout(predicate::function(|out: &str| {
+            let val = serde_yaml::from_str::<serde_yaml::Value>(out).unwrap();
+            let a = &val["stream"]["a"];
+            a["incomplete"].as_f64() == Some(1.0)
+                && a["summary"]["exit.code"]["mean"].as_f64() == Some(0.0)
+        }));
+
+    let results = tempfile::NamedTempFile::new().unwrap();
+    run!("./examples/stream.yml")
+        .env("SIRUN_STREAM", "1")
+        .arg("--output")
+        .arg(results.path())
+        .assert()
+        .success();
+    let contents = std::fs::read_to_string(results.path()).unwrap();
+    assert_eq!(contents.lines().count(), 7);
+    run!("--summarize")
+        .arg(results.path())
+        .assert()
+        .success()
+        .stdout(predicate::function(|out: &str| {
+            let val = serde_yaml::from_str::<serde_yaml::Value>(out).unwrap();
+            let a = &val["stream"]["a"];
+            let b = &val["stream"]["b"];
+            a.get("incomplete").is_none()
+                && a["started_at"].as_str().is_some()
+                && a["summary"]["exit.code"]["mean"].as_f64() == Some(0.0)
+                && b["summary"]["wall.time"]["mean"].as_f64().is_some()
+        }));
+}
+
+#[test]
+#[serial]
+fn progress() {
+    run!("./examples/stream.yml")
+        .args(["--variant", "a"])
+        .assert()
+        .success()
+        .stdout(predicate::function(|out: &str| {
+            serde_yaml::from_str::<serde_yaml::Value>(out).is_ok()
+        }))
+        .stderr(
+            predicate::str::is_match("\\[a\\] iteration 1/2: wall.time [0-9.]+(µs|ms|s), mean ")
+                .unwrap(),
+        )
+        .stderr(predicate::str::contains("[a] iteration 2/2: wall.time "));
+    run!("./examples/stream.yml")
+        .args(["--variant", "a", "--quiet"])
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("iteration 1/2").not());
+    run!("./examples/stream.yml")
+        .args(["--variant", "a"])
+        .env("SIRUN_NO_STDIO", "1")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("iteration 1/2").not());
+}
+
+#[test]
+#[serial]
+fn logging() {
+    run!("./examples/setup-steps.yml")
+        .args(["--variant", "ok"])
+        .env("SIRUN_LOG", "debug")
+        .assert()
+        .success()
+        .stderr(predicate::str::is_match("Debug: statsd listening on port [0-9]+\n").unwrap())
+        .stderr(predicate::str::contains(
+            "Info: starting iteration 0 (attempt 1)\n",
+        ))
+        .stderr(predicate::str::contains(
+            "Debug: setup step 1 of 3 attempt 1 exited with code 0\n",
+        ))
+        .stderr(
+            predicate::str::is_match(
+                "Debug: spawning \\[\"echo\", \"step one\"\\] .*SIRUN_ITERATION_INDEX=0",
+            )
+            .unwrap(),
+        )
+        .stderr(predicate::str::contains("Trace:").not());
+    run!("./examples/setup-steps.yml")
+        .args(["--variant", "ok"])
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Debug:").not())
+        .stderr(predicate::str::contains("Info:").not());
+    run!("./examples/setup-steps.yml")
+        .env("SIRUN_LOG", "loud")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "SIRUN_LOG must be one of off, error, warn, info, debug or trace, got 'loud'",
+        ));
+    // Malformed results are skipped rather than crashing the summary.
+    run!("--summarize")
+        .write_stdin(
+            "{\"name\":\"a\",\"variant\":\"b\",\"iterations\":[\"slow\"]}\n\
+             {\"name\":\"a\",\"variant\":\"c\",\"iterations\":[{\"wall.time\":1}]}\n",
+        )
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "Warning: skipping results on line 1 of stdin: invalid iteration 0: expected an object, got a string",
+        ))
+        .stdout(predicate::function(|out: &str| {
+            let val = serde_yaml::from_str::<serde_yaml::Value>(out).unwrap();
+            val["a"].get("b").is_none() && val["a"]["c"]["summary"]["wall.time"]["mean"].as_f64() == Some(1.0)
+        }));
+}
+
+#[test]
+#[serial]
+fn usage() {
+    assert_cmd::Command::cargo_bin("sirun")
+        .unwrap()
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("missing file argument"))
+        .stderr(predicate::str::contains("Usage:"));
+    run!("examples/simple.json")
+        .arg("--bogus")
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("unknown option '--bogus'"));
+    run!("--summarize")
+        .arg("--bogus")
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("Usage:"));
+    run!("--help")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Usage:"));
+    run!("--version")
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with(format!(
+            "sirun {} (",
+            env!("CARGO_PKG_VERSION")
+        )));
+}
+
+#[test]
+#[serial]
+fn exec() {
+    let output = run!("exec")
+        .args(["--iterations", "2", "--name", "quick", "--env", "GREETING=a=b"])
+        .args(["--timeout", "10", "--", "sh", "-c"])
+        .arg("test \"$GREETING\" = a=b && test \"$1\" = --iterations")
+        .args(["sh", "--iterations"])
+        .env("SIRUN_NO_STDIO", "1")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let val = serde_yaml::from_slice::<serde_yaml::Value>(&output.stdout).unwrap();
+    assert_eq!(val["name"].as_str(), Some("quick"));
+    let iterations = val["iterations"].as_sequence().unwrap();
+    assert_eq!(iterations.len(), 2);
+    assert!(iterations[0]["wall.time"].as_f64().is_some());
+    assert!(iterations[0]["user.time"].as_f64().is_some());
+
+    // The command doesn't need `--` in front of it, as long as it doesn't
+    // start with `--`.
+    run!("exec")
+        .args(["true"])
+        .env("SIRUN_NO_STDIO", "1")
+        .assert()
+        .success();
+    // Nor are its flags taken for sirun's.
+    run!("exec")
+        .args(["sh", "-c", "test \"$0\" = --version", "--version"])
+        .env("SIRUN_NO_STDIO", "1")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("\"iterations\""));
+
+    for args in [
+        &["--iterations", "2"][..],
+        &["--env", "GREETING", "--", "true"][..],
+        &["--iterations", "0", "--", "true"][..],
+        &["--bogus", "--", "true"][..],
+    ] {
+        run!("exec")
+            .args(args)
+            .assert()
+            .code(2)
+            .stderr(predicate::str::contains("Usage:"));
+    }
+}
+
+#[test]
+#[serial]
+fn set() {
+    run!("./examples/simple.json")
+        .args(["--set", "run=echo hi"])
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("settable keys are name"));
+
+    let output = run!("./examples/variants.json")
+        .args(["--set", "iterations=2", "--set", "name=overridden"])
+        .args(["--set", "env.GREETING=hello world"])
+        .env("SIRUN_NAME", "from env")
+        .env("SIRUN_NO_STDIO", "1")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let lines: Vec<serde_yaml::Value> = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| serde_yaml::from_str(line).unwrap())
+        .collect();
+    // Each variant is run by a child process, which gets the overrides too.
+    assert_eq!(lines.len(), 2);
+    for line in lines {
+        assert_eq!(line["name"].as_str(), Some("overridden"));
+        assert_eq!(line["iterations"].as_sequence().unwrap().len(), 2);
+    }
+
+    run!("./examples/simple.json")
+        .args(["--set", "env.GREETING=hello world", "--dry-run"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("# 'env.GREETING' set by --set"))
+        .stdout(predicate::str::contains("GREETING: hello world"));
+
+    run!("./examples/simple.json")
+        .args(["--set", "iterations=0"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("'iterations' must be an integer >=1"));
+}
+
+#[test]
+#[serial]
+fn suite() {
+    let output = run!("./examples/suite")
+        .env("SIRUN_NO_STDIO", "1")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let names: Vec<String> = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| {
+            let line = serde_yaml::from_str::<serde_yaml::Value>(line).unwrap();
+            let variant = line["variant"].as_str().unwrap_or("-").to_owned();
+            format!("{} {}", line["name"].as_str().unwrap(), variant)
+        })
+        .collect();
+    // Subdirectories aren't looked in by default, and a failing variant
+    // doesn't stop the rest.
+    assert_eq!(names, ["suite-simple -", "suite-variants passing"]);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("Suite results:"));
+    assert!(stderr.contains("variants.yml  failing  failed (exit code 1)"));
+    assert!(stderr.contains("variants.yml  passing  passed"));
+    assert!(stderr.contains("1 of 3 configs and variants in the suite failed"));
+
+    run!("./examples/suite")
+        .arg("--fail-fast")
+        .env("SIRUN_NO_STDIO", "1")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("variants.yml  passing  skipped"));
+
+    let results = tempfile::NamedTempFile::new().unwrap();
+    run!("./examples/suite")
+        .args(["--pattern", "*/meta.json", "--output"])
+        .arg(results.path())
+        .env("SIRUN_NO_STDIO", "1")
+        .assert()
+        .success()
+        .stdout("");
+    let results = std::fs::read_to_string(results.path()).unwrap();
+    assert_eq!(results.lines().count(), 2);
+    assert!(results.contains("\"name\":\"suite-nested\""));
+    assert!(results.contains("\"name\":\"suite-other\""));
+}
+
+#[test]
+#[serial]
+fn benchmarks() {
+    let results = tempfile::NamedTempFile::new().unwrap();
+    run!("./examples/benchmarks.yml")
+        .arg("--output")
+        .arg(results.path())
+        .env("SIRUN_NO_STDIO", "1")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Benchmark results:"));
+    let names: Vec<String> = std::fs::read_to_string(results.path())
+        .unwrap()
+        .lines()
+        .map(|line| {
+            let line = serde_yaml::from_str::<serde_yaml::Value>(line).unwrap();
+            let variant = line["variant"].as_str().unwrap_or("-").to_owned();
+            format!("{} {}", line["name"].as_str().unwrap(), variant)
+        })
+        .collect();
+    assert_eq!(
+        names,
+        [
+            "serialize -",
+            "decode -",
+            "roundtrip small",
+            "roundtrip large"
+        ]
+    );
+
+    run!("./examples/benchmarks.yml")
+        .args(["--benchmark", "roundtrip", "--variant", "large"])
+        .env("SIRUN_NO_STDIO", "1")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("\"name\":\"roundtrip\""))
+        .stdout(predicate::str::contains("\"variant\":\"large\""));
+
+    run!("./examples/benchmarks.yml")
+        .args(["--benchmark", "compress"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("benchmark compress does not exist"));
+}
+
+#[test]
+#[serial]
+fn library() {
+    std::env::set_var("SIRUN_EXE", env!("CARGO_BIN_EXE_sirun"));
+    let config = sirun::Config::from_file("./examples/variants.json", None).unwrap();
+    assert_eq!(config.variants().unwrap(), ["0", "1"]);
+    assert!(sirun::run_benchmark(config).is_err());
+
+    let config = sirun::Config::from_file("./examples/variants.json", Some("1"))
+        .unwrap()
+        .with_name("library")
+        .with_iterations(2);
+    let result = sirun::run_benchmark(config).unwrap();
+    assert_eq!(result.name.as_deref(), Some("library"));
+    assert_eq!(result.variant.as_deref(), Some("1"));
+    assert_eq!(result.iterations.len(), 2);
+    assert_eq!(result.iterations[0]["udp.data"], sirun::MetricValue::Num(50.0));
+    assert!(result.iterations[0].contains_key("wall.time"));
+
+    let summary = sirun::summarize(vec![result]);
+    let wall_time = summary.get("library", "1").unwrap()["summary"].as_map().unwrap();
+    assert!(wall_time["wall.time"].as_map().unwrap()["mean"].as_f64().unwrap() > 0.0);
+
+    let failing = sirun::Config::new(["false"]).with_iterations(1);
+    assert!(sirun::run_benchmark(failing).is_err());
+}
+
+fn config_files(dir: &std::path::Path, files: &mut Vec<PathBuf>) {
+    for entry in std::fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            config_files(&path, files);
+        } else if matches!(
+            path.extension().and_then(|e| e.to_str()),
+            Some("json" | "yml" | "yaml")
+        ) {
+            files.push(path);
+        }
+    }
+}
+
+/// The keywords `sirun --schema` uses, which are all `schema_errors` knows.
+const SCHEMA_KEYWORDS: &[&str] = &[
+    "$ref",
+    "$schema",
+    "additionalProperties",
+    "anyOf",
+    "const",
+    "definitions",
+    "description",
+    "enum",
+    "items",
+    "maximum",
+    "minItems",
+    "minProperties",
+    "minimum",
+    "not",
+    "oneOf",
+    "pattern",
+    "properties",
+    "required",
+    "title",
+    "type",
+];
+
+fn has_type(value: &serde_json::Value, name: &str) -> bool {
+    match name {
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => value.as_f64().is_some_and(|n| n.fract() == 0.0),
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        _ => panic!("unknown type {}", name),
+    }
+}
+
+/// Where `value`, found at `path`, doesn't conform to `schema`, with `root`
+/// holding the definitions its references point to. It's a JSON Schema
+/// (draft-07) validator for just the keywords in `SCHEMA_KEYWORDS`.
+fn schema_errors(
+    root: &serde_json::Value,
+    schema: &serde_json::Value,
+    value: &serde_json::Value,
+    path: &str,
+) -> Vec<String> {
+    for keyword in schema.as_object().unwrap().keys() {
+        assert!(
+            SCHEMA_KEYWORDS.contains(&keyword.as_str()),
+            "{} isn't supported",
+            keyword
+        );
+    }
+    let mut errors = Vec::new();
+    let mut nested = Vec::new();
+    let mut error = |message: String| errors.push(format!("{}: {}", path, message));
+    if let Some(reference) = schema["$ref"].as_str() {
+        let name = reference.trim_start_matches("#/definitions/");
+        let definition = &root["definitions"][name];
+        assert!(definition.is_object(), "{} doesn't resolve", reference);
+        nested.extend(schema_errors(root, definition, value, path));
+    }
+    let types: Vec<&str> = match &schema["type"] {
+        serde_json::Value::Array(types) => types.iter().filter_map(|t| t.as_str()).collect(),
+        serde_json::Value::String(name) => vec![name],
+        _ => Vec::new(),
+    };
+    if !types.is_empty() && !types.iter().any(|name| has_type(value, name)) {
+        error(format!("{} is not of type {}", value, types.join(" or ")));
+    }
+    if let Some(values) = schema["enum"].as_array() {
+        if !values.contains(value) {
+            error(format!("{} is not one of {:?}", value, values));
+        }
+    }
+    if let Some(constant) = schema.get("const") {
+        if value != constant {
+            error(format!("{} is not {}", value, constant));
+        }
+    }
+    if let Some(n) = value.as_f64() {
+        if schema["minimum"]
+            .as_f64()
+            .is_some_and(|minimum| n < minimum)
+        {
+            error(format!("{} is less than {}", n, schema["minimum"]));
+        }
+        if schema["maximum"]
+            .as_f64()
+            .is_some_and(|maximum| n > maximum)
+        {
+            error(format!("{} is more than {}", n, schema["maximum"]));
+        }
+    }
+    if let (Some(text), Some(pattern)) = (value.as_str(), schema["pattern"].as_str()) {
+        if !regex::Regex::new(pattern).unwrap().is_match(text) {
+            error(format!("{:?} doesn't match {:?}", text, pattern));
+        }
+    }
+    if let Some(items) = value.as_array() {
+        if schema["minItems"]
+            .as_u64()
+            .is_some_and(|min| (items.len() as u64) < min)
+        {
+            error(format!("has fewer than {} items", schema["minItems"]));
+        }
+        if let Some(items_schema) = schema.get("items") {
+            for (i, item) in items.iter().enumerate() {
+                let item_path = format!("{}[{}]", path, i);
+                nested.extend(schema_errors(root, items_schema, item, &item_path));
+            }
+        }
+    }
+    if let Some(object) = value.as_object() {
+        if schema["minProperties"]
+            .as_u64()
+            .is_some_and(|min| (object.len() as u64) < min)
+        {
+            error(format!("has fewer than {} keys", schema["minProperties"]));
+        }
+        for key in schema["required"].as_array().into_iter().flatten() {
+            if !object.contains_key(key.as_str().unwrap()) {
+                error(format!("is missing {}", key));
+            }
+        }
+        for (key, item) in object {
+            let item_path = format!("{}.{}", path, key);
+            match (
+                schema["properties"].get(key),
+                &schema["additionalProperties"],
+            ) {
+                (Some(property), _) => {
+                    nested.extend(schema_errors(root, property, item, &item_path))
+                }
+                (None, serde_json::Value::Bool(false)) => error(format!("{} is unknown", key)),
+                (None, additional) if additional.is_object() => {
+                    nested.extend(schema_errors(root, additional, item, &item_path))
+                }
+                (None, _) => {}
+            }
+        }
+    }
+    let valid = |schema: &serde_json::Value| schema_errors(root, schema, value, path).is_empty();
+    if let Some(schemas) = schema["anyOf"].as_array() {
+        if !schemas.iter().any(valid) {
+            error(format!("{} matches none of the allowed schemas", value));
+        }
+    }
+    if let Some(schemas) = schema["oneOf"].as_array() {
+        if schemas.iter().filter(|schema| valid(schema)).count() != 1 {
+            error(format!("{} doesn't match exactly one schema", value));
+        }
+    }
+    if let Some(not) = schema.get("not") {
+        if valid(not) {
+            error(format!("{}", not["description"]));
+        }
+    }
+    errors.extend(nested);
+    errors
+}
+
+fn refs<'a>(schema: &'a serde_json::Value, found: &mut Vec<&'a str>) {
+    match schema {
+        serde_json::Value::Object(object) => {
+            for (key, value) in object {
+                match (key.as_str(), value.as_str()) {
+                    ("$ref", Some(reference)) => found.push(reference),
+                    _ => refs(value, found),
+                }
+            }
+        }
+        serde_json::Value::Array(items) => items.iter().for_each(|item| refs(item, found)),
+        _ => {}
+    }
+}
+
+#[test]
+#[serial]
+fn schema() {
+    let output = run!("--schema").output().unwrap();
+    assert!(output.status.success());
+    let schema: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let properties = schema["definitions"]["config"]["properties"]
+        .as_object()
+        .unwrap();
+    for key in [
+        "run",
+        "env",
+        "variants",
+        "benchmarks",
+        "wait_for",
+        "run_stdio",
+    ] {
+        assert!(properties.contains_key(key), "{} is missing", key);
+    }
+    let mut references = Vec::new();
+    refs(&schema, &mut references);
+    for reference in references {
+        let name = reference.trim_start_matches("#/definitions/");
+        assert!(
+            schema["definitions"].get(name).is_some(),
+            "{} doesn't resolve",
+            reference
+        );
+    }
+
+    let mut files = Vec::new();
+    config_files(std::path::Path::new("./examples"), &mut files);
+    files.sort();
+    for path in files {
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let mut config: serde_json::Value = serde_yaml::from_str(&contents).unwrap();
+        if path.ends_with("package.json") {
+            config = config["sirun"].take();
+        }
+        let errors = schema_errors(&schema, &schema, &config, "");
+        // These are examples of configs sirun rejects or warns about.
+        let invalid = ["unknown-key.json", "unknown-key-strict.json"];
+        if invalid.iter().any(|name| path.ends_with(name)) {
+            assert!(!errors.is_empty(), "{} should not validate", path.display());
+        } else {
+            assert!(errors.is_empty(), "{}: {:?}", path.display(), errors);
+        }
+    }
+    for (config, error) in [
+        (
+            serde_json::json!({"run": "true", "iterations": "10"}),
+            ".iterations: \"10\" is not of type integer",
+        ),
+        (
+            serde_json::json!({"run": "true", "iterations": 0}),
+            ".iterations: 0 is less than 1",
+        ),
+        (
+            serde_json::json!({"run": ""}),
+            ".run: \"\" matches none of the allowed schemas",
+        ),
+        (
+            serde_json::json!({"run": "true", "assertions": {"wall.time": {"max": "fast"}}}),
+            ".assertions.wall.time.max: \"fast\" is not of type number",
+        ),
+        (
+            serde_json::json!({"run": "true", "variants": {"slow": {"iterations": "10"}}}),
+            ".variants: ",
+        ),
+        (
+            serde_json::json!({"run": "true", "iterations": 10, "duration": 5}),
+            ": \"'iterations' and 'duration' can't be used together.\"",
+        ),
+    ] {
+        let errors = schema_errors(&schema, &schema, &config, "");
+        assert!(
+            errors.iter().any(|e| e.starts_with(error)),
+            "{}: {:?}",
+            config,
+            errors
+        );
+    }
+}
+
+#[test]
+#[serial]
+fn error_paths() {
+    run!("-")
+        .write_stdin("run: 'true'\nvariants:\n  big:\n    env:\n      ITERS: [1]\n")
+        .args(["--variant", "big"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "'variants.big.env.ITERS' must be a string, number, boolean or null",
+        ));
+    run!("-")
+        .write_stdin("run: 'true'\nbenchmarks:\n  a:\n    wait_for: [{port: 70000}]\n")
+        .args(["--benchmark", "a"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "'benchmarks.a.wait_for[0].port' must be a valid port number",
+        ));
+    run!("-")
+        .write_stdin("run: 'true'\nenv: [\n")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("invalid config in stdin: "))
+        .stderr(predicate::str::contains("at line 3 column 1"));
+    run!("-")
+        .write_stdin("variants:\n  small: {iterations: 2}\n")
+        .args(["--variant", "small"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "'run' must be provided, but neither the config nor variant 'small' sets it",
+        ));
+}
+
+#[test]
+#[serial]
+fn cpu_affinity() {
+    if std::env::consts::OS != "linux" {
+        return;
+    }
+    run!("./examples/cpu-affinity.yml")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("Cpus_allowed_list:\t0\n5\n"))
+        .stdout(predicate::str::contains("\"cpu_affinity\":[0.0]"))
+        .stdout(predicate::str::contains("\"nice\":5.0"));
+    run!("-")
+        .write_stdin("run: 'true'\ncpu_affinity: [100000]\n")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "'cpu_affinity' has CPU 100000, but only CPUs 0 to",
+        ));
+    run!("-")
+        .write_stdin("run: 'true'\nnice: 20\n")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "'nice' must be an integer from -20 to 19",
+        ));
+}
+
+#[test]
+#[serial]
+fn env_serialization_is_stable() {
+    let serialize = || {
+        run!("examples/env-order.json")
+            .arg("--dry-run")
+            .output()
+            .unwrap()
+            .stdout
+    };
+    let first = serialize();
+    assert!(String::from_utf8_lossy(&first).contains("ALPHA"));
+    for _ in 0..5 {
+        assert_eq!(first, serialize());
+    }
+}
+
+#[test]
+#[serial]
+fn stdio_targets() {
+    let log = "target/stdio-setup.log";
+    let _ = std::fs::remove_file(log);
+    run!("./examples/stdio.json")
+        .env("SIRUN_NO_STDIO", "1")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("run output\n"))
+        .stdout(predicate::str::contains("setup output").not())
+        .stdout(predicate::str::contains("teardown output").not());
+    let log = std::fs::read_to_string(log).unwrap();
+    assert_eq!(
+        log,
+        "==> setup (iteration 0) <==\nsetup output\n\
+         ==> setup (iteration 1) <==\nsetup output\n\
+         ==> setup (iteration 2) <==\nsetup output\n"
+    );
+
+    run!("-")
+        .write_stdin("run: 'true'\nrun_stdio: console\n")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "'run_stdio' must be 'inherit', 'null' or 'file:<path>'",
+        ));
+}
+
+#[test]
+#[serial]
+fn failure_stderr() {
+    run!("./examples/failing-stderr.json")
+        .env("SIRUN_NO_STDIO", "1")
+        .assert()
+        .code(3)
+        .stdout(predicate::str::contains("progress").not())
+        .stderr(predicate::str::contains("Test exited with code 3"))
+        .stderr(predicate::str::contains(
+            "Last 16 bytes of stderr:\nsomething broke\n",
+        ));
+}
+
+#[test]
+#[serial]
+fn shell() {
+    run!("./examples/shell.json")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("SETUP RAN\n"))
+        .stdout(predicate::str::contains("PIPED\n"))
+        .stdout(predicate::str::contains("teardown | not piped\n"));
+}
+
+#[test]
+#[serial]
+fn argv_commands() {
+    run!("./examples/argv.json")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("setup: it's set up\n"))
+        .stdout(predicate::str::contains("arg: a \"quoted\" arg with spaces\n"));
+    run!("./examples/argv.yml")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("arg: it's\n"));
+    run!("-")
+        .write_stdin("run: [bash, 3]\n")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "'run' must be an array of strings, but has a non-string element",
+        ));
+    run!("-")
+        .write_stdin("run: {bash: 3}\n")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "'run' must be a string or an array of strings",
+        ));
+}
+
+#[test]
+#[serial]
+fn cwd() {
+    let root = std::env::current_dir().unwrap();
+    let examples = root.join("examples");
+    run!(examples.join("cwd.json"))
+        .current_dir("/")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(format!("{}\n", root.display())))
+        .stdout(predicate::str::contains(format!("{}\n", examples.display())));
+    run!("-")
+        .write_stdin("run: pwd\ncwd: does-not-exist\n")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "'cwd' directory ./does-not-exist does not exist",
+        ));
+}
+
+#[test]
+#[serial]
+fn paths_relative_to_config() {
+    run!("../examples/relative-paths.json")
+        .current_dir("tests")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("hello from hello.sh with setup\n"))
+        .stdout(predicate::str::contains("hello from hello.sh with run\n"));
+    run!("-")
+        .write_stdin("run: 'true'\npaths_relative_to_config: true\nsetup: ''\n")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("'setup' must not be empty"));
+}
+
+#[test]
+#[serial]
+fn setup_steps() {
+    run!("./examples/setup-steps.yml")
+        .arg("--variant")
+        .arg("ok")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "step one\nSTEP TWO\nstep three\nrun\nteardown one\nteardown two\n",
+        ));
+    run!("./examples/setup-steps.yml")
+        .arg("--variant")
+        .arg("killed")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "setup step 2 of 2 script was terminated by signal 9",
+        ));
+}
+
+#[test]
+#[serial]
+fn multiple_services() {
+    let _ = std::fs::remove_dir_all("target/services");
+    std::fs::create_dir_all("target/services").unwrap();
+    run!("./examples/services.yml")
+        .arg("--variant")
+        .arg("ok")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("both services running\n"));
+    run!("./examples/services.yml")
+        .arg("--variant")
+        .arg("missing")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "cannot start service [\"./does-not-exist\"]",
+        ));
+}
+
+#[test]
+#[serial]
+fn env_remove() {
+    let run = |variant| {
+        run!("./examples/env-remove.yml")
+            .arg("--variant")
+            .arg(variant)
+            .env("INHERITED_A", "a")
+            .env("INHERITED_B", "b")
+            .assert()
+            .success()
+    };
+    run("remove").stdout(predicate::str::contains("a=unset b=b kept=kept\n"));
+    run("clear").stdout(predicate::str::contains("a=unset b=unset kept=kept\n"));
+    run("restore").stdout(predicate::str::contains("a=restored b=b kept=kept\n"));
+}
+
+#[test]
+#[serial]
+fn env_scalars() {
+    run!("./examples/env-scalars.yml")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("text 1000 -5 1.0 0.25 true false\n"))
+        .stdout(predicate::str::contains("12 7 -5 2.5 0.25 false false\n"));
+    run!("-")
+        .write_stdin("run: 'true'\nenv:\n  LIST: [1, 2]\n")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "'env.LIST' must be a string, number, boolean or null",
+        ));
+}
+
+#[test]
+#[serial]
+fn extends() {
+    run!("./examples/extends/middle.yml")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "shared=base overridden=middle level=middle variant=base\n",
+        ));
+    run!("./examples/extends/bench.yml")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "shared=base overridden=middle level=bench variant=one\n",
+        ))
+        .stdout(predicate::str::contains(
+            "shared=base overridden=middle level=bench variant=two\n",
+        ))
+        .stdout(predicate::str::contains("variant=base").not());
+    let dir = std::fs::canonicalize("examples/extends").unwrap();
+    run!("./examples/extends/cycle-a.yml")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(format!(
+            "config files extend each other in a cycle: {0}/cycle-a.yml -> {0}/cycle-b.yml -> {0}/cycle-a.yml",
+            dir.display()
+        )));
+}
+
+#[test]
+#[serial]
+fn package_json() {
+    run!("./examples/package/package.json")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("from package.json fast\n"))
+        .stdout(predicate::str::contains("from package.json slow\n"))
+        .stdout(predicate::str::contains("\"name\":\"from-package\""));
+    run!("-")
+        .arg("--package")
+        .arg("--variant")
+        .arg("slow")
+        .write_stdin(std::fs::read("examples/package/package.json").unwrap())
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("from package.json slow\n"));
+    run!("./examples/simple.json")
+        .arg("--package")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("./examples/simple.json has no 'sirun' field"));
+}
+
+#[test]
+#[serial]
+fn matrix() {
+    run!("./examples/matrix.yml")
+        .arg("--list-variants")
+        .assert()
+        .success()
+        .stdout(
+            "version=1.x,payload=small\nversion=1.x,payload=large\n\
+             version=2.x,payload=small\nversion=2.x,payload=large\n\
+             version=3.x,payload=small\nversion=3.x,payload=large\n",
+        );
+    run!("./examples/matrix.yml")
+        .arg("--variant")
+        .arg("version=2.x,payload=large")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("version=2.x size=100 mode=large\n"))
+        .stdout(predicate::str::contains("\"variant\":\"version=2.x,payload=large\""));
+    run!("./examples/matrix.yml")
+        .env("SIRUN_NO_STDIO", "1")
+        .assert()
+        .success()
+        .stdout(predicate::function(|out: &str| out.lines().count() == 6));
+    run!("./examples/matrix.yml")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("version=3.x size=1 mode=default\n"));
+}
+
+#[test]
+#[serial]
+fn variant_filter() {
+    run!("./examples/matrix.yml")
+        .args(["--list-variants", "--only", "*large", "--skip", "version=1*"])
+        .assert()
+        .success()
+        .stdout("version=2.x,payload=large\nversion=3.x,payload=large\n");
+    run!("./examples/variants.json")
+        .args(["--list-variants", "--skip", "0"])
+        .assert()
+        .success()
+        .stdout("1\n");
+    run!("./examples/matrix.yml")
+        .env("SIRUN_VARIANT_FILTER", "version=3.x,*")
+        .env("SIRUN_NO_STDIO", "1")
+        .assert()
+        .success()
+        .stdout(predicate::function(|out: &str| {
+            out.lines().count() == 2 && out.lines().all(|line| line.contains("version=3.x"))
+        }));
+    run!("./examples/variants.json")
+        .args(["--only", "nope"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "no variants match the filter; available variants are: 0, 1",
+        ));
+}
+
+#[test]
+#[serial]
+fn interleave() {
+    let _ = std::fs::remove_file("target/interleave.log");
+    run!("./examples/interleave.yml")
+        .assert()
+        .success()
+        .stdout(predicate::function(|out: &str| {
+            let lines: Vec<_> = out
+                .lines()
+                .map(|line| serde_yaml::from_str::<serde_yaml::Value>(line).unwrap())
+                .collect();
+            let counts: Vec<_> = lines
+                .iter()
+                .map(|line| {
+                    let variant = line["variant"].as_str().unwrap().to_owned();
+                    (variant, line["iterations"].as_sequence().unwrap().len())
+                })
+                .collect();
+            counts == [("a".into(), 3), ("b".into(), 3), ("c".into(), 1)]
+        }));
+    let log = std::fs::read_to_string("target/interleave.log").unwrap();
+    assert_eq!(log, "a\nb\nc\na\nb\na\nb\n");
+}
+
+#[test]
+#[serial]
+fn parallel_variants() {
+    let _ = std::fs::remove_dir_all("target/parallel");
+    std::fs::create_dir_all("target/parallel").unwrap();
+    run!("./examples/parallel.yml")
+        .args(["--jobs", "2"])
+        .env("SIRUN_NO_STDIO", "1")
+        .assert()
+        .success()
+        .stdout(predicate::function(|out: &str| {
+            let mut variants: Vec<_> = out
+                .lines()
+                .map(|line| {
+                    let val = serde_yaml::from_str::<serde_yaml::Value>(line).unwrap();
+                    val["variant"].as_str().unwrap().to_owned()
+                })
+                .collect();
+            variants.sort();
+            variants == ["a", "b"]
+        }));
+    run!("./examples/parallel.yml")
+        .args(["--jobs", "0"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("--jobs must be an integer >=1"));
 }